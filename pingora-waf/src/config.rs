@@ -1,6 +1,8 @@
 use serde::Deserialize;
 use std::path::{Path, PathBuf};
 
+use crate::waf::rules::compiler::RulesSource;
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct AppConfig {
     pub metrics_listen: Option<String>,
@@ -13,10 +15,236 @@ pub struct AppConfig {
 
     pub upstream_config_path: PathBuf,
     pub upstream_hot_reload_secs: Option<u64>,
+    /// Idle upstream connections kept for reuse. Pingora's keepalive pool is shared by all
+    /// tenants and sized at startup; per tenant/endpoint, `connection.max_idle_conns` caps a
+    /// tenant's share and `connection.keepalive: false` opts out of it.
+    /// Default: 128
+    pub upstream_max_idle_conns: Option<usize>,
+
+    /// Legacy single rules file, loaded as the `default` ruleset.
+    pub rules_path: Option<PathBuf>,
+    /// Directory of named rulesets (`<name>.yaml`), selected per policy by `waf.ruleset`.
+    /// Takes precedence over `rules_path`.
+    pub rules_dir: Option<PathBuf>,
+    pub rules_hot_reload_secs: Option<u64>,
 
-    pub rules_path: PathBuf,
     pub policy: PolicyConfig,
     pub tls: TlsConfig,
+
+    /// JS challenge served for `challenge` decisions.
+    #[serde(default)]
+    pub challenge: ChallengeConfig,
+
+    /// CC limiter table bounds.
+    #[serde(default)]
+    pub cc: CcConfig,
+
+    /// Real client address behind trusted load balancers.
+    #[serde(default)]
+    pub client_ip: ClientIpConfig,
+
+    /// HAProxy PROXY protocol (v1/v2) in front of the HTTP / HTTPS listeners.
+    #[serde(default)]
+    pub proxy_protocol: ProxyProtocolConfig,
+
+    /// Local MaxMind databases for `country_in` / `asn_in` and the `country` / `asn` CC keys.
+    #[serde(default)]
+    pub geoip: GeoIpConfig,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct GeoIpConfig {
+    /// GeoLite2-Country / GeoIP2-Country (or City) `.mmdb`
+    pub country_db: Option<PathBuf>,
+    /// GeoLite2-ASN `.mmdb`
+    pub asn_db: Option<PathBuf>,
+    /// How often the files are checked for changes. Default: 60
+    pub hot_reload_secs: Option<u64>,
+}
+
+impl GeoIpConfig {
+    pub fn hot_reload_secs(&self) -> u64 {
+        self.hot_reload_secs.unwrap_or(60)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct ProxyProtocolConfig {
+    /// Default: off
+    pub http: Option<ProxyProtocolMode>,
+    /// Default: off
+    pub https: Option<ProxyProtocolMode>,
+    /// Sources (IPs / CIDRs) allowed to send a PROXY header; required unless both listeners are off.
+    #[serde(default)]
+    pub trusted_sources: Vec<String>,
+    /// Time allowed for the header to arrive. Default: 3000
+    pub header_timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolMode {
+    /// Listener is served by pingora directly.
+    #[default]
+    Off,
+    /// Header is parsed when present; connections without one use the TCP peer.
+    Optional,
+    /// Connections without a header are closed.
+    Required,
+}
+
+impl ProxyProtocolConfig {
+    pub fn http(&self) -> ProxyProtocolMode {
+        self.http.unwrap_or_default()
+    }
+
+    pub fn https(&self) -> ProxyProtocolMode {
+        self.https.unwrap_or_default()
+    }
+
+    pub fn header_timeout_ms(&self) -> u64 {
+        self.header_timeout_ms.unwrap_or(3000)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct ClientIpConfig {
+    /// Peers (IPs / CIDRs) whose forwarding headers are believed. Empty: always use the TCP peer.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// Default: x-forwarded-for
+    pub header: Option<ClientIpHeader>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientIpHeader {
+    /// Right-most address that isn't a trusted proxy.
+    XForwardedFor,
+    /// Single address set by the trusted proxy.
+    XRealIp,
+    /// RFC 7239 `for=`, walked like X-Forwarded-For.
+    Forwarded,
+}
+
+impl ClientIpConfig {
+    pub fn header(&self) -> ClientIpHeader {
+        self.header.unwrap_or(ClientIpHeader::XForwardedFor)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct CcConfig {
+    /// Hard cap on tracked keys; new keys evict the least recently seen ones,
+    /// keeping active bans as long as possible. Default: 1000000
+    pub max_entries: Option<usize>,
+    /// Keys not seen for this long are dropped (active bans are kept). Default: 600
+    pub idle_secs: Option<u64>,
+    /// Default: 10
+    pub prune_interval_secs: Option<u64>,
+    /// Snapshot file for active bans (and optionally counters), restored at startup.
+    /// Unset: state is kept in memory only.
+    pub state_path: Option<PathBuf>,
+    /// Default: 30
+    pub snapshot_interval_secs: Option<u64>,
+    /// Also save window counters / buckets, not just active bans. Default: false
+    pub snapshot_counters: Option<bool>,
+    /// Where counters are shared between instances. Default: memory (not shared)
+    #[serde(default)]
+    pub backend: CcBackendConfig,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct CcBackendConfig {
+    #[serde(rename = "type", default)]
+    pub kind: CcBackendType,
+    /// redis: `host:port` (a `redis://` prefix is accepted). Default: 127.0.0.1:6379
+    pub addr: Option<String>,
+    pub password: Option<String>,
+    pub db: Option<u32>,
+    /// Default: aegis:cc:
+    pub key_prefix: Option<String>,
+    /// How often local hits / bans are pushed and peer hits pulled. Default: 200
+    pub sync_interval_ms: Option<u64>,
+    /// Per sync round, including (re)connect. Default: 1000
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CcBackendType {
+    #[default]
+    Memory,
+    Redis,
+}
+
+impl CcBackendConfig {
+    pub fn addr(&self) -> String {
+        let a = self.addr.as_deref().unwrap_or("127.0.0.1:6379");
+        a.strip_prefix("redis://").unwrap_or(a).trim_end_matches('/').to_string()
+    }
+
+    pub fn key_prefix(&self) -> String {
+        self.key_prefix.clone().unwrap_or_else(|| "aegis:cc:".to_string())
+    }
+
+    pub fn sync_interval_ms(&self) -> u64 {
+        self.sync_interval_ms.unwrap_or(200)
+    }
+
+    pub fn timeout_ms(&self) -> u64 {
+        self.timeout_ms.unwrap_or(1000)
+    }
+}
+
+impl CcConfig {
+    pub fn max_entries(&self) -> usize {
+        self.max_entries.unwrap_or(crate::policy::cc::CC_DEFAULT_MAX_ENTRIES)
+    }
+
+    pub fn idle_secs(&self) -> u64 {
+        self.idle_secs.unwrap_or(600)
+    }
+
+    pub fn prune_interval_secs(&self) -> u64 {
+        self.prune_interval_secs.unwrap_or(10)
+    }
+
+    pub fn snapshot_interval_secs(&self) -> u64 {
+        self.snapshot_interval_secs.unwrap_or(30)
+    }
+
+    pub fn snapshot_counters(&self) -> bool {
+        self.snapshot_counters.unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct ChallengeConfig {
+    /// HMAC key for nonces and clearance cookies. Share it across instances behind one
+    /// load balancer; when unset a random per-process key is used.
+    pub secret: Option<String>,
+    /// Clearance cookie lifetime. Default: 1800
+    pub ttl_secs: Option<u64>,
+    /// Default: aegis_clearance
+    pub cookie_name: Option<String>,
+    /// Endpoint the challenge page calls back; answered by the proxy itself.
+    /// Default: /.aegis/challenge
+    pub callback_path: Option<String>,
+}
+
+impl ChallengeConfig {
+    pub fn ttl_secs(&self) -> u64 {
+        self.ttl_secs.unwrap_or(1800)
+    }
+
+    pub fn cookie_name(&self) -> String {
+        self.cookie_name.clone().unwrap_or_else(|| "aegis_clearance".to_string())
+    }
+
+    pub fn callback_path(&self) -> String {
+        self.callback_path.clone().unwrap_or_else(|| "/.aegis/challenge".to_string())
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -31,6 +259,9 @@ pub struct TlsConfig {
 pub struct PolicyConfig {
     pub domain_map_path: PathBuf,
     pub policies_dir: PathBuf,
+    /// Named IP lists (`<name>.txt`, one IP / CIDR per line) for `ip_in_list`;
+    /// hot reloaded on the policy interval.
+    pub lists_dir: Option<PathBuf>,
     pub hot_reload_secs: Option<u64>,
 }
 
@@ -63,6 +294,18 @@ impl AppConfig {
         self.upstream_hot_reload_secs.unwrap_or(3)
     }
 
+    pub fn rules_hot_reload_interval_secs(&self) -> u64 {
+        self.rules_hot_reload_secs.unwrap_or(3)
+    }
+
+    pub fn rules_source(&self) -> anyhow::Result<RulesSource> {
+        match (&self.rules_dir, &self.rules_path) {
+            (Some(dir), _) => Ok(RulesSource::Dir(dir.clone())),
+            (None, Some(path)) => Ok(RulesSource::File(path.clone())),
+            (None, None) => anyhow::bail!("either rules_dir or rules_path must be set"),
+        }
+    }
+
     pub fn mtls_required(&self) -> bool {
         self.tls.mtls.unwrap_or(false)
     }
@@ -80,11 +323,28 @@ impl AppConfig {
         }
 
         self.upstream_config_path = resolve_path(base_dir, &self.upstream_config_path);
-        self.rules_path = resolve_path(base_dir, &self.rules_path);
+        if let Some(p) = &self.rules_path {
+            self.rules_path = Some(resolve_path(base_dir, p));
+        }
+        if let Some(p) = &self.rules_dir {
+            self.rules_dir = Some(resolve_path(base_dir, p));
+        }
 
         self.tls.certs_dir = resolve_path(base_dir, &self.tls.certs_dir);
         self.policy.domain_map_path = resolve_path(base_dir, &self.policy.domain_map_path);
         self.policy.policies_dir = resolve_path(base_dir, &self.policy.policies_dir);
+        if let Some(p) = &self.policy.lists_dir {
+            self.policy.lists_dir = Some(resolve_path(base_dir, p));
+        }
+        if let Some(p) = &self.cc.state_path {
+            self.cc.state_path = Some(resolve_path(base_dir, p));
+        }
+        if let Some(p) = &self.geoip.country_db {
+            self.geoip.country_db = Some(resolve_path(base_dir, p));
+        }
+        if let Some(p) = &self.geoip.asn_db {
+            self.geoip.asn_db = Some(resolve_path(base_dir, p));
+        }
     }
 }
 
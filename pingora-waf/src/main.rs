@@ -3,6 +3,7 @@ use crate::upstream::router::UpstreamRouter;
 use crate::upstream::types::UpstreamConfigFile;
 use clap::Parser;
 use pingora::prelude::*;
+use pingora::server::configuration::ServerConf;
 use pingora_proxy::http_proxy_service;
 use std::fs;
 use std::path::{Path, PathBuf};
@@ -71,10 +72,15 @@ fn main() -> anyhow::Result<()> {
     // Upstream router
     let upstream_bytes = std::fs::read(&cfg.upstream_config_path)?;
     let upstream_cfg: UpstreamConfigFile = serde_yaml::from_slice(&upstream_bytes)?;
-    let router = UpstreamRouter::new(upstream_cfg)?;
+    let router = UpstreamRouter::new(upstream_cfg, upstream::health::HealthTable::default())?;
     let upstream_mgr = UpstreamManager::new(router);
 
-    let mut my_server = Server::new(None)?;
+    // same defaults as Server::new(None), plus our overrides
+    let mut server_conf = ServerConf::new().ok_or_else(|| anyhow::anyhow!("pingora server conf generation failed"))?;
+    if let Some(n) = cfg.upstream_max_idle_conns {
+        server_conf.upstream_keepalive_pool_size = n;
+    }
+    let mut my_server = Server::new_with_opt_and_conf(None, server_conf);
     my_server.bootstrap();
 
     let metrics_listen = cfg
@@ -99,17 +105,109 @@ fn main() -> anyhow::Result<()> {
     );
     my_server.add_service(updater_upstream);
 
+    // Background: active health checks (tenants with health_check in upstream.yaml)
+    let health_checker = background_service(
+        "upstream-health",
+        upstream::health::HealthChecker::new(upstream_mgr.clone()),
+    );
+    my_server.add_service(health_checker);
+
+    // WAF rulesets (must be loaded before policies bind to them)
+    let rules_src = cfg.rules_source()?;
+    let rulesets = waf::rules::compiler::compile_source(&rules_src)?;
+    let engine = waf::engine::WafEngine::new(rulesets);
+
+    // Background: rule hot reload
+    let updater_rule = background_service(
+        "rule-updater",
+        waf::update::RuleUpdater::new(
+            engine.clone(),
+            rules_src,
+            Duration::from_secs(cfg.rules_hot_reload_interval_secs()),
+        ),
+    );
+    my_server.add_service(updater_rule);
+
     // domain_map + policies hot reload
+    let (cc_backend, cc_syncer) = policy::cc_backend::build(&cfg.cc.backend)?;
+    if let Some(syncer) = cc_syncer {
+        my_server.add_service(background_service("cc-redis-syncer", syncer));
+    }
+    let cc_limiter = std::sync::Arc::new(policy::cc::CcLimiter::new(cfg.cc.max_entries(), cc_backend));
+    if let Some(path) = &cfg.cc.state_path {
+        // a bad snapshot only loses CC state, it must not keep the dataplane down
+        match policy::cc_persist::load(&cc_limiter, path) {
+            Ok(n) => tracing::info!(entries = n, path = %path.display(), "cc state restored"),
+            Err(e) => tracing::warn!("cc state restore failed: {:#}", e),
+        }
+    }
+    // Named IP lists (must be loaded before policies reference them)
+    let ip_lists = policy::iplist::IpLists::default();
+    if let Some(dir) = &cfg.policy.lists_dir {
+        let n = ip_lists.reload_dir(dir)?;
+        tracing::info!(lists = n, "ip lists loaded");
+
+        let updater_lists = background_service(
+            "ip-list-updater",
+            policy::update::IpListUpdater::new(
+                ip_lists.clone(),
+                dir.clone(),
+                Duration::from_secs(cfg.policy_hot_reload_interval_secs()),
+            ),
+        );
+        my_server.add_service(updater_lists);
+    }
+
+    // GeoIP / ASN databases for country / asn matching and CC keys
+    let geo = policy::geoip::GeoIp::load(&cfg.geoip);
+    if geo.is_enabled() {
+        let updater_geoip = background_service(
+            "geoip-updater",
+            policy::update::GeoIpUpdater::new(geo.clone(), Duration::from_secs(cfg.geoip.hot_reload_secs().max(1))),
+        );
+        my_server.add_service(updater_geoip);
+    }
+
     let policy_state = policy::manager::PolicyManager::load_from_files(
         &cfg.policy.domain_map_path,
         &cfg.policy.policies_dir,
+        &engine,
+        &ip_lists,
+        cc_limiter,
     )?;
     let policy_mgr = policy::manager::PolicyManager::new(policy_state);
 
+    // Background: CC limiter idle-key pruning + table gauges
+    let cc_pruner = background_service(
+        "cc-pruner",
+        policy::cc_prune::CcPruner::new(
+            policy_mgr.clone(),
+            Duration::from_secs(cfg.cc.idle_secs()),
+            Duration::from_secs(cfg.cc.prune_interval_secs().max(1)),
+        ),
+    );
+    my_server.add_service(cc_pruner);
+
+    // Background: CC state snapshots (periodic + on graceful shutdown)
+    if let Some(path) = &cfg.cc.state_path {
+        let cc_persister = background_service(
+            "cc-persister",
+            policy::cc_persist::CcPersister::new(
+                policy_mgr.clone(),
+                path.clone(),
+                cfg.cc.snapshot_counters(),
+                Duration::from_secs(cfg.cc.snapshot_interval_secs().max(1)),
+            ),
+        );
+        my_server.add_service(cc_persister);
+    }
+
     let updater_domain = background_service(
         "domain-map-updater",
         policy::update::DomainMapUpdater::new(
             policy_mgr.clone(),
+            engine.clone(),
+            ip_lists.clone(),
             cfg.policy.domain_map_path.clone(),
             cfg.policy.policies_dir.clone(),
             Duration::from_secs(cfg.policy_hot_reload_interval_secs()),
@@ -121,6 +219,8 @@ fn main() -> anyhow::Result<()> {
         "policies-updater",
         policy::update::PolicyDirUpdater::new(
             policy_mgr.clone(),
+            engine.clone(),
+            ip_lists.clone(),
             cfg.policy.domain_map_path.clone(),
             cfg.policy.policies_dir.clone(),
             Duration::from_secs(cfg.policy_hot_reload_interval_secs()),
@@ -128,27 +228,24 @@ fn main() -> anyhow::Result<()> {
     );
     my_server.add_service(updater_policies);
 
-    // WAF engine + proxy
-    let ruleset = waf::rules::compiler::compile_from_file(&cfg.rules_path)?;
-    let engine = waf::engine::WafEngine::new(ruleset);
+    // Proxy
+    let challenger = policy::challenge::Challenger::new(&cfg.challenge)?;
+    let nonce_pruner = background_service(
+        "challenge-nonce-pruner",
+        policy::challenge::NoncePruner::new(challenger.clone()),
+    );
+    my_server.add_service(nonce_pruner);
+    let mut pp_relays = server::proxy_protocol::ProxyProtocolRelays::new(&cfg.proxy_protocol)?;
     let proxy = server::proxy::WafProxy::new(
-        engine.clone(),
+        engine,
         upstream_mgr.clone(),
         policy_mgr.clone(),
+        challenger,
+        server::realip::RealIpResolver::new(&cfg.client_ip, pp_relays.conns())?,
+        geo,
         obs,
     );
 
-    // Background: rule hot reload
-    let updater_rule = background_service(
-        "rule-updater",
-        waf::update::RuleUpdater::new(
-            engine,
-            cfg.rules_path.clone(),
-            std::time::Duration::from_secs(3),
-        ),
-    );
-    my_server.add_service(updater_rule);
-
     let mut svc = http_proxy_service(&my_server.configuration, proxy);
 
     // HTTP + HTTPS listeners (SNI cert hot reload)
@@ -163,10 +260,17 @@ fn main() -> anyhow::Result<()> {
     );
     my_server.add_service(cert_updater);
 
-    server::listener::add_http_listener(&mut svc, &cfg);
-    server::listener::add_https_listener(&mut svc, &cfg, cert_store)?;
+    server::listener::add_http_listener(&mut svc, &cfg, &mut pp_relays)?;
+    server::listener::add_https_listener(&mut svc, &cfg, cert_store, &mut pp_relays)?;
+
+    // PROXY protocol relays in front of pingora's loopback listeners
+    let (relays, prebound) = pp_relays.into_relays();
+    for relay in relays {
+        let name = format!("proxy-protocol-{}", relay.name());
+        my_server.add_service(background_service(&name, relay));
+    }
 
-    my_server.add_service(svc);
+    my_server.add_service(prebound.serve(svc));
 
     my_server.run_forever();
 }
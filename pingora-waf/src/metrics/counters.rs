@@ -1,7 +1,7 @@
 use once_cell::sync::Lazy;
 use prometheus::{
-    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
-    HistogramVec, IntCounterVec, IntGaugeVec,
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
 };
 
 pub static REQ_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
@@ -60,6 +60,67 @@ pub static CC_HITS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
         .expect("register aegis_cc_hits_total")
 });
 
+pub static CC_ENTRIES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "aegis_cc_entries",
+        "Keys tracked by the CC limiter"
+    )
+        .expect("register aegis_cc_entries")
+});
+
+pub static CC_BANNED_ENTRIES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "aegis_cc_banned_entries",
+        "CC limiter keys currently banned"
+    )
+        .expect("register aegis_cc_banned_entries")
+});
+
+pub static CC_EVICTIONS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "aegis_cc_evictions_total",
+        "CC limiter keys dropped, by reason (idle|capacity)",
+        &["reason"]
+    )
+        .expect("register aegis_cc_evictions_total")
+});
+
+pub static CC_BACKEND_SYNC_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "aegis_cc_backend_sync_total",
+        "CC shared backend sync rounds, by result (ok|error)",
+        &["result"]
+    )
+        .expect("register aegis_cc_backend_sync_total")
+});
+
+pub static UPSTREAM_HEALTHY: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "aegis_upstream_healthy",
+        "Active health check state per upstream endpoint (1 healthy, 0 unhealthy)",
+        &["tenant", "endpoint"]
+    )
+        .expect("register aegis_upstream_healthy")
+});
+
+pub static UPSTREAM_EJECTIONS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "aegis_upstream_ejections_total",
+        "Upstream endpoints ejected by passive outlier detection",
+        &["tenant", "endpoint"]
+    )
+        .expect("register aegis_upstream_ejections_total")
+});
+
+pub static PROXY_PROTOCOL_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "aegis_proxy_protocol_connections_total",
+        "Connections on PROXY protocol listeners, by result (proxied|local|direct|rejected)",
+        &["listener", "result"]
+    )
+        .expect("register aegis_proxy_protocol_connections_total")
+});
+
 #[inline]
 pub fn on_req_start(host: &str) {
     REQ_TOTAL.with_label_values(&[host]).inc();
@@ -83,3 +144,42 @@ pub fn inc_decision(source: &str, kind: &str, rule_id: &str) {
 pub fn inc_cc_hit(rule_id: &str) {
     CC_HITS_TOTAL.with_label_values(&[rule_id]).inc();
 }
+
+#[inline]
+pub fn add_cc_evictions(reason: &str, n: u64) {
+    CC_EVICTIONS_TOTAL.with_label_values(&[reason]).inc_by(n);
+}
+
+#[inline]
+pub fn set_cc_entries(entries: usize, banned: usize) {
+    CC_ENTRIES.set(entries as i64);
+    CC_BANNED_ENTRIES.set(banned as i64);
+}
+
+#[inline]
+pub fn set_upstream_health(tenant: &str, endpoint: &str, healthy: bool) {
+    UPSTREAM_HEALTHY.with_label_values(&[tenant, endpoint]).set(healthy as i64);
+}
+
+pub fn remove_upstream_health(tenant: &str, endpoint: &str) {
+    let _ = UPSTREAM_HEALTHY.remove_label_values(&[tenant, endpoint]);
+}
+
+#[inline]
+pub fn inc_upstream_ejection(tenant: &str, endpoint: &str) {
+    UPSTREAM_EJECTIONS_TOTAL.with_label_values(&[tenant, endpoint]).inc();
+}
+
+pub fn remove_upstream_ejections(tenant: &str, endpoint: &str) {
+    let _ = UPSTREAM_EJECTIONS_TOTAL.remove_label_values(&[tenant, endpoint]);
+}
+
+#[inline]
+pub fn inc_proxy_protocol(listener: &str, result: &str) {
+    PROXY_PROTOCOL_TOTAL.with_label_values(&[listener, result]).inc();
+}
+
+#[inline]
+pub fn inc_cc_backend_sync(result: &str) {
+    CC_BACKEND_SYNC_TOTAL.with_label_values(&[result]).inc();
+}
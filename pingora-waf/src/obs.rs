@@ -7,6 +7,8 @@ use std::path::{Path, PathBuf};
 use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
 use tracing_subscriber::fmt::MakeWriter;
 
+use crate::waf::anomaly::RuleScore;
+
 /// Two JSONL sinks: access + events
 ///
 /// Active files:
@@ -38,8 +40,15 @@ pub struct AccessLog {
     pub path: String,
     pub status: u16,
     pub latency_ms: u64,
+    /// Final upstream (the last one tried when retries happened).
     pub upstream: Option<String>,
+    /// Upstream attempts, including retries; None when no upstream was contacted.
+    pub upstream_attempts: Option<u32>,
     pub client_ip: Option<String>,
+    /// TCP peer (load balancer) when it differs from `client_ip`.
+    pub peer_ip: Option<String>,
+    pub country: Option<String>,
+    pub asn: Option<u32>,
     pub user_agent: Option<String>,
     pub error: Option<String>,
 }
@@ -59,6 +68,12 @@ pub struct SecurityEvent {
     pub path: String,
     pub method: String,
     pub client_ip: Option<String>,
+    pub peer_ip: Option<String>,
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    /// Anomaly mode only: accumulated score and every rule that contributed to it.
+    pub anomaly_score: Option<u32>,
+    pub matched_rules: Vec<RuleScore>,
 }
 
 /// Internal serialized form for access lines (injects dataset)
@@ -77,7 +92,15 @@ struct AccessLine<'a> {
     status: u16,
     latency_ms: u64,
     upstream: &'a Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream_attempts: Option<u32>,
     client_ip: &'a Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer_ip: &'a Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    country: &'a Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    asn: Option<u32>,
     user_agent: &'a Option<String>,
     error: &'a Option<String>,
 }
@@ -100,6 +123,16 @@ struct EventLine<'a> {
     host: &'a str,
     path: &'a str,
     client_ip: &'a Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer_ip: &'a Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    country: &'a Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    asn: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    anomaly_score: Option<u32>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    matched_rules: &'a [RuleScore],
 }
 
 impl ObsSink {
@@ -144,7 +177,11 @@ impl ObsSink {
             status: rec.status,
             latency_ms: rec.latency_ms,
             upstream: &rec.upstream,
+            upstream_attempts: rec.upstream_attempts,
             client_ip: &rec.client_ip,
+            peer_ip: &rec.peer_ip,
+            country: &rec.country,
+            asn: rec.asn,
             user_agent: &rec.user_agent,
             error: &rec.error,
         };
@@ -173,6 +210,11 @@ impl ObsSink {
             host: &rec.host,
             path: &rec.path,
             client_ip: &rec.client_ip,
+            peer_ip: &rec.peer_ip,
+            country: &rec.country,
+            asn: rec.asn,
+            anomaly_score: rec.anomaly_score,
+            matched_rules: &rec.matched_rules,
         };
 
         if let Ok(json) = serde_json::to_string(&line) {
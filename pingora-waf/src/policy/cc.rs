@@ -1,12 +1,33 @@
 use dashmap::DashMap;
+use std::collections::{BinaryHeap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use super::cc_backend::CcBackend;
+use super::cc_persist::{CcSnapshot, CcSnapshotEntry, Clock, CounterSnapshot};
+use crate::waf::ratelimit::token_bucket::TokenBucket;
+
+/// CC 计数算法（从 cc.algorithm 编译得到）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CcAlgo {
+    /// 固定窗口：窗口到期整体清零，窗口边界处最多放过 2 倍请求
+    FixedWindow,
+    /// 滑动窗口日志：记录窗口内每个请求的时间，精确但每个 key 最多保存 max_requests 个时间点
+    SlidingWindowLog,
+    /// 滑动窗口计数：上一窗口计数按重叠比例加权 + 当前窗口计数，近似滑动窗口
+    SlidingWindowCounter,
+    /// 令牌桶：容量 burst，每秒补充 refill_per_sec 个
+    TokenBucket { burst: u64, refill_per_sec: f64 },
+}
+
 /// CC 限速参数（从 action.cc 编译/组装得到）
 #[derive(Debug, Clone, Copy)]
 pub struct CcParams {
     pub window_secs: u64,
     pub max_requests: u64,
     pub block_secs: u64,
+    pub algorithm: CcAlgo,
 }
 
 /// 命中（处于封禁或刚刚触发封禁）
@@ -15,30 +36,212 @@ pub struct CcHit {
     pub reason: String,
 }
 
-/// 单个 key 的状态
-#[derive(Debug, Clone)]
+/// 单个 key 的状态：封禁状态机各算法共用，计数部分按算法区分
+#[derive(Debug)]
 struct Entry {
-    window_start: Instant,
-    count: u64,
+    counter: Counter,
     blocked_until: Option<Instant>,
     last_seen: Instant,
+    /// 累计触发封禁次数，用于上调 pow 挑战难度
+    strikes: u32,
+}
+
+#[derive(Debug)]
+enum Counter {
+    Fixed { window_start: Instant, count: u64 },
+    Log(VecDeque<Instant>),
+    Sliding { window_start: Instant, prev: u64, cur: u64 },
+    Bucket(TokenBucket),
+}
+
+impl Counter {
+    fn new(algo: CcAlgo, now: Instant) -> Self {
+        match algo {
+            CcAlgo::FixedWindow => Counter::Fixed { window_start: now, count: 0 },
+            CcAlgo::SlidingWindowLog => Counter::Log(VecDeque::new()),
+            CcAlgo::SlidingWindowCounter => Counter::Sliding { window_start: now, prev: 0, cur: 0 },
+            CcAlgo::TokenBucket { burst, refill_per_sec } => Counter::Bucket(TokenBucket::new(burst.max(1), refill_per_sec, now)),
+        }
+    }
+
+    /// 策略热更新后对齐当前算法：换了算法则旧状态作废；
+    /// 令牌桶只改了 burst / refill_per_sec 时沿用剩余令牌（按新容量截断）
+    fn reconfigure(&mut self, algo: CcAlgo, now: Instant) {
+        match (&*self, algo) {
+            (Counter::Fixed { .. }, CcAlgo::FixedWindow)
+            | (Counter::Log(_), CcAlgo::SlidingWindowLog)
+            | (Counter::Sliding { .. }, CcAlgo::SlidingWindowCounter) => {}
+            (Counter::Bucket(b), CcAlgo::TokenBucket { burst, refill_per_sec }) => {
+                let (cap, refill, tokens, last) = b.state();
+                let burst = burst.max(1);
+                if cap != burst || refill != refill_per_sec {
+                    *self = Counter::Bucket(TokenBucket::with_state(burst, refill_per_sec, tokens, last));
+                }
+            }
+            _ => *self = Counter::new(algo, now),
+        }
+    }
+
+    /// 窗口滚动 / 过期时间点出队
+    fn roll(&mut self, now: Instant, window: Duration) {
+        match self {
+            Counter::Fixed { window_start, count } => {
+                if now.duration_since(*window_start) >= window {
+                    *window_start = now;
+                    *count = 0;
+                }
+            }
+            Counter::Log(log) => {
+                while log.front().is_some_and(|t| now.duration_since(*t) >= window) {
+                    log.pop_front();
+                }
+            }
+            Counter::Sliding { window_start, prev, cur } => {
+                let elapsed = now.duration_since(*window_start);
+                if elapsed >= window * 2 {
+                    *window_start = now;
+                    *prev = 0;
+                    *cur = 0;
+                } else if elapsed >= window {
+                    *window_start += window;
+                    *prev = *cur;
+                    *cur = 0;
+                }
+            }
+            Counter::Bucket(_) => {}
+        }
+    }
+
+    /// 并入其它节点的命中（视为刚刚发生），不判定超限
+    fn absorb(&mut self, n: u64, now: Instant, window: Duration, max_req: u64) {
+        self.roll(now, window);
+        match self {
+            Counter::Fixed { count, .. } => *count += n,
+            // 超过 max_requests 的时间点没有意义，不多存
+            Counter::Log(log) => {
+                let room = (max_req as usize).saturating_sub(log.len());
+                log.extend(std::iter::repeat_n(now, (n as usize).min(room)));
+            }
+            Counter::Sliding { cur, .. } => *cur += n,
+            Counter::Bucket(b) => b.consume(n, now),
+        }
+    }
+
+    /// 记一次请求，返回 false 表示超限
+    fn admit(&mut self, now: Instant, window: Duration, max_req: u64) -> bool {
+        self.roll(now, window);
+        match self {
+            Counter::Fixed { count, .. } => {
+                *count += 1;
+                *count <= max_req
+            }
+
+            Counter::Log(log) => {
+                if log.len() as u64 >= max_req {
+                    return false;
+                }
+                log.push_back(now);
+                true
+            }
+
+            Counter::Sliding { window_start, prev, cur } => {
+                // 上一窗口还与滑动窗口重叠的比例
+                let overlap = 1.0 - now.duration_since(*window_start).as_secs_f64() / window.as_secs_f64();
+                let estimate = *prev as f64 * overlap.max(0.0) + *cur as f64;
+                if estimate + 1.0 > max_req as f64 {
+                    return false;
+                }
+                *cur += 1;
+                true
+            }
+
+            Counter::Bucket(b) => b.allow(1, now),
+        }
+    }
 }
 
+impl Counter {
+    fn snapshot(&self, c: Clock) -> CounterSnapshot {
+        match self {
+            Counter::Fixed { window_start, count } => {
+                CounterSnapshot::FixedWindow { window_start_ms: c.to_ms(*window_start), count: *count }
+            }
+            Counter::Log(log) => CounterSnapshot::SlidingWindowLog { times_ms: log.iter().map(|t| c.to_ms(*t)).collect() },
+            Counter::Sliding { window_start, prev, cur } => {
+                CounterSnapshot::SlidingWindowCounter { window_start_ms: c.to_ms(*window_start), prev: *prev, cur: *cur }
+            }
+            Counter::Bucket(b) => {
+                let (cap, refill_per_sec, tokens, last) = b.state();
+                CounterSnapshot::TokenBucket { cap, refill_per_sec, tokens, last_ms: c.to_ms(last) }
+            }
+        }
+    }
+
+    /// 时间点早于本进程可表示范围的按“现在”处理
+    fn restore(s: CounterSnapshot, c: Clock) -> Self {
+        let at = |ms: i64| c.to_instant(ms).unwrap_or(c.now);
+        match s {
+            CounterSnapshot::FixedWindow { window_start_ms, count } => Counter::Fixed { window_start: at(window_start_ms), count },
+            CounterSnapshot::SlidingWindowLog { times_ms } => Counter::Log(times_ms.into_iter().map(at).collect()),
+            CounterSnapshot::SlidingWindowCounter { window_start_ms, prev, cur } => {
+                Counter::Sliding { window_start: at(window_start_ms), prev, cur }
+            }
+            CounterSnapshot::TokenBucket { cap, refill_per_sec, tokens, last_ms } => {
+                Counter::Bucket(TokenBucket::with_state(cap, refill_per_sec, tokens, at(last_ms)))
+            }
+        }
+    }
+}
+
+impl Entry {
+    fn is_banned(&self, now: Instant) -> bool {
+        self.blocked_until.is_some_and(|u| now < u)
+    }
+}
+
+/// 默认表项上限
+pub const CC_DEFAULT_MAX_ENTRIES: usize = 1_000_000;
+
+/// 淘汰时检查的样本数：一批的这么多倍，且不少于 EVICT_SAMPLE_MIN（小表直接全部检查）
+const EVICT_SAMPLE_FACTOR: usize = 4;
+const EVICT_SAMPLE_MIN: usize = 1024;
+
 /// 只做状态机 + 计数
 /// key 建议外部带 rule_id：
 ///   key = format!("rule={rule_id}|{key_body}")
+///
+/// 表项数有硬上限：插入新 key 时表满则按近似 LRU 批量淘汰（在一段轮转的样本里挑），
+/// 优先保留处于封禁期的 key，伪造 UA / 随机 cookie 构造的海量 key 只会挤掉不活跃的计数。
 #[derive(Debug)]
 pub struct CcLimiter {
     table: DashMap<String, Entry>,
+    max_entries: usize,
+    /// table.len() 要逐个分片加锁，这里单独计数
+    len: AtomicUsize,
+    /// 同一时间只有一个请求做淘汰，其余请求不等待（期间可能短暂超出上限）
+    evicting: AtomicBool,
+    /// 下一次淘汰从表的第几项开始取样，每次往后轮转
+    evict_cursor: AtomicUsize,
+    /// 与其它节点交换命中 / 封禁增量；计数本身始终在 table 里，后端不替代它
+    backend: Arc<dyn CcBackend>,
 }
 
 impl CcLimiter {
-    pub fn new() -> Self {
+    pub fn new(max_entries: usize, backend: Arc<dyn CcBackend>) -> Self {
         Self {
             table: DashMap::new(),
+            max_entries: max_entries.max(1),
+            len: AtomicUsize::new(0),
+            evicting: AtomicBool::new(false),
+            evict_cursor: AtomicUsize::new(0),
+            backend,
         }
     }
 
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
     /// 返回 Some 表示“应当认为触发 CC”（调用方再决定 block/challenge/log）
     pub fn check(&self, rule_id: &str, key_body: &str, p: CcParams) -> Option<CcHit> {
         let now = Instant::now();
@@ -47,71 +250,437 @@ impl CcLimiter {
         let block_for = Duration::from_secs(p.block_secs.max(1));
         let max_req = p.max_requests.max(1);
 
-        let k = format!("rule={}|{}", rule_id, key_body);
+        let k = table_key(rule_id, key_body);
+        // 新 key 且表满：先淘汰（不能在持有分片锁时做）
+        if self.len() >= self.max_entries && !self.table.contains_key(&k) {
+            self.evict_for_insert();
+        }
+
+        let remote = self.backend.take_remote(&k);
 
-        // 读出或初始化
-        let mut e = self.table.get(&k).map(|v| v.clone()).unwrap_or(Entry {
-            window_start: now,
-            count: 0,
-            blocked_until: None,
-            last_seen: now,
+        // 读出或初始化（持有该 key 的分片写锁直到返回）
+        let mut e = self.table.entry(k.clone()).or_insert_with(|| {
+            self.len.fetch_add(1, Ordering::Relaxed);
+            Entry {
+                counter: Counter::new(p.algorithm, now),
+                blocked_until: None,
+                last_seen: now,
+                strikes: 0,
+            }
         });
 
         e.last_seen = now;
+        e.counter.reconfigure(p.algorithm, now);
+
+        // 其它节点的封禁 / 命中
+        if let Some(until) = remote.ban_until_ms.and_then(|ms| Clock::now().to_instant(ms)) {
+            if until > now && !e.is_banned(now) {
+                e.blocked_until = Some(until);
+                e.strikes = e.strikes.saturating_add(1);
+            }
+        }
+        if remote.hits > 0 && !e.is_banned(now) {
+            e.counter.absorb(remote.hits, now, window, max_req);
+        }
 
         // 如果处于封禁期
         if let Some(until) = e.blocked_until {
             if now < until {
-                self.table.insert(k, e);
                 return Some(CcHit {
                     reason: format!("cc blocked: {}", rule_id),
                 });
             } else {
-                // 封禁过期，重置窗口
+                // 封禁过期，重新计数
                 e.blocked_until = None;
-                e.window_start = now;
-                e.count = 0;
+                e.counter = Counter::new(p.algorithm, now);
             }
         }
 
-        // 窗口滚动
-        if now.duration_since(e.window_start) >= window {
-            e.window_start = now;
-            e.count = 0;
+        // 计数 + 判断
+        self.backend.record_hit(&k, p.window_secs);
+        if !e.counter.admit(now, window, max_req) {
+            e.blocked_until = Some(now + block_for);
+            e.strikes = e.strikes.saturating_add(1);
+            let c = Clock::now();
+            self.backend.record_ban(&k, c.to_ms(now + block_for));
+            let reason = match p.algorithm {
+                CcAlgo::TokenBucket { burst, refill_per_sec } => {
+                    format!("cc exceeded burst {} at {}/s on {}", burst, refill_per_sec, rule_id)
+                }
+                _ => format!("cc exceeded {} req/{}s on {}", max_req, p.window_secs, rule_id),
+            };
+            return Some(CcHit { reason });
         }
 
-        // 计数 + 判断
-        e.count += 1;
+        None
+    }
 
-        if e.count > max_req {
-            e.blocked_until = Some(now + block_for);
-            self.table.insert(k, e);
-            return Some(CcHit {
-                reason: format!(
-                    "cc exceeded {} req/{}s on {}",
-                    max_req, p.window_secs, rule_id
-                ),
+    /// key 累计被封禁的次数（不计数、不改变状态）
+    pub fn strikes(&self, rule_id: &str, key_body: &str) -> u32 {
+        self.table.get(&table_key(rule_id, key_body)).map(|e| e.strikes).unwrap_or(0)
+    }
+
+    /// 导出状态：处于封禁期的 key 总是导出；counters 为 true 时也导出其余 key 的计数
+    pub fn snapshot(&self, counters: bool) -> CcSnapshot {
+        let c = Clock::now();
+        let mut entries = Vec::new();
+        for it in self.table.iter() {
+            let banned = it.is_banned(c.now);
+            if !banned && !counters {
+                continue;
+            }
+            entries.push(CcSnapshotEntry {
+                key: it.key().clone(),
+                blocked_until_ms: it.blocked_until.filter(|_| banned).map(|u| c.to_ms(u)),
+                last_seen_ms: c.to_ms(it.last_seen),
+                strikes: it.strikes,
+                counter: counters.then(|| it.counter.snapshot(c)),
             });
         }
+        CcSnapshot::new(c.now_ms, entries)
+    }
 
-        self.table.insert(k, e);
-        None
+    /// 导入快照（启动时调用），已过期的封禁丢弃，不超过表上限；返回导入的 key 数
+    pub fn restore(&self, snap: CcSnapshot) -> usize {
+        let c = Clock::now();
+        let mut n = 0;
+        for s in snap.entries {
+            if self.len() >= self.max_entries {
+                break;
+            }
+            let blocked_until = s.blocked_until_ms.filter(|ms| *ms > c.now_ms).and_then(|ms| c.to_instant(ms));
+            if blocked_until.is_none() && s.counter.is_none() {
+                continue;
+            }
+            // 只保存了封禁的 key 没有计数，先放一个空计数，首个请求按规则算法重建
+            let counter = match s.counter {
+                Some(cs) => Counter::restore(cs, c),
+                None => Counter::new(CcAlgo::FixedWindow, c.now),
+            };
+            let e = Entry {
+                counter,
+                blocked_until,
+                last_seen: c.to_instant(s.last_seen_ms).unwrap_or(c.now),
+                strikes: s.strikes,
+            };
+            if self.table.insert(s.key, e).is_none() {
+                self.len.fetch_add(1, Ordering::Relaxed);
+            }
+            n += 1;
+        }
+        n
     }
 
-    /// 可选：定期清理陈旧 key，避免 table 无限增长
-    /// 你可以在后台任务里每隔 N 秒调用一次
-    pub fn prune_older_than(&self, older_than: Duration) {
+    /// 清理超过 older_than 未访问的 key（处于封禁期的保留），由 CcPruner 定期调用
+    pub fn prune_older_than(&self, older_than: Duration) -> PruneStats {
         let now = Instant::now();
-        let mut remove_keys = Vec::new();
+        let mut st = PruneStats::default();
 
-        for it in self.table.iter() {
-            if now.duration_since(it.last_seen) > older_than {
-                remove_keys.push(it.key().clone());
+        self.table.retain(|_, e| {
+            let banned = e.is_banned(now);
+            if !banned && now.duration_since(e.last_seen) > older_than {
+                st.removed += 1;
+                return false;
+            }
+            st.banned += banned as usize;
+            true
+        });
+        self.len.fetch_sub(st.removed, Ordering::Relaxed);
+        st.entries = self.len();
+        st
+    }
+
+    /// 表满时淘汰一批（上限的 1/16）：不比较整张表，只在从上次位置接着取的一段样本里
+    /// 选出最久未访问的 key，封禁中的排在最后
+    fn evict_for_insert(&self) {
+        if self.evicting.swap(true, Ordering::Acquire) {
+            return;
+        }
+        let now = Instant::now();
+        let batch = (self.max_entries / 16).max(1);
+        let sample = (batch * EVICT_SAMPLE_FACTOR).max(EVICT_SAMPLE_MIN);
+        let len = self.len();
+        // 表在两次淘汰之间可能变小，剩下的不够一批就从头开始
+        let start = match self.evict_cursor.load(Ordering::Relaxed) {
+            c if c + batch > len => 0,
+            c => c,
+        };
+
+        // 大顶堆保存目前最该淘汰的 batch 个：堆顶是其中最值得保留的，遇到更该淘汰的就替换它
+        let mut heap: BinaryHeap<(bool, Instant, String)> = BinaryHeap::with_capacity(batch + 1);
+        let mut seen = 0;
+        for it in self.table.iter().skip(start).take(sample) {
+            seen += 1;
+            let rank = (it.is_banned(now), it.last_seen);
+            if heap.len() < batch {
+                heap.push((rank.0, rank.1, it.key().clone()));
+            } else if heap.peek().is_some_and(|top| rank < (top.0, top.1)) {
+                heap.pop();
+                heap.push((rank.0, rank.1, it.key().clone()));
             }
         }
 
-        for k in remove_keys {
-            self.table.remove(&k);
+        // 淘汰掉的项不再占序号，下一段样本从本段末尾减去淘汰数处开始；取到表尾则回到开头
+        let mut removed = 0;
+        for (_, _, k) in heap {
+            if self.table.remove(&k).is_some() {
+                removed += 1;
+            }
+        }
+        self.len.fetch_sub(removed, Ordering::Relaxed);
+        let next = if seen < sample { 0 } else { (start + seen).saturating_sub(removed) };
+        self.evict_cursor.store(next, Ordering::Relaxed);
+        crate::metrics::counters::add_cc_evictions("capacity", removed as u64);
+        self.evicting.store(false, Ordering::Release);
+    }
+}
+
+/// 一次清理的结果，用于更新指标
+#[derive(Debug, Default)]
+pub struct PruneStats {
+    pub removed: usize,
+    pub entries: usize,
+    pub banned: usize,
+}
+
+fn table_key(rule_id: &str, key_body: &str) -> String {
+    format!("rule={}|{}", rule_id, key_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(c: &Counter, clock: Clock) -> serde_json::Value {
+        serde_json::to_value(c.snapshot(clock)).unwrap()
+    }
+
+    #[test]
+    fn counters_survive_a_restart() {
+        let before = Clock::now();
+        // 重启后的进程：Instant 基准不同，墙钟过了 5 秒
+        let after = Clock { now: before.now + Duration::from_secs(3600), now_ms: before.now_ms + 5_000 };
+        let ago = |ms: u64| before.now - Duration::from_millis(ms);
+
+        let counters = [
+            Counter::Fixed { window_start: ago(2_000), count: 3 },
+            Counter::Log(VecDeque::from([ago(3_000), ago(1_000)])),
+            Counter::Sliding { window_start: ago(4_000), prev: 5, cur: 1 },
+            Counter::Bucket(TokenBucket::with_state(10, 2.5, 4.0, ago(500))),
+        ];
+        for c in counters {
+            let saved = c.snapshot(before);
+            let restored = Counter::restore(saved, after);
+            // 墙钟时间不变
+            assert_eq!(json(&restored, after), json(&c, before));
+        }
+
+        // 保存前 2 秒开始的窗口，重启后已经过了 7 秒
+        let saved = Counter::Fixed { window_start: ago(2_000), count: 3 }.snapshot(before);
+        let Counter::Fixed { window_start, count } = Counter::restore(saved, after) else {
+            panic!("algorithm changed");
+        };
+        assert_eq!((after.now.duration_since(window_start), count), (Duration::from_secs(7), 3));
+    }
+
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    /// 在 `t0 + at` 连续记 n 次请求，返回放行次数
+    fn admit_n(c: &mut Counter, t0: Instant, at: Duration, n: u64, max_req: u64) -> u64 {
+        (0..n).filter(|_| c.admit(t0 + at, WINDOW, max_req)).count() as u64
+    }
+
+    fn secs(s: f64) -> Duration {
+        Duration::from_secs_f64(s)
+    }
+
+    #[test]
+    fn fixed_window_admits_up_to_max_per_window() {
+        let t0 = Instant::now();
+        let mut c = Counter::new(CcAlgo::FixedWindow, t0);
+        assert_eq!(admit_n(&mut c, t0, secs(0.0), 3, 3), 3);
+        assert!(!c.admit(t0 + secs(9.9), WINDOW, 3));
+        // 窗口到期整体清零
+        assert_eq!(admit_n(&mut c, t0, secs(10.0), 4, 3), 3);
+    }
+
+    #[test]
+    fn sliding_log_frees_slots_as_requests_age_out() {
+        let t0 = Instant::now();
+        let mut c = Counter::new(CcAlgo::SlidingWindowLog, t0);
+        for at in [0.0, 1.0, 2.0] {
+            assert!(c.admit(t0 + secs(at), WINDOW, 3), "{at}");
+        }
+        assert!(!c.admit(t0 + secs(3.0), WINDOW, 3));
+        assert!(!c.admit(t0 + secs(9.9), WINDOW, 3));
+        // 第一个请求在 10s 时滑出窗口，只空出一个位置
+        assert!(c.admit(t0 + secs(10.0), WINDOW, 3));
+        assert!(!c.admit(t0 + secs(10.5), WINDOW, 3));
+        assert!(c.admit(t0 + secs(11.0), WINDOW, 3));
+        // 超限的请求不记录，不会延长封锁
+        let Counter::Log(log) = &c else { unreachable!() };
+        assert_eq!(log.len(), 3);
+    }
+
+    #[test]
+    fn sliding_counter_weights_the_previous_window() {
+        let t0 = Instant::now();
+        let mut c = Counter::new(CcAlgo::SlidingWindowCounter, t0);
+        assert_eq!(admit_n(&mut c, t0, secs(0.0), 11, 10), 10);
+        // 新窗口刚开始，上一窗口 10 次全部计入
+        assert!(!c.admit(t0 + secs(10.0), WINDOW, 10));
+        // 过了半个窗口：10 × 0.5 + 0，还能放 5 次
+        assert_eq!(admit_n(&mut c, t0, secs(15.0), 10, 10), 5);
+        // 再过四分之三个窗口：上一窗口（5 次）× 0.25 + 0
+        assert_eq!(admit_n(&mut c, t0, secs(27.5), 10, 10), 8);
+        // 隔了两个窗口以上，全部清零
+        assert_eq!(admit_n(&mut c, t0, secs(50.0), 11, 10), 10);
+    }
+
+    #[test]
+    fn token_bucket_refills_up_to_burst() {
+        let t0 = Instant::now();
+        let mut c = Counter::new(CcAlgo::TokenBucket { burst: 3, refill_per_sec: 2.0 }, t0);
+        assert_eq!(admit_n(&mut c, t0, secs(0.0), 5, 0), 3);
+        assert_eq!(admit_n(&mut c, t0, secs(0.5), 5, 0), 1);
+        assert_eq!(admit_n(&mut c, t0, secs(1.5), 5, 0), 2);
+        // 长时间空闲也只攒到 burst
+        assert_eq!(admit_n(&mut c, t0, secs(60.0), 5, 0), 3);
+        // 其它节点用掉的令牌
+        c.absorb(2, t0 + secs(61.0), WINDOW, 0);
+        assert_eq!(admit_n(&mut c, t0, secs(61.0), 5, 0), 0);
+    }
+
+    fn limiter(max_entries: usize) -> CcLimiter {
+        CcLimiter::new(max_entries, Arc::new(super::super::cc_backend::MemoryBackend))
+    }
+
+    /// 直接放入一个 `idle` 前访问过的 key，`banned` 时还在封禁期
+    fn put(l: &CcLimiter, key: &str, idle: Duration, banned: bool) {
+        let now = Instant::now();
+        let e = Entry {
+            counter: Counter::new(CcAlgo::FixedWindow, now),
+            blocked_until: banned.then(|| now + Duration::from_secs(600)),
+            last_seen: now - idle,
+            strikes: banned as u32,
+        };
+        assert!(l.table.insert(table_key("r", key), e).is_none());
+        l.len.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn keys(l: &CcLimiter) -> Vec<String> {
+        let mut v: Vec<String> = l.table.iter().map(|e| e.key().trim_start_matches("rule=r|").to_string()).collect();
+        v.sort();
+        v
+    }
+
+    fn request(l: &CcLimiter, key: &str) -> Option<CcHit> {
+        let p = CcParams { window_secs: 60, max_requests: 100, block_secs: 60, algorithm: CcAlgo::FixedWindow };
+        l.check("r", key, p)
+    }
+
+    #[test]
+    fn prune_drops_idle_entries_but_keeps_bans() {
+        let l = limiter(100);
+        put(&l, "idle", Duration::from_secs(120), false);
+        put(&l, "active", Duration::from_secs(1), false);
+        put(&l, "idle-banned", Duration::from_secs(120), true);
+        let st = l.prune_older_than(Duration::from_secs(60));
+        assert_eq!((st.removed, st.entries, st.banned), (1, 2, 1));
+        assert_eq!(keys(&l), ["active", "idle-banned"]);
+        assert_eq!(l.len(), 2);
+    }
+
+    #[test]
+    fn full_table_evicts_unbanned_keys_first() {
+        // 上限 32：一批淘汰 2 个
+        let l = limiter(32);
+        for i in 0..29 {
+            // 封禁中的 key 比其它的更久没有访问
+            put(&l, &format!("ban{i:02}"), Duration::from_secs(300 + i), true);
         }
+        put(&l, "old", Duration::from_secs(30), false);
+        put(&l, "older", Duration::from_secs(60), false);
+        put(&l, "recent", Duration::from_secs(1), false);
+
+        assert!(request(&l, "new").is_none());
+        let k = keys(&l);
+        assert_eq!(l.len(), 31);
+        assert_eq!(k.len(), 31);
+        assert!(k.contains(&"new".to_string()) && k.contains(&"recent".to_string()));
+        assert!(!k.contains(&"old".to_string()) && !k.contains(&"older".to_string()));
+        assert_eq!(k.iter().filter(|k| k.starts_with("ban")).count(), 29);
+
+        // 已有的 key 不触发淘汰
+        assert!(request(&l, "recent").is_none());
+        assert_eq!(l.len(), 31);
+    }
+
+    #[test]
+    fn full_table_of_bans_still_accepts_new_keys() {
+        let l = limiter(16);
+        for i in 0..16 {
+            put(&l, &format!("ban{i:02}"), Duration::from_secs(100 + i), true);
+        }
+        assert!(request(&l, "new").is_none());
+        let k = keys(&l);
+        assert_eq!((l.len(), k.len()), (16, 16));
+        assert!(k.contains(&"new".to_string()));
+        // 淘汰的是最久没访问的封禁 key
+        assert!(!k.contains(&"ban15".to_string()));
+        assert_eq!(l.strikes("r", "ban14"), 1);
+    }
+
+    #[test]
+    fn eviction_samples_rotate_through_the_table() {
+        // 上限 4096：一批 256，样本 1024
+        let l = limiter(4096);
+        for i in 0..4096 {
+            put(&l, &format!("k{i}"), Duration::from_secs(60), false);
+        }
+        let mut cursors = Vec::new();
+        for i in 0..8 * 256 {
+            assert!(request(&l, &format!("new{i}")).is_none());
+            let c = l.evict_cursor.load(Ordering::Relaxed);
+            if cursors.last() != Some(&c) {
+                cursors.push(c);
+            }
+            assert!(l.len() <= 4096);
+        }
+        // 每批只在样本里挑，下一批接着往后取；表尾不够一批时回到开头
+        assert_eq!(cursors[..6], [768, 1536, 2304, 3072, 3840, 0], "{cursors:?}");
+    }
+
+    #[test]
+    fn reload_keeps_or_resets_counters() {
+        let bucket = |burst, refill_per_sec| CcAlgo::TokenBucket { burst, refill_per_sec };
+        let tokens = |c: &Counter| match c {
+            Counter::Bucket(b) => (b.state().0, b.state().1, b.state().2),
+            _ => panic!("not a bucket: {c:?}"),
+        };
+        let t0 = Instant::now();
+        let mut c = Counter::new(bucket(10, 1.0), t0);
+        assert_eq!(admit_n(&mut c, t0, secs(0.0), 6, 0), 6);
+
+        // 参数不变：原样保留
+        c.reconfigure(bucket(10, 1.0), t0);
+        assert_eq!(tokens(&c), (10, 1.0, 4.0));
+        // 改了速率：沿用剩余令牌，之后按新速率补充
+        c.reconfigure(bucket(10, 4.0), t0);
+        assert_eq!(tokens(&c), (10, 4.0, 4.0));
+        assert_eq!(admit_n(&mut c, t0, secs(1.0), 10, 0), 8);
+        // 缩小容量：剩余令牌按新容量截断
+        let mut c = Counter::new(bucket(10, 1.0), t0);
+        c.reconfigure(bucket(3, 1.0), t0);
+        assert_eq!(tokens(&c), (3, 1.0, 3.0));
+        assert_eq!(admit_n(&mut c, t0, secs(0.0), 5, 0), 3);
+
+        // 换算法：旧状态作废
+        c.reconfigure(CcAlgo::SlidingWindowLog, t0);
+        assert!(matches!(&c, Counter::Log(l) if l.is_empty()));
+        assert_eq!(admit_n(&mut c, t0, secs(0.0), 3, 2), 2);
+        // 同一算法：计数保留
+        c.reconfigure(CcAlgo::SlidingWindowLog, t0 + secs(1.0));
+        assert!(!c.admit(t0 + secs(1.0), WINDOW, 2));
     }
 }
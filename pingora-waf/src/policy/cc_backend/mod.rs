@@ -0,0 +1,55 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::config::{CcBackendConfig, CcBackendType};
+
+pub mod redis;
+
+/// CC 计数在多个节点之间的同步通道，不是计数存储。
+///
+/// 计数、封禁状态机、持久化和淘汰都只在 `CcLimiter` 自己的 DashMap 上进行，不可替换；
+/// 后端只交换增量：把本地命中 / 封禁交出去，把其它节点的命中 / 封禁带回来，
+/// 由 limiter 在下一次检查该 key 时并入本地表。这样请求路径上的判定永远不等网络。
+/// 实现必须只做内存操作，网络 IO 放在自己的后台任务里。
+pub trait CcBackend: Send + Sync + Debug {
+    /// 本地计入一次请求（`window_secs` 用于远端按窗口分桶和过期）
+    fn record_hit(&self, key: &str, window_secs: u64);
+
+    /// 本地触发封禁，`until_ms` 为墙钟毫秒
+    fn record_ban(&self, key: &str, until_ms: i64);
+
+    /// 取走上次以来其它节点对该 key 的命中数和封禁到期时间（墙钟毫秒）
+    fn take_remote(&self, key: &str) -> RemoteUpdate;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteUpdate {
+    pub hits: u64,
+    pub ban_until_ms: Option<i64>,
+}
+
+/// 默认后端：不同步，各节点只按自己的 DashMap 计数
+#[derive(Debug, Default)]
+pub struct MemoryBackend;
+
+impl CcBackend for MemoryBackend {
+    fn record_hit(&self, _key: &str, _window_secs: u64) {}
+
+    fn record_ban(&self, _key: &str, _until_ms: i64) {}
+
+    fn take_remote(&self, _key: &str) -> RemoteUpdate {
+        RemoteUpdate::default()
+    }
+}
+
+/// 按配置构造后端；redis 后端还需要把返回的同步服务注册为后台服务
+pub fn build(cfg: &CcBackendConfig) -> anyhow::Result<(Arc<dyn CcBackend>, Option<redis::RedisSyncer>)> {
+    Ok(match cfg.kind {
+        CcBackendType::Memory => (Arc::new(MemoryBackend), None),
+        CcBackendType::Redis => {
+            let b = Arc::new(redis::RedisBackend::new(cfg)?);
+            let syncer = redis::RedisSyncer::new(b.clone(), cfg);
+            (b, Some(syncer))
+        }
+    })
+}
@@ -0,0 +1,531 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use pingora::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use super::{CcBackend, RemoteUpdate};
+use crate::config::CcBackendConfig;
+use crate::metrics::counters;
+
+/// Redis 协议后端：请求路径只写本地待同步表、读已拉回的远端增量，
+/// 由 RedisSyncer 周期性地批量 pipeline 到 Redis。
+///
+/// 远端布局（prefix 默认 `aegis:cc:`）：
+/// - `<prefix><key>:<窗口号>`：各节点 INCRBY 的命中数，窗口号 = Unix 秒 / window_secs，两个窗口后过期
+/// - `<prefix>ban:<key>`：封禁到期时间（墙钟毫秒），PX 到期自动删除
+///
+/// 待同步表每轮都会清空（Redis 不可用时这一批直接丢弃），远端增量过了所属窗口 / 封禁到期即淘汰，
+/// 两张表都不会随 Redis 故障或冷 key 无限增长。
+#[derive(Debug)]
+pub struct RedisBackend {
+    prefix: String,
+    pending: DashMap<String, Pending>,
+    remote: DashMap<String, Remote>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Pending {
+    hits: u64,
+    window_secs: u64,
+    /// 命中所属窗口号；跨窗口时旧窗口的命中作废
+    window_id: u64,
+    ban_until_ms: Option<i64>,
+}
+
+/// 拉回的远端增量和失效时间（墙钟毫秒）：命中到所属窗口结束，封禁到封禁到期
+#[derive(Debug, Clone, Copy, Default)]
+struct Remote {
+    update: RemoteUpdate,
+    expires_ms: i64,
+}
+
+impl Remote {
+    fn extend(&mut self, until_ms: i64) {
+        self.expires_ms = self.expires_ms.max(until_ms);
+    }
+}
+
+impl RedisBackend {
+    pub fn new(cfg: &CcBackendConfig) -> anyhow::Result<Self> {
+        if cfg.addr().is_empty() {
+            anyhow::bail!("cc redis backend needs addr");
+        }
+        Ok(Self {
+            prefix: cfg.key_prefix(),
+            pending: DashMap::new(),
+            remote: DashMap::new(),
+        })
+    }
+
+    /// 取走待同步的本地命中 / 封禁
+    fn drain_pending(&self) -> Vec<(String, Pending)> {
+        let mut out = Vec::new();
+        self.pending.retain(|k, v| {
+            out.push((k.clone(), *v));
+            false
+        });
+        out
+    }
+
+    /// 淘汰已失效的远端增量（对应 key 之后没再被请求过）
+    fn prune_remote(&self, now_ms: i64) {
+        self.remote.retain(|_, r| r.expires_ms > now_ms);
+    }
+}
+
+impl CcBackend for RedisBackend {
+    fn record_hit(&self, key: &str, window_secs: u64) {
+        let window_secs = window_secs.max(1);
+        let window_id = chrono::Utc::now().timestamp().max(0) as u64 / window_secs;
+        let mut p = self.pending.entry(key.to_string()).or_default();
+        if p.window_secs != window_secs || p.window_id != window_id {
+            p.hits = 0;
+        }
+        p.hits += 1;
+        p.window_secs = window_secs;
+        p.window_id = window_id;
+    }
+
+    fn record_ban(&self, key: &str, until_ms: i64) {
+        let mut p = self.pending.entry(key.to_string()).or_default();
+        p.ban_until_ms = Some(p.ban_until_ms.map_or(until_ms, |x| x.max(until_ms)));
+    }
+
+    fn take_remote(&self, key: &str) -> RemoteUpdate {
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        self.remote
+            .remove(key)
+            .map(|(_, r)| r)
+            .filter(|r| r.expires_ms > now_ms)
+            .map(|r| r.update)
+            .unwrap_or_default()
+    }
+}
+
+/// 同步任务里每个 key 当前窗口的账目：自己累计加了多少，已经见过多少其它节点的命中
+struct WindowState {
+    window_id: u64,
+    window_secs: u64,
+    own: u64,
+    seen_peer: u64,
+}
+
+/// 把 RedisBackend 的本地增量批量同步到 Redis，并拉回其它节点的增量。
+/// 连接失败 / 超时只影响共享：这一批丢弃，本地判定照常。
+pub struct RedisSyncer {
+    backend: Arc<RedisBackend>,
+    addr: String,
+    password: Option<String>,
+    db: u32,
+    interval: Duration,
+    timeout: Duration,
+}
+
+/// pipeline 里每条命令的用途，用于按顺序解析回复
+enum Expect {
+    Incr { key: String, hits: u64, window_id: u64, window_secs: u64 },
+    Ban { key: String },
+    Ignore,
+}
+
+impl RedisSyncer {
+    pub fn new(backend: Arc<RedisBackend>, cfg: &CcBackendConfig) -> Self {
+        Self {
+            backend,
+            addr: cfg.addr(),
+            password: cfg.password.clone(),
+            db: cfg.db.unwrap_or(0),
+            interval: Duration::from_millis(cfg.sync_interval_ms().max(10)),
+            timeout: Duration::from_millis(cfg.timeout_ms().max(10)),
+        }
+    }
+
+    async fn connect(&self) -> anyhow::Result<RespConn> {
+        let stream = TcpStream::connect(&self.addr)
+            .await
+            .with_context(|| format!("connect redis {} failed", self.addr))?;
+        stream.set_nodelay(true)?;
+        let mut conn = RespConn { stream: BufReader::new(stream) };
+
+        let mut init = Vec::new();
+        if let Some(pw) = &self.password {
+            init.push(cmd(&["AUTH", pw]));
+        }
+        if self.db != 0 {
+            init.push(cmd(&["SELECT", &self.db.to_string()]));
+        }
+        for r in conn.pipeline(&init).await? {
+            if let Reply::Error(e) = r {
+                anyhow::bail!("redis init failed: {}", e);
+            }
+        }
+        Ok(conn)
+    }
+
+    /// 一轮同步。待同步表先整体取走，连不上 / 超时这一批就丢弃，不会在 Redis 故障期间堆积；
+    /// 失败后 `conn` 置空，下一轮重连。
+    async fn sync_round(&self, conn: &mut Option<RespConn>, windows: &mut HashMap<String, WindowState>) -> anyhow::Result<()> {
+        let batch = self.backend.drain_pending();
+        self.backend.prune_remote(chrono::Utc::now().timestamp_millis());
+        let round = async {
+            if conn.is_none() {
+                *conn = Some(self.connect().await?);
+            }
+            match conn.as_mut() {
+                Some(c) => self.sync_once(c, batch, windows).await,
+                None => Ok(()),
+            }
+        };
+        let res = match tokio::time::timeout(self.timeout, round).await {
+            Ok(r) => r,
+            Err(_) => Err(anyhow::anyhow!("cc redis sync timed out")),
+        };
+        if res.is_err() {
+            *conn = None;
+        }
+        res
+    }
+
+    async fn sync_once(
+        &self,
+        conn: &mut RespConn,
+        batch: Vec<(String, Pending)>,
+        windows: &mut HashMap<String, WindowState>,
+    ) -> anyhow::Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let now_secs = (now_ms / 1000) as u64;
+        let prefix = &self.backend.prefix;
+
+        let mut cmds = Vec::with_capacity(batch.len() * 3);
+        let mut expect = Vec::with_capacity(batch.len() * 3);
+        for (key, p) in batch {
+            // 已经结束的窗口里的命中不再计入（否则会被记到当前窗口）
+            if p.hits > 0 && p.window_id == now_secs / p.window_secs {
+                let window_id = p.window_id;
+                let rkey = format!("{prefix}{key}:{window_id}");
+                cmds.push(cmd(&["INCRBY", &rkey, &p.hits.to_string()]));
+                expect.push(Expect::Incr { key: key.clone(), hits: p.hits, window_id, window_secs: p.window_secs });
+                cmds.push(cmd(&["PEXPIRE", &rkey, &(p.window_secs * 2000).to_string()]));
+                expect.push(Expect::Ignore);
+            }
+            let bkey = format!("{prefix}ban:{key}");
+            match p.ban_until_ms {
+                // 自己刚封禁的 key 不用再读回
+                Some(until) if until > now_ms => {
+                    cmds.push(cmd(&["SET", &bkey, &until.to_string(), "PX", &(until - now_ms).to_string()]));
+                    expect.push(Expect::Ignore);
+                }
+                Some(_) => {}
+                None => {
+                    cmds.push(cmd(&["GET", &bkey]));
+                    expect.push(Expect::Ban { key });
+                }
+            }
+        }
+
+        let replies = conn.pipeline(&cmds).await?;
+        for (exp, r) in expect.into_iter().zip(replies) {
+            match (exp, r) {
+                (Expect::Incr { key, hits, window_id, window_secs }, Reply::Int(total)) => {
+                    let st = windows.entry(key.clone()).or_insert(WindowState { window_id, window_secs, own: 0, seen_peer: 0 });
+                    if st.window_id != window_id {
+                        *st = WindowState { window_id, window_secs, own: 0, seen_peer: 0 };
+                    }
+                    st.own += hits;
+                    let peer = (total.max(0) as u64).saturating_sub(st.own);
+                    let delta = peer.saturating_sub(st.seen_peer);
+                    st.seen_peer = st.seen_peer.max(peer);
+                    if delta > 0 {
+                        let mut r = self.backend.remote.entry(key).or_default();
+                        r.update.hits += delta;
+                        r.extend(((window_id + 1) * window_secs * 1000) as i64);
+                    }
+                }
+                (Expect::Ban { key }, Reply::Bulk(Some(v))) => {
+                    let until = std::str::from_utf8(&v).ok().and_then(|s| s.parse::<i64>().ok());
+                    if let Some(until) = until.filter(|u| *u > now_ms) {
+                        let mut r = self.backend.remote.entry(key).or_default();
+                        r.update.ban_until_ms = Some(r.update.ban_until_ms.map_or(until, |x| x.max(until)));
+                        r.extend(until);
+                    }
+                }
+                (_, Reply::Error(e)) => tracing::warn!("cc redis command failed: {}", e),
+                _ => {}
+            }
+        }
+
+        // 过了当前窗口的账目不再需要
+        windows.retain(|_, st| now_secs / st.window_secs <= st.window_id);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BackgroundService for RedisSyncer {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut conn: Option<RespConn> = None;
+        let mut windows: HashMap<String, WindowState> = HashMap::new();
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    tracing::info!("cc redis syncer shutdown");
+                    return;
+                }
+                _ = ticker.tick() => {
+                    match self.sync_round(&mut conn, &mut windows).await {
+                        Ok(()) => counters::inc_cc_backend_sync("ok"),
+                        Err(e) => {
+                            // 下一轮重连；这一批本地增量已取走，不再重试
+                            counters::inc_cc_backend_sync("error");
+                            tracing::warn!("cc redis sync failed: {:#}", e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn cmd(args: &[&str]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for a in args {
+        out.extend_from_slice(format!("${}\r\n", a.len()).as_bytes());
+        out.extend_from_slice(a.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+#[derive(Debug)]
+enum Reply {
+    Simple,
+    Error(String),
+    Int(i64),
+    Bulk(Option<Vec<u8>>),
+    Array,
+}
+
+/// 最小 RESP2 客户端：只支持 pipeline 写命令、按顺序读回复
+struct RespConn {
+    stream: BufReader<TcpStream>,
+}
+
+impl RespConn {
+    async fn pipeline(&mut self, cmds: &[Vec<u8>]) -> anyhow::Result<Vec<Reply>> {
+        if cmds.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.stream.get_mut().write_all(&cmds.concat()).await?;
+        let mut out = Vec::with_capacity(cmds.len());
+        for _ in cmds {
+            out.push(self.read_reply().await?);
+        }
+        Ok(out)
+    }
+
+    async fn read_line(&mut self) -> anyhow::Result<String> {
+        let mut buf = Vec::new();
+        if self.stream.read_until(b'\n', &mut buf).await? == 0 {
+            anyhow::bail!("redis connection closed");
+        }
+        if !buf.ends_with(b"\r\n") {
+            anyhow::bail!("malformed redis reply");
+        }
+        buf.truncate(buf.len() - 2);
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    async fn read_reply(&mut self) -> anyhow::Result<Reply> {
+        let line = self.read_line().await?;
+        let (t, rest) = line.split_at(line.len().min(1));
+        Ok(match t {
+            "+" => Reply::Simple,
+            "-" => Reply::Error(rest.to_string()),
+            ":" => Reply::Int(rest.parse()?),
+            "$" => {
+                let n: i64 = rest.parse()?;
+                if n < 0 {
+                    Reply::Bulk(None)
+                } else {
+                    let mut v = vec![0u8; n as usize + 2];
+                    self.stream.read_exact(&mut v).await?;
+                    v.truncate(n as usize);
+                    Reply::Bulk(Some(v))
+                }
+            }
+            "*" => {
+                // 这里用到的命令都不返回数组，读掉即可
+                let n: i64 = rest.parse()?;
+                for _ in 0..n.max(0) {
+                    Box::pin(self.read_reply()).await?;
+                }
+                Reply::Array
+            }
+            _ => anyhow::bail!("unexpected redis reply: {}", line),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::config::CcBackendType;
+
+    type Store = Arc<Mutex<HashMap<String, String>>>;
+
+    /// 只实现同步用到的几条命令的 Redis 替身
+    async fn fake_redis() -> (String, Store) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let store = Store::default();
+        let st = store.clone();
+        tokio::spawn(async move {
+            while let Ok((sock, _)) = listener.accept().await {
+                let st = st.clone();
+                tokio::spawn(async move {
+                    let mut sock = BufReader::new(sock);
+                    while let Some(args) = read_cmd(&mut sock).await {
+                        let reply = {
+                            let mut m = st.lock().unwrap();
+                            match args[0].to_ascii_uppercase().as_str() {
+                                "INCRBY" => {
+                                    let v = m.get(&args[1]).map_or(0, |v| v.parse::<i64>().unwrap()) + args[2].parse::<i64>().unwrap();
+                                    m.insert(args[1].clone(), v.to_string());
+                                    format!(":{v}\r\n")
+                                }
+                                "PEXPIRE" => ":1\r\n".to_string(),
+                                "SET" => {
+                                    m.insert(args[1].clone(), args[2].clone());
+                                    "+OK\r\n".to_string()
+                                }
+                                "GET" => match m.get(&args[1]) {
+                                    Some(v) => format!("${}\r\n{v}\r\n", v.len()),
+                                    None => "$-1\r\n".to_string(),
+                                },
+                                _ => "+OK\r\n".to_string(),
+                            }
+                        };
+                        if sock.get_mut().write_all(reply.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (addr, store)
+    }
+
+    async fn read_cmd(sock: &mut BufReader<tokio::net::TcpStream>) -> Option<Vec<String>> {
+        let mut line = String::new();
+        sock.read_line(&mut line).await.ok().filter(|n| *n > 0)?;
+        let n: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+        let mut args = Vec::with_capacity(n);
+        for _ in 0..n {
+            line.clear();
+            sock.read_line(&mut line).await.ok()?;
+            let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+            let mut v = vec![0u8; len + 2];
+            sock.read_exact(&mut v).await.ok()?;
+            v.truncate(len);
+            args.push(String::from_utf8(v).ok()?);
+        }
+        Some(args)
+    }
+
+    fn node(addr: &str) -> (Arc<RedisBackend>, RedisSyncer) {
+        let cfg = CcBackendConfig {
+            kind: CcBackendType::Redis,
+            addr: Some(addr.to_string()),
+            password: None,
+            db: None,
+            key_prefix: None,
+            sync_interval_ms: None,
+            timeout_ms: Some(500),
+        };
+        let b = Arc::new(RedisBackend::new(&cfg).unwrap());
+        let s = RedisSyncer::new(b.clone(), &cfg);
+        (b, s)
+    }
+
+    #[tokio::test]
+    async fn shares_hits_and_bans_between_nodes() {
+        let (addr, _store) = fake_redis().await;
+        let (a, sa) = node(&addr);
+        let (b, sb) = node(&addr);
+        let (mut ca, mut cb) = (None, None);
+        let (mut wa, mut wb) = (HashMap::new(), HashMap::new());
+
+        for _ in 0..3 {
+            a.record_hit("r1|1.2.3.4", 3600);
+        }
+        let until = chrono::Utc::now().timestamp_millis() + 60_000;
+        a.record_ban("r1|5.6.7.8", until);
+        sa.sync_round(&mut ca, &mut wa).await.unwrap();
+
+        b.record_hit("r1|1.2.3.4", 3600);
+        b.record_hit("r1|5.6.7.8", 3600);
+        sb.sync_round(&mut cb, &mut wb).await.unwrap();
+
+        assert_eq!(b.take_remote("r1|1.2.3.4").hits, 3);
+        assert_eq!(b.take_remote("r1|5.6.7.8").ban_until_ms, Some(until));
+        // 取走后清空
+        assert_eq!(b.take_remote("r1|1.2.3.4"), RemoteUpdate::default());
+        assert!(a.pending.is_empty() && b.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn drops_hits_from_expired_windows() {
+        let (addr, store) = fake_redis().await;
+        let (a, sa) = node(&addr);
+        let now = chrono::Utc::now().timestamp() as u64;
+        a.pending.insert(
+            "r1|1.2.3.4".into(),
+            Pending { hits: 10, window_secs: 10, window_id: now / 10 - 2, ban_until_ms: None },
+        );
+        sa.sync_round(&mut None, &mut HashMap::new()).await.unwrap();
+        assert!(!store.lock().unwrap().keys().any(|k| k.starts_with("aegis:cc:r1|1.2.3.4:")));
+
+        // 过期的远端增量在下一轮被淘汰
+        a.remote.insert("r1|9.9.9.9".into(), Remote { update: RemoteUpdate { hits: 5, ban_until_ms: None }, expires_ms: 1 });
+        sa.sync_round(&mut None, &mut HashMap::new()).await.unwrap();
+        assert!(a.remote.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unreachable_redis_does_not_accumulate() {
+        // 绑定后立即关闭，得到一个没人监听的端口
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+        let (a, sa) = node(&addr);
+        let mut conn = None;
+        let mut windows = HashMap::new();
+        for _ in 0..3 {
+            for i in 0..100 {
+                a.record_hit(&format!("r1|10.0.0.{i}"), 60);
+            }
+            a.record_ban("r1|10.0.0.1", chrono::Utc::now().timestamp_millis() + 60_000);
+            assert!(sa.sync_round(&mut conn, &mut windows).await.is_err());
+            assert!(conn.is_none());
+            assert!(a.pending.is_empty());
+        }
+        assert_eq!(a.take_remote("r1|10.0.0.1"), RemoteUpdate::default());
+    }
+}
@@ -0,0 +1,263 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use pingora::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use serde::{Deserialize, Serialize};
+
+use super::cc::CcLimiter;
+use super::manager::PolicyManager;
+
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// CC 状态快照文件。时间一律存 Unix 毫秒：Instant 只在本进程内有意义，
+/// 重启后按墙钟换算回来，封禁到期时间不会因重启被重置或拉长。
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CcSnapshot {
+    pub version: u32,
+    pub saved_at_ms: i64,
+    pub entries: Vec<CcSnapshotEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CcSnapshotEntry {
+    /// limiter 内部 key（含 rule_id）
+    pub key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocked_until_ms: Option<i64>,
+    pub last_seen_ms: i64,
+    #[serde(default)]
+    pub strikes: u32,
+    /// 只在开启 snapshot_counters 时保存
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counter: Option<CounterSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "algo", rename_all = "snake_case")]
+pub enum CounterSnapshot {
+    FixedWindow { window_start_ms: i64, count: u64 },
+    SlidingWindowLog { times_ms: Vec<i64> },
+    SlidingWindowCounter { window_start_ms: i64, prev: u64, cur: u64 },
+    TokenBucket { cap: u64, refill_per_sec: f64, tokens: f64, last_ms: i64 },
+}
+
+/// Instant 与墙钟毫秒互换：以同一时刻取到的 (Instant, Unix 毫秒) 为基准
+#[derive(Clone, Copy)]
+pub(super) struct Clock {
+    pub now: Instant,
+    pub now_ms: i64,
+}
+
+impl Clock {
+    pub fn now() -> Self {
+        Self { now: Instant::now(), now_ms: chrono::Utc::now().timestamp_millis() }
+    }
+
+    pub fn to_ms(self, t: Instant) -> i64 {
+        if t <= self.now {
+            self.now_ms - self.now.duration_since(t).as_millis() as i64
+        } else {
+            self.now_ms + t.duration_since(self.now).as_millis() as i64
+        }
+    }
+
+    /// 早于进程可表示范围的时间取 None
+    pub fn to_instant(self, ms: i64) -> Option<Instant> {
+        let d = ms - self.now_ms;
+        if d >= 0 {
+            self.now.checked_add(Duration::from_millis(d as u64))
+        } else {
+            self.now.checked_sub(Duration::from_millis(d.unsigned_abs()))
+        }
+    }
+}
+
+/// 写快照：先写临时文件再 rename，进程中途退出不会留下半个文件
+pub fn save(cc: &CcLimiter, path: &Path, counters: bool) -> anyhow::Result<usize> {
+    let snap = cc.snapshot(counters);
+    let n = snap.entries.len();
+    let bytes = serde_json::to_vec(&snap)?;
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("create dir failed: {}", dir.display()))?;
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, bytes).with_context(|| format!("write cc state failed: {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("rename cc state failed: {}", path.display()))?;
+    Ok(n)
+}
+
+/// 启动时恢复；文件不存在不算错误
+pub fn load(cc: &CcLimiter, path: &Path) -> anyhow::Result<usize> {
+    let bytes = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).with_context(|| format!("read cc state failed: {}", path.display())),
+    };
+    let snap: CcSnapshot =
+        serde_json::from_slice(&bytes).with_context(|| format!("parse cc state failed: {}", path.display()))?;
+    if snap.version != SNAPSHOT_VERSION {
+        anyhow::bail!("unsupported cc state version {} in {}", snap.version, path.display());
+    }
+    Ok(cc.restore(snap))
+}
+
+impl CcSnapshot {
+    pub(super) fn new(saved_at_ms: i64, entries: Vec<CcSnapshotEntry>) -> Self {
+        Self { version: SNAPSHOT_VERSION, saved_at_ms, entries }
+    }
+}
+
+/// 定期和优雅退出时把 CC 状态写到本地文件
+pub struct CcPersister {
+    mgr: PolicyManager,
+    path: PathBuf,
+    counters: bool,
+    interval: Duration,
+}
+
+impl CcPersister {
+    pub fn new(mgr: PolicyManager, path: PathBuf, counters: bool, interval: Duration) -> Self {
+        Self { mgr, path, counters, interval }
+    }
+
+    async fn save_now(&self) {
+        let cc: Arc<CcLimiter> = self.mgr.load().cc.clone();
+        let path = self.path.clone();
+        let counters = self.counters;
+        match tokio::task::spawn_blocking(move || save(&cc, &path, counters)).await {
+            Ok(Ok(n)) => tracing::debug!(entries = n, "cc state saved"),
+            Ok(Err(e)) => tracing::warn!("cc state save failed: {:#}", e),
+            Err(e) => tracing::warn!("cc state save task failed: {}", e),
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for CcPersister {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut ticker = tokio::time::interval(self.interval);
+        // 第一次 tick 立即触发，启动时刚恢复过，跳过
+        ticker.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    self.save_now().await;
+                    tracing::info!("cc persister shutdown");
+                    return;
+                }
+                _ = ticker.tick() => {
+                    self.save_now().await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::cc::{CcAlgo, CcParams};
+    use crate::policy::cc_backend::MemoryBackend;
+
+    const PARAMS: CcParams = CcParams {
+        window_secs: 10,
+        max_requests: 1,
+        block_secs: 60,
+        algorithm: CcAlgo::FixedWindow,
+    };
+
+    fn limiter() -> CcLimiter {
+        CcLimiter::new(100, Arc::new(MemoryBackend))
+    }
+
+    fn banned_entry(key: &str, blocked_until_ms: i64) -> CcSnapshotEntry {
+        CcSnapshotEntry {
+            key: key.to_string(),
+            blocked_until_ms: Some(blocked_until_ms),
+            last_seen_ms: blocked_until_ms - 60_000,
+            strikes: 2,
+            counter: None,
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cc-state-{}-{name}.json", std::process::id()))
+    }
+
+    #[test]
+    fn clock_converts_across_restarts() {
+        let before = Clock::now();
+        // 重启后的进程：Instant 基准不同，墙钟过了 5 秒
+        let after = Clock { now: before.now + Duration::from_secs(3600), now_ms: before.now_ms + 5_000 };
+
+        let ban = before.to_ms(before.now + Duration::from_secs(60));
+        assert_eq!(ban, before.now_ms + 60_000);
+        assert_eq!(after.to_instant(ban), Some(after.now + Duration::from_secs(55)));
+
+        let seen = before.to_ms(before.now - Duration::from_millis(1_500));
+        assert_eq!(seen, before.now_ms - 1_500);
+        let restored = after.to_instant(seen).unwrap();
+        assert_eq!(restored, after.now - Duration::from_millis(6_500));
+        assert_eq!(after.to_ms(restored), seen);
+    }
+
+    #[test]
+    fn restore_drops_expired_bans_and_keeps_the_remaining_time() {
+        let now_ms = Clock::now().now_ms;
+        let snap = CcSnapshot::new(
+            now_ms,
+            vec![banned_entry("rule=1|expired", now_ms - 1_000), banned_entry("rule=1|live", now_ms + 30_000)],
+        );
+        let cc = limiter();
+        assert_eq!(cc.restore(snap), 1);
+
+        let out = cc.snapshot(false);
+        assert_eq!(out.entries.len(), 1);
+        let e = &out.entries[0];
+        assert_eq!((e.key.as_str(), e.strikes), ("rule=1|live", 2));
+        let until = e.blocked_until_ms.unwrap();
+        assert!((now_ms + 29_000..=now_ms + 30_001).contains(&until), "{until} vs {now_ms}");
+
+        assert!(cc.check("1", "live", PARAMS).is_some_and(|h| h.reason.contains("blocked")));
+        assert!(cc.check("1", "expired", PARAMS).is_none());
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let cc = limiter();
+        assert!(cc.check("1", "a", PARAMS).is_none());
+        assert!(cc.check("1", "a", PARAMS).is_some());
+        assert!(cc.check("1", "b", PARAMS).is_none());
+
+        let path = temp_path("round-trip");
+        // 不带计数时只保存封禁中的 key
+        assert_eq!(save(&cc, &path, false).unwrap(), 1);
+        assert_eq!(save(&cc, &path, true).unwrap(), 2);
+
+        let restored = limiter();
+        assert_eq!(load(&restored, &path).unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored.strikes("1", "a"), 1);
+        assert!(restored.check("1", "a", PARAMS).is_some_and(|h| h.reason.contains("blocked")));
+        // b 的计数也恢复了：窗口内第二个请求触发封禁
+        assert!(restored.check("1", "b", PARAMS).is_some_and(|h| h.reason.contains("exceeded")));
+    }
+
+    #[test]
+    fn load_rejects_other_versions_and_ignores_missing_files() {
+        let cc = limiter();
+        assert_eq!(load(&cc, &temp_path("missing")).unwrap(), 0);
+
+        let path = temp_path("version");
+        std::fs::write(&path, r#"{"version":2,"saved_at_ms":0,"entries":[]}"#).unwrap();
+        let err = load(&cc, &path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.to_string().contains("unsupported cc state version 2"), "{err:#}");
+    }
+}
@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use pingora::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+
+use super::manager::PolicyManager;
+use crate::metrics::counters;
+
+/// 定期清理 CC limiter 里长时间不活跃的 key，并更新表大小指标
+pub struct CcPruner {
+    mgr: PolicyManager,
+    idle: Duration,
+    interval: Duration,
+}
+
+impl CcPruner {
+    pub fn new(mgr: PolicyManager, idle: Duration, interval: Duration) -> Self {
+        Self { mgr, idle, interval }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for CcPruner {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut ticker = tokio::time::interval(self.interval);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    tracing::info!("cc pruner shutdown");
+                    return;
+                }
+                _ = ticker.tick() => {
+                    // 策略热更新沿用同一个 limiter，这里每次取当前的
+                    let cc = self.mgr.load().cc.clone();
+                    let idle = self.idle;
+                    // retain 逐个分片加写锁遍历，放到阻塞线程池，不占用 IO 线程
+                    let st = match tokio::task::spawn_blocking(move || cc.prune_older_than(idle)).await {
+                        Ok(st) => st,
+                        Err(e) => {
+                            tracing::warn!("cc prune task failed: {}", e);
+                            continue;
+                        }
+                    };
+                    counters::add_cc_evictions("idle", st.removed as u64);
+                    counters::set_cc_entries(st.entries, st.banned);
+                    if st.removed > 0 {
+                        tracing::debug!(removed = st.removed, entries = st.entries, banned = st.banned, "cc pruned");
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,528 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use dashmap::{DashMap, Entry};
+use pingora::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+use crate::config::ChallengeConfig;
+use crate::waf::context::{HeaderView, WafContext};
+use crate::waf::cookie::get_cookie_value;
+use crate::waf::decision::ChallengeKind;
+
+/// 挑战页 nonce 有效期（秒）：页面打开到 JS 回调之间
+const NONCE_TTL_SECS: i64 = 300;
+
+/// pow 答案（计数器）的最大长度，校验只做一次 SHA-256
+const POW_ANSWER_MAX_LEN: usize = 16;
+
+/// 已用 nonce 记录的上限：清理之间记满时拒绝新的回调（宁可让客户端重做挑战，也不让表无限增长）
+const USED_NONCE_MAX: usize = 100_000;
+
+/// 后台清理过期 nonce 记录的间隔（秒）
+const USED_NONCE_PRUNE_SECS: u64 = 30;
+
+/// JS / pow 挑战：签发 nonce，校验回调答案，签发 / 校验放行 cookie。
+///
+/// 放行 cookie 形如 `<过期时间戳>.<挑战类型>.<难度>.<HMAC>`，HMAC 覆盖这三项和客户端 IP、UA、策略 ID，
+/// 换 IP / UA / 策略后失效，不需要服务端保存任何状态。
+#[derive(Clone)]
+pub struct Challenger {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    key: Vec<u8>,
+    cookie_name: String,
+    callback_path: String,
+    ttl_secs: u64,
+    /// 已通过校验的 nonce 随机数 -> 签发时间，过期前拒绝重放（仅本进程内，由 [`NoncePruner`] 定期清理）
+    used: DashMap<String, i64>,
+}
+
+/// 绑定挑战结果的客户端身份
+pub struct ClientBinding<'a> {
+    pub ip: String,
+    pub ua: &'a str,
+    pub policy_id: &'a str,
+}
+
+impl<'a> ClientBinding<'a> {
+    pub fn new(wctx: &'a WafContext, policy_id: &'a str) -> Self {
+        Self {
+            ip: wctx.client_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            ua: wctx.user_agent.as_deref().unwrap_or(""),
+            policy_id,
+        }
+    }
+}
+
+/// 放行 cookie 记录的已通过挑战
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clearance {
+    Js,
+    Pow { difficulty: u32 },
+}
+
+impl Clearance {
+    /// 难度 0 的 nonce 是 JS 挑战
+    fn from_difficulty(difficulty: u32) -> Self {
+        match difficulty {
+            0 => Clearance::Js,
+            difficulty => Clearance::Pow { difficulty },
+        }
+    }
+
+    fn parts(self) -> (&'static str, u32) {
+        match self {
+            Clearance::Js => ("js", 0),
+            Clearance::Pow { difficulty } => ("pow", difficulty),
+        }
+    }
+
+    /// 是否满足某个挑战：JS 挑战任何放行都满足，pow 要求已解难度不低于当前（上调后的）难度
+    pub fn satisfies(self, kind: ChallengeKind) -> bool {
+        match (kind, self) {
+            (ChallengeKind::Js, _) => true,
+            (ChallengeKind::Pow { difficulty: want, .. }, Clearance::Pow { difficulty }) => difficulty >= want,
+            (ChallengeKind::Pow { .. }, Clearance::Js) => false,
+        }
+    }
+}
+
+/// 每累计一次 CC 封禁，pow 难度增加的比特数（每次约 4 倍计算量）
+const POW_STRIKE_BITS: u32 = 2;
+
+/// pow 挑战：客户端在 CC 规则里被封禁过的，按次数上调难度（不超过 max_difficulty）
+pub fn escalate_pow(kind: ChallengeKind, strikes: u32) -> ChallengeKind {
+    match kind {
+        ChallengeKind::Pow { difficulty, max_difficulty } => ChallengeKind::Pow {
+            difficulty: difficulty.saturating_add(strikes.saturating_mul(POW_STRIKE_BITS)).min(max_difficulty),
+            max_difficulty,
+        },
+        ChallengeKind::Js => ChallengeKind::Js,
+    }
+}
+
+/// 密钥最短长度（字节）
+const MIN_SECRET_LEN: usize = 16;
+
+/// 示例配置里常见的占位密钥，视为未改过，拒绝启动
+fn is_placeholder(s: &str) -> bool {
+    const PLACEHOLDERS: &[&str] = &["change-me", "changeme", "change_me", "secret", "your-secret", "example", "default", "xxx"];
+    PLACEHOLDERS.iter().any(|p| s.eq_ignore_ascii_case(p))
+}
+
+impl Challenger {
+    pub fn new(cfg: &ChallengeConfig) -> anyhow::Result<Self> {
+        let key = match cfg.secret.as_deref().map(str::trim) {
+            Some(s) if is_placeholder(s) => {
+                anyhow::bail!("challenge.secret is a placeholder ({s:?}); set a random secret or remove it")
+            }
+            Some(s) if s.len() < MIN_SECRET_LEN => {
+                anyhow::bail!("challenge.secret must be at least {MIN_SECRET_LEN} bytes")
+            }
+            Some(s) if !s.is_empty() => s.as_bytes().to_vec(),
+            _ => {
+                // 未配置密钥：进程内随机，重启或多实例之间放行 cookie 不通用
+                tracing::warn!(
+                    "challenge.secret not set, using a random per-process key; \
+                     instances behind one load balancer must share a configured secret"
+                );
+                let mut k = vec![0u8; 32];
+                openssl::rand::rand_bytes(&mut k)?;
+                k
+            }
+        };
+        Ok(Self {
+            inner: Arc::new(Inner {
+                key,
+                cookie_name: cfg.cookie_name(),
+                callback_path: cfg.callback_path(),
+                ttl_secs: cfg.ttl_secs(),
+                used: DashMap::new(),
+            }),
+        })
+    }
+
+    pub fn callback_path(&self) -> &str {
+        &self.inner.callback_path
+    }
+
+    /// 请求带的有效放行 cookie 记录的挑战；是否满足某条规则由 [`Clearance::satisfies`] 判断
+    pub fn clearance(&self, headers: &dyn HeaderView, b: &ClientBinding) -> Option<Clearance> {
+        let v = headers.get("cookie").and_then(|c| get_cookie_value(c, &self.inner.cookie_name))?;
+        let mut parts = v.splitn(4, '.');
+        let (Some(exp), Some(kind), Some(d), Some(sig)) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return None;
+        };
+        let (Ok(exp_ts), Ok(difficulty)) = (exp.parse::<i64>(), d.parse::<u32>()) else {
+            return None;
+        };
+        let c = Clearance::from_difficulty(difficulty);
+        if c.parts().0 != kind || exp_ts <= now() {
+            return None;
+        }
+        self.verify(&format!("clearance|{exp}|{kind}|{difficulty}|{}|{}|{}", b.ip, b.ua, b.policy_id), sig)
+            .then_some(c)
+    }
+
+    /// 挑战页用的一次性 nonce：`<时间戳>.<随机数>.<难度>.<HMAC>`，难度 0 为 JS 挑战
+    pub fn issue_nonce(&self, b: &ClientBinding, difficulty: u32) -> String {
+        let mut r = [0u8; 8];
+        // rand_bytes 只在熵源不可用时失败，退化为时间戳也仍受 HMAC 保护
+        let _ = openssl::rand::rand_bytes(&mut r);
+        let rand: String = r.iter().map(|x| format!("{x:02x}")).collect();
+        let ts = now();
+        let sig = self.sign(&format!("nonce|{ts}|{rand}|{difficulty}|{}|{}|{}", b.ip, b.ua, b.policy_id));
+        format!("{ts}.{rand}.{difficulty}.{sig}")
+    }
+
+    /// 校验回调：nonce 是本服务为同一客户端签发、未过期且未用过，答案是页面 JS 算出的值
+    /// （pow：`SHA-256("<nonce>:<答案>")` 至少有难度要求的前导零比特）。通过时返回 nonce 里的挑战
+    pub fn verify_solution(&self, nonce: &str, answer: &str, b: &ClientBinding) -> Result<Clearance, &'static str> {
+        let mut parts = nonce.splitn(4, '.');
+        let (Some(ts), Some(rand), Some(d), Some(sig)) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err("malformed nonce");
+        };
+        let (Ok(ts_v), Ok(difficulty)) = (ts.parse::<i64>(), d.parse::<u32>()) else {
+            return Err("malformed nonce");
+        };
+        if !self.verify(&format!("nonce|{ts}|{rand}|{difficulty}|{}|{}|{}", b.ip, b.ua, b.policy_id), sig) {
+            return Err("nonce signature mismatch");
+        }
+        if now() - ts_v > NONCE_TTL_SECS {
+            return Err("nonce expired");
+        }
+        if difficulty > 0 {
+            if answer.is_empty() || answer.len() > POW_ANSWER_MAX_LEN || !answer.bytes().all(|c| c.is_ascii_digit()) {
+                return Err("malformed answer");
+            }
+            if leading_zero_bits(&openssl::sha::sha256(format!("{nonce}:{answer}").as_bytes())) < difficulty {
+                return Err("wrong answer");
+            }
+        } else {
+            let want = js_answer(nonce);
+            if want.len() != answer.len() || !openssl::memcmp::eq(want.as_bytes(), answer.as_bytes()) {
+                return Err("wrong answer");
+            }
+        }
+        self.consume_nonce(rand, ts_v)?;
+        Ok(Clearance::from_difficulty(difficulty))
+    }
+
+    /// 答案正确后登记 nonce，同一 nonce 第二次提交直接拒绝
+    fn consume_nonce(&self, rand: &str, ts: i64) -> Result<(), &'static str> {
+        let used = &self.inner.used;
+        if used.len() >= USED_NONCE_MAX {
+            return Err("too many pending challenges");
+        }
+        match used.entry(rand.to_string()) {
+            Entry::Occupied(_) => Err("nonce already used"),
+            Entry::Vacant(e) => {
+                e.insert(ts);
+                Ok(())
+            }
+        }
+    }
+
+    /// 删除已过期的 nonce 记录（过期 nonce 校验时本来就会被拒绝），返回删除数
+    fn prune_used(&self) -> usize {
+        let used = &self.inner.used;
+        let before = used.len();
+        let cutoff = now() - NONCE_TTL_SECS;
+        used.retain(|_, t| *t >= cutoff);
+        before.saturating_sub(used.len())
+    }
+
+    /// 通过挑战后下发的 Set-Cookie 值，记录通过的挑战类型和难度
+    pub fn clearance_cookie(&self, cleared: Clearance, b: &ClientBinding, secure: bool) -> String {
+        let exp = now() + self.inner.ttl_secs as i64;
+        let (kind, difficulty) = cleared.parts();
+        let sig = self.sign(&format!("clearance|{exp}|{kind}|{difficulty}|{}|{}|{}", b.ip, b.ua, b.policy_id));
+        let mut c = format!(
+            "{}={exp}.{kind}.{difficulty}.{sig}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            self.inner.cookie_name, self.inner.ttl_secs
+        );
+        if secure {
+            c.push_str("; Secure");
+        }
+        c
+    }
+
+    fn sign(&self, msg: &str) -> String {
+        URL_SAFE_NO_PAD.encode(self.mac(msg))
+    }
+
+    fn verify(&self, msg: &str, sig: &str) -> bool {
+        match URL_SAFE_NO_PAD.decode(sig) {
+            Ok(got) => {
+                let want = self.mac(msg);
+                got.len() == want.len() && openssl::memcmp::eq(&got, &want)
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn mac(&self, msg: &str) -> Vec<u8> {
+        // HMAC-SHA256 构造只在 key / digest 非法时失败，这里两者都固定
+        PKey::hmac(&self.inner.key)
+            .and_then(|k| {
+                let mut s = Signer::new(MessageDigest::sha256(), &k)?;
+                s.update(msg.as_bytes())?;
+                s.sign_to_vec()
+            })
+            .expect("hmac-sha256")
+    }
+}
+
+/// 定期清理已用 nonce 记录，回调路径上不再遍历整张表
+pub struct NoncePruner {
+    challenger: Challenger,
+}
+
+impl NoncePruner {
+    pub fn new(challenger: Challenger) -> Self {
+        Self { challenger }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for NoncePruner {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut ticker = tokio::time::interval(Duration::from_secs(USED_NONCE_PRUNE_SECS));
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    tracing::info!("challenge nonce pruner shutdown");
+                    return;
+                }
+                _ = ticker.tick() => {
+                    let c = self.challenger.clone();
+                    match tokio::task::spawn_blocking(move || c.prune_used()).await {
+                        Ok(n) if n > 0 => tracing::debug!(removed = n, "challenge nonces pruned"),
+                        Ok(_) => {}
+                        Err(e) => tracing::warn!("challenge nonce prune task failed: {}", e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 挑战页 JS 的计算：nonce 的 FNV-1a 32 位哈希（与 assets/challenge/challenge.html 保持一致）
+fn js_answer(nonce: &str) -> String {
+    let mut h: u32 = 0x811c9dc5;
+    for b in nonce.bytes() {
+        h ^= b as u32;
+        h = h.wrapping_mul(0x0100_0193);
+    }
+    format!("{h:08x}")
+}
+
+fn leading_zero_bits(h: &[u8]) -> u32 {
+    let mut n = 0;
+    for b in h {
+        if *b != 0 {
+            return n + b.leading_zeros();
+        }
+        n += 8;
+    }
+    n
+}
+
+/// 回调里的返回地址只允许站内相对路径，防止开放重定向。
+/// 浏览器会从 URL 里删掉 tab / CR / LF，`/\t/evil.com` 会变成 `//evil.com`，所以控制字符和空白一律拒绝
+pub fn safe_return_path(r: Option<&str>) -> &str {
+    match r {
+        Some(p)
+            if p.starts_with('/')
+                && !p.starts_with("//")
+                && !p.contains('\\')
+                && !p.chars().any(|c| c.is_control() || c.is_whitespace()) =>
+        {
+            p
+        }
+        _ => "/",
+    }
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn challenger() -> Challenger {
+        Challenger::new(&ChallengeConfig::default()).unwrap()
+    }
+
+    fn binding() -> ClientBinding<'static> {
+        ClientBinding {
+            ip: "192.0.2.1".to_string(),
+            ua: "Mozilla/5.0",
+            policy_id: "default",
+        }
+    }
+
+    #[test]
+    fn nonce_cannot_be_replayed() {
+        let c = challenger();
+        let b = binding();
+        let nonce = c.issue_nonce(&b, 0);
+        let answer = js_answer(&nonce);
+        assert_eq!(c.verify_solution(&nonce, &answer, &b), Ok(Clearance::Js));
+        assert_eq!(c.verify_solution(&nonce, &answer, &b), Err("nonce already used"));
+
+        // 答错不消耗 nonce
+        let other = c.issue_nonce(&b, 0);
+        assert_eq!(c.verify_solution(&other, "00000000", &b), Err("wrong answer"));
+        assert_eq!(c.verify_solution(&other, &js_answer(&other), &b), Ok(Clearance::Js));
+    }
+
+    /// 暴力求解 pow：返回第一个前导零比特数达到 `bits` 的计数器，和它实际的零比特数
+    fn solve(nonce: &str, bits: u32) -> (String, u32) {
+        (0u64..)
+            .map(|c| (c.to_string(), leading_zero_bits(&openssl::sha::sha256(format!("{nonce}:{c}").as_bytes()))))
+            .find(|(_, z)| *z >= bits)
+            .unwrap()
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff, 0x00]), 0);
+        assert_eq!(leading_zero_bits(&[0x01, 0xff]), 7);
+        assert_eq!(leading_zero_bits(&[0x00, 0x80]), 8);
+        assert_eq!(leading_zero_bits(&[0x00, 0x0f, 0x00]), 12);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+        assert_eq!(leading_zero_bits(&[]), 0);
+    }
+
+    #[test]
+    fn pow_needs_enough_zero_bits() {
+        let c = challenger();
+        let b = binding();
+        let nonce = c.issue_nonce(&b, 8);
+        let (answer, _) = solve(&nonce, 8);
+        // 找一个零比特不足 8 位的答案
+        let (short, _) = (0u64..)
+            .map(|c| (c.to_string(), leading_zero_bits(&openssl::sha::sha256(format!("{nonce}:{c}").as_bytes()))))
+            .find(|(_, z)| *z < 8)
+            .unwrap();
+        assert_eq!(c.verify_solution(&nonce, &short, &b), Err("wrong answer"));
+        for bad in ["", "12a", "-1", "12345678901234567"] {
+            assert_eq!(c.verify_solution(&nonce, bad, &b), Err("malformed answer"), "{bad:?}");
+        }
+        assert_eq!(c.verify_solution(&nonce, &answer, &b), Ok(Clearance::Pow { difficulty: 8 }));
+        assert_eq!(c.verify_solution(&nonce, &answer, &b), Err("nonce already used"));
+
+        // 难度写在签名里，改小即签名不符
+        let lowered = nonce.replacen(".8.", ".1.", 1);
+        assert_eq!(c.verify_solution(&lowered, &solve(&lowered, 1).0, &b), Err("nonce signature mismatch"));
+    }
+
+    #[test]
+    fn difficulty_rises_with_strikes() {
+        let pow = ChallengeKind::Pow { difficulty: 10, max_difficulty: 16 };
+        let at = |strikes| match escalate_pow(pow, strikes) {
+            ChallengeKind::Pow { difficulty, .. } => difficulty,
+            ChallengeKind::Js => unreachable!(),
+        };
+        assert_eq!(at(0), 10);
+        assert_eq!(at(1), 12);
+        assert_eq!(at(3), 16);
+        assert_eq!(at(4), 16);
+        assert_eq!(at(u32::MAX), 16);
+        assert_eq!(escalate_pow(ChallengeKind::Js, 5), ChallengeKind::Js);
+    }
+
+    #[test]
+    fn clearance_records_the_solved_challenge() {
+        let c = challenger();
+        let b = binding();
+        let cookie_header = |cleared| {
+            let set = c.clearance_cookie(cleared, &b, false);
+            let mut h = http::HeaderMap::new();
+            h.insert("cookie", set.split(';').next().unwrap().parse().unwrap());
+            h
+        };
+        let pow = |difficulty| ChallengeKind::Pow { difficulty, max_difficulty: 28 };
+
+        let js = c.clearance(&cookie_header(Clearance::Js), &b).unwrap();
+        assert_eq!(js, Clearance::Js);
+        assert!(js.satisfies(ChallengeKind::Js));
+        assert!(!js.satisfies(pow(1)));
+
+        let solved = c.clearance(&cookie_header(Clearance::Pow { difficulty: 12 }), &b).unwrap();
+        assert_eq!(solved, Clearance::Pow { difficulty: 12 });
+        assert!(solved.satisfies(ChallengeKind::Js));
+        assert!(solved.satisfies(pow(12)));
+        assert!(!solved.satisfies(pow(14)));
+
+        // 改写类型或难度后签名不符
+        let h = cookie_header(Clearance::Js);
+        let v = h.get("cookie").unwrap().to_str().unwrap();
+        for forged in [v.replacen(".js.0.", ".pow.20.", 1), v.replacen(".js.0.", ".pow.0.", 1)] {
+            let mut h = http::HeaderMap::new();
+            h.insert("cookie", forged.parse().unwrap());
+            assert_eq!(c.clearance(&h, &b), None, "{forged}");
+        }
+
+        // 绑定其它策略
+        let other = ClientBinding { policy_id: "other", ..binding() };
+        assert_eq!(c.clearance(&cookie_header(Clearance::Js), &other), None);
+    }
+
+    #[test]
+    fn return_path_stays_on_site() {
+        assert_eq!(safe_return_path(Some("/shop/cart?x=1&y=%2F")), "/shop/cart?x=1&y=%2F");
+        assert_eq!(safe_return_path(None), "/");
+        let bad = ["https://evil.com", "//evil.com", "/\\evil.com", "/\t/evil.com", "/\r\n/evil.com", "/ /evil.com"];
+        for bad in bad {
+            assert_eq!(safe_return_path(Some(bad)), "/", "{bad:?}");
+        }
+        // 回调参数是解码后的值：r=/%09/evil.com 到这里已是 tab
+        let query = b"r=/%09/evil.com&r=/%0d%0a/evil.com";
+        for (_, r) in form_urlencoded::parse(query) {
+            assert_eq!(safe_return_path(Some(&r)), "/", "{r:?}");
+        }
+    }
+
+    #[test]
+    fn expired_entries_are_pruned() {
+        let c = challenger();
+        let stale = now() - NONCE_TTL_SECS - 1;
+        for i in 0..100 {
+            c.inner.used.insert(format!("{i:016x}"), stale);
+        }
+        assert_eq!(c.consume_nonce("fresh", now()), Ok(()));
+        assert_eq!(c.prune_used(), 100);
+        assert_eq!(c.inner.used.len(), 1);
+        assert_eq!(c.consume_nonce("fresh", now()), Err("nonce already used"));
+    }
+
+    #[test]
+    fn full_nonce_table_fails_closed() {
+        let c = challenger();
+        for i in 0..USED_NONCE_MAX {
+            c.inner.used.insert(format!("{i:016x}"), now());
+        }
+        let b = binding();
+        let nonce = c.issue_nonce(&b, 0);
+        assert_eq!(c.verify_solution(&nonce, &js_answer(&nonce), &b), Err("too many pending challenges"));
+        assert_eq!(c.inner.used.len(), USED_NONCE_MAX);
+        // 清理前记录都没过期，清理后仍然拒绝
+        assert_eq!(c.prune_used(), 0);
+        assert_eq!(c.verify_solution(&nonce, &js_answer(&nonce), &b), Err("too many pending challenges"));
+    }
+}
@@ -1,7 +1,9 @@
 use std::sync::Arc;
 
-use crate::policy::protection::compiled::{compile_rules, CompiledRule};
-use crate::policy::types::{PolicyFile, WafConfig};
+use crate::policy::iplist::IpLists;
+use crate::policy::protection::compiled::{compile_match, compile_rules, CompiledMatchExpr, CompiledRule};
+use crate::policy::types::{ExclusionSpec, PolicyFile, WafConfig};
+use crate::waf::engine::WafEngine;
 
 #[derive(Debug)]
 pub struct CompiledPolicy {
@@ -12,11 +14,36 @@ pub struct CompiledPolicy {
 
     pub precise: Vec<CompiledRule>,
     pub base: Vec<CompiledRule>,
+
+    pub exclusions: Vec<CompiledExclusion>,
+}
+
+#[derive(Debug)]
+pub struct CompiledExclusion {
+    pub matcher: CompiledMatchExpr,
+    pub spec: ExclusionSpec,
 }
 
-pub fn compile_policy(p: &PolicyFile) -> anyhow::Result<Arc<CompiledPolicy>> {
-    let precise = compile_rules(&p.protections.precise)?;
-    let base = compile_rules(&p.protections.base)?;
+pub fn compile_policy(p: &PolicyFile, waf: &WafEngine, lists: &IpLists) -> anyhow::Result<Arc<CompiledPolicy>> {
+    // 启用 WAF 的策略必须绑定到已加载的规则集
+    if p.waf.enabled && !waf.has_ruleset(p.waf.ruleset_name()) {
+        anyhow::bail!("policy '{}' references unknown waf ruleset '{}'", p.id, p.waf.ruleset_name());
+    }
+
+    let precise = compile_rules(&p.protections.precise, lists)?;
+    let base = compile_rules(&p.protections.base, lists)?;
+
+    let mut exclusions = Vec::with_capacity(p.waf.exclusions.len());
+    for e in &p.waf.exclusions {
+        // 不选规则时只允许排除检查目标（对所有规则生效）；整体关闭请用 waf.enabled: false
+        if e.rule_ids.is_empty() && e.tags.is_empty() && !e.is_target_exclusion() {
+            anyhow::bail!("policy '{}' exclusion '{}' selects no rules", p.id, e.id);
+        }
+        exclusions.push(CompiledExclusion {
+            matcher: compile_match(&e.match_expr, lists)?,
+            spec: e.clone(),
+        });
+    }
 
     Ok(Arc::new(CompiledPolicy {
         version: p.version,
@@ -24,5 +51,63 @@ pub fn compile_policy(p: &PolicyFile) -> anyhow::Result<Arc<CompiledPolicy>> {
         waf: p.waf.clone(),
         precise,
         base,
+        exclusions,
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::waf::rules::compiler::{compile_source, RulesSource};
+
+    /// 规则目录里放好 `files`，按文件名加载成规则集
+    fn engine(name: &str, files: &[(&str, &str)]) -> WafEngine {
+        let dir = std::env::temp_dir().join(format!("policy-rules-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (f, text) in files {
+            std::fs::write(dir.join(f), text).unwrap();
+        }
+        let rulesets = compile_source(&RulesSource::Dir(dir.clone())).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        WafEngine::new(rulesets)
+    }
+
+    fn policy(waf: &str) -> PolicyFile {
+        serde_yaml::from_str(&format!("version: 1\nid: shop\nwaf: {waf}\n")).unwrap()
+    }
+
+    #[test]
+    fn policy_binds_to_named_ruleset() {
+        let waf = engine(
+            "bind",
+            &[
+                ("default.yaml", "rules:\n  - { id: d-1, when: { uri_ac: [x] }, action: block }\n"),
+                ("strict.yaml", "rules:\n  - { id: s-1, when: { uri_ac: [x] }, action: block }\n"),
+                ("crs.conf", "SecRule REQUEST_URI \"@contains /etc/passwd\" \"id:930120,phase:1,deny\"\n"),
+            ],
+        );
+        let lists = IpLists::default();
+        let bound = |waf_yaml: &str| {
+            let p = compile_policy(&policy(waf_yaml), &waf, &lists).unwrap();
+            waf.ruleset(p.waf.ruleset_name()).unwrap().rules[0].id.clone()
+        };
+        assert_eq!(bound("{ enabled: true, ruleset: strict }"), "s-1");
+        assert_eq!(bound("{ enabled: true, ruleset: crs }"), "930120");
+        // 不写 ruleset 用 default
+        assert_eq!(bound("{ enabled: true }"), "d-1");
+    }
+
+    #[test]
+    fn policy_naming_missing_ruleset_fails() {
+        let waf = engine("missing", &[("strict.yaml", "rules: []\n")]);
+        let lists = IpLists::default();
+        let err = |waf_yaml: &str| format!("{:#}", compile_policy(&policy(waf_yaml), &waf, &lists).unwrap_err());
+
+        assert!(err("{ enabled: true, ruleset: lax }").contains("policy 'shop' references unknown waf ruleset 'lax'"));
+        // 目录里没有 default.yaml
+        assert!(err("{ enabled: true }").contains("unknown waf ruleset 'default'"));
+        // 未启用 WAF 的策略不要求规则集存在
+        assert!(compile_policy(&policy("{ enabled: false, ruleset: lax }"), &waf, &lists).is_ok());
+    }
+}
@@ -1,12 +1,23 @@
+use std::cell::OnceCell;
+use std::sync::Arc;
+
 use pingora::http::RequestHeader;
 
+use crate::waf::anomaly::AnomalyScore;
 use crate::waf::context::WafContext;
-use crate::waf::decision::Decision;
+use crate::waf::decision::{ChallengeKind, Decision};
 use crate::waf::engine::WafEngine;
+use crate::waf::exclusion::RuleExclusions;
+use crate::waf::rules::rule::Action;
+use crate::waf::rules::compiler::CompiledRuleset;
 
+use super::challenge::{escalate_pow, Challenger, ClientBinding};
+use super::compiled::CompiledPolicy;
+use super::cc::CcLimiter;
 use super::manager::PolicyManager;
 use super::protection::engine::ProtectionEngine;
-use super::protection::matcher::HeaderView;
+use super::protection::matcher::{self, HeaderView};
+use super::types::BodyInspectionConfig;
 
 struct ReqHeaderView<'a> {
     req: &'a RequestHeader,
@@ -16,24 +27,43 @@ impl<'a> HeaderView for ReqHeaderView<'a> {
     fn get(&self, name: &str) -> Option<&str> {
         self.req.headers.get(name).and_then(|v| v.to_str().ok())
     }
+
+    fn get_all(&self, name: &str) -> Vec<&str> {
+        self.req.headers.get_all(name).iter().filter_map(|v| v.to_str().ok()).collect()
+    }
 }
 
 pub struct EnforceResult {
     pub decision: Decision,
     pub policy_id: String,
+    /// 命中策略绑定的规则集快照；body 规则下标基于它
+    pub ruleset: Option<Arc<CompiledRuleset>>,
     pub req_body_rules: Vec<usize>,
     pub resp_body_rules: Vec<usize>,
+    /// 异常分模式下的累计状态，body 阶段继续累加
+    pub anomaly: Option<AnomalyScore>,
+    /// 结构化 body 检查的限制（来自策略）
+    pub body: BodyInspectionConfig,
+    /// 本请求命中的规则排除，body 阶段继续使用
+    pub exclusions: RuleExclusions,
+}
+
+impl EnforceResult {
+    fn decided(decision: Decision, policy_id: String) -> Self {
+        Self { decision, policy_id, ruleset: None, req_body_rules: vec![], resp_body_rules: vec![], anomaly: None, body: BodyInspectionConfig::default(), exclusions: RuleExclusions::default() }
+    }
 }
 
 #[derive(Clone)]
 pub struct PolicyEnforcer {
     mgr: PolicyManager,
     engine: WafEngine,
+    challenger: Challenger,
 }
 
 impl PolicyEnforcer {
-    pub fn new(mgr: PolicyManager, engine: WafEngine) -> Self {
-        Self { mgr, engine }
+    pub fn new(mgr: PolicyManager, engine: WafEngine, challenger: Challenger) -> Self {
+        Self { mgr, engine, challenger }
     }
 
     pub fn enforce_request_headers(&self, wctx: &WafContext, req: &RequestHeader) -> EnforceResult {
@@ -46,24 +76,89 @@ impl PolicyEnforcer {
         let st = self.mgr.load();
         let limiter = st.cc.as_ref();
 
+        // 放行 cookie 只跳过它满足的 challenge 规则：pow 规则要求通过的是 pow 且难度不低于上调后的难度
+        let clearance = self.challenger.clearance(&hv, &ClientBinding::new(wctx, &policy_id));
+        let strikes = OnceCell::new();
+        let challenge = |d: Decision| match d {
+            Decision::Challenge { status, reason, rule_id, kind } => {
+                let kind = match kind {
+                    ChallengeKind::Pow { .. } => {
+                        escalate_pow(kind, *strikes.get_or_init(|| cc_strikes(&policy, wctx, &hv, limiter)))
+                    }
+                    ChallengeKind::Js => kind,
+                };
+                match clearance {
+                    Some(c) if c.satisfies(kind) => None,
+                    _ => Some(Decision::Challenge { status, reason, rule_id, kind }),
+                }
+            }
+            d => Some(d),
+        };
+
         // 1) precise
-        let d1 = ProtectionEngine::eval_rules(&policy.precise, wctx, &hv, limiter);
+        let d1 = ProtectionEngine::eval_rules(&policy.precise, wctx, &hv, limiter, &challenge);
         if d1.is_terminal() {
-            return EnforceResult { decision: d1, policy_id, req_body_rules: vec![], resp_body_rules: vec![] };
+            return EnforceResult::decided(d1, policy_id);
         }
 
         // 2) base
-        let d2 = ProtectionEngine::eval_rules(&policy.base, wctx, &hv, limiter);
+        let d2 = ProtectionEngine::eval_rules(&policy.base, wctx, &hv, limiter, &challenge);
         if d2.is_terminal() {
-            return EnforceResult { decision: d2, policy_id, req_body_rules: vec![], resp_body_rules: vec![] };
+            return EnforceResult::decided(d2, policy_id);
         }
 
         // 3) WAF switch
         if !policy.waf.enabled {
-            return EnforceResult { decision: Decision::Allow, policy_id, req_body_rules: vec![], resp_body_rules: vec![] };
+            return EnforceResult::decided(Decision::Allow, policy_id);
+        }
+
+        // 4) 策略绑定的规则集（加载时已校验存在，这里兜底）
+        let Some(ruleset) = self.engine.ruleset(policy.waf.ruleset_name()) else {
+            tracing::warn!(policy_id=%policy_id, ruleset=%policy.waf.ruleset_name(), "waf ruleset not loaded");
+            return EnforceResult::decided(Decision::Allow, policy_id);
+        };
+
+        let mut exclusions = resolve_exclusions(&policy, &ruleset, wctx, &hv);
+        // WAF 规则集的 challenge 都是 JS 挑战，任何放行 cookie 都满足
+        if clearance.is_some() {
+            for (idx, _) in ruleset.rules.iter().enumerate().filter(|(_, r)| matches!(r.action, Action::Challenge)) {
+                exclusions.remove(idx);
+            }
         }
+        let mut anomaly = policy.waf.anomaly.as_ref().map(|a| AnomalyScore::new(a.inbound_threshold()));
+        // 异常分模式：header 阶段已达阈值直接拦截，否则留给 body 阶段继续累加
+        let (decision, req_body_rules, resp_body_rules) =
+            WafEngine::eval_request_headers(&ruleset, wctx, &hv, &exclusions, anomaly.as_mut());
+        EnforceResult { decision, policy_id, ruleset: Some(ruleset), req_body_rules, resp_body_rules, anomaly, body: policy.waf.body.clone(), exclusions }
+    }
+}
 
-        let (decision, req_body_rules, resp_body_rules) = self.engine.eval_request_headers(wctx);
-        EnforceResult { decision, policy_id, req_body_rules, resp_body_rules }
+/// 客户端在本策略 CC 规则里累计被封禁的次数
+fn cc_strikes(policy: &CompiledPolicy, wctx: &WafContext, hv: &dyn HeaderView, limiter: &CcLimiter) -> u32 {
+    ProtectionEngine::cc_strikes(&policy.precise, wctx, hv, limiter).max(ProtectionEngine::cc_strikes(&policy.base, wctx, hv, limiter))
+}
+
+/// 按当前规则集快照解析命中本请求的排除项（规则 ID / tag 换成规则下标）
+fn resolve_exclusions(policy: &CompiledPolicy, rs: &CompiledRuleset, wctx: &WafContext, hv: &dyn HeaderView) -> RuleExclusions {
+    let mut out = RuleExclusions::default();
+    for e in &policy.exclusions {
+        if !matcher::eval(&e.matcher, wctx, hv) {
+            continue;
+        }
+        let s = &e.spec;
+        // 不选规则的目标排除对所有规则生效
+        let idxs: Vec<usize> = if s.rule_ids.is_empty() && s.tags.is_empty() {
+            (0..rs.rules.len()).collect()
+        } else {
+            rs.select(&s.rule_ids, &s.tags).collect()
+        };
+        for idx in idxs {
+            if s.is_target_exclusion() {
+                out.exclude_targets(idx, &s.args, &s.headers);
+            } else {
+                out.remove(idx);
+            }
+        }
     }
+    out
 }
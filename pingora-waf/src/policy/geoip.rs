@@ -0,0 +1,301 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use arc_swap::ArcSwapOption;
+use maxminddb::Reader;
+use serde::Deserialize;
+
+use crate::config::GeoIpConfig;
+use crate::waf::context::WafContext;
+
+/// 本地 MaxMind 格式（.mmdb）国家 / ASN 库，热更新时整库替换
+#[derive(Clone, Default)]
+pub struct GeoIp {
+    country: Arc<GeoDb>,
+    asn: Arc<GeoDb>,
+}
+
+#[derive(Default)]
+struct GeoDb {
+    path: Option<PathBuf>,
+    reader: ArcSwapOption<Reader<Vec<u8>>>,
+}
+
+/// 只解码需要的字段，其余（多语言名称等）跳过
+#[derive(Deserialize)]
+struct CountryRecord<'a> {
+    #[serde(borrow)]
+    country: Option<IsoCode<'a>>,
+    /// 没有 country 时（如部分 anycast 段）用注册国家
+    #[serde(borrow)]
+    registered_country: Option<IsoCode<'a>>,
+}
+
+#[derive(Deserialize)]
+struct IsoCode<'a> {
+    iso_code: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct AsnRecord {
+    autonomous_system_number: Option<u32>,
+}
+
+impl GeoDb {
+    fn new(path: Option<PathBuf>) -> Self {
+        Self { path, reader: ArcSwapOption::empty() }
+    }
+
+    /// 未配置时返回 Ok(false)
+    fn reload(&self) -> anyhow::Result<bool> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+        let reader = Reader::open_readfile(path)
+            .map_err(|e| anyhow::anyhow!("open geoip db {} failed: {}", path.display(), e))?;
+        self.reader.store(Some(Arc::new(reader)));
+        Ok(true)
+    }
+}
+
+impl GeoIp {
+    /// 启动时加载；库文件缺失或损坏只打 warn（通常由单独的任务下载），之后由 GeoIpUpdater 补上
+    pub fn load(cfg: &GeoIpConfig) -> Self {
+        let g = Self {
+            country: Arc::new(GeoDb::new(cfg.country_db.clone())),
+            asn: Arc::new(GeoDb::new(cfg.asn_db.clone())),
+        };
+        g.reload();
+        g
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.country.path.is_some() || self.asn.path.is_some()
+    }
+
+    /// 已配置的库文件路径，用于热更新检测
+    pub fn paths(&self) -> Vec<&Path> {
+        [&self.country.path, &self.asn.path].into_iter().flatten().map(|p| p.as_path()).collect()
+    }
+
+    /// 重新加载两个库；单个库失败时保留旧内容
+    pub fn reload(&self) {
+        for (name, db) in [("country", &self.country), ("asn", &self.asn)] {
+            match db.reload() {
+                Ok(true) => tracing::info!(db = name, "geoip db loaded"),
+                Ok(false) => {}
+                Err(e) => tracing::warn!(db = name, "geoip db load failed (keep old): {:#}", e),
+            }
+        }
+    }
+
+    /// ISO 3166-1 alpha-2 国家代码（大写）
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let r = self.country.reader.load();
+        let rec: CountryRecord = r.as_ref()?.lookup(ip).ok()?;
+        rec.country
+            .and_then(|c| c.iso_code)
+            .or_else(|| rec.registered_country.and_then(|c| c.iso_code))
+            .map(|s| s.to_ascii_uppercase())
+    }
+
+    pub fn asn(&self, ip: IpAddr) -> Option<u32> {
+        let r = self.asn.reader.load();
+        let rec: AsnRecord = r.as_ref()?.lookup(ip).ok()?;
+        rec.autonomous_system_number
+    }
+
+    /// 按 client_ip 填充 wctx 的 country / asn
+    pub fn enrich(&self, wctx: &mut WafContext) {
+        let Some(ip) = wctx.client_ip else {
+            return;
+        };
+        wctx.country = self.country(ip);
+        wctx.asn = self.asn(ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::iplist::IpLists;
+    use crate::policy::protection::compiled::compile_match;
+    use crate::policy::protection::matcher;
+
+    // MaxMind DB 数据段编码，只用到字符串 / uint32 / map
+    fn utf8(s: &str) -> Vec<u8> {
+        // 29..=284 字节的长度放在后随的一个字节里
+        let mut out = match s.len() {
+            n @ 0..=28 => vec![0x40 | n as u8],
+            n => vec![0x40 | 29, (n - 29) as u8],
+        };
+        out.extend_from_slice(s.as_bytes());
+        out
+    }
+
+    fn uint32(n: u32) -> Vec<u8> {
+        let mut out = vec![0xC4];
+        out.extend_from_slice(&n.to_be_bytes());
+        out
+    }
+
+    fn map(pairs: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut out = vec![0xE0 | pairs.len() as u8];
+        for (k, v) in pairs {
+            out.extend(utf8(k));
+            out.extend_from_slice(v);
+        }
+        out
+    }
+
+    /// 只含 IPv4 前缀的最小 .mmdb（24 位记录），写到临时文件
+    fn mmdb(name: &str, db_type: &str, records: &[(&str, Vec<u8>)]) -> PathBuf {
+        #[derive(Clone, Copy)]
+        enum Rec {
+            Empty,
+            Node(usize),
+            Data(usize),
+        }
+        let mut nodes = vec![[Rec::Empty; 2]];
+        let mut data = Vec::new();
+        for (cidr, rec) in records {
+            let net: ipnet::Ipv4Net = cidr.parse().unwrap();
+            let bits = u32::from(net.addr());
+            let mut node = 0;
+            for i in 0..net.prefix_len() {
+                let bit = (bits >> (31 - i) & 1) as usize;
+                if i + 1 == net.prefix_len() {
+                    nodes[node][bit] = Rec::Data(data.len());
+                    break;
+                }
+                node = match nodes[node][bit] {
+                    Rec::Node(n) => n,
+                    _ => {
+                        nodes.push([Rec::Empty; 2]);
+                        nodes[node][bit] = Rec::Node(nodes.len() - 1);
+                        nodes.len() - 1
+                    }
+                };
+            }
+            data.extend_from_slice(rec);
+        }
+
+        let count = nodes.len();
+        let mut out = Vec::new();
+        for r in nodes.iter().flatten() {
+            let v = match *r {
+                Rec::Empty => count,
+                Rec::Node(n) => n,
+                Rec::Data(off) => count + 16 + off,
+            };
+            out.extend_from_slice(&(v as u32).to_be_bytes()[1..]);
+        }
+        out.extend_from_slice(&[0; 16]);
+        out.extend(data);
+        out.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+        out.extend(map(&[
+            ("binary_format_major_version", uint32(2)),
+            ("binary_format_minor_version", uint32(0)),
+            ("build_epoch", uint32(0)),
+            ("database_type", utf8(db_type)),
+            ("description", map(&[])),
+            ("ip_version", uint32(4)),
+            // 空数组（扩展类型 11）
+            ("languages", vec![0x00, 0x04]),
+            ("node_count", uint32(count as u32)),
+            ("record_size", uint32(24)),
+        ]));
+
+        let path = std::env::temp_dir().join(format!("geoip-{}-{name}.mmdb", std::process::id()));
+        std::fs::write(&path, out).unwrap();
+        path
+    }
+
+    fn iso(code: &str) -> Vec<u8> {
+        map(&[("iso_code", utf8(code))])
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn load(name: &str) -> GeoIp {
+        let country_db = mmdb(
+            &format!("{name}-country"),
+            "Test-Country",
+            &[
+                ("1.0.0.0/8", map(&[("country", iso("de")), ("registered_country", iso("DE"))])),
+                // 没有 country 时用 registered_country
+                ("2.0.0.0/8", map(&[("registered_country", iso("fr"))])),
+                ("3.0.0.0/16", map(&[("country", iso("US")), ("registered_country", iso("CA"))])),
+                ("4.0.0.0/8", map(&[("country", map(&[]))])),
+            ],
+        );
+        let asn_db = mmdb(
+            &format!("{name}-asn"),
+            "Test-ASN",
+            &[
+                ("1.0.0.0/8", map(&[("autonomous_system_number", uint32(13335))])),
+                ("2.0.0.0/8", map(&[("autonomous_system_organization", utf8("no number"))])),
+            ],
+        );
+        let g = GeoIp::load(&GeoIpConfig { country_db: Some(country_db.clone()), asn_db: Some(asn_db.clone()), ..Default::default() });
+        std::fs::remove_file(country_db).unwrap();
+        std::fs::remove_file(asn_db).unwrap();
+        g
+    }
+
+    #[test]
+    fn country_and_asn_lookup() {
+        let g = load("lookup");
+        assert!(g.is_enabled());
+
+        assert_eq!(g.country(ip("1.2.3.4")).as_deref(), Some("DE"));
+        assert_eq!(g.country(ip("2.2.3.4")).as_deref(), Some("FR"));
+        assert_eq!(g.country(ip("3.0.255.1")).as_deref(), Some("US"));
+        assert_eq!(g.country(ip("3.1.0.1")), None);
+        assert_eq!(g.country(ip("4.4.4.4")), None);
+        assert_eq!(g.country(ip("9.9.9.9")), None);
+
+        assert_eq!(g.asn(ip("1.1.1.1")), Some(13335));
+        assert_eq!(g.asn(ip("2.2.2.2")), None);
+        assert_eq!(g.asn(ip("9.9.9.9")), None);
+
+        let mut wctx = WafContext { client_ip: Some(ip("1.0.0.1")), ..Default::default() };
+        g.enrich(&mut wctx);
+        assert_eq!((wctx.country.as_deref(), wctx.asn), (Some("DE"), Some(13335)));
+    }
+
+    #[test]
+    fn missing_db_matches_nothing() {
+        let missing = GeoIp::load(&GeoIpConfig {
+            country_db: Some(std::env::temp_dir().join("geoip-does-not-exist.mmdb")),
+            ..Default::default()
+        });
+        assert!(missing.is_enabled());
+        assert_eq!(missing.country(ip("1.2.3.4")), None);
+        assert!(!GeoIp::default().is_enabled());
+
+        let lists = IpLists::default();
+        let expr = |yaml: &str| compile_match(&serde_yaml::from_str(yaml).unwrap(), &lists).unwrap();
+        let country = expr("{ country_in: [de, us] }");
+        let asn = expr("{ asn_in: [13335] }");
+        let not_country = expr("{ not: { country_in: [de] } }");
+        let headers = http::HeaderMap::new();
+        let eval = |g: &GeoIp, addr: &str| {
+            let mut wctx = WafContext { client_ip: Some(ip(addr)), ..Default::default() };
+            g.enrich(&mut wctx);
+            [&country, &asn, &not_country].map(|m| matcher::eval(m, &wctx, &headers))
+        };
+
+        // 未加载库：country_in / asn_in 都不命中，取反则命中
+        for g in [&missing, &GeoIp::default()] {
+            assert_eq!(eval(g, "1.2.3.4"), [false, false, true]);
+        }
+        // 加载后按大写代码比较
+        let g = load("match");
+        assert_eq!(eval(&g, "1.2.3.4"), [true, true, false]);
+        assert_eq!(eval(&g, "2.2.3.4"), [false, false, true]);
+    }
+}
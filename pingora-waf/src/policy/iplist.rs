@@ -0,0 +1,281 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use ipnet::IpNet;
+
+/// IPv4 / IPv6 前缀树：插入 CIDR，按客户端 IP 做最长路径查找，O(地址位数)
+#[derive(Debug, Default)]
+pub struct IpTrie {
+    v4: BitTrie,
+    v6: BitTrie,
+}
+
+#[derive(Debug)]
+struct BitTrie {
+    /// nodes[0] 为根；child 为 0 表示没有子节点（根不会是任何节点的子节点）
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Node {
+    child: [u32; 2],
+    /// 从根到这里的前缀是一条已插入的 CIDR
+    term: bool,
+}
+
+impl Default for BitTrie {
+    fn default() -> Self {
+        Self { nodes: vec![Node::default()] }
+    }
+}
+
+impl BitTrie {
+    /// bits 高位对齐
+    fn insert(&mut self, bits: u128, prefix_len: u8) {
+        let mut n = 0usize;
+        for i in 0..prefix_len as u32 {
+            if self.nodes[n].term {
+                // 已被更短的前缀覆盖
+                return;
+            }
+            let b = ((bits >> (127 - i)) & 1) as usize;
+            let next = self.nodes[n].child[b] as usize;
+            n = if next == 0 {
+                self.nodes.push(Node::default());
+                let idx = self.nodes.len() - 1;
+                self.nodes[n].child[b] = idx as u32;
+                idx
+            } else {
+                next
+            };
+        }
+        self.nodes[n].term = true;
+    }
+
+    fn contains(&self, bits: u128, width: u32) -> bool {
+        let mut n = 0usize;
+        for i in 0..width {
+            if self.nodes[n].term {
+                return true;
+            }
+            let b = ((bits >> (127 - i)) & 1) as usize;
+            n = self.nodes[n].child[b] as usize;
+            if n == 0 {
+                return false;
+            }
+        }
+        self.nodes[n].term
+    }
+}
+
+impl IpTrie {
+    pub fn insert(&mut self, net: IpNet) {
+        match net {
+            IpNet::V4(n) => self.v4.insert((u32::from(n.network()) as u128) << 96, n.prefix_len()),
+            IpNet::V6(n) => self.v6.insert(u128::from(n.network()), n.prefix_len()),
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // ::ffff:a.b.c.d 按 IPv4 匹配
+        match ip.to_canonical() {
+            IpAddr::V4(a) => self.v4.contains((u32::from(a) as u128) << 96, 32),
+            IpAddr::V6(a) => self.v6.contains(u128::from(a), 128),
+        }
+    }
+
+    /// 策略里的 `ip_in: [...]`
+    pub fn from_cidrs(items: &[String]) -> anyhow::Result<Self> {
+        let mut t = Self::default();
+        for s in items {
+            t.insert(parse_net(s)?);
+        }
+        Ok(t)
+    }
+
+    /// 名单文件：每行一个 IP 或 CIDR，`#` 之后为注释，空行忽略
+    pub fn parse_list(text: &str) -> anyhow::Result<Self> {
+        let mut t = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let item = line.split('#').next().unwrap_or("").trim();
+            if item.is_empty() {
+                continue;
+            }
+            t.insert(parse_net(item).with_context(|| format!("line {}", i + 1))?);
+        }
+        Ok(t)
+    }
+}
+
+/// `10.0.0.0/8`、`2001:db8::/32` 或单个地址
+fn parse_net(s: &str) -> anyhow::Result<IpNet> {
+    let s = s.trim();
+    match s.parse::<IpNet>() {
+        Ok(n) => Ok(n.trunc()),
+        Err(_) => s
+            .parse::<IpAddr>()
+            .map(IpNet::from)
+            .map_err(|_| anyhow::anyhow!("invalid ip or cidr '{}'", s)),
+    }
+}
+
+/// 策略编译时拿到的名单引用；名单热更新只替换槽位里的前缀树，不需要重新编译策略
+#[derive(Debug, Clone)]
+pub struct IpListRef {
+    slot: Arc<ArcSwap<IpTrie>>,
+}
+
+impl IpListRef {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.slot.load().contains(ip)
+    }
+}
+
+/// 命名 IP 名单：目录下的 `<name>.txt` / `<name>.list`
+#[derive(Clone, Default)]
+pub struct IpLists {
+    slots: Arc<DashMap<String, Arc<ArcSwap<IpTrie>>>>,
+}
+
+impl IpLists {
+    pub fn get(&self, name: &str) -> Option<IpListRef> {
+        self.slots.get(name).map(|s| IpListRef { slot: s.value().clone() })
+    }
+
+    /// 重新加载整个目录。单个文件解析失败时保留该名单的旧内容；文件被删除的名单清空
+    /// （已编译的策略仍引用它，匹配不到任何 IP）。返回成功加载的名单数。
+    pub fn reload_dir(&self, dir: &Path) -> anyhow::Result<usize> {
+        let rd = std::fs::read_dir(dir).with_context(|| format!("read lists dir failed: {}", dir.display()))?;
+
+        let mut seen = Vec::new();
+        let mut loaded = 0;
+        for ent in rd {
+            let path = ent?.path();
+            if !is_list_file(&path) {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|s| s.to_str()).map(str::to_string) else {
+                continue;
+            };
+            seen.push(name.clone());
+
+            let parsed = std::fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|text| IpTrie::parse_list(&text));
+            match parsed {
+                Ok(t) => {
+                    self.store(&name, t);
+                    loaded += 1;
+                }
+                Err(e) => tracing::error!("ip list {} reload failed (keep old): {:#}", path.display(), e),
+            }
+        }
+
+        for it in self.slots.iter() {
+            if !seen.contains(it.key()) {
+                tracing::warn!(list = %it.key(), "ip list file removed, list is now empty");
+                it.value().store(Arc::new(IpTrie::default()));
+            }
+        }
+        Ok(loaded)
+    }
+
+    fn store(&self, name: &str, t: IpTrie) {
+        match self.slots.get(name) {
+            Some(s) => s.store(Arc::new(t)),
+            None => {
+                self.slots.insert(name.to_string(), Arc::new(ArcSwap::from_pointee(t)));
+            }
+        }
+    }
+}
+
+pub fn is_list_file(p: &Path) -> bool {
+    matches!(p.extension().and_then(|s| s.to_str()), Some("txt" | "list"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trie(items: &[&str]) -> IpTrie {
+        IpTrie::from_cidrs(&items.iter().map(|s| s.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    fn has(t: &IpTrie, ip: &str) -> bool {
+        t.contains(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn zero_prefix_matches_everything() {
+        let t = trie(&["0.0.0.0/0", "::/0"]);
+        assert!(has(&t, "0.0.0.0"));
+        assert!(has(&t, "255.255.255.255"));
+        assert!(has(&t, "2001:db8::1"));
+        assert!(has(&t, "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"));
+
+        let v4_only = trie(&["0.0.0.0/0"]);
+        assert!(!has(&v4_only, "2001:db8::1"));
+    }
+
+    #[test]
+    fn host_entries() {
+        let t = trie(&["192.0.2.1", "198.51.100.7/32", "2001:db8::1", "2001:db8::2/128"]);
+        assert!(has(&t, "192.0.2.1"));
+        assert!(has(&t, "198.51.100.7"));
+        assert!(!has(&t, "192.0.2.0"));
+        assert!(!has(&t, "192.0.2.2"));
+        assert!(has(&t, "2001:db8::1"));
+        assert!(has(&t, "2001:db8::2"));
+        assert!(!has(&t, "2001:db8::3"));
+    }
+
+    // 掩码外的位被截掉
+    #[test]
+    fn host_bits_are_truncated() {
+        let t = trie(&["10.1.2.3/8"]);
+        assert!(has(&t, "10.200.0.1"));
+        assert!(!has(&t, "11.0.0.1"));
+    }
+
+    #[test]
+    fn covered_prefixes_in_either_order() {
+        let mut t = trie(&["10.0.0.0/8"]);
+        let nodes = t.v4.nodes.len();
+        t.insert(parse_net("10.1.0.0/16").unwrap());
+        assert_eq!(t.v4.nodes.len(), nodes);
+        assert!(has(&t, "10.1.2.3"));
+        assert!(has(&t, "10.200.0.1"));
+
+        let t = trie(&["10.1.0.0/16", "10.0.0.0/8"]);
+        assert!(has(&t, "10.1.2.3"));
+        assert!(has(&t, "10.200.0.1"));
+        assert!(!has(&t, "11.0.0.1"));
+    }
+
+    #[test]
+    fn mapped_ipv6_matches_ipv4_entries() {
+        let t = trie(&["10.0.0.0/8", "192.0.2.1"]);
+        assert!(has(&t, "::ffff:10.1.2.3"));
+        assert!(has(&t, "::ffff:192.0.2.1"));
+        assert!(!has(&t, "::ffff:192.0.2.2"));
+        // IPv4-compatible（::a.b.c.d）不是映射地址
+        assert!(!has(&t, "::10.1.2.3"));
+    }
+
+    #[test]
+    fn parse_list_comments_and_errors() {
+        let t = IpTrie::parse_list("# office\n\n10.0.0.0/8  # vpn\n  2001:db8::/32\n#192.0.2.1\n").unwrap();
+        assert!(has(&t, "10.9.9.9"));
+        assert!(has(&t, "2001:db8::5"));
+        assert!(!has(&t, "192.0.2.1"));
+
+        let err = IpTrie::parse_list("10.0.0.0/8\n# comment\nnot-an-ip\n").unwrap_err();
+        assert_eq!(format!("{err:#}"), "line 3: invalid ip or cidr 'not-an-ip'");
+        assert!(IpTrie::parse_list("10.0.0.0/33").is_err());
+    }
+}
@@ -7,6 +7,8 @@ use std::{
 use anyhow::Context;
 use arc_swap::ArcSwap;
 use crate::policy::cc::CcLimiter;
+use crate::policy::iplist::IpLists;
+use crate::waf::engine::WafEngine;
 use super::{
     compiled::{compile_policy, CompiledPolicy},
     domain_map::{DomainMapFile, DomainMatcher},
@@ -55,18 +57,25 @@ impl PolicyManager {
                     waf: Default::default(),
                     precise: vec![],
                     base: vec![],
+                    exclusions: vec![],
                 })
             })
     }
 
-    pub fn load_from_files(domain_map_path: &Path, policies_dir: &Path) -> anyhow::Result<PolicyState> {
+    pub fn load_from_files(
+        domain_map_path: &Path,
+        policies_dir: &Path,
+        waf: &WafEngine,
+        lists: &IpLists,
+        cc: Arc<CcLimiter>,
+    ) -> anyhow::Result<PolicyState> {
         let dm_bytes = std::fs::read(domain_map_path)
             .with_context(|| format!("read domain_map failed: {}", domain_map_path.display()))?;
         let dm: DomainMapFile = serde_yaml::from_slice(&dm_bytes)
             .with_context(|| "parse domain_map yaml failed")?;
         let matcher = DomainMatcher::from_file(dm);
 
-        let policies = load_and_compile_policies_dir(policies_dir)?;
+        let policies = load_and_compile_policies_dir(policies_dir, waf, lists)?;
 
         let default_id = matcher.default_policy().to_string();
         if !policies.contains_key(&default_id) {
@@ -80,13 +89,15 @@ impl PolicyManager {
         Ok(PolicyState {
             matcher,
             policies,
-            cc: Arc::new(CcLimiter::new()),
+            cc,
         })
     }
 }
 
 fn load_and_compile_policies_dir(
     policies_dir: &Path,
+    waf: &WafEngine,
+    lists: &IpLists,
 ) -> anyhow::Result<HashMap<String, Arc<CompiledPolicy>>> {
     let mut map = HashMap::new();
 
@@ -105,7 +116,7 @@ fn load_and_compile_policies_dir(
         let p: PolicyFile = serde_yaml::from_slice(&bytes)
             .with_context(|| format!("parse policy failed: {}", path.display()))?;
 
-        let compiled = compile_policy(&p)
+        let compiled = compile_policy(&p, waf, lists)
             .with_context(|| format!("compile policy failed: {}", path.display()))?;
 
         map.insert(compiled.id.clone(), compiled);
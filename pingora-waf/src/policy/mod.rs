@@ -4,6 +4,15 @@ pub mod domain_map;
 pub mod manager;
 pub mod update;
 pub mod enforcer;
+pub mod challenge;
+pub mod cc;
+pub mod cc_backend;
+pub mod cc_persist;
+pub mod cc_prune;
+pub mod iplist;
+pub mod geoip;
 mod compiled;
 mod protection;
-mod cc;
\ No newline at end of file
+
+/// 请求 key 拼装（CC key_parts 写法），upstream 一致性哈希复用
+pub use protection::key::build_key;
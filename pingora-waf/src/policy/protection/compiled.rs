@@ -1,5 +1,11 @@
+use std::sync::Arc;
+
 use regex::Regex;
 
+use crate::policy::cc::CcAlgo;
+use crate::policy::iplist::{IpListRef, IpLists, IpTrie};
+use crate::waf::decision::ChallengeKind;
+
 use super::types::*;
 
 #[derive(Debug, Clone)]
@@ -21,6 +27,11 @@ pub enum CompiledMatchExpr {
     HeaderEquals { name: String, value: String },
     HeaderRegex { name: String, re: Regex },
 
+    IpIn(Arc<IpTrie>),
+    IpInList(IpListRef),
+    CountryIn(Vec<String>),
+    AsnIn(Vec<u32>),
+
     And(Vec<CompiledMatchExpr>),
     Or(Vec<CompiledMatchExpr>),
     Not(Box<CompiledMatchExpr>),
@@ -32,7 +43,7 @@ pub enum CompiledAction {
     Log { reason: String },
 
     Block { status: u16, reason: String },
-    Challenge { status: u16, reason: String },
+    Challenge { status: u16, reason: String, kind: ChallengeKind },
 
     /// ✅ CC 动作（保留 cc 关键字来源）
     Cc {
@@ -40,23 +51,24 @@ pub enum CompiledAction {
         window_secs: u64,
         max_requests: u64,
         block_secs: u64,
+        algorithm: CcAlgo,
         on_limit: Box<CompiledAction>, // 只会是 log/block/challenge
     },
 }
 
-pub fn compile_rules(rs: &[RuleSpec]) -> anyhow::Result<Vec<CompiledRule>> {
+pub fn compile_rules(rs: &[RuleSpec], lists: &IpLists) -> anyhow::Result<Vec<CompiledRule>> {
     let mut out = Vec::with_capacity(rs.len());
     for r in rs {
         out.push(CompiledRule {
             id: r.id.clone(),
-            matcher: compile_match(&r.match_expr)?,
+            matcher: compile_match(&r.match_expr, lists)?,
             action: compile_action(&r.action)?,
         });
     }
     Ok(out)
 }
 
-fn compile_match(m: &MatchExpr) -> anyhow::Result<CompiledMatchExpr> {
+pub fn compile_match(m: &MatchExpr, lists: &IpLists) -> anyhow::Result<CompiledMatchExpr> {
     Ok(match m {
         MatchExpr::Any => CompiledMatchExpr::Any,
 
@@ -80,10 +92,27 @@ fn compile_match(m: &MatchExpr) -> anyhow::Result<CompiledMatchExpr> {
             }
         }
 
+        MatchExpr::IpIn { ip_in } => CompiledMatchExpr::IpIn(Arc::new(
+            IpTrie::from_cidrs(ip_in).map_err(|e| anyhow::anyhow!("bad ip_in: {}", e))?,
+        )),
+
+        MatchExpr::IpInList { ip_in_list } => CompiledMatchExpr::IpInList(
+            lists.get(ip_in_list).ok_or_else(|| anyhow::anyhow!("unknown ip list '{}'", ip_in_list))?,
+        ),
+
+        MatchExpr::CountryIn { country_in } => {
+            if let Some(c) = country_in.iter().find(|c| c.len() != 2 || !c.chars().all(|x| x.is_ascii_alphabetic())) {
+                anyhow::bail!("bad country_in code '{}' (want ISO 3166-1 alpha-2)", c);
+            }
+            CompiledMatchExpr::CountryIn(country_in.iter().map(|c| c.to_ascii_uppercase()).collect())
+        }
+
+        MatchExpr::AsnIn { asn_in } => CompiledMatchExpr::AsnIn(asn_in.clone()),
+
         MatchExpr::And { and } => {
             let mut xs = Vec::with_capacity(and.len());
             for x in and {
-                xs.push(compile_match(x)?);
+                xs.push(compile_match(x, lists)?);
             }
             CompiledMatchExpr::And(xs)
         }
@@ -91,12 +120,12 @@ fn compile_match(m: &MatchExpr) -> anyhow::Result<CompiledMatchExpr> {
         MatchExpr::Or { or } => {
             let mut xs = Vec::with_capacity(or.len());
             for x in or {
-                xs.push(compile_match(x)?);
+                xs.push(compile_match(x, lists)?);
             }
             CompiledMatchExpr::Or(xs)
         }
 
-        MatchExpr::Not { not } => CompiledMatchExpr::Not(Box::new(compile_match(not)?)),
+        MatchExpr::Not { not } => CompiledMatchExpr::Not(Box::new(compile_match(not, lists)?)),
     })
 }
 
@@ -108,24 +137,70 @@ fn compile_action(a: &ActionSpec) -> anyhow::Result<CompiledAction> {
 
         ActionSpec::Block { block } => CompiledAction::Block { status: block.status, reason: block.reason.clone() },
 
-        ActionSpec::Challenge { challenge } => CompiledAction::Challenge {
-            status: challenge.status,
-            reason: challenge.reason.clone(),
-        },
+        ActionSpec::Challenge { challenge } => compile_challenge(challenge)?,
 
         ActionSpec::Cc { cc } => {
             let on = match &cc.on_limit {
                 OnLimitActionSpec::Log { log } => CompiledAction::Log { reason: log.reason.clone() },
                 OnLimitActionSpec::Block { block } => CompiledAction::Block { status: block.status, reason: block.reason.clone() },
-                OnLimitActionSpec::Challenge { challenge } => CompiledAction::Challenge { status: challenge.status, reason: challenge.reason.clone() },
+                OnLimitActionSpec::Challenge { challenge } => compile_challenge(challenge)?,
             };
             CompiledAction::Cc {
                 key_parts: cc.key_parts.clone(),
                 window_secs: cc.window_secs,
                 max_requests: cc.max_requests,
                 block_secs: cc.block_secs,
+                algorithm: compile_cc_algorithm(cc)?,
                 on_limit: Box::new(on),
             }
         }
     })
 }
+
+/// 滑动窗口日志每个 key 最多保存 max_requests 个时间点，限制上限避免内存放大
+const CC_LOG_MAX_REQUESTS: u64 = 10_000;
+
+fn compile_cc_algorithm(cc: &CcSpec) -> anyhow::Result<CcAlgo> {
+    Ok(match cc.algorithm {
+        CcAlgorithm::FixedWindow => CcAlgo::FixedWindow,
+        CcAlgorithm::SlidingWindowLog => {
+            if cc.max_requests > CC_LOG_MAX_REQUESTS {
+                anyhow::bail!(
+                    "sliding_window_log max_requests {} exceeds {}, use sliding_window_counter",
+                    cc.max_requests,
+                    CC_LOG_MAX_REQUESTS
+                );
+            }
+            CcAlgo::SlidingWindowLog
+        }
+        CcAlgorithm::SlidingWindowCounter => CcAlgo::SlidingWindowCounter,
+        CcAlgorithm::TokenBucket => {
+            let (burst, refill_per_sec) = (cc.burst(), cc.refill_per_sec());
+            if burst == 0 {
+                anyhow::bail!("token_bucket burst must be > 0");
+            }
+            if !(refill_per_sec.is_finite() && refill_per_sec > 0.0) {
+                anyhow::bail!("token_bucket refill_per_sec must be > 0, got {}", refill_per_sec);
+            }
+            CcAlgo::TokenBucket { burst, refill_per_sec }
+        }
+    })
+}
+
+fn compile_challenge(c: &ChallengeSpec) -> anyhow::Result<CompiledAction> {
+    let kind = match c.kind {
+        ChallengeType::Js => ChallengeKind::Js,
+        ChallengeType::Pow => {
+            let d = c.difficulty.ok_or_else(|| anyhow::anyhow!("pow challenge needs difficulty"))?;
+            if d == 0 || d > POW_MAX_DIFFICULTY {
+                anyhow::bail!("pow difficulty must be in 1..={}, got {}", POW_MAX_DIFFICULTY, d);
+            }
+            let max = c.max_difficulty.unwrap_or(d + 8).min(POW_MAX_DIFFICULTY);
+            if max < d {
+                anyhow::bail!("pow max_difficulty {} is below difficulty {}", max, d);
+            }
+            ChallengeKind::Pow { difficulty: d, max_difficulty: max }
+        }
+    };
+    Ok(CompiledAction::Challenge { status: c.status(), reason: c.reason(), kind })
+}
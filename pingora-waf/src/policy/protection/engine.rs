@@ -10,18 +10,25 @@ use super::matcher::{self, HeaderView};
 pub struct ProtectionEngine;
 
 impl ProtectionEngine {
+    /// `challenge`：challenge 结果先交给它（上调 pow 难度、比对放行 cookie），返回 None 表示已通过，继续匹配后续规则
     pub fn eval_rules(
         rules: &[CompiledRule],
         wctx: &WafContext,
         headers: &dyn HeaderView,
         limiter: &CcLimiter,
+        challenge: &dyn Fn(Decision) -> Option<Decision>,
     ) -> Decision {
         for r in rules {
             if !matcher::eval(&r.matcher, wctx, headers) {
                 continue;
             }
-            let d = Self::exec_action(&r.id, &r.action, wctx, headers, limiter);
-
+            let d = match Self::exec_action(&r.id, &r.action, wctx, headers, limiter) {
+                d @ Decision::Challenge { .. } => match challenge(d) {
+                    Some(d) => d,
+                    None => continue,
+                },
+                d => d,
+            };
             // Log 不终止，并且我们把 log 当作 side-effect，因此这里直接继续
             if d.is_terminal() {
                 return d;
@@ -33,6 +40,18 @@ impl ProtectionEngine {
         Decision::Allow
     }
 
+    /// 客户端在这些规则的 CC 计数里累计被封禁的最大次数
+    pub fn cc_strikes(rules: &[CompiledRule], wctx: &WafContext, headers: &dyn HeaderView, limiter: &CcLimiter) -> u32 {
+        rules
+            .iter()
+            .filter_map(|r| match &r.action {
+                CompiledAction::Cc { key_parts, .. } => Some(limiter.strikes(&r.id, &build_key(key_parts, wctx, headers))),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
     fn exec_action(
         rule_id: &str,
         action: &CompiledAction,
@@ -55,19 +74,21 @@ impl ProtectionEngine {
                 reason: reason.clone(),
             },
 
-            CompiledAction::Challenge { status, reason } => Decision::Challenge {
+            CompiledAction::Challenge { status, reason, kind } => Decision::Challenge {
                 status: *status,
                 rule_id: rule_id.to_string(),
                 reason: reason.clone(),
+                kind: *kind,
             },
 
-            CompiledAction::Cc { key_parts, window_secs, max_requests, block_secs, on_limit } => {
+            CompiledAction::Cc { key_parts, window_secs, max_requests, block_secs, algorithm, on_limit } => {
                 let key_body = build_key(key_parts, wctx, headers);
 
                 let params = CcParams {
                     window_secs: *window_secs,
                     max_requests: *max_requests,
                     block_secs: *block_secs,
+                    algorithm: *algorithm,
                 };
 
                 if let Some(hit) = limiter.check(rule_id, &key_body, params) {
@@ -86,10 +107,11 @@ impl ProtectionEngine {
                             Decision::Allow
                         }
 
-                        CompiledAction::Challenge { status, reason } => Decision::Challenge {
+                        CompiledAction::Challenge { status, reason, kind } => Decision::Challenge {
                             status: *status,
                             rule_id: rule_id.to_string(),
                             reason: format!("{}; {}", hit.reason, reason),
+                            kind: *kind,
                         },
 
                         CompiledAction::Block { status, reason } => Decision::Block {
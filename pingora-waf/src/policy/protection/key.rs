@@ -25,6 +25,8 @@ fn part_value(part: &str, wctx: &WafContext, headers: &dyn HeaderView, cookie_he
         "path" => wctx.path.clone(),
         "method" => wctx.method.clone(),
         "user_agent" => wctx.user_agent.clone().unwrap_or_default(),
+        "country" => wctx.country.clone().unwrap_or_default(),
+        "asn" => wctx.asn.map(|a| a.to_string()).unwrap_or_default(),
 
         _ => {
             if let Some(name) = part.strip_prefix("header:") {
@@ -1,11 +1,8 @@
+pub use crate::waf::context::HeaderView;
 use crate::waf::context::WafContext;
 
 use super::compiled::CompiledMatchExpr;
 
-pub trait HeaderView {
-    fn get(&self, name: &str) -> Option<&str>;
-}
-
 pub fn eval(m: &CompiledMatchExpr, wctx: &WafContext, headers: &dyn HeaderView) -> bool {
     match m {
         CompiledMatchExpr::Any => true,
@@ -25,6 +22,13 @@ pub fn eval(m: &CompiledMatchExpr, wctx: &WafContext, headers: &dyn HeaderView)
 
         CompiledMatchExpr::HeaderRegex { name, re } => headers.get(name).is_some_and(|v| re.is_match(v)),
 
+        CompiledMatchExpr::IpIn(t) => wctx.client_ip.is_some_and(|ip| t.contains(ip)),
+        CompiledMatchExpr::IpInList(l) => wctx.client_ip.is_some_and(|ip| l.contains(ip)),
+
+        // 没有 geoip 库或查不到时不命中
+        CompiledMatchExpr::CountryIn(cs) => wctx.country.as_ref().is_some_and(|c| cs.contains(c)),
+        CompiledMatchExpr::AsnIn(xs) => wctx.asn.is_some_and(|a| xs.contains(&a)),
+
         CompiledMatchExpr::And(xs) => xs.iter().all(|x| eval(x, wctx, headers)),
         CompiledMatchExpr::Or(xs) => xs.iter().any(|x| eval(x, wctx, headers)),
         CompiledMatchExpr::Not(x) => !eval(x, wctx, headers),
@@ -32,6 +32,15 @@ pub enum MatchExpr {
     HeaderEquals { header_equals: HeaderEq },
     HeaderRegex { header_regex: HeaderRegex },
 
+    /// 客户端 IP 命中任一 IP / CIDR
+    IpIn { ip_in: Vec<String> },
+    /// 客户端 IP 命中名单目录里的 `<name>.txt`，名单可单独热更新
+    IpInList { ip_in_list: String },
+    /// 客户端国家（ISO 代码，如 CN / US）命中任一；需要配置 geoip.country_db
+    CountryIn { country_in: Vec<String> },
+    /// 客户端 ASN 命中任一；需要配置 geoip.asn_db
+    AsnIn { asn_in: Vec<u32> },
+
     And { and: Vec<MatchExpr> },
     Or { or: Vec<MatchExpr> },
     Not { not: Box<MatchExpr> },
@@ -55,7 +64,7 @@ pub enum ActionSpec {
     Allow { allow: AllowSpec },
     Log { log: LogSpec },
     Block { block: BlockSpec },
-    Challenge { challenge: BlockSpec },
+    Challenge { challenge: ChallengeSpec },
 
     // ✅ 保留 cc 关键字
     Cc { cc: CcSpec },
@@ -78,6 +87,42 @@ pub struct BlockSpec {
     pub reason: String,
 }
 
+/// pow 难度上限：浏览器纯 JS SHA-256 约每秒几十万次，28 位已需要数分钟
+pub const POW_MAX_DIFFICULTY: u32 = 28;
+
+/// 挑战动作：`{ status, reason }` 为 JS 挑战，`{ type: pow, difficulty: N }` 为工作量证明
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChallengeSpec {
+    /// 默认 403
+    pub status: Option<u16>,
+    /// 默认 "challenge"
+    pub reason: Option<String>,
+    #[serde(rename = "type", default)]
+    pub kind: ChallengeType,
+    /// pow：要求 SHA-256 前导零比特数
+    pub difficulty: Option<u32>,
+    /// pow：CC 已标记的客户端难度逐级上调的上限，默认 difficulty + 8
+    pub max_difficulty: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChallengeType {
+    #[default]
+    Js,
+    Pow,
+}
+
+impl ChallengeSpec {
+    pub fn status(&self) -> u16 {
+        self.status.unwrap_or(403)
+    }
+
+    pub fn reason(&self) -> String {
+        self.reason.clone().unwrap_or_else(|| "challenge".to_string())
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct CcSpec {
     pub key_parts: Vec<String>,
@@ -85,14 +130,43 @@ pub struct CcSpec {
     pub max_requests: u64,
     pub block_secs: u64,
 
+    /// 计数算法，默认 fixed_window
+    #[serde(default)]
+    pub algorithm: CcAlgorithm,
+    /// token_bucket：桶容量，默认 max_requests
+    pub burst: Option<u64>,
+    /// token_bucket：每秒补充令牌数，默认 max_requests / window_secs
+    pub refill_per_sec: Option<f64>,
+
     /// 超限后执行的动作（仍然是统一 Action，只不过限制在 allow/log/block/challenge）
     pub on_limit: OnLimitActionSpec,
 }
 
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CcAlgorithm {
+    #[default]
+    FixedWindow,
+    SlidingWindowLog,
+    SlidingWindowCounter,
+    TokenBucket,
+}
+
+impl CcSpec {
+    pub fn burst(&self) -> u64 {
+        self.burst.unwrap_or(self.max_requests)
+    }
+
+    pub fn refill_per_sec(&self) -> f64 {
+        self.refill_per_sec
+            .unwrap_or(self.max_requests as f64 / self.window_secs.max(1) as f64)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum OnLimitActionSpec {
     Log { log: LogSpec },
     Block { block: BlockSpec },
-    Challenge { challenge: BlockSpec },
+    Challenge { challenge: ChallengeSpec },
 }
@@ -1,6 +1,7 @@
 use serde::Deserialize;
 
-use super::protection::types::ProtectionsSpec;
+use super::protection::types::{MatchExpr, ProtectionsSpec};
+use crate::waf::rules::compiler::DEFAULT_RULESET;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct PolicyFile {
@@ -18,4 +19,95 @@ pub struct PolicyFile {
 pub struct WafConfig {
     pub enabled: bool,
     pub ruleset: Option<String>,
+    /// 配置后按异常分累计判定（CRS 风格），否则首条命中即生效
+    pub anomaly: Option<AnomalyConfig>,
+    /// 结构化 body 检查（表单 / JSON / multipart）的缓冲上限与异常处理
+    #[serde(default)]
+    pub body: BodyInspectionConfig,
+    /// 规则排除：对匹配的请求移除规则，或让规则不检查某些参数 / header
+    #[serde(default)]
+    pub exclusions: Vec<ExclusionSpec>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExclusionSpec {
+    pub id: String,
+
+    #[serde(rename = "match", default)]
+    pub match_expr: MatchExpr,
+
+    /// 按规则 ID 选择
+    #[serde(default)]
+    pub rule_ids: Vec<String>,
+    /// 按规则 tag 选择
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// 不检查的参数名（查询参数和 body 参数）；args / headers 都为空时整条规则移除
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// 不检查的 header 名
+    #[serde(default)]
+    pub headers: Vec<String>,
+}
+
+impl ExclusionSpec {
+    /// 只排除检查目标，规则本身保留
+    pub fn is_target_exclusion(&self) -> bool {
+        !self.args.is_empty() || !self.headers.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct BodyInspectionConfig {
+    /// 最多缓冲多少字节用于解析，默认 128KiB
+    pub max_bytes: Option<usize>,
+    /// body 超过 max_bytes 时的处理，默认 log（放行且不再检查）
+    pub on_oversize: Option<BodyLimitAction>,
+    /// 按 Content-Type 解析失败时的处理，默认 log
+    pub on_parse_error: Option<BodyLimitAction>,
+}
+
+impl BodyInspectionConfig {
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes.unwrap_or(128 * 1024)
+    }
+
+    pub fn on_oversize(&self) -> BodyLimitAction {
+        self.on_oversize.unwrap_or(BodyLimitAction::Log)
+    }
+
+    pub fn on_parse_error(&self) -> BodyLimitAction {
+        self.on_parse_error.unwrap_or(BodyLimitAction::Log)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyLimitAction {
+    /// 放行，不记事件
+    Allow,
+    /// 放行并写 log 事件
+    Log,
+    /// 直接拦截
+    Block,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct AnomalyConfig {
+    /// 入站累计分 >= 阈值时拦截，低于阈值只记日志
+    pub inbound_threshold: Option<u32>,
+}
+
+impl AnomalyConfig {
+    pub fn inbound_threshold(&self) -> u32 {
+        self.inbound_threshold.unwrap_or(5)
+    }
+}
+
+impl WafConfig {
+    /// 未指定 ruleset 时使用 default
+    pub fn ruleset_name(&self) -> &str {
+        self.ruleset.as_deref().unwrap_or(DEFAULT_RULESET)
+    }
 }
@@ -5,18 +5,30 @@ use async_trait::async_trait;
 use pingora::server::ShutdownWatch;
 use pingora_core::services::background::BackgroundService;
 
-use super::manager::{PolicyManager, PolicyState};
+use super::geoip::GeoIp;
+use super::iplist::{is_list_file, IpLists};
+use super::manager::PolicyManager;
+use crate::waf::engine::WafEngine;
 
 pub struct DomainMapUpdater {
     mgr: PolicyManager,
+    waf: WafEngine,
+    lists: IpLists,
     domain_map_path: PathBuf,
     policies_dir: PathBuf,
     interval: Duration,
 }
 
 impl DomainMapUpdater {
-    pub fn new(mgr: PolicyManager, domain_map_path: PathBuf, policies_dir: PathBuf, interval: Duration) -> Self {
-        Self { mgr, domain_map_path, policies_dir, interval }
+    pub fn new(
+        mgr: PolicyManager,
+        waf: WafEngine,
+        lists: IpLists,
+        domain_map_path: PathBuf,
+        policies_dir: PathBuf,
+        interval: Duration,
+    ) -> Self {
+        Self { mgr, waf, lists, domain_map_path, policies_dir, interval }
     }
 }
 
@@ -49,19 +61,16 @@ impl BackgroundService for DomainMapUpdater {
                     }
                     last_mtime = Some(mtime);
 
+                    // 沿用旧 CC limiter（计数状态不丢）
                     match super::manager::PolicyManager::load_from_files(
                         Path::new(&self.domain_map_path),
                         Path::new(&self.policies_dir),
+                        &self.waf,
+                        &self.lists,
+                        self.mgr.load().cc.clone(),
                     ) {
                         Ok(new_state) => {
-                            // 保留旧 CC limiter（计数状态不丢）
-                            let old = self.mgr.load();
-                            let merged = PolicyState {
-                                matcher: new_state.matcher,
-                                policies: new_state.policies,
-                                cc: old.cc.clone(),
-                            };
-                            self.mgr.swap(merged);
+                            self.mgr.swap(new_state);
                             tracing::info!("domain_map reloaded");
                         }
                         Err(e) => {
@@ -76,14 +85,23 @@ impl BackgroundService for DomainMapUpdater {
 
 pub struct PolicyDirUpdater {
     mgr: PolicyManager,
+    waf: WafEngine,
+    lists: IpLists,
     domain_map_path: PathBuf,
     policies_dir: PathBuf,
     interval: Duration,
 }
 
 impl PolicyDirUpdater {
-    pub fn new(mgr: PolicyManager, domain_map_path: PathBuf, policies_dir: PathBuf, interval: Duration) -> Self {
-        Self { mgr, domain_map_path, policies_dir, interval }
+    pub fn new(
+        mgr: PolicyManager,
+        waf: WafEngine,
+        lists: IpLists,
+        domain_map_path: PathBuf,
+        policies_dir: PathBuf,
+        interval: Duration,
+    ) -> Self {
+        Self { mgr, waf, lists, domain_map_path, policies_dir, interval }
     }
 }
 
@@ -100,7 +118,7 @@ impl BackgroundService for PolicyDirUpdater {
                     return;
                 }
                 _ = ticker.tick() => {
-                    let sig = match dir_signature(&self.policies_dir).await {
+                    let sig = match dir_signature(&self.policies_dir, is_yaml).await {
                         Ok(s) => s,
                         Err(e) => {
                             tracing::warn!("policy dir signature error: {}", e);
@@ -113,19 +131,16 @@ impl BackgroundService for PolicyDirUpdater {
                     }
                     last_sig = Some(sig);
 
+                    // 沿用旧 CC limiter
                     match super::manager::PolicyManager::load_from_files(
                         Path::new(&self.domain_map_path),
                         Path::new(&self.policies_dir),
+                        &self.waf,
+                        &self.lists,
+                        self.mgr.load().cc.clone(),
                     ) {
                         Ok(new_state) => {
-                            // 保留旧 CC limiter
-                            let old = self.mgr.load();
-                            let merged = PolicyState {
-                                matcher: new_state.matcher,
-                                policies: new_state.policies,
-                                cc: old.cc.clone(),
-                            };
-                            self.mgr.swap(merged);
+                            self.mgr.swap(new_state);
                             tracing::info!("policies reloaded");
                         }
                         Err(e) => {
@@ -138,15 +153,122 @@ impl BackgroundService for PolicyDirUpdater {
     }
 }
 
-/// 计算目录签名：遍历 yaml 文件，组合 (文件名+mtime+size) 的 hash
-async fn dir_signature(dir: &PathBuf) -> anyhow::Result<u64> {
+/// IP 名单目录热更新：目录签名变化时重新加载全部名单（策略无需重新编译）
+pub struct IpListUpdater {
+    lists: IpLists,
+    lists_dir: PathBuf,
+    interval: Duration,
+}
+
+impl IpListUpdater {
+    pub fn new(lists: IpLists, lists_dir: PathBuf, interval: Duration) -> Self {
+        Self { lists, lists_dir, interval }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for IpListUpdater {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut last_sig: Option<u64> = None;
+        let mut ticker = tokio::time::interval(self.interval);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    tracing::info!("ip list updater shutdown");
+                    return;
+                }
+                _ = ticker.tick() => {
+                    let sig = match dir_signature(&self.lists_dir, is_list_file).await {
+                        Ok(s) => s,
+                        Err(e) => {
+                            tracing::warn!("ip lists dir signature error: {}", e);
+                            continue;
+                        }
+                    };
+
+                    if last_sig.map(|x| x == sig).unwrap_or(false) {
+                        continue;
+                    }
+                    last_sig = Some(sig);
+
+                    match self.lists.reload_dir(&self.lists_dir) {
+                        Ok(n) => tracing::info!(lists = n, "ip lists reloaded"),
+                        Err(e) => tracing::error!("ip lists reload failed (keep old): {}", e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct GeoIpUpdater {
+    geo: GeoIp,
+    interval: Duration,
+}
+
+impl GeoIpUpdater {
+    pub fn new(geo: GeoIp, interval: Duration) -> Self {
+        Self { geo, interval }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for GeoIpUpdater {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut last_sig: Option<u64> = None;
+        let mut ticker = tokio::time::interval(self.interval);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    tracing::info!("geoip updater shutdown");
+                    return;
+                }
+                _ = ticker.tick() => {
+                    // 库文件可能在运行中才出现：缺失的文件也参与签名
+                    let mut sig: u64 = 0xcbf29ce484222325;
+                    for p in self.geo.paths() {
+                        sig = fnv1a_mix(sig, p.as_os_str().as_encoded_bytes());
+                        if let Ok(meta) = tokio::fs::metadata(p).await {
+                            sig = fnv1a_mix(sig, &meta.len().to_le_bytes());
+                            if let Ok(t) = meta.modified() {
+                                let nanos = t.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos();
+                                sig = fnv1a_mix(sig, &nanos.to_le_bytes());
+                            }
+                        }
+                    }
+
+                    // 第一轮只记录签名，启动时已经加载过
+                    let changed = last_sig.is_some_and(|x| x != sig);
+                    last_sig = Some(sig);
+                    if !changed {
+                        continue;
+                    }
+
+                    // mmdb 可能上百 MB，读取和解析放到阻塞线程
+                    let geo = self.geo.clone();
+                    if let Err(e) = tokio::task::spawn_blocking(move || geo.reload()).await {
+                        tracing::error!("geoip reload task failed: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn is_yaml(p: &Path) -> bool {
+    matches!(p.extension().and_then(|s| s.to_str()), Some("yaml" | "yml"))
+}
+
+/// 计算目录签名：遍历 keep 选中的文件，组合 (文件名+mtime+size) 的 hash
+async fn dir_signature(dir: &PathBuf, keep: fn(&Path) -> bool) -> anyhow::Result<u64> {
     let mut h: u64 = 0xcbf29ce484222325;
 
     let mut rd = tokio::fs::read_dir(dir).await?;
     while let Some(ent) = rd.next_entry().await? {
         let p = ent.path();
-        let ext = p.extension().and_then(|s| s.to_str()).unwrap_or("");
-        if ext != "yaml" && ext != "yml" {
+        if !keep(&p) {
             continue;
         }
         let meta = ent.metadata().await?;
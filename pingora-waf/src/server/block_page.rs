@@ -3,14 +3,17 @@ use std::sync::Arc;
 #[derive(Clone)]
 pub struct BlockPage {
     tpl_403: Arc<String>,
+    tpl_challenge: Arc<String>,
 }
 
 impl BlockPage {
     pub fn load_from_assets() -> anyhow::Result<Self> {
         // Built-in template. This avoids runtime fs path issues (industrial-grade behavior).
         let tpl_403 = include_str!("../../assets/block/403.html").to_string();
+        let tpl_challenge = include_str!("../../assets/challenge/challenge.html").to_string();
         Ok(Self {
             tpl_403: Arc::new(tpl_403),
+            tpl_challenge: Arc::new(tpl_challenge),
         })
     }
 
@@ -26,6 +29,20 @@ impl BlockPage {
             .replace("{{time}}", &now)
             .replace("{{brand}}", "Aegis")
     }
+
+    /// JS 挑战页；nonce 只含 [0-9a-zA-Z._-]，callback 来自配置，以 JS 字符串字面量写入
+    /// `difficulty` 为 0 时渲染普通 JS 挑战，否则渲染工作量证明求解页
+    pub fn render_challenge(&self, nonce: &str, difficulty: u32, callback: &str, request_id: &str) -> String {
+        let now = chrono::Utc::now().to_rfc3339();
+        let callback = serde_json::to_string(callback).unwrap_or_else(|_| "\"/\"".to_string());
+        self.tpl_challenge
+            .replace("{{nonce}}", nonce)
+            .replace("{{difficulty}}", &difficulty.to_string())
+            .replace("{{callback}}", &callback)
+            .replace("{{request_id}}", request_id)
+            .replace("{{time}}", &now)
+            .replace("{{brand}}", "Aegis")
+    }
 }
 
 fn html_escape(s: &str) -> String {
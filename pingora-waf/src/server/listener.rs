@@ -5,14 +5,17 @@ use pingora::prelude::*;
 
 use crate::config::AppConfig;
 use crate::server::certs::CertStoreHandle;
+use crate::server::proxy_protocol::ProxyProtocolRelays;
 
 /// Add plain HTTP listener (no TLS)
 pub fn add_http_listener(
     svc: &mut pingora_core::services::listening::Service<pingora_proxy::HttpProxy<crate::server::proxy::WafProxy>>,
     cfg: &AppConfig,
-) {
-    let http_listen = cfg.listen_http_addr();
+    relays: &mut ProxyProtocolRelays,
+) -> anyhow::Result<()> {
+    let http_listen = relays.front("http", &cfg.listen_http_addr(), cfg.proxy_protocol.http())?;
     svc.add_tcp(&http_listen);
+    Ok(())
 }
 
 /// Add HTTPS listener with mTLS + SNI multi-cert
@@ -20,8 +23,10 @@ pub fn add_https_listener(
     svc: &mut pingora_core::services::listening::Service<pingora_proxy::HttpProxy<crate::server::proxy::WafProxy>>,
     cfg: &AppConfig,
     cert_store: CertStoreHandle,
-) -> pingora::Result<()> {
-    let listen = cfg.listen_addr();
+    relays: &mut ProxyProtocolRelays,
+) -> anyhow::Result<()> {
+    // TLS is terminated by pingora behind the relay, so SNI / mTLS work unchanged
+    let listen = relays.front("https", &cfg.listen_addr(), cfg.proxy_protocol.https())?;
     let certs = &cfg.tls.certs_dir;
 
     let default_cert = certs.join("server/default/cert.pem");
@@ -2,3 +2,5 @@ mod block_page;
 pub mod certs;
 pub mod listener;
 pub mod proxy;
+pub mod proxy_protocol;
+pub mod realip;
@@ -1,19 +1,30 @@
 use crate::obs::{AccessLog, ObsSink, SecurityEvent};
 use crate::server::block_page::BlockPage;
-use crate::upstream::{manager::UpstreamManager, router::UpstreamRouter};
+use crate::upstream::types::{RetryOn, RetrySpec};
+use crate::upstream::{health::InflightGuard, manager::UpstreamManager};
+use crate::waf::anomaly::{AnomalyScore, ANOMALY_RULE_ID};
+use crate::waf::body::BodyKind;
 use crate::waf::context::WafContext;
-use crate::waf::decision::Decision;
+use crate::waf::decision::{ChallengeKind, Decision};
 use crate::waf::engine::WafEngine;
+use crate::waf::exclusion::RuleExclusions;
+use crate::waf::rules::compiler::{CompiledRule, CompiledRuleset};
+use crate::waf::rules::rule::Action;
 use async_trait::async_trait;
 use bytes::Bytes;
 use chrono::Utc;
 use pingora::http::ResponseHeader;
 use pingora::prelude::*;
 use pingora_proxy::{ProxyHttp, Session};
+use super::realip::RealIpResolver;
+use crate::policy::geoip::GeoIp;
+use crate::policy::challenge::{safe_return_path, Challenger, ClientBinding};
 use crate::policy::enforcer::PolicyEnforcer;
 use crate::policy::manager::PolicyManager;
+use crate::policy::types::{BodyInspectionConfig, BodyLimitAction};
 use once_cell::sync::Lazy;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 static REQ_COUNTER: Lazy<AtomicU64> = Lazy::new(|| AtomicU64::new(1));
 
@@ -26,23 +37,38 @@ fn gen_request_id() -> String {
 
 #[derive(Clone)]
 pub struct WafProxy {
-    pub engine: WafEngine,
     pub upstream_mgr: UpstreamManager,
     block_page: BlockPage,
     pub policy_mgr: PolicyManager,
     pub enforcer: PolicyEnforcer,
+    challenger: Challenger,
+    real_ip: Arc<RealIpResolver>,
+    geo: GeoIp,
     pub obs: ObsSink,
 }
 
 impl WafProxy {
-    pub fn new(engine: WafEngine, upstream_mgr: UpstreamManager, policy_mgr: PolicyManager, obs: ObsSink) -> Self {
+    pub fn new(
+        engine: WafEngine,
+        upstream_mgr: UpstreamManager,
+        policy_mgr: PolicyManager,
+        challenger: Challenger,
+        real_ip: RealIpResolver,
+        geo: GeoIp,
+        obs: ObsSink,
+    ) -> Self {
         let block_page = BlockPage::load_from_assets().unwrap();
-        let enforcer = PolicyEnforcer::new(policy_mgr.clone(), engine.clone());
+        let enforcer = PolicyEnforcer::new(policy_mgr.clone(), engine.clone(), challenger.clone());
 
-        Self { engine, upstream_mgr, block_page, policy_mgr, enforcer, obs }
+        Self { upstream_mgr, block_page, policy_mgr, enforcer, challenger, real_ip: Arc::new(real_ip), geo, obs }
     }
 }
 
+/// Only logged when a trusted proxy supplied the client address.
+fn log_peer_ip(wctx: &WafContext) -> Option<String> {
+    wctx.peer_ip.filter(|p| Some(*p) != wctx.client_ip).map(|ip| ip.to_string())
+}
+
 #[derive(Default)]
 pub struct ProxyCtx {
     pub ctx: Option<WafContext>,
@@ -50,14 +76,35 @@ pub struct ProxyCtx {
     pub request_id: Option<String>,
     pub edge_key: Option<String>,
     pub upstream: Option<String>,
+    // passive health: status of the current upstream attempt, and whether its outcome was already reported
+    pub upstream_status: Option<u16>,
+    pub upstream_reported: bool,
+    // in-flight count on the selected endpoint (least_conn), released when the request ends
+    pub upstream_inflight: Option<InflightGuard>,
+    // retries: tenant policy bound at the first attempt, attempts so far and every endpoint tried
+    pub retry: Option<Arc<RetrySpec>>,
+    pub upstream_attempts: u32,
+    pub upstream_tried: Vec<String>,
     pub policy_id: Option<String>,
     pub action: Option<String>,
     pub decision_status: Option<u16>,
 
+    // ruleset snapshot bound at request headers; body rule indexes refer to it
+    pub ruleset: Option<Arc<CompiledRuleset>>,
+    // anomaly scoring mode: score carried from request headers into the body phase
+    pub anomaly: Option<AnomalyScore>,
+
     // request body scan
     pub req_tail: Vec<u8>,
     pub req_body_rules: Vec<usize>,
 
+    // buffered request body: rules on parsed args / body_regex, body held back until inspected
+    pub req_body_arg_rules: Vec<usize>,
+    pub req_body_buf: Vec<u8>,
+    pub body_limits: BodyInspectionConfig,
+    // policy rule exclusions resolved at request headers (indexes into `ruleset`)
+    pub exclusions: RuleExclusions,
+
     // response body scan
     pub resp_tail: Vec<u8>,
     pub resp_body_rules: Vec<usize>,
@@ -87,6 +134,66 @@ impl WafProxy {
         Ok(())
     }
 
+    async fn write_challenge_page(&self, session: &mut Session, status: u16, kind: ChallengeKind, wctx: &WafContext, policy_id: &str, request_id: &str) -> pingora::Result<()> {
+        let difficulty = match kind {
+            ChallengeKind::Js => 0,
+            ChallengeKind::Pow { difficulty, .. } => difficulty,
+        };
+        let nonce = self.challenger.issue_nonce(&ClientBinding::new(wctx, policy_id), difficulty);
+        let html = self.block_page.render_challenge(&nonce, difficulty, self.challenger.callback_path(), request_id);
+        let body = Bytes::from(html);
+        let len = body.len().to_string();
+
+        let mut resp = ResponseHeader::build(status, None)?;
+        resp.insert_header("content-type", "text/html; charset=utf-8")?;
+        resp.insert_header("content-length", len.as_str())?;
+        resp.insert_header("cache-control", "no-store")?;
+        resp.insert_header("x-request-id", request_id)?;
+
+        session.write_response_header(Box::new(resp), false).await?;
+        session.write_response_body(Some(body), true).await?;
+        Ok(())
+    }
+
+    /// JS / proof-of-work challenge callback: check the answer, then set the clearance cookie and send the
+    /// client back to where it was challenged. Always answered here, never proxied.
+    async fn handle_challenge_callback(&self, session: &mut Session, ctx: &mut ProxyCtx, wctx: &WafContext) -> pingora::Result<bool> {
+        let policy_id = self.policy_mgr.get_policy_for_host(wctx.host.as_deref().unwrap_or("")).id.clone();
+        ctx.policy_id = Some(policy_id.clone());
+        ctx.blocked = true;
+        let request_id = ctx.request_id.clone().unwrap_or_default();
+
+        let arg = |k: &str| wctx.args.iter().find(|(n, _)| n == k).map(|(_, v)| v.as_str());
+        let binding = ClientBinding::new(wctx, &policy_id);
+        match self.challenger.verify_solution(arg("n").unwrap_or(""), arg("a").unwrap_or(""), &binding) {
+            Ok(cleared) => {
+                let secure = session.digest().is_some_and(|d| d.ssl_digest.is_some());
+                let cookie = self.challenger.clearance_cookie(cleared, &binding, secure);
+                ctx.action = Some("challenge_pass".to_string());
+                ctx.decision_status = Some(302);
+                self.log_event(ctx, wctx, "challenge_pass", "challenge", "challenge solved", "challenge_callback", 302);
+
+                let mut resp = ResponseHeader::build(302, None)?;
+                // the return path is client supplied; fall back to / if it isn't a valid header value
+                if resp.insert_header("location", safe_return_path(arg("r"))).is_err() {
+                    resp.insert_header("location", "/")?;
+                }
+                resp.insert_header("set-cookie", cookie.as_str())?;
+                resp.insert_header("content-length", "0")?;
+                resp.insert_header("cache-control", "no-store")?;
+                resp.insert_header("x-request-id", request_id.as_str())?;
+                session.write_response_header(Box::new(resp), true).await?;
+            }
+            Err(reason) => {
+                ctx.action = Some("challenge_fail".to_string());
+                ctx.decision_status = Some(403);
+                self.log_event(ctx, wctx, "challenge_fail", "challenge", reason, "challenge_callback", 403);
+                self.write_block_html(session, 403, "challenge", reason, &request_id).await?;
+            }
+        }
+        Ok(true)
+    }
+
     async fn write_block_text(session: &mut Session, status: u16, msg: &str, request_id: &str) -> pingora::Result<()> {
         let body = Bytes::from(msg.to_string());
         let len = body.len().to_string();
@@ -102,6 +209,31 @@ impl WafProxy {
         Ok(())
     }
 
+    /// Feed the outcome of the current upstream attempt back to the router (once per attempt)
+    fn report_upstream(&self, ctx: &mut ProxyCtx, ok: bool) {
+        if ctx.upstream_reported {
+            return;
+        }
+        let (Some(tenant), Some(upstream)) = (ctx.edge_key.as_deref(), ctx.upstream.as_deref()) else {
+            return;
+        };
+        self.upstream_mgr.get().report_outcome(tenant, upstream, ok);
+        ctx.upstream_reported = true;
+    }
+
+    /// Whether the current attempt may be retried for `on`. Only connect errors (nothing sent yet)
+    /// are retried for non-idempotent methods; the request body must still be in the retry buffer,
+    /// and nothing of the response may have gone to the client yet.
+    fn may_retry(session: &Session, ctx: &ProxyCtx, on: RetryOn) -> bool {
+        let Some(p) = ctx.retry.as_ref() else {
+            return false;
+        };
+        if !p.permits(on, ctx.upstream_attempts, session.req_header().method.is_idempotent()) {
+            return false;
+        }
+        !session.as_ref().retry_buffer_truncated() && session.as_ref().response_written().is_none()
+    }
+
     fn log_event(&self, ctx: &ProxyCtx, wctx: &WafContext, action: &str, rule_id: &str, reason: &str, phase: &str, status: u16) {
         let request_id = ctx.request_id.clone().unwrap_or_else(|| "".to_string());
         let edge_key = ctx.edge_key.clone().unwrap_or_else(|| "default".to_string());
@@ -120,8 +252,141 @@ impl WafProxy {
             path: wctx.path.clone(),
             method: wctx.method.clone(),
             client_ip: wctx.client_ip.map(|ip| ip.to_string()),
+            peer_ip: log_peer_ip(wctx),
+            country: wctx.country.clone(),
+            asn: wctx.asn,
+            anomaly_score: ctx.anomaly.as_ref().map(|a| a.total).filter(|&t| t > 0),
+            matched_rules: ctx.anomaly.as_ref().map(|a| a.hits.clone()).unwrap_or_default(),
         });
     }
+
+    /// Streaming `body_ac` scan over the previous tail + this chunk. Returns (rule_id, reason) to block with.
+    fn scan_req_body_window(&self, ctx: &mut ProxyCtx, ruleset: &CompiledRuleset, chunk: &[u8]) -> Option<(String, String)> {
+        let mut keep = 0usize;
+        for &idx in &ctx.req_body_rules {
+            if let Some(r) = ruleset.rules.get(idx) {
+                keep = keep.max(r.body_keep_len());
+            }
+        }
+
+        let mut window = Vec::with_capacity(ctx.req_tail.len() + chunk.len());
+        window.extend_from_slice(&ctx.req_tail);
+        window.extend_from_slice(chunk);
+
+        // each body rule fires at most once per request
+        let mut matched = Vec::new();
+        ctx.req_body_rules.retain(|&idx| match ruleset.rules.get(idx) {
+            Some(rule) if rule.body_match(&window) => {
+                matched.push((rule, None));
+                false
+            }
+            _ => true,
+        });
+        let hit = self.body_verdict(ctx, matched, "request body match", "request_body");
+
+        if keep > 0 {
+            if window.len() > keep {
+                ctx.req_tail = window[window.len() - keep..].to_vec();
+            } else {
+                ctx.req_tail = window;
+            }
+        }
+        hit
+    }
+
+    /// Full-body rules (parsed args, body_regex): hold chunks back from upstream until the body is complete
+    /// (or over `max_bytes`), parse it by Content-Type, then release it unless a rule blocks.
+    fn inspect_req_body_args(
+        &self,
+        ctx: &mut ProxyCtx,
+        ruleset: &CompiledRuleset,
+        body: &mut Option<Bytes>,
+        end: bool,
+    ) -> Option<(String, String)> {
+        let max = ctx.body_limits.max_bytes();
+        let chunk_len = body.as_ref().map_or(0, |b| b.len());
+
+        if ctx.req_body_buf.len() + chunk_len > max {
+            // too big to inspect: let everything held so far through and stop buffering
+            let mut held = std::mem::take(&mut ctx.req_body_buf);
+            if let Some(c) = body.as_ref() {
+                held.extend_from_slice(c);
+            }
+            *body = Some(Bytes::from(held));
+            ctx.req_body_arg_rules.clear();
+            let reason = format!("request body exceeds {max} bytes");
+            return self.body_limit_hit(ctx, ctx.body_limits.on_oversize(), "body_oversize", reason);
+        }
+
+        if let Some(c) = body.as_ref() {
+            ctx.req_body_buf.extend_from_slice(c);
+        }
+        if !end {
+            // an empty chunk forwards nothing; None would end the upstream body
+            *body = Some(Bytes::new());
+            return None;
+        }
+
+        let raw = std::mem::take(&mut ctx.req_body_buf);
+        let rules = std::mem::take(&mut ctx.req_body_arg_rules);
+        let kind = BodyKind::from_content_type(ctx.ctx.as_ref().and_then(|w| w.content_type.as_deref()));
+        let parsed = kind.parse(&raw);
+        let hits = WafEngine::eval_request_body(ruleset, &rules, parsed.as_ref().ok(), &raw, &ctx.exclusions);
+        let hit = self.body_verdict(ctx, hits, "request body args match", "request_body");
+        if !raw.is_empty() {
+            *body = Some(Bytes::from(raw));
+        }
+
+        match parsed {
+            Err(e) => {
+                let reason = format!("request body parse failed: {e:#}");
+                self.body_limit_hit(ctx, ctx.body_limits.on_parse_error(), "body_parse_error", reason)
+            }
+            Ok(_) => hit,
+        }
+    }
+
+    /// Turn body-phase rule matches into a block (rule_id, reason), honouring each rule's action:
+    /// `log` rules only write an event, `allow` rules are ignored, and in anomaly mode
+    /// blocking rules add to the score instead of deciding alone.
+    fn body_verdict(
+        &self,
+        ctx: &mut ProxyCtx,
+        hits: Vec<(&CompiledRule, Option<String>)>,
+        default_reason: &str,
+        phase: &str,
+    ) -> Option<(String, String)> {
+        for (rule, detail) in &hits {
+            if let Action::Log = rule.action {
+                let wctx = event_ctx(ctx);
+                let reason = detail.as_deref().unwrap_or(default_reason);
+                self.log_event(ctx, &wctx, "log", &rule.id, reason, phase, 0);
+            }
+        }
+        WafEngine::body_block(hits, ctx.anomaly.as_mut(), default_reason)
+    }
+
+    /// Apply the policy's oversize / parse-error choice.
+    fn body_limit_hit(&self, ctx: &ProxyCtx, action: BodyLimitAction, rule_id: &str, reason: String) -> Option<(String, String)> {
+        match action {
+            BodyLimitAction::Allow => None,
+            BodyLimitAction::Log => {
+                let wctx = event_ctx(ctx);
+                self.log_event(ctx, &wctx, "log", rule_id, &reason, "request_body", 0);
+                None
+            }
+            BodyLimitAction::Block => Some((rule_id.to_string(), reason)),
+        }
+    }
+}
+
+/// Request context for events raised after request_filter; falls back when it never ran.
+fn event_ctx(ctx: &ProxyCtx) -> WafContext {
+    ctx.ctx.clone().unwrap_or_else(|| WafContext {
+        method: "UNKNOWN".to_string(),
+        host: ctx.host.clone(),
+        ..Default::default()
+    })
 }
 
 #[async_trait]
@@ -138,24 +403,55 @@ impl ProxyHttp for WafProxy {
         let request_id = gen_request_id();
         ctx.request_id = Some(request_id.clone());
 
-        let wctx = WafContext::from_session(session).await?;
+        let peer_addr = session.client_addr().and_then(|a| a.as_inet()).copied();
+        if self.real_ip.bypasses_relay(session.server_addr().and_then(|a| a.as_inet()).copied(), peer_addr) {
+            tracing::warn!(peer = ?peer_addr, "request on a PROXY protocol backend port that bypassed the relay");
+            ctx.blocked = true;
+            ctx.decision_status = Some(403);
+            Self::write_block_text(session, 403, "Forbidden", &request_id).await?;
+            return Ok(true);
+        }
+
+        let peer_ip = self.real_ip.peer(peer_addr);
+        let client_ip = self.real_ip.resolve(peer_ip, &session.req_header().headers);
+        let mut wctx = WafContext::from_session(session, peer_ip, client_ip).await?;
+        self.geo.enrich(&mut wctx);
         let host = wctx.host.clone().unwrap_or_else(|| "unknown".to_string());
         ctx.host = Some(host.clone());
         crate::metrics::counters::on_req_start(&host);
 
         // Resolve edge_key + upstream early so blocked requests still have edge_key.
         let router = self.upstream_mgr.get();
-        let (edge_key, upstream) = router.pick_endpoint_and_edge_key(Some(&host)).await;
+        let (edge_key, upstream) = router
+            .pick_endpoint_and_edge_key(Some(&host), Some((&wctx, &session.req_header().headers)))
+            .await;
         ctx.edge_key = Some(edge_key);
         ctx.upstream = Some(upstream);
 
         let req = session.req_header();
         let r = self.enforcer.enforce_request_headers(&wctx, req);
 
+        // The callback runs after the policy so IP-denied or CC-banned clients
+        // can't keep hitting the HMAC/PoW verification; a challenge decision
+        // still reaches it, otherwise the client could never solve one.
+        if wctx.path == self.challenger.callback_path() && !matches!(r.decision, Decision::Block { .. }) {
+            ctx.ctx = Some(wctx.clone());
+            return self.handle_challenge_callback(session, ctx, &wctx).await;
+        }
+
         ctx.ctx = Some(wctx.clone());
         ctx.policy_id = Some(r.policy_id.clone());
-        ctx.req_body_rules = r.req_body_rules;
+        let (arg_rules, raw_rules) = r
+            .req_body_rules
+            .iter()
+            .partition(|&&i| r.ruleset.as_ref().is_some_and(|rs| rs.rules[i].needs_full_body()));
+        ctx.req_body_arg_rules = arg_rules;
+        ctx.req_body_rules = raw_rules;
+        ctx.body_limits = r.body;
+        ctx.exclusions = r.exclusions;
+        ctx.ruleset = r.ruleset;
         ctx.resp_body_rules = r.resp_body_rules;
+        ctx.anomaly = r.anomaly;
         ctx.action = Some(r.decision.kind_str().to_string());
 
         match r.decision {
@@ -172,11 +468,11 @@ impl ProxyHttp for WafProxy {
                 self.write_block_html(session, status, &rule_id, &reason, &request_id).await?;
                 Ok(true)
             }
-            Decision::Challenge { status, reason, rule_id } => {
+            Decision::Challenge { status, reason, rule_id, kind } => {
                 ctx.blocked = true;
                 ctx.decision_status = Some(status);
                 self.log_event(ctx, &wctx, "challenge", &rule_id, &reason, "request_headers", status);
-                Self::write_block_text(session, status, &format!("challenge: {reason}"), &request_id).await?;
+                self.write_challenge_page(session, status, kind, &wctx, &r.policy_id, &request_id).await?;
                 Ok(true)
             }
         }
@@ -186,59 +482,35 @@ impl ProxyHttp for WafProxy {
         &self,
         session: &mut Session,
         body: &mut Option<Bytes>,
-        _end: bool,
+        end: bool,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<()> {
-        if ctx.blocked || ctx.req_body_rules.is_empty() {
+        if ctx.blocked || (ctx.req_body_rules.is_empty() && ctx.req_body_arg_rules.is_empty()) {
             return Ok(());
         }
-        let Some(chunk) = body.as_ref() else {
+        let Some(ruleset) = ctx.ruleset.clone() else {
             return Ok(());
         };
 
-        let ruleset = self.engine.rules_snapshot();
-        let mut keep = 0usize;
-        for &idx in &ctx.req_body_rules {
-            if let Some(r) = ruleset.rules.get(idx) {
-                keep = keep.max(r.body_keep_len());
-            }
+        let mut hit = match body.as_ref() {
+            Some(chunk) if !ctx.req_body_rules.is_empty() => self.scan_req_body_window(ctx, &ruleset, chunk),
+            _ => None,
+        };
+        if hit.is_none() && !ctx.req_body_arg_rules.is_empty() {
+            hit = self.inspect_req_body_args(ctx, &ruleset, body, end);
         }
 
-        let mut window = Vec::with_capacity(ctx.req_tail.len() + chunk.len());
-        window.extend_from_slice(&ctx.req_tail);
-        window.extend_from_slice(chunk);
+        if let Some((rule_id, reason)) = hit {
+            ctx.blocked = true;
+            ctx.action = Some("block".to_string());
+            ctx.decision_status = Some(403);
+            *body = None;
 
-        for &idx in &ctx.req_body_rules {
-            if let Some(rule) = ruleset.rules.get(idx) {
-                if rule.body_match(&window) {
-                    ctx.blocked = true;
-                    ctx.action = Some("block".to_string());
-                    ctx.decision_status = Some(403);
-                    *body = None;
-
-                    let wctx = ctx.ctx.clone().unwrap_or_else(|| WafContext {
-                        method: "UNKNOWN".to_string(),
-                        path: "".to_string(),
-                        client_ip: None,
-                        host: ctx.host.clone(),
-                        user_agent: None,
-                    });
-
-                    self.log_event(ctx, &wctx, "block", &rule.id, "request body match", "request_body", 403);
-
-                    let rid = ctx.request_id.clone().unwrap_or_else(|| gen_request_id());
-                    Self::write_block_text(session, 403, "blocked by WAF (request body)", &rid).await?;
-                    return Ok(());
-                }
-            }
-        }
+            let wctx = event_ctx(ctx);
+            self.log_event(ctx, &wctx, "block", &rule_id, &reason, "request_body", 403);
 
-        if keep > 0 {
-            if window.len() > keep {
-                ctx.req_tail = window[window.len() - keep..].to_vec();
-            } else {
-                ctx.req_tail = window;
-            }
+            let rid = ctx.request_id.clone().unwrap_or_else(|| gen_request_id());
+            Self::write_block_text(session, 403, "blocked by WAF (request body)", &rid).await?;
         }
         Ok(())
     }
@@ -256,8 +528,9 @@ impl ProxyHttp for WafProxy {
         let Some(chunk) = body.as_ref() else {
             return Ok(None);
         };
-
-        let ruleset = self.engine.rules_snapshot();
+        let Some(ruleset) = ctx.ruleset.clone() else {
+            return Ok(None);
+        };
 
         let mut keep = 0usize;
         for &idx in &ctx.resp_body_rules {
@@ -270,24 +543,19 @@ impl ProxyHttp for WafProxy {
         window.extend_from_slice(&ctx.resp_tail);
         window.extend_from_slice(chunk);
 
-        for &idx in &ctx.resp_body_rules {
-            if let Some(rule) = ruleset.rules.get(idx) {
-                if rule.body_match(&window) {
-                    ctx.blocked = true;
-                    ctx.action = Some("block".to_string());
-                    *body = None;
-
-                    let wctx = ctx.ctx.clone().unwrap_or_else(|| WafContext {
-                        method: "UNKNOWN".to_string(),
-                        path: "".to_string(),
-                        client_ip: None,
-                        host: ctx.host.clone(),
-                        user_agent: None,
-                    });
-
-                    self.log_event(ctx, &wctx, "block", &rule.id, "response body match", "response_body", 0);
-                    return Ok(None);
-                }
+        let matched = WafEngine::eval_response_body(&ruleset, &ctx.resp_body_rules, &window);
+        if !matched.is_empty() {
+            // a rule counts once per response, however many chunks match it
+            ctx.resp_body_rules.retain(|i| !matched.contains(i));
+            let hits = matched.iter().filter_map(|&i| ruleset.rules.get(i)).map(|r| (r, None)).collect();
+            // same verdict as the request body: in anomaly mode a match only adds to the score
+            if let Some((rule_id, reason)) = self.body_verdict(ctx, hits, "response body match", "response_body") {
+                ctx.blocked = true;
+                ctx.action = Some("block".to_string());
+                *body = None;
+                let wctx = event_ctx(ctx);
+                self.log_event(ctx, &wctx, "block", &rule_id, &reason, "response_body", 0);
+                return Ok(None);
             }
         }
 
@@ -308,6 +576,13 @@ impl ProxyHttp for WafProxy {
         err: Option<&pingora::Error>,
         ctx: &mut Self::CTX,
     ) {
+        // passive health: only attempts that reached the upstream count; downstream errors say nothing about the origin
+        let upstream_err = err.is_some_and(|e| e.esource() == &ErrorSource::Upstream);
+        if upstream_err || ctx.upstream_status.is_some() {
+            let ok = !upstream_err && ctx.upstream_status.is_some_and(|s| s < 500);
+            self.report_upstream(ctx, ok);
+        }
+
         let host = ctx.host.as_deref().unwrap_or("unknown");
         let elapsed = ctx
             .start
@@ -317,17 +592,29 @@ impl ProxyHttp for WafProxy {
         crate::metrics::counters::on_req_end(host, elapsed);
         let status = ctx.decision_status.unwrap_or(200);
 
-        let wctx = ctx.ctx.clone().unwrap_or_else(|| WafContext {
-            method: session.req_header().method.to_string(),
-            path: session.req_header().uri.path().to_string(),
-            client_ip: session
-                .client_addr()
-                .and_then(|a| a.to_string().parse::<std::net::SocketAddr>().ok())
-                .map(|sa| sa.ip()),
-            host: ctx.host.clone(),
-            user_agent: None,
+
+        let wctx = ctx.ctx.clone().unwrap_or_else(|| {
+            let peer_ip = self.real_ip.peer(session.client_addr().and_then(|a| a.as_inet()).copied());
+            let mut w = WafContext {
+                method: session.req_header().method.to_string(),
+                path: session.req_header().uri.path().to_string(),
+                client_ip: self.real_ip.resolve(peer_ip, &session.req_header().headers),
+                peer_ip,
+                host: ctx.host.clone(),
+                ..Default::default()
+            };
+            self.geo.enrich(&mut w);
+            w
         });
 
+        // anomaly score below threshold: one log event with every contributing rule
+        if !ctx.blocked {
+            if let Some(reason) = ctx.anomaly.as_ref().filter(|a| a.total > 0).map(|a| a.reason()) {
+                ctx.action = Some("log".to_string());
+                self.log_event(ctx, &wctx, "log", ANOMALY_RULE_ID, &reason, "request", 0);
+            }
+        }
+
         let access = AccessLog {
             ts: Utc::now(),
             request_id: ctx.request_id.clone().unwrap_or_else(|| "".to_string()),
@@ -340,7 +627,11 @@ impl ProxyHttp for WafProxy {
             status,
             latency_ms: (elapsed * 1000.0) as u64,
             upstream: ctx.upstream.clone(),
+            upstream_attempts: (ctx.upstream_attempts > 0).then_some(ctx.upstream_attempts),
             client_ip: wctx.client_ip.map(|ip| ip.to_string()),
+            peer_ip: log_peer_ip(&wctx),
+            country: wctx.country.clone(),
+            asn: wctx.asn,
             user_agent: wctx.user_agent.clone(),
             error: err.map(|e| e.to_string()),
         };
@@ -355,17 +646,114 @@ impl ProxyHttp for WafProxy {
             .and_then(|w| w.host.as_deref())
             .or_else(|| session.req_header().headers.get("host").and_then(|v| v.to_str().ok()));
 
+        let router = self.upstream_mgr.get();
         if ctx.upstream.is_none() {
-            let router = self.upstream_mgr.get();
-            let (edge_key, upstream) = router.pick_endpoint_and_edge_key(host).await;
+            let headers = &session.req_header().headers;
+            let req = ctx.ctx.as_ref().map(|w| (w, headers));
+            let (edge_key, upstream) = router.pick_endpoint_and_edge_key(host, req).await;
             ctx.edge_key = Some(edge_key);
             ctx.upstream = Some(upstream);
+        } else if ctx.upstream_attempts > 0 {
+            // retry: move on to an endpoint not tried yet (keep the last one once all were tried)
+            if let Some(tenant) = ctx.edge_key.as_deref() {
+                let headers = &session.req_header().headers;
+                let req = ctx.ctx.as_ref().map(|w| (w, headers));
+                if let Some(next) = router.pick_retry(tenant, &ctx.upstream_tried, req) {
+                    ctx.upstream = Some(next);
+                }
+            }
+        }
+
+        if ctx.upstream_attempts == 0 {
+            ctx.retry = ctx.edge_key.as_deref().and_then(|t| router.retry_policy(t));
+        }
+        ctx.upstream_attempts += 1;
+        if let Some(u) = ctx.upstream.as_ref().filter(|u| !ctx.upstream_tried.contains(u)) {
+            ctx.upstream_tried.push(u.clone());
+        }
+
+        // a new attempt (pingora retries call upstream_peer again)
+        ctx.upstream_status = None;
+        ctx.upstream_reported = false;
+        ctx.upstream_inflight = None;
+        if let (Some(tenant), Some(upstream)) = (ctx.edge_key.as_deref(), ctx.upstream.as_deref()) {
+            ctx.upstream_inflight = router.track(tenant, upstream);
         }
 
         let selected = ctx.upstream.clone().unwrap_or_else(|| "".to_string());
-        let peer = UpstreamRouter::build_peer(&selected)
+        let tenant = ctx.edge_key.as_deref().unwrap_or("default");
+        let mut peer = router
+            .build_peer_for(tenant, &selected)
             .map_err(|_e| pingora::Error::new(pingora::ErrorType::InternalError))?;
+        // the retry policy's per-try timeout takes precedence over the connection settings
+        if let Some(ms) = ctx.retry.as_ref().and_then(|r| r.per_try_timeout_ms) {
+            let t = std::time::Duration::from_millis(ms);
+            peer.options.total_connection_timeout = Some(t);
+            peer.options.read_timeout = Some(t);
+            peer.options.write_timeout = Some(t);
+        }
 
         Ok(Box::new(peer))
     }
+
+    fn upstream_response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()> {
+        let status = upstream_response.status.as_u16();
+        ctx.upstream_status = Some(status);
+
+        // nothing has been sent downstream yet: turn a retryable 502/503 into an error pingora retries
+        let on = match status {
+            502 => RetryOn::Http502,
+            503 => RetryOn::Http503,
+            _ => return Ok(()),
+        };
+        if Self::may_retry(session, ctx, on) {
+            self.report_upstream(ctx, false);
+            let mut e = pingora::Error::new_up(ErrorType::HTTPStatus(status));
+            e.set_retry(true);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<pingora::Error>,
+    ) -> Box<pingora::Error> {
+        self.report_upstream(ctx, false);
+        if Self::may_retry(session, ctx, RetryOn::ConnectError) {
+            e.set_retry(true);
+        }
+        e
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<pingora::Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<pingora::Error> {
+        // same as the default: retry a reused connection that the upstream closed, if the body can be replayed
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        e.retry.decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+
+        if e.esource() == &ErrorSource::Upstream {
+            self.report_upstream(ctx, false);
+            if matches!(e.etype(), ErrorType::ReadTimedout | ErrorType::WriteTimedout)
+                && Self::may_retry(session, ctx, RetryOn::Timeout)
+            {
+                e.set_retry(true);
+            }
+        }
+        e
+    }
 }
@@ -0,0 +1,521 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::IntoRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use dashmap::{DashMap, DashSet};
+use pingora::server::{ListenFds, ShutdownWatch};
+use pingora_core::services::background::BackgroundService;
+use pingora_core::services::Service;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::{ProxyProtocolConfig, ProxyProtocolMode};
+use crate::metrics::counters;
+use crate::policy::iplist::IpTrie;
+
+/// v1 header upper bound, CRLF included.
+const V1_MAX_LEN: usize = 107;
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V2_SIG: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// What a complete PROXY header says about the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Header {
+    /// Original client address.
+    Proxied(SocketAddr),
+    /// v2 LOCAL, v1 UNKNOWN or a non-IP family (LB health checks): use the TCP peer.
+    Local,
+}
+
+#[derive(Debug)]
+enum Parse {
+    /// Need more bytes to decide.
+    Incomplete,
+    /// The stream doesn't start with a PROXY header.
+    Absent,
+    /// Header and its length in bytes.
+    Done(Header, usize),
+}
+
+/// Parse a PROXY v1 or v2 header at the start of `buf`.
+fn parse(buf: &[u8]) -> anyhow::Result<Parse> {
+    if starts_with(buf, V2_SIG) {
+        return parse_v2(buf);
+    }
+    if starts_with(buf, V1_PREFIX) {
+        return parse_v1(buf);
+    }
+    if V2_SIG.starts_with(buf) || V1_PREFIX.starts_with(buf) {
+        return Ok(Parse::Incomplete);
+    }
+    Ok(Parse::Absent)
+}
+
+/// `buf` is long enough and begins with `prefix`.
+fn starts_with(buf: &[u8], prefix: &[u8]) -> bool {
+    buf.len() >= prefix.len() && buf.starts_with(prefix)
+}
+
+/// `PROXY TCP4 <src> <dst> <sport> <dport>\r\n`, `PROXY TCP6 ...` or `PROXY UNKNOWN ...\r\n`.
+fn parse_v1(buf: &[u8]) -> anyhow::Result<Parse> {
+    let window = &buf[..buf.len().min(V1_MAX_LEN)];
+    let Some(end) = window.windows(2).position(|w| w == b"\r\n") else {
+        if buf.len() >= V1_MAX_LEN {
+            anyhow::bail!("PROXY v1 header too long");
+        }
+        return Ok(Parse::Incomplete);
+    };
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len()..end])?;
+    let mut parts = line.split(' ');
+    let header = match parts.next() {
+        Some("UNKNOWN") => Header::Local,
+        Some(proto @ ("TCP4" | "TCP6")) => {
+            let f: Vec<&str> = parts.collect();
+            let [src, _dst, sport, _dport] = f[..] else {
+                anyhow::bail!("malformed PROXY v1 header");
+            };
+            let ip: IpAddr = src.parse()?;
+            if ip.is_ipv4() != (proto == "TCP4") {
+                anyhow::bail!("PROXY v1 address doesn't match {}", proto);
+            }
+            Header::Proxied(SocketAddr::new(ip, sport.parse()?))
+        }
+        _ => anyhow::bail!("unsupported PROXY v1 protocol"),
+    };
+    Ok(Parse::Done(header, end + 2))
+}
+
+/// 12 byte signature, version/command, family/transport, big-endian length, addresses, TLVs.
+fn parse_v2(buf: &[u8]) -> anyhow::Result<Parse> {
+    if buf.len() < 16 {
+        return Ok(Parse::Incomplete);
+    }
+    let ver_cmd = buf[12];
+    if ver_cmd >> 4 != 2 {
+        anyhow::bail!("unsupported PROXY v2 version {}", ver_cmd >> 4);
+    }
+    let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(Parse::Incomplete);
+    }
+    let body = &buf[16..len];
+
+    let header = match (ver_cmd & 0x0f, buf[13] >> 4) {
+        (0x0, _) => Header::Local,
+        // AF_INET
+        (0x1, 0x1) => {
+            if body.len() < 12 {
+                anyhow::bail!("short PROXY v2 inet address block");
+            }
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Header::Proxied(SocketAddr::new(ip.into(), port))
+        }
+        // AF_INET6
+        (0x1, 0x2) => {
+            if body.len() < 36 {
+                anyhow::bail!("short PROXY v2 inet6 address block");
+            }
+            let mut a = [0u8; 16];
+            a.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Header::Proxied(SocketAddr::new(Ipv6Addr::from(a).into(), port))
+        }
+        // AF_UNSPEC / AF_UNIX
+        (0x1, _) => Header::Local,
+        (cmd, _) => anyhow::bail!("unsupported PROXY v2 command {}", cmd),
+    };
+    Ok(Parse::Done(header, len))
+}
+
+/// Connections the relays opened to pingora, keyed by the relay side address (what pingora
+/// reports as the client), mapped to the real peer: the source from the PROXY header, or
+/// the TCP peer for direct (optional mode), LOCAL and UNKNOWN connections.
+#[derive(Debug, Clone, Default)]
+pub struct ProxiedConns {
+    map: Arc<DashMap<SocketAddr, SocketAddr>>,
+    /// The loopback addresses pingora listens on behind a relay.
+    backends: Arc<DashSet<SocketAddr>>,
+}
+
+impl ProxiedConns {
+    pub fn source(&self, relay: &SocketAddr) -> Option<SocketAddr> {
+        self.map.get(relay).map(|v| *v)
+    }
+
+    /// A connection to a relay's loopback port that no relay opened: some local process
+    /// connected directly and skipped the trusted-source check, so it must not be served.
+    pub fn bypasses_relay(&self, local: &SocketAddr, peer: &SocketAddr) -> bool {
+        self.backends.contains(local) && !self.map.contains_key(peer)
+    }
+}
+
+/// Sets up the PROXY protocol relays while the listeners are added.
+///
+/// pingora reads straight from accepted sockets, so a listener with PROXY protocol enabled
+/// is fronted by a relay: the relay owns the public address, strips the header and pipes the
+/// rest of the connection (HTTP or still-encrypted TLS) to pingora on a loopback port.
+/// Any local process can connect to that port; requests on it that didn't come through a
+/// relay are refused (see `ProxiedConns::bypasses_relay`).
+pub struct ProxyProtocolRelays {
+    trusted: Arc<IpTrie>,
+    header_timeout: Duration,
+    conns: ProxiedConns,
+    relays: Vec<ProxyProtocolRelay>,
+    backends: Vec<(String, std::net::TcpListener)>,
+}
+
+impl ProxyProtocolRelays {
+    pub fn new(cfg: &ProxyProtocolConfig) -> anyhow::Result<Self> {
+        let enabled = cfg.http() != ProxyProtocolMode::Off || cfg.https() != ProxyProtocolMode::Off;
+        if enabled && cfg.trusted_sources.is_empty() {
+            anyhow::bail!("proxy_protocol needs trusted_sources");
+        }
+        let trusted = IpTrie::from_cidrs(&cfg.trusted_sources)
+            .map_err(|e| anyhow::anyhow!("bad proxy_protocol.trusted_sources: {}", e))?;
+        Ok(Self {
+            trusted: Arc::new(trusted),
+            header_timeout: Duration::from_millis(cfg.header_timeout_ms().max(1)),
+            conns: ProxiedConns::default(),
+            relays: Vec::new(),
+            backends: Vec::new(),
+        })
+    }
+
+    pub fn conns(&self) -> ProxiedConns {
+        self.conns.clone()
+    }
+
+    /// The address pingora should listen on for `public`: itself when `mode` is off,
+    /// otherwise a loopback port behind a relay bound to `public`.
+    pub fn front(&mut self, name: &'static str, public: &str, mode: ProxyProtocolMode) -> anyhow::Result<String> {
+        if mode == ProxyProtocolMode::Off {
+            return Ok(public.to_string());
+        }
+        let listener = std::net::TcpListener::bind(public)
+            .map_err(|e| anyhow::anyhow!("bind {} listener {} failed: {}", name, public, e))?;
+        listener.set_nonblocking(true)?;
+
+        // held until pingora starts and takes it over (see `Prebound`), so nothing else can grab the port
+        let backend_listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        backend_listener.set_nonblocking(true)?;
+        let backend = backend_listener.local_addr()?;
+        self.conns.backends.insert(backend);
+        self.backends.push((backend.to_string(), backend_listener));
+
+        tracing::info!(listener = name, %public, %backend, ?mode, "proxy protocol relay");
+        self.relays.push(ProxyProtocolRelay {
+            listener: Mutex::new(Some(listener)),
+            inner: Arc::new(RelayInner {
+                name,
+                backend,
+                mode,
+                trusted: self.trusted.clone(),
+                header_timeout: self.header_timeout,
+                conns: self.conns.clone(),
+            }),
+        });
+        Ok(backend.to_string())
+    }
+
+    /// Relay services to register with the server, and the loopback listeners pingora must
+    /// serve on (hand them over with `Prebound::serve`).
+    pub fn into_relays(self) -> (Vec<ProxyProtocolRelay>, Prebound) {
+        (self.relays, Prebound(self.backends))
+    }
+}
+
+/// Loopback listeners bound for the relays, keyed by the address given to pingora.
+pub struct Prebound(Vec<(String, std::net::TcpListener)>);
+
+impl Prebound {
+    /// Wraps `inner` so it listens on the already bound sockets instead of binding again.
+    pub fn serve<S: Service>(self, inner: S) -> PreboundService<S> {
+        PreboundService { inner, listeners: self.0 }
+    }
+}
+
+/// Puts the prebound sockets into pingora's listen fd table (the one it also uses for
+/// graceful upgrades) before starting the wrapped service, which then picks them up by address.
+pub struct PreboundService<S> {
+    inner: S,
+    listeners: Vec<(String, std::net::TcpListener)>,
+}
+
+#[async_trait]
+impl<S: Service> Service for PreboundService<S> {
+    async fn start_service(&mut self, fds: Option<ListenFds>, shutdown: ShutdownWatch, listeners_per_fd: usize) {
+        if let Some(fds) = &fds {
+            let mut table = fds.lock().await;
+            for (addr, listener) in self.listeners.drain(..) {
+                // an fd passed on by an upgrading process wins; ours is closed on drop
+                if table.get(&addr).is_none() {
+                    table.add(addr, listener.into_raw_fd());
+                }
+            }
+        }
+        self.inner.start_service(fds, shutdown, listeners_per_fd).await
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn threads(&self) -> Option<usize> {
+        self.inner.threads()
+    }
+}
+
+pub struct ProxyProtocolRelay {
+    /// Bound at startup so a taken port fails the boot; moved into the runtime on start.
+    listener: Mutex<Option<std::net::TcpListener>>,
+    inner: Arc<RelayInner>,
+}
+
+impl ProxyProtocolRelay {
+    pub fn name(&self) -> &'static str {
+        self.inner.name
+    }
+}
+
+struct RelayInner {
+    name: &'static str,
+    backend: SocketAddr,
+    mode: ProxyProtocolMode,
+    trusted: Arc<IpTrie>,
+    header_timeout: Duration,
+    conns: ProxiedConns,
+}
+
+impl RelayInner {
+    async fn serve(&self, mut client: TcpStream, peer: SocketAddr) -> anyhow::Result<()> {
+        client.set_nodelay(true)?;
+        let read = tokio::time::timeout(self.header_timeout, self.read_header(&mut client, peer));
+        let (source, leftover) = match read.await {
+            Ok(Ok(r)) => r,
+            Ok(Err(e)) => {
+                counters::inc_proxy_protocol(self.name, "rejected");
+                return Err(e);
+            }
+            Err(_) => {
+                counters::inc_proxy_protocol(self.name, "rejected");
+                anyhow::bail!("no PROXY header within {:?}", self.header_timeout);
+            }
+        };
+
+        let mut upstream = TcpStream::connect(self.backend).await?;
+        upstream.set_nodelay(true)?;
+        let relay_addr = upstream.local_addr()?;
+        // registered before any byte reaches pingora; without a PROXY source the real peer is
+        // the TCP peer, not the relay's loopback address
+        self.conns.map.insert(relay_addr, source.unwrap_or(peer));
+        let res = async {
+            upstream.write_all(&leftover).await?;
+            tokio::io::copy_bidirectional(&mut client, &mut upstream).await
+        }
+        .await;
+        self.conns.map.remove(&relay_addr);
+        res?;
+        Ok(())
+    }
+
+    /// Reads until the header is complete (or known to be absent). Returns the PROXY source,
+    /// if any, and the bytes read past the header.
+    async fn read_header(&self, client: &mut TcpStream, peer: SocketAddr) -> anyhow::Result<(Option<SocketAddr>, Vec<u8>)> {
+        let trusted = self.trusted.contains(peer.ip());
+        if self.mode == ProxyProtocolMode::Required && !trusted {
+            anyhow::bail!("untrusted source on a PROXY protocol listener");
+        }
+
+        let mut buf = Vec::with_capacity(512);
+        loop {
+            match parse(&buf)? {
+                Parse::Incomplete => {
+                    if client.read_buf(&mut buf).await? == 0 {
+                        anyhow::bail!("connection closed before PROXY header");
+                    }
+                }
+                Parse::Absent => {
+                    if self.mode == ProxyProtocolMode::Required {
+                        anyhow::bail!("missing PROXY header");
+                    }
+                    counters::inc_proxy_protocol(self.name, "direct");
+                    return Ok((None, buf));
+                }
+                Parse::Done(header, len) => {
+                    if !trusted {
+                        anyhow::bail!("PROXY header from untrusted source");
+                    }
+                    let rest = buf.split_off(len);
+                    return Ok(match header {
+                        Header::Proxied(src) => {
+                            counters::inc_proxy_protocol(self.name, "proxied");
+                            (Some(src), rest)
+                        }
+                        Header::Local => {
+                            counters::inc_proxy_protocol(self.name, "local");
+                            (None, rest)
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for ProxyProtocolRelay {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let Some(std_listener) = self.listener.lock().unwrap().take() else {
+            return;
+        };
+        let listener = match TcpListener::from_std(std_listener) {
+            Ok(l) => l,
+            Err(e) => {
+                tracing::error!(listener = self.inner.name, "proxy protocol relay start failed: {}", e);
+                return;
+            }
+        };
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    tracing::info!(listener = self.inner.name, "proxy protocol relay shutdown");
+                    return;
+                }
+                accepted = listener.accept() => {
+                    let (client, peer) = match accepted {
+                        Ok(a) => a,
+                        Err(e) => {
+                            tracing::warn!(listener = self.inner.name, "accept failed: {}", e);
+                            // e.g. out of fds: don't spin
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    };
+                    let inner = self.inner.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = inner.serve(client, peer).await {
+                            tracing::debug!(listener = inner.name, %peer, "proxy protocol connection closed: {:#}", e);
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn done(buf: &[u8]) -> (Header, usize) {
+        match parse(buf).unwrap() {
+            Parse::Done(h, n) => (h, n),
+            p => panic!("expected a complete header, got {p:?}"),
+        }
+    }
+
+    fn v2(cmd: u8, fam: u8, body: &[u8]) -> Vec<u8> {
+        let mut b = V2_SIG.to_vec();
+        b.push(0x20 | cmd);
+        b.push(fam);
+        b.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        b.extend_from_slice(body);
+        b
+    }
+
+    #[test]
+    fn v1_tcp4_and_tcp6() {
+        let line = b"PROXY TCP4 203.0.113.9 192.0.2.1 51234 443\r\nGET / HTTP/1.1\r\n";
+        let (h, n) = done(line);
+        assert_eq!(h, Header::Proxied("203.0.113.9:51234".parse().unwrap()));
+        assert_eq!(&line[n..], b"GET / HTTP/1.1\r\n");
+
+        let (h, _) = done(b"PROXY TCP6 2001:db8::1 2001:db8::2 443 8443\r\n");
+        assert_eq!(h, Header::Proxied("[2001:db8::1]:443".parse().unwrap()));
+
+        assert!(parse(b"PROXY TCP4 2001:db8::1 2001:db8::2 443 8443\r\n").is_err());
+        assert!(parse(b"PROXY TCP6 203.0.113.9 192.0.2.1 51234 443\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 203.0.113.9 51234\r\n").is_err());
+    }
+
+    #[test]
+    fn v1_unknown_uses_the_peer() {
+        assert_eq!(done(b"PROXY UNKNOWN\r\n"), (Header::Local, 15));
+        assert_eq!(done(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n").0, Header::Local);
+    }
+
+    #[test]
+    fn v1_without_crlf_within_the_limit_fails() {
+        let mut long = b"PROXY TCP4 ".to_vec();
+        long.resize(V1_MAX_LEN - 1, b'1');
+        assert!(matches!(parse(&long).unwrap(), Parse::Incomplete));
+        long.push(b'1');
+        assert!(parse(&long).is_err());
+        long.extend_from_slice(b"\r\n");
+        assert!(parse(&long).is_err());
+    }
+
+    #[test]
+    fn v2_local_inet_and_inet6() {
+        // LOCAL ignores the address block
+        let (h, n) = done(&v2(0x0, 0x11, &[0; 12]));
+        assert_eq!((h, n), (Header::Local, 28));
+
+        let mut inet = vec![203, 0, 113, 9, 192, 0, 2, 1];
+        inet.extend_from_slice(&51234u16.to_be_bytes());
+        inet.extend_from_slice(&443u16.to_be_bytes());
+        // trailing TLVs are skipped with the rest of the header
+        inet.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        let mut buf = v2(0x1, 0x11, &inet);
+        let header_len = buf.len();
+        buf.extend_from_slice(b"GET /");
+        let (h, n) = done(&buf);
+        assert_eq!(h, Header::Proxied("203.0.113.9:51234".parse().unwrap()));
+        assert_eq!(n, header_len);
+
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut inet6 = src.octets().to_vec();
+        inet6.extend_from_slice(&[0; 16]);
+        inet6.extend_from_slice(&443u16.to_be_bytes());
+        inet6.extend_from_slice(&8443u16.to_be_bytes());
+        let (h, _) = done(&v2(0x1, 0x21, &inet6));
+        assert_eq!(h, Header::Proxied("[2001:db8::1]:443".parse().unwrap()));
+
+        // AF_UNIX
+        assert_eq!(done(&v2(0x1, 0x31, &[0; 216])).0, Header::Local);
+    }
+
+    #[test]
+    fn v2_rejects_bad_headers() {
+        assert!(parse(&v2(0x1, 0x11, &[0; 8])).is_err());
+        assert!(parse(&v2(0x1, 0x21, &[0; 20])).is_err());
+        assert!(parse(&v2(0x2, 0x11, &[0; 12])).is_err());
+        let mut v1 = v2(0x1, 0x11, &[0; 12]);
+        v1[12] = 0x11;
+        assert!(parse(&v1).is_err());
+    }
+
+    #[test]
+    fn partial_prefixes_are_incomplete() {
+        let full = v2(0x1, 0x11, &[0; 12]);
+        for n in 0..full.len() {
+            assert!(matches!(parse(&full[..n]).unwrap(), Parse::Incomplete), "v2 prefix of {n} bytes");
+        }
+        let line = b"PROXY TCP4 203.0.113.9 192.0.2.1 51234 443\r\n";
+        for n in 0..line.len() {
+            assert!(matches!(parse(&line[..n]).unwrap(), Parse::Incomplete), "v1 prefix of {n} bytes");
+        }
+    }
+
+    #[test]
+    fn http_is_absent() {
+        assert!(matches!(parse(b"GET / HTTP/1.1\r\n").unwrap(), Parse::Absent));
+        assert!(matches!(parse(b"PROXZ").unwrap(), Parse::Absent));
+        assert!(matches!(parse(b"\x16\x03\x01").unwrap(), Parse::Absent));
+        assert!(matches!(parse(b"\r\n\r\nX").unwrap(), Parse::Absent));
+    }
+}
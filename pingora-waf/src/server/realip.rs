@@ -0,0 +1,190 @@
+use std::net::{IpAddr, SocketAddr};
+
+use http::HeaderMap;
+
+use crate::config::{ClientIpConfig, ClientIpHeader};
+use crate::policy::iplist::IpTrie;
+use crate::server::proxy_protocol::ProxiedConns;
+
+/// Works out the real client address when requests arrive through our own load balancers.
+///
+/// Forwarding headers are only believed when the TCP peer is a trusted proxy. For the
+/// multi-hop headers (X-Forwarded-For, Forwarded) the chain is walked from the right and
+/// the first address that isn't a trusted proxy wins, so a client can't spoof its way in
+/// by prepending entries.
+#[derive(Debug)]
+pub struct RealIpResolver {
+    trusted: IpTrie,
+    header: ClientIpHeader,
+    enabled: bool,
+    proxied: ProxiedConns,
+}
+
+impl RealIpResolver {
+    pub fn new(cfg: &ClientIpConfig, proxied: ProxiedConns) -> anyhow::Result<Self> {
+        let trusted = IpTrie::from_cidrs(&cfg.trusted_proxies)
+            .map_err(|e| anyhow::anyhow!("bad client_ip.trusted_proxies: {}", e))?;
+        Ok(Self { trusted, header: cfg.header(), enabled: !cfg.trusted_proxies.is_empty(), proxied })
+    }
+
+    /// The connection's peer; for connections from a PROXY protocol relay, the peer the
+    /// relay recorded (the PROXY header source, or the relay's own TCP peer without one).
+    pub fn peer(&self, addr: Option<SocketAddr>) -> Option<IpAddr> {
+        let addr = addr?;
+        Some(self.proxied.source(&addr).unwrap_or(addr).ip())
+    }
+
+    /// The request arrived on a PROXY protocol relay's loopback port without going through the relay.
+    pub fn bypasses_relay(&self, local: Option<SocketAddr>, peer: Option<SocketAddr>) -> bool {
+        matches!((local, peer), (Some(l), Some(p)) if self.proxied.bypasses_relay(&l, &p))
+    }
+
+    /// The client address for policy / CC / logging; `peer` when it isn't a trusted proxy.
+    pub fn resolve(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let peer = peer?;
+        if !self.enabled || !self.trusted.contains(peer) {
+            return Some(peer);
+        }
+        let found = match self.header {
+            ClientIpHeader::XRealIp => headers
+                .get("x-real-ip")
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_addr),
+            ClientIpHeader::XForwardedFor => {
+                let hops = header_list(headers, "x-forwarded-for").map(parse_addr);
+                self.rightmost_untrusted(hops)
+            }
+            ClientIpHeader::Forwarded => {
+                let hops = header_list(headers, "forwarded").map(forwarded_for);
+                self.rightmost_untrusted(hops)
+            }
+        };
+        Some(found.unwrap_or(peer))
+    }
+
+    /// `hops` is in header order (client first). An unparsable hop ends the walk at the
+    /// last trusted address (None when it is the right-most hop, i.e. fall back to the
+    /// peer), since nothing to its left can be vouched for.
+    fn rightmost_untrusted(&self, hops: impl Iterator<Item = Option<IpAddr>>) -> Option<IpAddr> {
+        let hops: Vec<Option<IpAddr>> = hops.collect();
+        let mut last = None;
+        for hop in hops.into_iter().rev() {
+            let Some(ip) = hop else {
+                return last;
+            };
+            if !self.trusted.contains(ip) {
+                return Some(ip);
+            }
+            last = Some(ip);
+        }
+        // every hop is one of ours: the left-most is the closest we have to the client
+        last
+    }
+}
+
+/// Comma separated elements across every instance of the header, in order.
+fn header_list<'a>(headers: &'a HeaderMap, name: &'static str) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+}
+
+/// `192.0.2.1`, `192.0.2.1:443`, `2001:db8::1` or `[2001:db8::1]:443`.
+fn parse_addr(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    s.parse::<IpAddr>()
+        .ok()
+        .or_else(|| s.parse::<SocketAddr>().ok().map(|sa| sa.ip()))
+        .or_else(|| s.strip_prefix('[').and_then(|x| x.strip_suffix(']')).and_then(|x| x.parse().ok()))
+}
+
+/// The `for=` parameter of one RFC 7239 element; `unknown` and obfuscated names give None.
+fn forwarded_for(element: &str) -> Option<IpAddr> {
+    element.split(';').find_map(|pair| {
+        let (k, v) = pair.split_once('=')?;
+        if !k.trim().eq_ignore_ascii_case("for") {
+            return None;
+        }
+        parse_addr(v.trim().trim_matches('"'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LB: &str = "10.0.0.1";
+
+    fn resolver(header: ClientIpHeader) -> RealIpResolver {
+        let cfg = ClientIpConfig {
+            trusted_proxies: vec!["10.0.0.0/8".to_string()],
+            header: Some(header),
+        };
+        RealIpResolver::new(&cfg, ProxiedConns::default()).unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        for (k, v) in pairs {
+            h.append(*k, v.parse().unwrap());
+        }
+        h
+    }
+
+    fn resolve(r: &RealIpResolver, peer: &str, h: &HeaderMap) -> String {
+        r.resolve(Some(peer.parse().unwrap()), h).unwrap().to_string()
+    }
+
+    #[test]
+    fn untrusted_peer_ignores_headers() {
+        let h = headers(&[("x-forwarded-for", "198.51.100.7"), ("x-real-ip", "198.51.100.7")]);
+        assert_eq!(resolve(&resolver(ClientIpHeader::XForwardedFor), "192.0.2.1", &h), "192.0.2.1");
+        assert_eq!(resolve(&resolver(ClientIpHeader::XRealIp), "192.0.2.1", &h), "192.0.2.1");
+    }
+
+    #[test]
+    fn spoofed_left_most_entry_is_ignored() {
+        let r = resolver(ClientIpHeader::XForwardedFor);
+        let h = headers(&[("x-forwarded-for", "1.1.1.1, 198.51.100.7, 10.0.0.2")]);
+        assert_eq!(resolve(&r, LB, &h), "198.51.100.7");
+    }
+
+    #[test]
+    fn unparsable_hop_stops_the_walk() {
+        let r = resolver(ClientIpHeader::XForwardedFor);
+        let h = headers(&[("x-forwarded-for", "198.51.100.7, garbage, 10.0.0.2")]);
+        assert_eq!(resolve(&r, LB, &h), "10.0.0.2");
+        let h = headers(&[("x-forwarded-for", "198.51.100.7, garbage")]);
+        assert_eq!(resolve(&r, LB, &h), LB);
+    }
+
+    #[test]
+    fn all_trusted_hops_use_the_left_most() {
+        let r = resolver(ClientIpHeader::XForwardedFor);
+        let h = headers(&[("x-forwarded-for", "10.1.1.1, 10.0.0.2")]);
+        assert_eq!(resolve(&r, LB, &h), "10.1.1.1");
+        assert_eq!(resolve(&r, LB, &HeaderMap::new()), LB);
+    }
+
+    #[test]
+    fn forwarded_for_with_ipv6_and_port() {
+        let r = resolver(ClientIpHeader::Forwarded);
+        let h = headers(&[("forwarded", r#"for="[2001:db8::1]:443";proto=https, for=10.0.0.2"#)]);
+        assert_eq!(resolve(&r, LB, &h), "2001:db8::1");
+        let h = headers(&[("forwarded", "for=unknown")]);
+        assert_eq!(resolve(&r, LB, &h), LB);
+    }
+
+    #[test]
+    fn repeated_headers_are_one_list() {
+        let r = resolver(ClientIpHeader::XForwardedFor);
+        let h = headers(&[("x-forwarded-for", "198.51.100.7"), ("x-forwarded-for", "203.0.113.5, 10.0.0.2")]);
+        assert_eq!(resolve(&r, LB, &h), "203.0.113.5");
+        let h = headers(&[("x-forwarded-for", "198.51.100.7, 10.0.0.3"), ("x-forwarded-for", "10.0.0.2")]);
+        assert_eq!(resolve(&r, LB, &h), "198.51.100.7");
+    }
+}
@@ -0,0 +1,443 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use dashmap::DashMap;
+use http::Uri;
+use once_cell::sync::Lazy;
+use pingora::server::ShutdownWatch;
+use pingora_core::connectors::TransportConnector;
+use pingora_core::services::background::BackgroundService;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::manager::UpstreamManager;
+use super::router::UpstreamRouter;
+use super::types::{HealthCheckSpec, HealthCheckType, OutlierDetectionSpec};
+use crate::metrics::counters;
+
+/// 摘除截止时间的基准，避免在 atomic 里存 Instant
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+
+fn since_epoch_ms(t: Instant) -> u64 {
+    t.saturating_duration_since(*EPOCH).as_millis() as u64
+}
+
+/// 单个源站的健康状态。路由按 tenant 持有引用，upstream.yaml 热更新后同一 (tenant, 源站) 沿用原状态
+#[derive(Debug)]
+pub struct EndpointState {
+    // 主动检查
+    healthy: AtomicBool,
+    ok_streak: AtomicU32,
+    fail_streak: AtomicU32,
+    /// 上一次检查还没结束时不再发起
+    checking: AtomicBool,
+
+    // 被动检查：摘除到 EPOCH 后多少毫秒，0 表示未摘除
+    ejected_until_ms: AtomicU64,
+    passive: Mutex<PassiveStats>,
+
+    /// 本进程正在发往该源站的请求数（least_conn / random_two_choices 用）
+    inflight: AtomicU32,
+}
+
+#[derive(Debug, Default)]
+struct PassiveStats {
+    window_start: Option<Instant>,
+    total: u32,
+    errors: u32,
+    /// 连续摘除次数，决定下次摘除时长
+    ejections: u32,
+    last_ejected_until: Option<Instant>,
+}
+
+impl Default for EndpointState {
+    /// 未检查过的源站视为健康
+    fn default() -> Self {
+        Self {
+            healthy: AtomicBool::new(true),
+            ok_streak: AtomicU32::new(0),
+            fail_streak: AtomicU32::new(0),
+            checking: AtomicBool::new(false),
+            ejected_until_ms: AtomicU64::new(0),
+            passive: Mutex::new(PassiveStats::default()),
+            inflight: AtomicU32::new(0),
+        }
+    }
+}
+
+impl EndpointState {
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn inflight(&self) -> u32 {
+        self.inflight.load(Ordering::Relaxed)
+    }
+
+    pub fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until_ms.load(Ordering::Relaxed) > since_epoch_ms(now)
+    }
+
+    /// 主动检查健康且没有被被动摘除
+    pub fn is_available(&self, now: Instant) -> bool {
+        self.is_healthy() && !self.is_ejected(now)
+    }
+
+    /// 记一次真实请求的结果，窗口内错误率达到阈值时返回 true。摘除期间的结果（在途请求）忽略
+    pub fn record_outcome(&self, ok: bool, spec: &OutlierDetectionSpec, now: Instant) -> bool {
+        if self.is_ejected(now) {
+            return false;
+        }
+        let mut p = self.passive.lock().unwrap_or_else(|e| e.into_inner());
+        let window = Duration::from_secs(spec.interval_secs());
+        if p.window_start.is_none_or(|s| now.duration_since(s) >= window) {
+            p.window_start = Some(now);
+            p.total = 0;
+            p.errors = 0;
+        }
+        p.total += 1;
+        if !ok {
+            p.errors += 1;
+        }
+        !ok && p.total >= spec.min_requests() && p.errors * 100 >= p.total * spec.error_rate_percent()
+    }
+
+    /// 摘除并返回时长：base * 2^(连续次数)，不超过上限；上次摘除结束后超过上限时长没再出问题则重新计数
+    pub fn eject(&self, spec: &OutlierDetectionSpec, now: Instant) -> Duration {
+        let mut p = self.passive.lock().unwrap_or_else(|e| e.into_inner());
+        let max = Duration::from_secs(spec.max_ejection_secs());
+        if p.last_ejected_until.is_some_and(|t| now.saturating_duration_since(t) > max) {
+            p.ejections = 0;
+        }
+        let secs = spec.base_ejection_secs().saturating_mul(1u64 << p.ejections.min(16));
+        let d = Duration::from_secs(secs).min(max);
+        p.ejections += 1;
+        p.last_ejected_until = Some(now + d);
+        p.window_start = None;
+        self.ejected_until_ms.store(since_epoch_ms(now + d), Ordering::Relaxed);
+        d
+    }
+
+    /// 不再主动检查（热更新去掉了 health_check）：恢复为健康，否则之前判为不健康的源站会一直被排除
+    pub fn reset_active(&self) {
+        self.healthy.store(true, Ordering::Relaxed);
+        self.ok_streak.store(0, Ordering::Relaxed);
+        self.fail_streak.store(0, Ordering::Relaxed);
+    }
+
+    /// 记一次检查结果（只有检查任务写），状态翻转时返回新状态
+    fn record(&self, ok: bool, rise: u32, fall: u32) -> Option<bool> {
+        let healthy = self.is_healthy();
+        if ok {
+            self.fail_streak.store(0, Ordering::Relaxed);
+            let n = self.ok_streak.fetch_add(1, Ordering::Relaxed) + 1;
+            if !healthy && n >= rise {
+                self.healthy.store(true, Ordering::Relaxed);
+                return Some(true);
+            }
+        } else {
+            self.ok_streak.store(0, Ordering::Relaxed);
+            let n = self.fail_streak.fetch_add(1, Ordering::Relaxed) + 1;
+            if healthy && n >= fall {
+                self.healthy.store(false, Ordering::Relaxed);
+                return Some(false);
+            }
+        }
+        None
+    }
+}
+
+/// 请求发往源站期间持有，drop 时在途数减一
+pub struct InflightGuard(Arc<EndpointState>);
+
+impl InflightGuard {
+    pub fn new(state: Arc<EndpointState>) -> Self {
+        state.inflight.fetch_add(1, Ordering::Relaxed);
+        Self(state)
+    }
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.0.inflight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// (tenant, 源站) -> 健康状态，跨 UpstreamRouter 重建共享
+#[derive(Debug, Clone, Default)]
+pub struct HealthTable {
+    states: Arc<DashMap<(String, String), Arc<EndpointState>>>,
+}
+
+impl HealthTable {
+    pub fn state(&self, tenant: &str, endpoint: &str) -> Arc<EndpointState> {
+        self.states
+            .entry((tenant.to_string(), endpoint.to_string()))
+            .or_default()
+            .value()
+            .clone()
+    }
+
+    /// 去掉已不在配置里的源站及其指标
+    fn retain(&self, keep: &HashSet<(String, String)>) {
+        self.states.retain(|k, _| {
+            let alive = keep.contains(k);
+            if !alive {
+                counters::remove_upstream_health(&k.0, &k.1);
+                counters::remove_upstream_ejections(&k.0, &k.1);
+            }
+            alive
+        });
+    }
+}
+
+/// 需要主动检查的一个源站
+pub struct HealthTarget {
+    pub tenant: String,
+    pub endpoint: String,
+    pub spec: Arc<HealthCheckSpec>,
+    pub state: Arc<EndpointState>,
+}
+
+/// 按各 tenant 的 health_check 周期探测源站，结果写回 HealthTable
+pub struct HealthChecker {
+    mgr: UpstreamManager,
+    connector: Arc<TransportConnector>,
+}
+
+impl HealthChecker {
+    pub fn new(mgr: UpstreamManager) -> Self {
+        Self { mgr, connector: Arc::new(TransportConnector::new(None)) }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for HealthChecker {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        // 各 tenant 的间隔以秒为单位，这里每秒看一次哪些到期
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        let mut last_run: HashMap<(String, String), Instant> = HashMap::new();
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    tracing::info!("upstream health checker shutdown");
+                    return;
+                }
+                _ = ticker.tick() => {
+                    let router = self.mgr.get();
+                    let keep = router.endpoint_keys();
+                    router.health().retain(&keep);
+                    last_run.retain(|k, _| keep.contains(k));
+
+                    let now = Instant::now();
+                    for t in router.health_targets() {
+                        let key = (t.tenant.clone(), t.endpoint.clone());
+                        let interval = Duration::from_secs(t.spec.interval_secs());
+                        if last_run.get(&key).is_some_and(|at| now.duration_since(*at) < interval) {
+                            continue;
+                        }
+                        if t.state.checking.swap(true, Ordering::AcqRel) {
+                            continue;
+                        }
+                        last_run.insert(key, now);
+
+                        let connector = self.connector.clone();
+                        tokio::spawn(async move {
+                            let timeout = Duration::from_millis(t.spec.timeout_ms());
+                            let res = match tokio::time::timeout(timeout, probe(&connector, &t.endpoint, &t.spec)).await {
+                                Ok(r) => r,
+                                Err(_) => Err(anyhow::anyhow!("timed out after {:?}", timeout)),
+                            };
+                            report(&t, res);
+                            t.state.checking.store(false, Ordering::Release);
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn report(t: &HealthTarget, res: anyhow::Result<()>) {
+    let err = res.err().map(|e| format!("{:#}", e));
+    match t.state.record(err.is_none(), t.spec.rise(), t.spec.fall()) {
+        Some(true) => tracing::info!(tenant = %t.tenant, endpoint = %t.endpoint, "upstream healthy again"),
+        Some(false) => tracing::warn!(
+            tenant = %t.tenant,
+            endpoint = %t.endpoint,
+            "upstream marked unhealthy: {}",
+            err.unwrap_or_default()
+        ),
+        None => {
+            if let Some(e) = err {
+                tracing::debug!(tenant = %t.tenant, endpoint = %t.endpoint, "upstream health check failed: {}", e);
+            }
+        }
+    }
+    counters::set_upstream_health(&t.tenant, &t.endpoint, t.state.is_healthy());
+}
+
+/// tcp：能建连（https 源站包括 TLS 握手）即健康；http：再发一个 GET，按状态码判断
+async fn probe(connector: &TransportConnector, endpoint: &str, spec: &HealthCheckSpec) -> anyhow::Result<()> {
+    let peer = UpstreamRouter::build_peer(endpoint)?;
+    let mut stream = connector
+        .new_stream(&peer)
+        .await
+        .map_err(|e| anyhow::anyhow!("connect failed: {}", e))?;
+    if spec.kind == HealthCheckType::Tcp {
+        return Ok(());
+    }
+
+    let host = spec.host.clone().unwrap_or_else(|| {
+        endpoint
+            .parse::<Uri>()
+            .ok()
+            .and_then(|u| u.authority().map(|a| a.to_string()))
+            .unwrap_or_else(|| endpoint.to_string())
+    });
+    let req = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: aegis-health-check\r\nConnection: close\r\n\r\n",
+        spec.path(),
+        host
+    );
+    stream.write_all(req.as_bytes()).await?;
+    stream.flush().await?;
+
+    // 只需要状态行
+    let mut buf = Vec::with_capacity(256);
+    let mut chunk = [0u8; 256];
+    while !buf.windows(2).any(|w| w == b"\r\n") {
+        if buf.len() > 4096 {
+            anyhow::bail!("status line too long");
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("connection closed before status line");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let line = String::from_utf8_lossy(&buf);
+    let status: u16 = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("bad status line"))?;
+    if !spec.status_ok(status) {
+        anyhow::bail!("unexpected status {}", status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(yaml: &str) -> OutlierDetectionSpec {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    #[test]
+    fn needs_min_requests_and_error_rate() {
+        let s = spec("{ min_requests: 4, error_rate_percent: 50 }");
+        let st = EndpointState::default();
+        let t0 = Instant::now();
+        assert!(!st.record_outcome(false, &s, t0));
+        assert!(!st.record_outcome(true, &s, t0));
+        assert!(!st.record_outcome(false, &s, t0));
+        // 第 4 个请求到达 min_requests，3/4 失败
+        assert!(st.record_outcome(false, &s, t0));
+        // 成功的结果从不触发摘除
+        assert!(!st.record_outcome(true, &s, t0));
+    }
+
+    #[test]
+    fn window_rolls_over() {
+        let s = spec("{ interval_secs: 10, min_requests: 2 }");
+        let st = EndpointState::default();
+        let t0 = Instant::now();
+        assert!(!st.record_outcome(false, &s, t0));
+        // 新窗口重新计数，上一窗口的失败不算
+        assert!(!st.record_outcome(false, &s, t0 + secs(11)));
+        assert!(st.record_outcome(false, &s, t0 + secs(12)));
+    }
+
+    #[test]
+    fn ejection_backs_off_up_to_the_max() {
+        let s = spec("{ base_ejection_secs: 10, max_ejection_secs: 35, min_requests: 1 }");
+        let st = EndpointState::default();
+        let t0 = Instant::now();
+        assert_eq!(st.eject(&s, t0), secs(10));
+        assert!(st.is_ejected(t0 + secs(9)));
+        assert!(!st.is_available(t0 + secs(9)));
+        // 摘除期间的结果忽略
+        assert!(!st.record_outcome(false, &s, t0 + secs(9)));
+        assert!(!st.is_ejected(t0 + secs(10)));
+
+        assert_eq!(st.eject(&s, t0 + secs(20)), secs(20));
+        assert_eq!(st.eject(&s, t0 + secs(50)), secs(35));
+        assert_eq!(st.eject(&s, t0 + secs(90)), secs(35));
+        // 上次摘除结束（t0 + 125）后安静超过上限时长，重新从 base 开始
+        assert_eq!(st.eject(&s, t0 + secs(161)), secs(10));
+    }
+
+    #[test]
+    fn active_checks_count_rise_and_fall() {
+        let st = EndpointState::default();
+        assert!(st.is_healthy());
+        // fall = 3：中间一次成功让失败重新计数
+        assert_eq!(st.record(false, 2, 3), None);
+        assert_eq!(st.record(false, 2, 3), None);
+        assert_eq!(st.record(true, 2, 3), None);
+        assert_eq!(st.record(false, 2, 3), None);
+        assert_eq!(st.record(false, 2, 3), None);
+        assert_eq!(st.record(false, 2, 3), Some(false));
+        assert!(!st.is_available(Instant::now()));
+        // 已经不健康，继续失败不再翻转
+        assert_eq!(st.record(false, 2, 3), None);
+
+        // rise = 2：一次失败打断连续成功
+        assert_eq!(st.record(true, 2, 3), None);
+        assert_eq!(st.record(false, 2, 3), None);
+        assert_eq!(st.record(true, 2, 3), None);
+        assert_eq!(st.record(true, 2, 3), Some(true));
+        assert!(st.is_available(Instant::now()));
+        assert_eq!(st.record(true, 2, 3), None);
+    }
+
+    fn router(tenant: &str, table: &HealthTable) -> UpstreamRouter {
+        let yaml = format!(
+            "{{ version: 1, resolver: {{ mode: static, host_to_cname: {{}} }}, \
+             cname_routing: {{ tenant_from_cname_regex: '^(.+)$' }}, \
+             tenants: {{ t: {tenant} }}, default: {{ upstreams: [d] }} }}"
+        );
+        UpstreamRouter::new(serde_yaml::from_str(&yaml).unwrap(), table.clone()).unwrap()
+    }
+
+    #[test]
+    fn reload_without_health_check_marks_endpoints_healthy() {
+        let table = HealthTable::default();
+        let checked = "{ upstreams: [a, b], health_check: { type: tcp, fall: 1 } }";
+        let r = router(checked, &table);
+        assert_eq!(r.health_targets().len(), 2);
+        let a = table.state("t", "a");
+        assert_eq!(a.record(false, 1, 1), Some(false));
+
+        // 热更新保留检查：沿用不健康状态
+        router(checked, &table);
+        assert!(!table.state("t", "a").is_healthy());
+
+        // 去掉检查：不会再有检查把它恢复，直接视为健康，计数清零
+        let r = router("{ upstreams: [a, b] }", &table);
+        assert!(r.health_targets().is_empty());
+        assert!(a.is_healthy());
+        assert_eq!(a.record(false, 2, 2), None);
+        assert!(a.is_healthy());
+    }
+}
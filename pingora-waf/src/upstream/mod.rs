@@ -1,3 +1,4 @@
+pub mod health;
 pub mod manager;
 pub mod reload;
 pub mod router;
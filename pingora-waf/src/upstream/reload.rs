@@ -19,7 +19,7 @@ pub async fn upstream_hot_reload_loop(mgr: UpstreamManager, path: PathBuf, inter
                 if changed {
                     match serde_yaml::from_slice::<UpstreamConfigFile>(&bytes)
                         .map_err(|e| anyhow::anyhow!("parse upstream.yaml failed: {e}"))
-                        .and_then(|cfg| UpstreamRouter::new(cfg))
+                        .and_then(|cfg| UpstreamRouter::new(cfg, mgr.get().health().clone()))
                     {
                         Ok(router) => {
                             mgr.swap(router);
@@ -1,8 +1,8 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc,
+        Arc, Mutex,
     },
     time::{Duration, Instant},
 };
@@ -17,9 +17,17 @@ use hickory_resolver::{
 };
 use http::Uri;
 use pingora::prelude::HttpPeer;
+use rand::Rng;
 use regex::Regex;
 use tracing::warn;
-use super::types::{ResolverConfig as MyResolverConfig, UpstreamConfigFile};
+use super::health::{EndpointState, HealthTable, HealthTarget, InflightGuard};
+use super::types::{
+    ConnectionSpec, H2Mode, HealthCheckSpec, LbKind, OutlierDetectionSpec, ResolverConfig as MyResolverConfig,
+    RetrySpec, TenantUpstreams, UpstreamConfigFile, MAX_WEIGHT,
+};
+use crate::metrics::counters;
+use crate::policy::build_key;
+use crate::waf::context::WafContext;
 
 #[derive(Clone)]
 pub struct UpstreamRouter {
@@ -39,11 +47,89 @@ struct Inner {
     tenant_re: Regex,
 
     // tenant -> upstreams
-    tenants: HashMap<String, Vec<String>>,
-    default_upstreams: Vec<String>,
+    tenants: HashMap<String, Pool>,
+    default_pool: Pool,
 
     // rr counter per tenant
     rr: DashMap<String, AtomicUsize>,
+
+    // 健康状态（跨热更新共享）
+    health: HealthTable,
+}
+
+/// 一个 tenant 的源站，weights / conns / health 与 upstreams 一一对应
+struct Pool {
+    upstreams: Vec<String>,
+    weights: Vec<u32>,
+    conns: Vec<ConnectionSpec>,
+    health: Vec<Arc<EndpointState>>,
+    check: Option<Arc<HealthCheckSpec>>,
+    outlier: Option<OutlierDetectionSpec>,
+    retry: Option<Arc<RetrySpec>>,
+    lb: LbKind,
+    hash_key: Vec<String>,
+    /// consistent_hash 的哈希环：(虚拟节点 hash, 源站下标)，按 hash 排序
+    ring: Vec<(u64, usize)>,
+    /// weighted 的平滑加权轮询当前值，与 upstreams 一一对应
+    current: Mutex<Vec<i64>>,
+}
+
+/// 每单位权重的虚拟节点数
+const RING_VNODES: u32 = 100;
+
+impl Pool {
+    fn new(tenant: &str, t: TenantUpstreams, table: &HealthTable) -> anyhow::Result<Self> {
+        if let Some(u) = t.upstreams.iter().find(|u| u.weight() > MAX_WEIGHT) {
+            anyhow::bail!("tenant {}: upstream {} weight {} exceeds {}", tenant, u.url(), u.weight(), MAX_WEIGHT);
+        }
+        let hash_key = vec![t.hash_key().to_string()];
+        let upstreams: Vec<String> = t.upstreams.iter().map(|u| u.url().to_string()).collect();
+        let weights: Vec<u32> = t.upstreams.iter().map(|u| u.weight()).collect();
+        let conns = t.upstreams.iter().map(|u| u.connection(&t.connection)).collect();
+        let health: Vec<Arc<EndpointState>> = upstreams.iter().map(|u| table.state(tenant, u)).collect();
+        if t.health_check.is_none() {
+            for (u, st) in upstreams.iter().zip(&health) {
+                st.reset_active();
+                counters::remove_upstream_health(tenant, u);
+            }
+        }
+
+        let mut ring = Vec::new();
+        if t.lb == LbKind::ConsistentHash {
+            for (i, (u, w)) in upstreams.iter().zip(&weights).enumerate() {
+                for v in 0..w.saturating_mul(RING_VNODES) {
+                    ring.push((hash64(format!("{u}#{v}").as_bytes()), i));
+                }
+            }
+            ring.sort_unstable();
+        }
+
+        Ok(Self {
+            weights,
+            conns,
+            health,
+            check: t.health_check.map(Arc::new),
+            outlier: t.outlier_detection,
+            retry: t.retry.map(Arc::new),
+            lb: t.lb,
+            hash_key,
+            ring,
+            current: Mutex::new(vec![0; upstreams.len()]),
+            upstreams,
+        })
+    }
+
+    /// 权重 > 0、主动检查健康且未被摘除
+    fn usable(&self, i: usize, now: Instant) -> bool {
+        self.weights[i] > 0 && self.health[i].is_available(now)
+    }
+
+    /// a 的 在途数 / 权重 是否小于 b
+    fn less_loaded(&self, a: usize, b: usize) -> bool {
+        let la = self.health[a].inflight() as u64 * self.weights[b] as u64;
+        let lb = self.health[b].inflight() as u64 * self.weights[a] as u64;
+        la < lb
+    }
 }
 
 enum ResolverMode {
@@ -56,18 +142,22 @@ enum ResolverMode {
 }
 
 impl UpstreamRouter {
-    pub fn new(cfg: UpstreamConfigFile) -> anyhow::Result<Self> {
+    /// health 由 UpstreamManager 持有，热更新时传入同一个以保留健康状态
+    pub fn new(cfg: UpstreamConfigFile, health: HealthTable) -> anyhow::Result<Self> {
         let tenant_re = Regex::new(&cfg.cname_routing.tenant_from_cname_regex)
             .with_context(|| "bad tenant_from_cname_regex")?;
 
         let tenants = cfg
             .tenants
             .into_iter()
-            .map(|(k, v)| (k, v.upstreams))
-            .collect::<HashMap<_, _>>();
-
-        let default_upstreams = cfg.default.upstreams;
-        if default_upstreams.is_empty() {
+            .map(|(k, v)| {
+                let pool = Pool::new(&k, v, &health)?;
+                Ok((k, pool))
+            })
+            .collect::<anyhow::Result<HashMap<_, _>>>()?;
+
+        let default_pool = Pool::new("default", cfg.default, &health)?;
+        if default_pool.upstreams.is_empty() {
             warn!("default.upstreams cannot be empty");
         }
 
@@ -106,30 +196,37 @@ impl UpstreamRouter {
                 cname_cache: DashMap::new(),
                 tenant_re,
                 tenants,
-                default_upstreams,
+                default_pool,
                 rr: DashMap::new(),
+                health,
             }),
         })
     }
-    pub async fn pick_endpoint_and_edge_key(&self, host: Option<&str>) -> (String, String) {
+    /// req 用于 consistent_hash 计算 hash_key，没有时按空 key 处理
+    pub async fn pick_endpoint_and_edge_key(
+        &self,
+        host: Option<&str>,
+        req: Option<(&WafContext, &http::HeaderMap)>,
+    ) -> (String, String) {
         let tenant = match host {
             Some(h) => self.tenant_from_request_host(h).await,
             None => None,
         };
 
-        let (key, ups) = if let Some(t) = tenant {
-            if let Some(list) = self.inner.tenants.get(&t) {
-                (t, list.as_slice())
+        let (key, pool) = if let Some(t) = tenant {
+            if let Some(pool) = self.inner.tenants.get(&t) {
+                (t, pool)
             } else {
-                ("default".to_string(), self.inner.default_upstreams.as_slice())
+                ("default".to_string(), &self.inner.default_pool)
             }
         } else {
-            ("default".to_string(), self.inner.default_upstreams.as_slice())
+            ("default".to_string(), &self.inner.default_pool)
         };
 
-        let upstream = rr_pick(&self.inner.rr, &key, ups).unwrap_or_else(|| {
+        let upstream = pick(&self.inner.rr, &key, pool, req, &[]).map(|i| pool.upstreams[i].clone()).unwrap_or_else(|| {
             self.inner
-                .default_upstreams
+                .default_pool
+                .upstreams
                 .get(0)
                 .cloned()
                 .unwrap_or_else(|| "".to_string())
@@ -138,8 +235,101 @@ impl UpstreamRouter {
         (key, upstream)
     }
 
+    /// edge_key -> 源站池，未知 tenant 用 default
+    fn pool(&self, tenant: &str) -> &Pool {
+        self.inner.tenants.get(tenant).unwrap_or(&self.inner.default_pool)
+    }
+
+    pub fn retry_policy(&self, tenant: &str) -> Option<Arc<RetrySpec>> {
+        self.pool(tenant).retry.clone()
+    }
+
+    /// 重试时换一个源站：按同样的 lb 在没试过的源站里选；都试过了返回 None（沿用上一个）
+    pub fn pick_retry(
+        &self,
+        tenant: &str,
+        tried: &[String],
+        req: Option<(&WafContext, &http::HeaderMap)>,
+    ) -> Option<String> {
+        let pool = self.pool(tenant);
+        let i = pick(&self.inner.rr, tenant, pool, req, tried)?;
+        let u = &pool.upstreams[i];
+        (!tried.contains(u)).then(|| u.clone())
+    }
+
+    /// 代理把真实请求结果（连接失败、超时、5xx 为失败）报回来，用于被动摘除
+    pub fn report_outcome(&self, tenant: &str, endpoint: &str, ok: bool) {
+        let pool = self.pool(tenant);
+        let Some(spec) = &pool.outlier else {
+            return;
+        };
+        let Some(i) = pool.upstreams.iter().position(|u| u == endpoint) else {
+            return;
+        };
+        let st = &pool.health[i];
+        let now = Instant::now();
+        if !st.record_outcome(ok, spec, now) {
+            return;
+        }
+
+        // 摘除后超过 max_ejected_percent 就不摘，保住剩余容量
+        let ejected = pool.health.iter().filter(|h| h.is_ejected(now)).count();
+        if (ejected + 1) * 100 > pool.upstreams.len() * spec.max_ejected_percent() as usize {
+            tracing::debug!(tenant, endpoint, ejected, "outlier not ejected: max_ejected_percent reached");
+            return;
+        }
+        let d = st.eject(spec, now);
+        counters::inc_upstream_ejection(tenant, endpoint);
+        warn!(tenant, endpoint, "upstream ejected for {:?}: error rate over {}%", d, spec.error_rate_percent());
+    }
+
+    /// 请求发往该源站期间持有（least_conn 计数），源站已不在配置里时返回 None
+    pub fn track(&self, tenant: &str, endpoint: &str) -> Option<InflightGuard> {
+        let pool = self.pool(tenant);
+        let i = pool.upstreams.iter().position(|u| u == endpoint)?;
+        Some(InflightGuard::new(pool.health[i].clone()))
+    }
+
+    pub fn health(&self) -> &HealthTable {
+        &self.inner.health
+    }
+
+    fn pools(&self) -> impl Iterator<Item = (&str, &Pool)> {
+        self.inner
+            .tenants
+            .iter()
+            .map(|(k, p)| (k.as_str(), p))
+            .chain(std::iter::once(("default", &self.inner.default_pool)))
+    }
+
+    /// 当前配置里所有 (tenant, 源站)
+    pub fn endpoint_keys(&self) -> HashSet<(String, String)> {
+        self.pools()
+            .flat_map(|(t, p)| p.upstreams.iter().map(move |u| (t.to_string(), u.clone())))
+            .collect()
+    }
+
+    /// 配置了 health_check 的 tenant 下的所有源站
+    pub fn health_targets(&self) -> Vec<HealthTarget> {
+        let mut out = Vec::new();
+        for (t, p) in self.pools() {
+            let Some(spec) = &p.check else {
+                continue;
+            };
+            for (u, st) in p.upstreams.iter().zip(&p.health) {
+                out.push(HealthTarget {
+                    tenant: t.to_string(),
+                    endpoint: u.clone(),
+                    spec: spec.clone(),
+                    state: st.clone(),
+                });
+            }
+        }
+        out
+    }
+
     pub async fn pick_endpoint(&self, host: Option<&str>) -> String {
-        self.pick_endpoint_and_edge_key(host).await.1
+        self.pick_endpoint_and_edge_key(host, None).await.1
     }
 
     async fn tenant_from_request_host(&self, host: &str) -> Option<String> {
@@ -228,6 +418,19 @@ impl UpstreamRouter {
         Ok(Some(cur))
     }
 
+    /// build_peer 再套上 tenant / 源站的连接设置；源站已不在配置里时只用默认值
+    pub fn build_peer_for(&self, tenant: &str, upstream: &str) -> anyhow::Result<HttpPeer> {
+        let mut peer = Self::build_peer(upstream)?;
+        let pool = self.pool(tenant);
+        if let Some(i) = pool.upstreams.iter().position(|u| u == upstream) {
+            apply_connection(&mut peer, &pool.conns[i]);
+            if let Some(max) = pool.conns[i].max_idle_conns {
+                limit_idle(&mut peer, tenant, &pool.health[i], max);
+            }
+        }
+        Ok(peer)
+    }
+
     pub fn build_peer(upstream: &str) -> anyhow::Result<HttpPeer> {
         if let Ok(uri) = upstream.parse::<Uri>() {
             if let Some(auth) = uri.authority() {
@@ -259,6 +462,47 @@ impl UpstreamRouter {
     }
 }
 
+fn apply_connection(peer: &mut HttpPeer, c: &ConnectionSpec) {
+    let o = &mut peer.options;
+    if let Some(ms) = c.connect_timeout_ms {
+        o.connection_timeout = Some(Duration::from_millis(ms));
+    }
+    if let Some(ms) = c.total_connect_timeout_ms {
+        o.total_connection_timeout = Some(Duration::from_millis(ms));
+    }
+    if let Some(ms) = c.read_timeout_ms {
+        o.read_timeout = Some(Duration::from_millis(ms));
+    }
+    if let Some(ms) = c.write_timeout_ms {
+        o.write_timeout = Some(Duration::from_millis(ms));
+    }
+    if let Some(s) = c.idle_timeout_secs {
+        o.idle_timeout = Some(Duration::from_secs(s));
+    }
+    // 放回连接池时空闲超时为 0，连接池立即关闭它
+    if c.keepalive == Some(false) {
+        o.idle_timeout = Some(Duration::ZERO);
+    }
+    match c.h2 {
+        Some(H2Mode::Prefer) => o.set_http_version(2, 1),
+        Some(H2Mode::Only) => o.set_http_version(2, 2),
+        Some(H2Mode::Off) | None => {}
+    }
+    if let Some(n) = c.max_h2_streams {
+        o.max_h2_streams = n.max(1);
+    }
+}
+
+/// 按 (tenant, 源站) 限制空闲连接：group_key 让该 tenant 的连接在池里单独分组，
+/// 在途请求（含本次，调用前已 track）超过上限时这次的连接用完即关。
+/// 池里有空闲连接时 pingora 总是先复用，所以带 keepalive 的连接（空闲 + 使用中）不会超过上限
+fn limit_idle(peer: &mut HttpPeer, tenant: &str, st: &EndpointState, max: u32) {
+    peer.group_key = hash64(format!("tenant#{tenant}").as_bytes());
+    if st.inflight() > max {
+        peer.options.idle_timeout = Some(Duration::ZERO);
+    }
+}
+
 fn strip_port(host: &str) -> &str {
     if let Some(idx) = host.rfind(':') {
         let left = &host[..idx];
@@ -270,13 +514,435 @@ fn strip_port(host: &str) -> &str {
     host
 }
 
-fn rr_pick(rr: &DashMap<String, AtomicUsize>, key: &str, ups: &[String]) -> Option<String> {
-    if ups.is_empty() {
+/// 按 tenant 的 lb 选源站下标。只在可用且不在 exclude 里的源站中选；
+/// 没有时退回轮询（优先没排除的，权重 0 的只在全部为 0 时参与），总比直接 502 好
+fn pick(
+    rr: &DashMap<String, AtomicUsize>,
+    key: &str,
+    pool: &Pool,
+    req: Option<(&WafContext, &http::HeaderMap)>,
+    exclude: &[String],
+) -> Option<usize> {
+    let len = pool.upstreams.len();
+    if len == 0 {
         return None;
     }
-    let counter = rr
+    let n = rr
         .entry(key.to_string())
-        .or_insert_with(|| AtomicUsize::new(0));
-    let i = counter.fetch_add(1, Ordering::Relaxed) % ups.len();
-    Some(ups[i].clone())
+        .or_insert_with(|| AtomicUsize::new(0))
+        .fetch_add(1, Ordering::Relaxed);
+
+    let now = Instant::now();
+    let allowed = |i: usize| !exclude.contains(&pool.upstreams[i]);
+    let ok = |i: usize| allowed(i) && pool.usable(i, now);
+    let usable = || (0..len).filter(move |&i| ok(i));
+    let count = usable().count();
+    let drained = pool.weights.iter().all(|&w| w == 0);
+    let weighted = |i: usize| drained || pool.weights[i] > 0;
+    // 在符合条件的源站之间轮流，不偏向被排除源站后面的那个
+    let rotate = |f: &dyn Fn(usize) -> bool| {
+        let c = (0..len).filter(|&i| f(i)).count();
+        (0..len).filter(|&i| f(i)).nth(n % c.max(1))
+    };
+    let fallback = rotate(&|i| weighted(i) && allowed(i)).or_else(|| rotate(&weighted)).unwrap_or(n % len);
+    if count == 0 {
+        return Some(fallback);
+    }
+
+    let picked = match pool.lb {
+        LbKind::RoundRobin => usable().nth(n % count),
+
+        // 平滑加权轮询（同 nginx）：各源站当前值加上权重，选最大的再减去总权重，
+        // 大权重源站的请求不会连成一片，小流量的灰度源站穿插其中
+        LbKind::Weighted => {
+            let mut cur = pool.current.lock().unwrap_or_else(|e| e.into_inner());
+            let mut total = 0;
+            let mut best: Option<usize> = None;
+            for i in usable() {
+                let w = pool.weights[i] as i64;
+                cur[i] += w;
+                total += w;
+                if best.is_none_or(|b| cur[i] > cur[b]) {
+                    best = Some(i);
+                }
+            }
+            if let Some(b) = best {
+                cur[b] -= total;
+            }
+            best
+        }
+
+        // 负载最小的有多个时按轮询计数在它们之间轮流，不偏向忙碌源站后面的那个
+        LbKind::LeastConn => {
+            let best = usable().reduce(|best, i| if pool.less_loaded(i, best) { i } else { best });
+            best.and_then(|b| {
+                let tied = || usable().filter(move |&i| !pool.less_loaded(b, i));
+                tied().nth(n % tied().count())
+            })
+        }
+
+        LbKind::RandomTwoChoices => {
+            let mut rng = rand::thread_rng();
+            let x = rng.gen_range(0..count);
+            // 第二个从剩下的 count - 1 个里取，保证和第一个不同
+            let mut y = rng.gen_range(0..count.saturating_sub(1).max(1));
+            if y >= x {
+                y += 1;
+            }
+            match (usable().nth(x), usable().nth(y)) {
+                (Some(a), Some(b)) if pool.less_loaded(b, a) => Some(b),
+                (a, _) => a,
+            }
+        }
+
+        // 顺时针找第一个可用源站的虚拟节点
+        LbKind::ConsistentHash => {
+            let k = req.map(|(w, h)| build_key(&pool.hash_key, w, h)).unwrap_or_default();
+            let h = hash64(k.as_bytes());
+            let start = pool.ring.partition_point(|&(x, _)| x < h);
+            (0..pool.ring.len())
+                .map(|o| pool.ring[(start + o) % pool.ring.len()].1)
+                .find(|&i| ok(i))
+        }
+    };
+    Some(picked.unwrap_or(fallback))
+}
+
+/// fnv1a 分布不够均匀（虚拟节点名只差末尾几位），再过一遍 splitmix64 的混合
+fn hash64(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for &b in data {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    mix64(h)
+}
+
+fn mix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::types::RetryOn;
+    use pingora::upstreams::peer::Peer;
+
+    fn pool(yaml: &str) -> Pool {
+        let t: TenantUpstreams = serde_yaml::from_str(yaml).unwrap();
+        Pool::new("t", t, &HealthTable::default()).unwrap()
+    }
+
+    fn eject(p: &Pool, i: usize) {
+        let spec: OutlierDetectionSpec = serde_yaml::from_str("{}").unwrap();
+        p.health[i].eject(&spec, Instant::now());
+    }
+
+    /// n 次连续选择（同一个轮询计数）
+    fn picks(p: &Pool, n: usize, exclude: &[String]) -> Vec<usize> {
+        let rr = DashMap::new();
+        (0..n).map(|_| pick(&rr, "t", p, None, exclude).unwrap()).collect()
+    }
+
+    fn counts(picks: &[usize], len: usize) -> Vec<usize> {
+        (0..len).map(|i| picks.iter().filter(|&&p| p == i).count()).collect()
+    }
+
+    #[test]
+    fn weighted_follows_weights() {
+        let p = pool(
+            "{ lb: weighted, upstreams: [{ url: a, weight: 1 }, { url: b, weight: 3 }, { url: c, weight: 0 }] }",
+        );
+        assert_eq!(counts(&picks(&p, 400, &[]), 3), [100, 300, 0]);
+        eject(&p, 1);
+        assert_eq!(counts(&picks(&p, 10, &[]), 3), [10, 0, 0]);
+    }
+
+    #[test]
+    fn weighted_interleaves_small_weights() {
+        let p = pool("{ lb: weighted, upstreams: [{ url: a, weight: 4 }, b] }");
+        assert_eq!(picks(&p, 10, &[]), [0, 0, 1, 0, 0, 0, 0, 1, 0, 0]);
+
+        // 灰度源站出现在一轮中间，而不是大权重源站连续 1000 次之后
+        let p = pool("{ lb: weighted, upstreams: [{ url: a, weight: 1000 }, b] }");
+        let seq = picks(&p, 2002, &[]);
+        let canary: Vec<usize> = seq.iter().enumerate().filter(|(_, &i)| i == 1).map(|(n, _)| n).collect();
+        assert_eq!(canary.len(), 2);
+        assert!((400..600).contains(&canary[0]), "{canary:?}");
+        assert_eq!(canary[1] - canary[0], 1001);
+    }
+
+    #[test]
+    fn least_conn_rotates_on_ties() {
+        let p = pool("{ lb: least_conn, upstreams: [a, b, c] }");
+        assert_eq!(counts(&picks(&p, 3, &[]), 3), [1, 1, 1]);
+        let _busy = InflightGuard::new(p.health[0].clone());
+        assert_eq!(counts(&picks(&p, 6, &[]), 3), [0, 3, 3]);
+
+        // 按 在途数 / 权重 比较：a = 2/2 与 b = 1/1 打平，c = 2/1 最忙
+        let p = pool("{ lb: least_conn, upstreams: [{ url: a, weight: 2 }, b, c] }");
+        let _g: Vec<_> = [0, 0, 1, 2, 2].iter().map(|&i| InflightGuard::new(p.health[i].clone())).collect();
+        assert_eq!(counts(&picks(&p, 6, &[]), 3), [3, 3, 0]);
+    }
+
+    #[test]
+    fn two_choices_with_one_or_two_candidates() {
+        let p = pool("{ lb: random_two_choices, upstreams: [a, b, c] }");
+        eject(&p, 0);
+        eject(&p, 1);
+        assert_eq!(counts(&picks(&p, 20, &[]), 3), [0, 0, 20]);
+
+        // 两个候选总是都被抽到，始终选空闲的那个
+        let p = pool("{ lb: random_two_choices, upstreams: [a, b, c] }");
+        eject(&p, 2);
+        let _busy = InflightGuard::new(p.health[0].clone());
+        assert_eq!(counts(&picks(&p, 50, &[]), 3), [0, 50, 0]);
+    }
+
+    #[test]
+    fn two_choices_draws_random_pairs() {
+        // 三个候选：两两抽取，忙的那个永远比不过另一个，空闲的两个都会被选到
+        let p = pool("{ lb: random_two_choices, upstreams: [a, b, c] }");
+        let _busy = InflightGuard::new(p.health[0].clone());
+        let c = counts(&picks(&p, 300, &[]), 3);
+        assert_eq!(c[0], 0);
+        assert!(c[1] > 50 && c[2] > 50, "{c:?}");
+
+        // 不跟着轮询计数走：两个独立的计数器不会得到同一串选择
+        let p = pool("{ lb: random_two_choices, upstreams: [a, b, c, d, e, f, g, h] }");
+        assert_ne!(picks(&p, 64, &[]), picks(&p, 64, &[]));
+    }
+
+    #[test]
+    fn consistent_hash_skips_unusable_endpoints() {
+        let p = pool("{ lb: consistent_hash, upstreams: [a, b, c, d] }");
+        let rr = DashMap::new();
+        let headers = http::HeaderMap::new();
+        let pick_for = |ip: u8| {
+            let w = WafContext {
+                client_ip: Some(std::net::IpAddr::from([192, 0, 2, ip])),
+                ..Default::default()
+            };
+            pick(&rr, "t", &p, Some((&w, &headers)), &[]).unwrap()
+        };
+
+        let before: Vec<usize> = (1..=100).map(pick_for).collect();
+        assert_eq!((1..=100).map(pick_for).collect::<Vec<_>>(), before);
+        assert!(counts(&before, 4).iter().all(|&c| c > 0), "{before:?}");
+
+        eject(&p, before[0]);
+        for (ip, was) in (1..=100).zip(&before) {
+            let now = pick_for(ip);
+            if *was == before[0] {
+                assert_ne!(now, *was);
+            } else {
+                // 其它 key 不受影响
+                assert_eq!(now, *was);
+            }
+        }
+    }
+
+    #[test]
+    fn all_down_falls_back_to_round_robin() {
+        let p = pool("{ lb: least_conn, upstreams: [a, b, c] }");
+        for i in 0..3 {
+            eject(&p, i);
+        }
+        assert_eq!(counts(&picks(&p, 6, &[]), 3), [2, 2, 2]);
+        // 仍然避开已经试过的
+        assert_eq!(counts(&picks(&p, 6, &["a".to_string()]), 3), [0, 3, 3]);
+        assert_eq!(pick(&DashMap::new(), "t", &pool("{ upstreams: [] }"), None, &[]), None);
+
+        // 权重 0 的源站不接退回的流量，已经试过的有权重源站也比它优先
+        let p = pool("{ upstreams: [a, { url: b, weight: 0 }, c] }");
+        for i in [0, 2] {
+            eject(&p, i);
+        }
+        assert_eq!(counts(&picks(&p, 6, &[]), 3), [3, 0, 3]);
+        assert_eq!(counts(&picks(&p, 6, &["a".to_string()]), 3), [0, 0, 6]);
+        assert_eq!(counts(&picks(&p, 6, &["a".to_string(), "c".to_string()]), 3), [3, 0, 3]);
+        // 全是 0 时只能用它们
+        let p = pool("{ upstreams: [{ url: a, weight: 0 }, { url: b, weight: 0 }] }");
+        assert_eq!(counts(&picks(&p, 4, &["a".to_string()]), 2), [0, 4]);
+    }
+
+    fn router(tenant: &str) -> UpstreamRouter {
+        let yaml = format!(
+            "{{ version: 1, resolver: {{ mode: static, host_to_cname: {{}} }}, \
+             cname_routing: {{ tenant_from_cname_regex: '^(.+)$' }}, \
+             tenants: {{ t: {tenant} }}, default: {{ upstreams: [d] }} }}"
+        );
+        UpstreamRouter::new(serde_yaml::from_str(&yaml).unwrap(), HealthTable::default()).unwrap()
+    }
+
+    fn ejected(r: &UpstreamRouter) -> Vec<bool> {
+        let now = Instant::now();
+        r.pool("t").health.iter().map(|h| h.is_ejected(now)).collect()
+    }
+
+    #[test]
+    fn max_ejected_percent_keeps_capacity() {
+        // 默认 50%：只有一个源站时永远不摘
+        let r = router("{ upstreams: [a], outlier_detection: { min_requests: 1 } }");
+        for _ in 0..5 {
+            r.report_outcome("t", "a", false);
+        }
+        assert_eq!(ejected(&r), [false]);
+
+        let r = router("{ upstreams: [a, b], outlier_detection: { min_requests: 1 } }");
+        r.report_outcome("t", "a", false);
+        r.report_outcome("t", "b", false);
+        assert_eq!(ejected(&r), [true, false]);
+
+        let r = router("{ upstreams: [a, b], outlier_detection: { min_requests: 1, max_ejected_percent: 100 } }");
+        r.report_outcome("t", "a", false);
+        r.report_outcome("t", "b", false);
+        assert_eq!(ejected(&r), [true, true]);
+
+        // 没配 outlier_detection 不摘
+        let r = router("{ upstreams: [a] }");
+        r.report_outcome("t", "a", false);
+        assert_eq!(ejected(&r), [false]);
+    }
+
+    #[test]
+    fn retry_picks_an_untried_endpoint() {
+        let r = router("{ upstreams: [a, b, c] }");
+        let tried = |t: &[&str]| t.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let retries = |t: &[&str]| (0..6).map(|_| r.pick_retry("t", &tried(t), None)).collect::<Vec<_>>();
+
+        let one = retries(&["a"]);
+        assert!(one.iter().all(|u| matches!(u.as_deref(), Some("b" | "c"))), "{one:?}");
+        assert!(one.contains(&Some("b".to_string())) && one.contains(&Some("c".to_string())));
+        assert!(retries(&["a", "c"]).iter().all(|u| u.as_deref() == Some("b")));
+        // 都试过了：不换（沿用上一个）
+        assert!(retries(&["a", "b", "c"]).iter().all(Option::is_none));
+
+        // 优先没试过且可用的；没试过的都不可用时也换过去
+        eject(r.pool("t"), 1);
+        assert!(retries(&["a"]).iter().all(|u| u.as_deref() == Some("c")));
+        eject(r.pool("t"), 2);
+        let down = retries(&["a"]);
+        assert!(down.iter().all(|u| matches!(u.as_deref(), Some("b" | "c"))), "{down:?}");
+
+        // 未知 tenant 用 default 池
+        assert_eq!(r.pick_retry("nope", &tried(&["d"]), None), None);
+    }
+
+    #[test]
+    fn retry_budget_and_methods() {
+        let spec: RetrySpec = serde_yaml::from_str("{ max_attempts: 3, retry_on: [connect_error, timeout] }").unwrap();
+        assert!(spec.permits(RetryOn::ConnectError, 1, true));
+        assert!(spec.permits(RetryOn::ConnectError, 2, false));
+        // 已经试了 max_attempts 次
+        assert!(!spec.permits(RetryOn::ConnectError, 3, true));
+        // 请求已经发出：非幂等方法不重试
+        assert!(spec.permits(RetryOn::Timeout, 1, true));
+        assert!(!spec.permits(RetryOn::Timeout, 1, false));
+        // 没配置的失败类型
+        assert!(!spec.permits(RetryOn::Http502, 1, true));
+
+        let default: RetrySpec = serde_yaml::from_str("{}").unwrap();
+        assert!(default.permits(RetryOn::ConnectError, 1, false));
+        assert!(!default.permits(RetryOn::ConnectError, 2, false));
+        assert!(!default.permits(RetryOn::Timeout, 1, true));
+    }
+
+    #[test]
+    fn connection_settings_layer_endpoint_over_tenant_over_defaults() {
+        use pingora::protocols::ALPN;
+
+        let r = router(
+            "{ connection: { connect_timeout_ms: 500, read_timeout_ms: 3000, idle_timeout_secs: 30, h2: prefer }, \
+             upstreams: [127.0.0.1:8001, \
+               { url: 127.0.0.1:8002, connection: { read_timeout_ms: 100, write_timeout_ms: 200, h2: off, keepalive: false } }, \
+               { url: 127.0.0.1:8003, connection: { total_connect_timeout_ms: 900, h2: only, max_h2_streams: 0 } }] }",
+        );
+        let peer = |u: &str| r.build_peer_for("t", u).unwrap();
+
+        // 只有 tenant 设置
+        let o = peer("127.0.0.1:8001").options;
+        assert_eq!(o.connection_timeout, Some(Duration::from_millis(500)));
+        assert_eq!(o.read_timeout, Some(Duration::from_millis(3000)));
+        assert_eq!((o.write_timeout, o.total_connection_timeout), (None, None));
+        assert_eq!(o.idle_timeout, Some(Duration::from_secs(30)));
+        assert!(matches!(o.alpn, ALPN::H2H1));
+
+        // 源站逐项覆盖，没写的沿用 tenant；keepalive: false 覆盖 idle_timeout_secs
+        let o = peer("127.0.0.1:8002").options;
+        assert_eq!(o.connection_timeout, Some(Duration::from_millis(500)));
+        assert_eq!(o.read_timeout, Some(Duration::from_millis(100)));
+        assert_eq!(o.write_timeout, Some(Duration::from_millis(200)));
+        assert_eq!(o.idle_timeout, Some(Duration::ZERO));
+        assert!(matches!(o.alpn, ALPN::H1));
+
+        let o = peer("127.0.0.1:8003").options;
+        assert_eq!(o.total_connection_timeout, Some(Duration::from_millis(900)));
+        assert!(matches!(o.alpn, ALPN::H2));
+        assert_eq!(o.max_h2_streams, 1);
+
+        // 都没配置，或源站已不在配置里：pingora 默认值
+        let defaults = HttpPeer::new("127.0.0.1:8001", false, String::new()).options;
+        for o in [router("{ upstreams: [127.0.0.1:8001] }").build_peer_for("t", "127.0.0.1:8001").unwrap().options, peer("127.0.0.1:8009").options] {
+            assert_eq!(o.connection_timeout, defaults.connection_timeout);
+            assert_eq!(o.read_timeout, defaults.read_timeout);
+            assert_eq!(o.idle_timeout, defaults.idle_timeout);
+            assert!(matches!(o.alpn, ALPN::H1));
+            assert_eq!(o.max_h2_streams, defaults.max_h2_streams);
+        }
+    }
+
+    #[test]
+    fn max_idle_conns_applies_per_tenant_group() {
+        let yaml = "{ version: 1, resolver: { mode: static, host_to_cname: {} }, \
+             cname_routing: { tenant_from_cname_regex: '^(.+)$' }, \
+             tenants: { \
+               t: { connection: { idle_timeout_secs: 30, max_idle_conns: 1 }, \
+                    upstreams: [127.0.0.1:8001, { url: 127.0.0.1:8002, connection: { max_idle_conns: 2 } }] }, \
+               u: { upstreams: [127.0.0.1:8001] } }, \
+             default: { upstreams: [d] } }";
+        let r = UpstreamRouter::new(serde_yaml::from_str(yaml).unwrap(), HealthTable::default()).unwrap();
+        let peer = |t: &str, u: &str| r.build_peer_for(t, u).unwrap();
+        let inflight = |u: usize, n: usize| -> Vec<_> { (0..n).map(|_| InflightGuard::new(r.pool("t").health[u].clone())).collect() };
+
+        // 源站 b 的上限 2 覆盖 tenant 的 1
+        let _a = inflight(0, 1);
+        let _b = inflight(1, 2);
+        assert_eq!(peer("t", "127.0.0.1:8001").options.idle_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(peer("t", "127.0.0.1:8002").options.idle_timeout, Some(Duration::from_secs(30)));
+        let _a2 = inflight(0, 1);
+        let _b2 = inflight(1, 1);
+        assert_eq!(peer("t", "127.0.0.1:8001").options.idle_timeout, Some(Duration::ZERO));
+        assert_eq!(peer("t", "127.0.0.1:8002").options.idle_timeout, Some(Duration::ZERO));
+
+        // 设了上限的 tenant 单独一组，到同一源站的其他 tenant 不受影响
+        let (ta, tb, ua) = (peer("t", "127.0.0.1:8001"), peer("t", "127.0.0.1:8002"), peer("u", "127.0.0.1:8001"));
+        assert_eq!(ta.group_key, tb.group_key);
+        assert_ne!(ta.group_key, 0);
+        assert_eq!(ua.group_key, 0);
+        assert_eq!(ua.options.idle_timeout, None);
+        assert_ne!(ta.reuse_hash(), ua.reuse_hash());
+    }
+
+    #[test]
+    fn idle_cap_closes_connections_past_the_limit() {
+        let st = Arc::new(EndpointState::default());
+        let _a = InflightGuard::new(st.clone());
+        let mut peer = HttpPeer::new("127.0.0.1:80", false, String::new());
+        limit_idle(&mut peer, "a", &st, 1);
+        assert_eq!(peer.options.idle_timeout, None);
+
+        let _b = InflightGuard::new(st.clone());
+        let mut over = HttpPeer::new("127.0.0.1:80", false, String::new());
+        limit_idle(&mut over, "a", &st, 1);
+        assert_eq!(over.options.idle_timeout, Some(Duration::ZERO));
+        // 同一 tenant 共用一组空闲连接，不同 tenant 分开
+        assert_eq!(peer.reuse_hash(), over.reuse_hash());
+        let mut other = HttpPeer::new("127.0.0.1:80", false, String::new());
+        limit_idle(&mut other, "b", &st, 1);
+        assert_ne!(peer.reuse_hash(), other.reuse_hash());
+    }
 }
@@ -34,5 +34,289 @@ pub struct CnameRouting {
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct TenantUpstreams {
-    pub upstreams: Vec<String>,
+    pub upstreams: Vec<UpstreamEntry>,
+
+    /// 负载均衡方式，默认 round_robin
+    #[serde(default)]
+    pub lb: LbKind,
+
+    /// consistent_hash 的 key，写法同 CC key_parts：client_ip / path / header:<name> / cookie:<name> 等，默认 client_ip
+    pub hash_key: Option<String>,
+
+    /// 主动健康检查；不配置则所有源站一直视为健康
+    #[serde(default)]
+    pub health_check: Option<HealthCheckSpec>,
+
+    /// 被动健康检查：按真实请求的错误率临时摘除源站
+    #[serde(default)]
+    pub outlier_detection: Option<OutlierDetectionSpec>,
+
+    /// 失败重试；不配置则不重试
+    #[serde(default)]
+    pub retry: Option<RetrySpec>,
+
+    /// 到源站的超时、连接复用和 HTTP/2 设置；源站上的 connection 逐项覆盖这里
+    #[serde(default)]
+    pub connection: ConnectionSpec,
+}
+
+/// 不配置的项用 pingora 默认值
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ConnectionSpec {
+    /// TCP 建连超时
+    pub connect_timeout_ms: Option<u64>,
+    /// 建连加 TLS 握手的总超时
+    pub total_connect_timeout_ms: Option<u64>,
+    /// 单次读等待上限（包括等响应头）
+    pub read_timeout_ms: Option<u64>,
+    /// 单次写等待上限
+    pub write_timeout_ms: Option<u64>,
+    /// 空闲连接在连接池里保留多久，默认一直保留到对端关闭或被池淘汰
+    pub idle_timeout_secs: Option<u64>,
+    /// false：请求结束即关闭连接，不进连接池
+    pub keepalive: Option<bool>,
+    /// 本 tenant 到单个源站最多保留的空闲连接数，默认只受全局连接池大小限制
+    pub max_idle_conns: Option<u32>,
+    /// 到源站的 HTTP/2，默认 off
+    pub h2: Option<H2Mode>,
+    /// 单个 h2 连接上的并发流数，默认 1
+    pub max_h2_streams: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum H2Mode {
+    /// 只用 HTTP/1.1
+    Off,
+    /// TLS 源站 ALPN 协商 h2，不支持时回落 1.1；明文源站仍用 1.1
+    Prefer,
+    /// 只用 h2，明文源站按 h2c（prior knowledge）
+    Only,
+}
+
+impl ConnectionSpec {
+    /// 逐项合并：self 没配的用 base 的
+    pub fn or(&self, base: &ConnectionSpec) -> ConnectionSpec {
+        ConnectionSpec {
+            connect_timeout_ms: self.connect_timeout_ms.or(base.connect_timeout_ms),
+            total_connect_timeout_ms: self.total_connect_timeout_ms.or(base.total_connect_timeout_ms),
+            read_timeout_ms: self.read_timeout_ms.or(base.read_timeout_ms),
+            write_timeout_ms: self.write_timeout_ms.or(base.write_timeout_ms),
+            idle_timeout_secs: self.idle_timeout_secs.or(base.idle_timeout_secs),
+            keepalive: self.keepalive.or(base.keepalive),
+            max_idle_conns: self.max_idle_conns.or(base.max_idle_conns),
+            h2: self.h2.or(base.h2),
+            max_h2_streams: self.max_h2_streams.or(base.max_h2_streams),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RetrySpec {
+    /// 总尝试次数（含第一次），默认 2，最大 10
+    pub max_attempts: Option<u32>,
+    /// 哪些失败可以重试，默认只重试连接失败
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<RetryOn>,
+    /// 单次尝试的建连和读写超时
+    pub per_try_timeout_ms: Option<u64>,
+}
+
+fn default_retry_on() -> Vec<RetryOn> {
+    vec![RetryOn::ConnectError]
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// 建连失败（含建连超时），请求还没发出，任何方法都可重试
+    ConnectError,
+    /// 发出请求后读写超时，只重试幂等方法
+    Timeout,
+    /// 源站返回 502 / 503，只重试幂等方法
+    #[serde(rename = "http_502")]
+    Http502,
+    #[serde(rename = "http_503")]
+    Http503,
+}
+
+impl RetrySpec {
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts.unwrap_or(2).clamp(1, 10)
+    }
+
+    pub fn allows(&self, on: RetryOn) -> bool {
+        self.retry_on.contains(&on)
+    }
+
+    /// 已经尝试 attempts 次后能否因 on 再试一次：非幂等方法只在建连失败（请求还没发出）时重试
+    pub fn permits(&self, on: RetryOn, attempts: u32, idempotent: bool) -> bool {
+        self.allows(on) && attempts < self.max_attempts() && (idempotent || on == RetryOn::ConnectError)
+    }
+}
+
+/// 权重上限：一致性哈希每单位权重 100 个虚拟节点，不设上限时一个大权重就能撑爆环
+pub const MAX_WEIGHT: u32 = 1000;
+
+/// 源站：直接写地址，或 { url, weight, connection }
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum UpstreamEntry {
+    Url(String),
+    Detailed {
+        url: String,
+        weight: Option<u32>,
+        #[serde(default)]
+        connection: ConnectionSpec,
+    },
+}
+
+impl UpstreamEntry {
+    pub fn url(&self) -> &str {
+        match self {
+            UpstreamEntry::Url(u) => u,
+            UpstreamEntry::Detailed { url, .. } => url,
+        }
+    }
+
+    /// 默认 1；0 表示不分配流量（所有源站都不可用时除外）；最大 MAX_WEIGHT
+    pub fn weight(&self) -> u32 {
+        match self {
+            UpstreamEntry::Url(_) => 1,
+            UpstreamEntry::Detailed { weight, .. } => weight.unwrap_or(1),
+        }
+    }
+
+    /// 源站自己的连接设置，没配的项用 tenant 的
+    pub fn connection(&self, tenant: &ConnectionSpec) -> ConnectionSpec {
+        match self {
+            UpstreamEntry::Url(_) => tenant.clone(),
+            UpstreamEntry::Detailed { connection, .. } => connection.or(tenant),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LbKind {
+    /// 依次轮询，不看权重大小
+    #[default]
+    RoundRobin,
+    /// 按权重比例轮询
+    Weighted,
+    /// 在途请求数 / 权重最小
+    LeastConn,
+    /// 随机取两个，选在途请求数 / 权重较小的
+    RandomTwoChoices,
+    /// 按 hash_key 一致性哈希，源站增减时只影响相邻区间
+    ConsistentHash,
+}
+
+impl TenantUpstreams {
+    pub fn hash_key(&self) -> &str {
+        self.hash_key.as_deref().unwrap_or("client_ip")
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthCheckSpec {
+    /// tcp：只建连；http：GET path 并检查状态码
+    #[serde(rename = "type", default)]
+    pub kind: HealthCheckType,
+    /// 默认 /
+    pub path: Option<String>,
+    /// http 检查的 Host 头，默认取源站地址
+    pub host: Option<String>,
+    /// 期望的状态码，默认 200-399 都算健康
+    #[serde(default)]
+    pub expected_status: Vec<u16>,
+    /// 默认 5
+    pub interval_secs: Option<u64>,
+    /// 默认 1000
+    pub timeout_ms: Option<u64>,
+    /// 连续成功多少次恢复为健康，默认 2
+    pub rise: Option<u32>,
+    /// 连续失败多少次判为不健康，默认 3
+    pub fall: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OutlierDetectionSpec {
+    /// 统计窗口，默认 10
+    pub interval_secs: Option<u64>,
+    /// 窗口内请求数不足时不判断，默认 10
+    pub min_requests: Option<u32>,
+    /// 错误率（连接失败、超时、5xx）达到多少百分比摘除，默认 50
+    pub error_rate_percent: Option<u32>,
+    /// 首次摘除时长，之后每次翻倍，默认 30
+    pub base_ejection_secs: Option<u64>,
+    /// 摘除时长上限，默认 300
+    pub max_ejection_secs: Option<u64>,
+    /// 同一 tenant 最多同时摘除多少百分比的源站，默认 50
+    pub max_ejected_percent: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheckType {
+    #[default]
+    Tcp,
+    Http,
+}
+
+impl HealthCheckSpec {
+    pub fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or("/")
+    }
+
+    pub fn interval_secs(&self) -> u64 {
+        self.interval_secs.unwrap_or(5).max(1)
+    }
+
+    pub fn timeout_ms(&self) -> u64 {
+        self.timeout_ms.unwrap_or(1000).max(1)
+    }
+
+    pub fn rise(&self) -> u32 {
+        self.rise.unwrap_or(2).max(1)
+    }
+
+    pub fn fall(&self) -> u32 {
+        self.fall.unwrap_or(3).max(1)
+    }
+
+    /// 未配置 expected_status 时 2xx / 3xx 都算通过
+    pub fn status_ok(&self, status: u16) -> bool {
+        if self.expected_status.is_empty() {
+            (200..400).contains(&status)
+        } else {
+            self.expected_status.contains(&status)
+        }
+    }
+}
+
+impl OutlierDetectionSpec {
+    pub fn interval_secs(&self) -> u64 {
+        self.interval_secs.unwrap_or(10).max(1)
+    }
+
+    pub fn min_requests(&self) -> u32 {
+        self.min_requests.unwrap_or(10).max(1)
+    }
+
+    pub fn error_rate_percent(&self) -> u32 {
+        self.error_rate_percent.unwrap_or(50).clamp(1, 100)
+    }
+
+    pub fn base_ejection_secs(&self) -> u64 {
+        self.base_ejection_secs.unwrap_or(30).max(1)
+    }
+
+    pub fn max_ejection_secs(&self) -> u64 {
+        self.max_ejection_secs.unwrap_or(300).max(self.base_ejection_secs())
+    }
+
+    pub fn max_ejected_percent(&self) -> u32 {
+        self.max_ejected_percent.unwrap_or(50).min(100)
+    }
 }
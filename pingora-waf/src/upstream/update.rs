@@ -61,7 +61,7 @@ impl BackgroundService for UpstreamUpdater {
                         Ok(bytes) => {
                             match serde_yaml::from_slice::<UpstreamConfigFile>(&bytes)
                                 .map_err(|e| anyhow::anyhow!("parse upstream.yaml failed: {e}"))
-                                .and_then(|cfg| UpstreamRouter::new(cfg))
+                                .and_then(|cfg| UpstreamRouter::new(cfg, self.mgr.get().health().clone()))
                             {
                                 Ok(router) => {
                                     self.mgr.swap(router);
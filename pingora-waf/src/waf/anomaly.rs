@@ -0,0 +1,65 @@
+use serde::Serialize;
+
+use super::decision::Decision;
+
+/// Rule ID reported when the accumulated score crosses the inbound threshold.
+pub const ANOMALY_RULE_ID: &str = "anomaly_score";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleScore {
+    pub rule_id: String,
+    pub score: u32,
+    /// Detector fingerprint, when the rule matched through one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Per-request anomaly score, accumulated across the header and body phases.
+#[derive(Debug, Clone)]
+pub struct AnomalyScore {
+    pub threshold: u32,
+    pub total: u32,
+    pub hits: Vec<RuleScore>,
+}
+
+impl AnomalyScore {
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            total: 0,
+            hits: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, rule_id: &str, score: u32, detail: Option<String>) {
+        self.total = self.total.saturating_add(score);
+        self.hits.push(RuleScore {
+            rule_id: rule_id.to_string(),
+            score,
+            detail,
+        });
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.total > 0 && self.total >= self.threshold
+    }
+
+    pub fn reason(&self) -> String {
+        let mut r = format!("anomaly score {} (threshold {})", self.total, self.threshold);
+        for h in &self.hits {
+            if let Some(d) = &h.detail {
+                r.push_str(&format!("; {}: {}", h.rule_id, d));
+            }
+        }
+        r
+    }
+
+    /// Block once the threshold is reached, otherwise let the request through.
+    pub fn decision(&self) -> Decision {
+        if self.exceeded() {
+            Decision::block(ANOMALY_RULE_ID, self.reason())
+        } else {
+            Decision::Allow
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use super::context::parse_args;
+
+/// Decoded request body, flattened into (name or path, value) pairs.
+#[derive(Debug, Clone, Default)]
+pub struct BodyArgs {
+    /// Form fields by name; JSON scalars by path (`user.name`, `items[0].id`).
+    pub args: Vec<(String, String)>,
+    /// Multipart upload filenames (file contents are not inspected).
+    pub filenames: Vec<String>,
+}
+
+/// Body parser picked from the request Content-Type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BodyKind {
+    Urlencoded,
+    Json,
+    Multipart { boundary: String },
+    /// Anything else: no structured arguments.
+    Other,
+}
+
+impl BodyKind {
+    pub fn from_content_type(ct: Option<&str>) -> Self {
+        let Some(ct) = ct else {
+            return BodyKind::Other;
+        };
+        let mut parts = ct.split(';');
+        let mime = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        match mime.as_str() {
+            "application/x-www-form-urlencoded" => BodyKind::Urlencoded,
+            "application/json" => BodyKind::Json,
+            m if m.starts_with("application/") && m.ends_with("+json") => BodyKind::Json,
+            "multipart/form-data" => {
+                let boundary = parts
+                    .filter_map(|p| p.split_once('='))
+                    .find(|(k, _)| k.trim().eq_ignore_ascii_case("boundary"))
+                    .map(|(_, v)| v.trim().trim_matches('"').to_string())
+                    .unwrap_or_default();
+                BodyKind::Multipart { boundary }
+            }
+            _ => BodyKind::Other,
+        }
+    }
+
+    /// An empty body has no arguments whatever the Content-Type (e.g. a GET sending `application/json`).
+    pub fn parse(&self, body: &[u8]) -> Result<BodyArgs> {
+        if body.is_empty() {
+            return Ok(BodyArgs::default());
+        }
+        match self {
+            BodyKind::Urlencoded => {
+                let s = std::str::from_utf8(body).context("urlencoded body is not utf-8")?;
+                Ok(BodyArgs {
+                    args: parse_args(s),
+                    filenames: Vec::new(),
+                })
+            }
+            BodyKind::Json => {
+                let v: Value = serde_json::from_slice(body).context("invalid json body")?;
+                let mut args = Vec::new();
+                flatten_json(&v, String::new(), &mut args);
+                Ok(BodyArgs {
+                    args,
+                    filenames: Vec::new(),
+                })
+            }
+            BodyKind::Multipart { boundary } => parse_multipart(body, boundary),
+            BodyKind::Other => Ok(BodyArgs::default()),
+        }
+    }
+}
+
+fn flatten_json(v: &Value, path: String, out: &mut Vec<(String, String)>) {
+    match v {
+        Value::Object(m) => {
+            for (k, child) in m {
+                let p = if path.is_empty() { k.clone() } else { format!("{path}.{k}") };
+                flatten_json(child, p, out);
+            }
+        }
+        Value::Array(a) => {
+            for (i, child) in a.iter().enumerate() {
+                flatten_json(child, format!("{path}[{i}]"), out);
+            }
+        }
+        Value::String(s) => out.push((path, s.clone())),
+        Value::Null => out.push((path, String::new())),
+        other => out.push((path, other.to_string())),
+    }
+}
+
+fn find(hay: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    hay.get(from..)?
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|i| i + from)
+}
+
+fn parse_multipart(body: &[u8], boundary: &str) -> Result<BodyArgs> {
+    if boundary.is_empty() {
+        bail!("multipart body without boundary");
+    }
+    let delim = format!("--{boundary}");
+    let delim = delim.as_bytes();
+    let mut out = BodyArgs::default();
+
+    let mut pos = find(body, delim, 0).context("multipart boundary not found")? + delim.len();
+    loop {
+        // "--" right after a delimiter closes the body
+        if body[pos..].starts_with(b"--") {
+            return Ok(out);
+        }
+        let start = find(body, b"\r\n", pos).context("truncated multipart part")? + 2;
+        let next = find(body, delim, start).context("multipart closing boundary not found")?;
+        // the CRLF before the next delimiter belongs to it
+        let part = &body[start..next.saturating_sub(2).max(start)];
+        parse_part(part, &mut out)?;
+        pos = next + delim.len();
+    }
+}
+
+fn parse_part(part: &[u8], out: &mut BodyArgs) -> Result<()> {
+    // a part without headers starts with the blank line itself
+    let (head, content) = match part.strip_prefix(b"\r\n") {
+        Some(content) => (Default::default(), content),
+        None => {
+            let split = find(part, b"\r\n\r\n", 0).context("multipart part without header terminator")?;
+            (String::from_utf8_lossy(&part[..split]), &part[split + 4..])
+        }
+    };
+
+    let mut name = None;
+    // `filename` and `filename*` may both be present and disagree; keep both
+    let mut filenames = Vec::new();
+    for line in head.split("\r\n") {
+        let Some((k, v)) = line.split_once(':') else {
+            continue;
+        };
+        if !k.trim().eq_ignore_ascii_case("content-disposition") {
+            continue;
+        }
+        for p in split_params(v).into_iter().skip(1) {
+            let Some((pk, pv)) = p.split_once('=') else {
+                continue;
+            };
+            let pv = pv.trim().trim_matches('"').to_string();
+            match pk.trim().to_ascii_lowercase().as_str() {
+                "name" => name = Some(pv),
+                "filename" => filenames.push(pv),
+                "filename*" => filenames.push(ext_value(&pv)),
+                _ => {}
+            }
+        }
+    }
+
+    let name = name.context("multipart part without name")?;
+    if filenames.is_empty() {
+        out.args.push((name, String::from_utf8_lossy(content).into_owned()));
+    } else {
+        out.filenames.extend(filenames);
+    }
+    Ok(())
+}
+
+/// Split header parameters on `;` outside double quotes (`filename="a;b.php"` is one parameter).
+fn split_params(v: &str) -> Vec<&str> {
+    let mut out = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in v.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                out.push(&v[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    out.push(&v[start..]);
+    out
+}
+
+/// RFC 8187 `charset'lang'percent-encoded` value; decoded bytes that aren't UTF-8 become U+FFFD.
+fn ext_value(v: &str) -> String {
+    let encoded = v.splitn(3, '\'').nth(2).unwrap_or(v);
+    let b = encoded.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        let hex = b
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (b[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (c, _) => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CT: &str = "multipart/form-data; boundary=XyZ";
+
+    fn multipart(body: &str) -> Result<BodyArgs> {
+        BodyKind::from_content_type(Some(CT)).parse(body.as_bytes())
+    }
+
+    fn pairs(args: &[(String, String)]) -> Vec<(&str, &str)> {
+        args.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect()
+    }
+
+    #[test]
+    fn content_types_and_boundaries() {
+        assert_eq!(BodyKind::from_content_type(Some("application/problem+json")), BodyKind::Json);
+        assert_eq!(BodyKind::from_content_type(Some("text/plain")), BodyKind::Other);
+        assert_eq!(BodyKind::from_content_type(None), BodyKind::Other);
+        let quoted = BodyKind::from_content_type(Some("Multipart/Form-Data; charset=utf-8; Boundary=\"--a b\""));
+        assert_eq!(quoted, BodyKind::Multipart { boundary: "--a b".to_string() });
+
+        let missing = BodyKind::from_content_type(Some("multipart/form-data"));
+        assert_eq!(missing, BodyKind::Multipart { boundary: String::new() });
+        assert!(missing.parse(b"--\r\n").is_err());
+    }
+
+    #[test]
+    fn empty_body_has_no_args_for_any_type() {
+        for ct in ["application/json", "application/x-www-form-urlencoded", CT, "multipart/form-data"] {
+            let args = BodyKind::from_content_type(Some(ct)).parse(b"").unwrap();
+            assert!(args.args.is_empty() && args.filenames.is_empty(), "{ct}");
+        }
+    }
+
+    #[test]
+    fn urlencoded_and_json_paths() {
+        let form = BodyKind::Urlencoded.parse(b"a=1&b=%3Cx%3E+y&c").unwrap();
+        assert_eq!(pairs(&form.args), [("a", "1"), ("b", "<x> y"), ("c", "")]);
+
+        let json = br#"{"user":{"name":"bob"},"items":[{"id":7},"x",null,true]}"#;
+        let json = BodyKind::Json.parse(json).unwrap();
+        assert_eq!(
+            pairs(&json.args),
+            [("items[0].id", "7"), ("items[1]", "x"), ("items[2]", ""), ("items[3]", "true"), ("user.name", "bob")]
+        );
+        assert!(BodyKind::Json.parse(b"{\"a\":").is_err());
+    }
+
+    #[test]
+    fn multipart_fields_and_files() {
+        let body = "preamble\r\n--XyZ\r\n\
+                    Content-Disposition: form-data; name=\"q\"\r\n\r\n\
+                    a\r\nb\r\n\
+                    --XyZ\r\n\
+                    content-disposition: form-data; name=\"f\"; filename=\"x;y.php\"\r\n\
+                    Content-Type: text/plain\r\n\r\n\
+                    <?php ?>\r\n\
+                    --XyZ--\r\n";
+        let out = multipart(body).unwrap();
+        assert_eq!(pairs(&out.args), [("q", "a\r\nb")]);
+        assert_eq!(out.filenames, ["x;y.php"]);
+    }
+
+    #[test]
+    fn multipart_extended_filename() {
+        let body = "--XyZ\r\n\
+                    Content-Disposition: form-data; name=\"f\"; filename*=UTF-8''shell%2Ephp\r\n\r\n\
+                    data\r\n\
+                    --XyZ\r\n\
+                    Content-Disposition: form-data; name=\"g\"; filename=\"a.txt\"; filename*=utf-8'en'b%20c.jsp\r\n\r\n\
+                    data\r\n\
+                    --XyZ--";
+        let out = multipart(body).unwrap();
+        // file contents never show up as arguments
+        assert!(out.args.is_empty());
+        assert_eq!(out.filenames, ["shell.php", "a.txt", "b c.jsp"]);
+    }
+
+    #[test]
+    fn malformed_multipart_is_an_error() {
+        // truncated final part: no closing delimiter
+        assert!(multipart("--XyZ\r\nContent-Disposition: form-data; name=\"q\"\r\n\r\nabc").is_err());
+        // delimiter at the very end of the body
+        assert!(multipart("--XyZ").is_err());
+        // boundary never appears
+        assert!(multipart("--other\r\n\r\nx\r\n--other--").is_err());
+        // header block never terminated
+        assert!(multipart("--XyZ\r\nContent-Disposition: form-data; name=\"q\"\r\n--XyZ--").is_err());
+        // a part without headers has no name
+        assert!(multipart("--XyZ\r\n\r\nvalue\r\n--XyZ--").is_err());
+        // an empty multipart body is fine
+        assert!(multipart("--XyZ--").unwrap().args.is_empty());
+    }
+}
@@ -1,45 +1,94 @@
 use crate::waf::normalizer::Normalizer;
 
-#[derive(Debug, Clone)]
+/// Read-only view over request headers, shared by the WAF engine and policy matchers.
+pub trait HeaderView {
+    /// First value of the header.
+    fn get(&self, name: &str) -> Option<&str>;
+
+    /// Every value of a repeated header, in request order.
+    fn get_all(&self, name: &str) -> Vec<&str> {
+        self.get(name).into_iter().collect()
+    }
+}
+
+impl HeaderView for http::HeaderMap {
+    fn get(&self, name: &str) -> Option<&str> {
+        http::HeaderMap::get(self, name).and_then(|v| v.to_str().ok())
+    }
+
+    fn get_all(&self, name: &str) -> Vec<&str> {
+        http::HeaderMap::get_all(self, name).iter().filter_map(|v| v.to_str().ok()).collect()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct WafContext {
     pub method: String,
     pub path: String,
+    /// Raw (still percent-encoded) query string, without the leading `?`.
+    pub query: Option<String>,
+    /// Decoded query arguments in request order; repeated names are kept.
+    pub args: Vec<(String, String)>,
+    /// Real client: the TCP peer, or taken from forwarding headers when the peer is a trusted proxy.
     pub client_ip: Option<std::net::IpAddr>,
+    /// The TCP peer as seen by this process.
+    pub peer_ip: Option<std::net::IpAddr>,
+    /// ISO country code of `client_ip`, when a GeoIP country database is loaded.
+    pub country: Option<String>,
+    /// Autonomous system number of `client_ip`, when a GeoIP ASN database is loaded.
+    pub asn: Option<u32>,
     pub host: Option<String>,
     pub user_agent: Option<String>,
-
+    /// Picks the body parser for structured body rules.
+    pub content_type: Option<String>,
 }
 
 impl WafContext {
-    pub async fn from_session(session: &mut pingora_proxy::Session) -> pingora::Result<Self> {
-        let (method, path, host, user_agent) = {
+    /// `peer_ip` / `client_ip` are resolved by the caller (PROXY protocol, trusted forwarding headers).
+    pub async fn from_session(
+        session: &mut pingora_proxy::Session,
+        peer_ip: Option<std::net::IpAddr>,
+        client_ip: Option<std::net::IpAddr>,
+    ) -> pingora::Result<Self> {
+        let (method, path, query, host, user_agent, content_type) = {
             let req: &pingora::http::RequestHeader = session.req_header();
 
             let raw_path = req.uri.path().to_string();
             let path = Normalizer::normalize_path(&raw_path);
+            let query = req.uri.query().map(|q| q.to_string());
 
             // Host fix (HTTP/2 uses :authority). Pingora may expose it as "authority".
             let host = extract_host(req);
             let user_agent = req.headers.get("user-agent").and_then(|v| v.to_str().ok()).map(|s| s.to_string());
+            let content_type = req.headers.get("content-type").and_then(|v| v.to_str().ok()).map(|s| s.to_string());
 
-            (req.method.to_string(), path, host, user_agent)
+            (req.method.to_string(), path, query, host, user_agent, content_type)
         };
-
-        let client_ip = session
-            .client_addr()
-            .and_then(|a| a.to_string().parse::<std::net::SocketAddr>().ok())
-            .map(|sa| sa.ip());
+        let args = query.as_deref().map(parse_args).unwrap_or_default();
 
         Ok(Self {
             method,
             path,
+            query,
+            args,
             client_ip,
+            peer_ip,
+            country: None,
+            asn: None,
             host,
             user_agent,
+            content_type,
         })
     }
 }
 
+/// Decode `a=1&b=%20x+y` into (name, value) pairs (`+` is a space, invalid UTF-8 is replaced).
+pub fn parse_args(query: &str) -> Vec<(String, String)> {
+    form_urlencoded::parse(query.as_bytes())
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect()
+}
+
 fn extract_host(req: &pingora::http::RequestHeader) -> Option<String> {
     // 1) "host" header (HTTP/1.1)
     let mut host = req
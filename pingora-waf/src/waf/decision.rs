@@ -19,9 +19,21 @@ pub enum Decision {
         status: u16,
         reason: String,
         rule_id: String,
+        kind: ChallengeKind,
     },
 }
 
+/// What the client has to do to pass a challenge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChallengeKind {
+    /// Run the page's script once.
+    #[default]
+    Js,
+    /// Find a counter whose SHA-256 with the nonce starts with `difficulty` zero bits.
+    /// `max_difficulty` caps escalation for clients the CC limiter has flagged.
+    Pow { difficulty: u32, max_difficulty: u32 },
+}
+
 impl Decision {
     pub fn block(rule_id: impl Into<String>, reason: impl Into<String>) -> Self {
         Self::Block {
@@ -72,6 +84,7 @@ impl Decision {
             status: 403,
             reason: reason.into(),
             rule_id: rule_id.into(),
+            kind: ChallengeKind::Js,
         }
     }
 
@@ -0,0 +1,8 @@
+//! Native attack detectors usable as rule conditions (`detect_sqli`, `detect_xss`).
+//! Each returns a short fingerprint describing what matched, for the security event.
+
+pub mod sqli;
+pub mod xss;
+
+pub use sqli::detect_sqli;
+pub use xss::detect_xss;
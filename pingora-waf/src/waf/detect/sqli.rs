@@ -0,0 +1,306 @@
+//! SQL injection detection in the spirit of libinjection: tokenize the input the way a SQL
+//! parser would, fold it into a short fingerprint of token classes, and compare the
+//! fingerprint against known attack shapes.
+//!
+//! Token classes:
+//! `s` string, `1` number / TRUE / NULL, `n` bareword, `k` keyword, `E` statement keyword
+//! (SELECT, DROP, ...), `U` UNION, `f` known function, `&` AND / OR / XOR, `=` comparison
+//! (`=`, `<>`, `>=`, ...), `o` other operator, `w` operator word (LIKE, IN, IS, ...),
+//! `c` trailing comment, and the punctuation `(` `)` `,` `;`.
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/// Tokens folded into a fingerprint; attacks show their shape well within this.
+const MAX_TOKENS: usize = 8;
+
+/// Known attack shapes, matched against the fingerprint.
+static BAD_FINGERPRINTS: Lazy<Vec<Regex>> = Lazy::new(|| {
+    [
+        // UNION [ALL] SELECT
+        r"U\(*E",
+        // ' or 1=1 / ' or 'a' like 'a
+        r"^s\)*&\(*[s1n]\)*[=ow]\(*[s1n]",
+        // 1 or 1=1 / 1) or (2>1: without a quote only a comparison makes it SQL,
+        // so prose like "3 or 4 in stock" or "5 and more is better" does not match
+        r"^1\)*&\(*[s1n]\)*=\(*[s1n]",
+        // ' or 1-- / ' or 'x
+        r"^s\)*&\(*[s1]\)*(c|$)",
+        // 1 and sleep(5) / ' or benchmark(...)
+        r"^[s1]\)*&\(*f\(",
+        // 1; drop table x
+        r";\(*E",
+        // admin'--
+        r"^s\)*c$",
+    ]
+    .iter()
+    .map(|p| Regex::new(p).expect("sqli fingerprint pattern"))
+    .collect()
+});
+
+const STATEMENTS: &[&str] = &[
+    "select", "insert", "update", "delete", "drop", "create", "alter", "truncate", "exec", "execute",
+    "declare", "shutdown", "waitfor", "grant", "revoke", "rename", "replace", "merge", "call",
+];
+
+const KEYWORDS: &[&str] = &[
+    "from", "where", "into", "values", "table", "order", "group", "by", "having", "limit", "offset",
+    "as", "set", "join", "case", "when", "then", "else", "end", "all", "distinct", "top", "procedure",
+    "outfile", "dumpfile", "delay", "database", "schema",
+];
+
+const OPERATOR_WORDS: &[&str] = &[
+    "like", "rlike", "regexp", "is", "in", "between", "div", "mod", "not", "sounds", "collate", "escape",
+];
+
+const FUNCTIONS: &[&str] = &[
+    "sleep", "benchmark", "pg_sleep", "char", "chr", "concat", "concat_ws", "group_concat", "version",
+    "user", "current_user", "system_user", "substring", "substr", "mid", "ascii", "ord", "hex", "unhex",
+    "extractvalue", "updatexml", "load_file", "count", "length", "if", "ifnull", "coalesce", "cast",
+    "convert", "md5", "sha1", "randomblob", "dbms_pipe.receive_message", "utl_inaddr.get_host_name",
+];
+
+/// Detect SQL injection in `input`. Returns the fingerprint that matched.
+///
+/// Like libinjection, the input is also tried as if it continued a quoted string, which is
+/// where most injections start (`' or '1'='1`).
+pub fn detect_sqli(input: &str) -> Option<String> {
+    let contexts: [Option<u8>; 3] = [None, Some(b'\''), Some(b'"')];
+    for ctx in contexts {
+        if let Some(q) = ctx {
+            if !input.as_bytes().contains(&q) {
+                continue;
+            }
+        }
+        let fp = fingerprint(input, ctx);
+        if BAD_FINGERPRINTS.iter().any(|re| re.is_match(&fp)) {
+            return Some(fp);
+        }
+    }
+    None
+}
+
+fn fingerprint(input: &str, quote_ctx: Option<u8>) -> String {
+    let mut lx = Lexer {
+        b: input.as_bytes(),
+        i: 0,
+        in_exec_comment: false,
+    };
+    let mut tokens: Vec<u8> = Vec::with_capacity(MAX_TOKENS);
+
+    if let Some(q) = quote_ctx {
+        // the value starts inside a string opened by the surrounding query
+        lx.skip_string_body(q);
+        tokens.push(b's');
+    }
+
+    while tokens.len() < MAX_TOKENS {
+        let Some(t) = lx.next() else {
+            break;
+        };
+        // a comment only counts at the end; in the middle it's whitespace (union/**/select)
+        if tokens.last() == Some(&b'c') {
+            tokens.pop();
+        }
+        // UNION ALL / UNION DISTINCT fold into U
+        if tokens.last() == Some(&b'U') && t == b'k' {
+            continue;
+        }
+        tokens.push(t);
+    }
+    String::from_utf8(tokens).unwrap_or_default()
+}
+
+struct Lexer<'a> {
+    b: &'a [u8],
+    i: usize,
+    /// Inside MySQL `/*! ... */`, whose contents are executed.
+    in_exec_comment: bool,
+}
+
+impl Lexer<'_> {
+    fn peek(&self, off: usize) -> Option<u8> {
+        self.b.get(self.i + off).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        loop {
+            let c = self.peek(0)?;
+            match c {
+                b' ' | b'\t' | b'\n' | b'\r' | 0x0b | 0x0c | 0xa0 | 0 => self.i += 1,
+                b'*' if self.in_exec_comment && self.peek(1) == Some(b'/') => {
+                    self.in_exec_comment = false;
+                    self.i += 2;
+                }
+                b'/' if self.peek(1) == Some(b'*') => {
+                    if self.peek(2) == Some(b'!') {
+                        // MySQL executable comment: skip the marker and optional version
+                        self.i += 3;
+                        while self.peek(0).is_some_and(|d| d.is_ascii_digit()) {
+                            self.i += 1;
+                        }
+                        self.in_exec_comment = true;
+                        continue;
+                    }
+                    match find(self.b, b"*/", self.i + 2) {
+                        Some(end) => self.i = end + 2,
+                        None => self.i = self.b.len(),
+                    }
+                    return Some(b'c');
+                }
+                b'-' if self.peek(1) == Some(b'-') => {
+                    self.skip_line();
+                    return Some(b'c');
+                }
+                b'#' => {
+                    self.skip_line();
+                    return Some(b'c');
+                }
+                b'\'' | b'"' => {
+                    self.i += 1;
+                    self.skip_string_body(c);
+                    return Some(b's');
+                }
+                b'`' => {
+                    self.i += 1;
+                    self.skip_string_body(b'`');
+                    return Some(b'n');
+                }
+                b'(' | b')' | b',' | b';' => {
+                    self.i += 1;
+                    return Some(c);
+                }
+                b'0'..=b'9' => {
+                    self.skip_number();
+                    return Some(b'1');
+                }
+                b'.' if self.peek(1).is_some_and(|d| d.is_ascii_digit()) => {
+                    self.skip_number();
+                    return Some(b'1');
+                }
+                b'&' | b'|' if self.peek(1) == Some(c) => {
+                    self.i += 2;
+                    return Some(b'&');
+                }
+                b'=' | b'<' | b'>' | b'!' | b'+' | b'-' | b'*' | b'/' | b'%' | b'^' | b'|' | b'&' | b'~' => {
+                    // runs like `<=`, `!=` or `=-` are one operator
+                    let start = self.i;
+                    self.i += 1;
+                    while self.peek(0).is_some_and(|d| b"=<>!+-*/%^~".contains(&d)) && !self.at_comment() {
+                        self.i += 1;
+                    }
+                    let comparison = self.b[start..self.i].iter().any(|d| matches!(d, b'=' | b'<' | b'>'));
+                    return Some(if comparison { b'=' } else { b'o' });
+                }
+                c if c.is_ascii_alphabetic() || c == b'_' || c == b'@' || c == b'$' => {
+                    return Some(self.word());
+                }
+                _ => self.i += 1,
+            }
+        }
+    }
+
+    fn word(&mut self) -> u8 {
+        let start = self.i;
+        while self
+            .peek(0)
+            .is_some_and(|d| d.is_ascii_alphanumeric() || matches!(d, b'_' | b'@' | b'$' | b'.'))
+        {
+            self.i += 1;
+        }
+        let w = String::from_utf8_lossy(&self.b[start..self.i]).to_ascii_lowercase();
+        match w.as_str() {
+            "union" => b'U',
+            "and" | "or" | "xor" => b'&',
+            "true" | "false" | "null" => b'1',
+            w if STATEMENTS.contains(&w) => b'E',
+            w if KEYWORDS.contains(&w) => b'k',
+            w if OPERATOR_WORDS.contains(&w) => b'w',
+            w if FUNCTIONS.contains(&w) && self.next_non_space() == Some(b'(') => b'f',
+            _ => b'n',
+        }
+    }
+
+    fn at_comment(&self) -> bool {
+        matches!((self.peek(0), self.peek(1)), (Some(b'-'), Some(b'-')) | (Some(b'/'), Some(b'*')))
+    }
+
+    fn next_non_space(&self) -> Option<u8> {
+        self.b[self.i..].iter().copied().find(|c| !c.is_ascii_whitespace())
+    }
+
+    /// Consume up to and including the closing quote; `''` and backslash escapes stay inside.
+    fn skip_string_body(&mut self, q: u8) {
+        while let Some(c) = self.peek(0) {
+            self.i += 1;
+            if c == b'\\' {
+                self.i += 1;
+            } else if c == q {
+                if self.peek(0) == Some(q) {
+                    self.i += 1;
+                } else {
+                    return;
+                }
+            }
+        }
+        self.i = self.i.min(self.b.len());
+    }
+
+    fn skip_number(&mut self) {
+        if self.peek(0) == Some(b'0') && matches!(self.peek(1), Some(b'x' | b'X')) {
+            self.i += 2;
+            while self.peek(0).is_some_and(|d| d.is_ascii_hexdigit()) {
+                self.i += 1;
+            }
+            return;
+        }
+        while self
+            .peek(0)
+            .is_some_and(|d| d.is_ascii_digit() || d == b'.' || d == b'e' || d == b'E')
+        {
+            self.i += 1;
+        }
+    }
+
+    fn skip_line(&mut self) {
+        match self.b[self.i..].iter().position(|&c| c == b'\n') {
+            Some(p) => self.i += p + 1,
+            None => self.i = self.b.len(),
+        }
+    }
+}
+
+fn find(hay: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    hay.get(from..)?
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|p| p + from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::detect_sqli;
+
+    #[test]
+    fn detects_classic_injections() {
+        for s in [
+            "' or 1=1--",
+            "1 or 1=1",
+            "1) or (2>1",
+            "' or 'a' like 'a",
+            "1 union all select password from users",
+            "1 and sleep(5)",
+            "1; drop table users",
+            "admin'--",
+        ] {
+            assert!(detect_sqli(s).is_some(), "{s}");
+        }
+    }
+
+    #[test]
+    fn ignores_and_or_in_prose() {
+        for s in ["3 or 4 in stock", "5 and more is better", "2 or 3 - maybe more", "rock and roll"] {
+            assert_eq!(detect_sqli(s), None, "{s}");
+        }
+    }
+}
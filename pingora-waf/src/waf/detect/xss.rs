@@ -0,0 +1,213 @@
+//! XSS detection in the spirit of libinjection's HTML5 mode: walk the input as a browser
+//! would tokenize tags and attributes and flag the constructs that execute script.
+//!
+//! Fingerprints: `tag:<name>`, `attr:<on...>`, `url:<scheme>`, `style:expression`.
+
+/// Tags that run script or load active content by themselves.
+const BAD_TAGS: &[&str] = &[
+    "script", "iframe", "frame", "frameset", "object", "embed", "applet", "svg", "math", "style",
+    "link", "meta", "base", "xml", "xss", "import", "template", "portal",
+];
+
+/// Attributes whose value is loaded or navigated to.
+const URL_ATTRS: &[&str] = &[
+    "href", "src", "action", "formaction", "xlink:href", "data", "background", "poster", "lowsrc",
+    "dynsrc", "codebase", "srcdoc",
+];
+
+/// DOM event handler names after the `on` prefix. Plain words like "online" or "one" are not handlers.
+const EVENTS: &[&str] = &[
+    "load", "error", "click", "dblclick", "auxclick", "contextmenu", "mouseover", "mouseout", "mousedown",
+    "mouseup", "mousemove", "mouseenter", "mouseleave", "pointerover", "pointerdown", "pointerup",
+    "pointerenter", "pointerleave", "pointermove", "wheel", "focus", "blur", "focusin", "focusout",
+    "change", "input", "submit", "reset", "select", "search", "keydown", "keyup", "keypress", "abort",
+    "scroll", "resize", "toggle", "drag", "dragstart", "dragend", "dragenter", "dragover", "drop", "copy",
+    "cut", "paste", "beforeunload", "unload", "hashchange", "message", "pageshow", "popstate",
+    "animationstart", "animationend", "animationiteration", "transitionend", "transitionrun", "begin",
+    "end", "play", "playing", "canplay", "loadstart", "loadeddata", "durationchange", "timeupdate",
+    "readystatechange", "beforeprint", "afterprint", "show", "start", "finish", "invalid", "formdata",
+];
+
+const BAD_SCHEMES: &[&str] = &["javascript:", "vbscript:", "livescript:", "data:text/html"];
+
+/// Detect XSS in `input`. Returns the fingerprint that matched.
+///
+/// Besides HTML text, the input is tried as if it were written into a quoted or unquoted
+/// attribute value (`" onmouseover="alert(1)`), and as a bare URL (`javascript:alert(1)`).
+/// Outside a real tag an event handler only counts when its value looks like script, so
+/// prose such as `set onload=1` passes.
+pub fn detect_xss(input: &str) -> Option<String> {
+    if let Some(scheme) = bad_scheme(input) {
+        return Some(format!("url:{}", scheme.trim_end_matches(':')));
+    }
+    let b = input.as_bytes();
+    if let Some(fp) = scan_text(b) {
+        return Some(fp);
+    }
+    // attribute contexts: whatever follows the first quote (or space) is more attributes
+    for q in [b'"', b'\'', b' '] {
+        if let Some(p) = b.iter().position(|&c| c == q) {
+            if let Some(fp) = scan_attrs(b, p + 1, false).0 {
+                return Some(fp);
+            }
+        }
+    }
+    None
+}
+
+fn scan_text(b: &[u8]) -> Option<String> {
+    let mut i = 0;
+    while let Some(off) = b[i..].iter().position(|&c| c == b'<') {
+        i += off + 1;
+        // closing tags can still carry attributes in broken markup
+        let start = if b.get(i) == Some(&b'/') { i + 1 } else { i };
+        let end = start
+            + b[start..]
+                .iter()
+                .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, b':' | b'-' | b'_'))
+                .count();
+        if end == start {
+            continue;
+        }
+        let name = String::from_utf8_lossy(&b[start..end]).to_ascii_lowercase();
+        if BAD_TAGS.contains(&name.as_str()) {
+            return Some(format!("tag:{name}"));
+        }
+        let (fp, next) = scan_attrs(b, end, true);
+        if fp.is_some() {
+            return fp;
+        }
+        i = next;
+    }
+    None
+}
+
+/// Parse attributes from `i` up to the end of the tag. Returns a fingerprint and where the tag ended.
+/// `in_tag` is false for the attribute-breakout guesses, where a handler needs a script-like value.
+fn scan_attrs(b: &[u8], mut i: usize, in_tag: bool) -> (Option<String>, usize) {
+    loop {
+        // separators between attributes
+        while i < b.len() && (b[i].is_ascii_whitespace() || matches!(b[i], b'/' | b'"' | b'\'' | 0)) {
+            i += 1;
+        }
+        if i >= b.len() || b[i] == b'>' {
+            return (None, i);
+        }
+
+        let start = i;
+        while i < b.len() && !b[i].is_ascii_whitespace() && !matches!(b[i], b'=' | b'>' | b'/' | 0) {
+            i += 1;
+        }
+        let name = String::from_utf8_lossy(&b[start..i]).to_ascii_lowercase();
+
+        while i < b.len() && b[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        let mut value = None;
+        if b.get(i) == Some(&b'=') {
+            i += 1;
+            while i < b.len() && b[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            let (v, next) = attr_value(b, i);
+            value = Some(v);
+            i = next;
+        }
+        // only attributes that carry a value can do harm
+        let Some(value) = value else {
+            continue;
+        };
+
+        let handler = name.strip_prefix("on").is_some_and(|ev| EVENTS.contains(&ev));
+        if handler && (in_tag || looks_like_script(&value)) {
+            return (Some(format!("attr:{name}")), i);
+        }
+        if URL_ATTRS.contains(&name.as_str()) {
+            if let Some(scheme) = bad_scheme(&value) {
+                return (Some(format!("url:{}", scheme.trim_end_matches(':'))), i);
+            }
+        }
+        if name == "style" && value.to_ascii_lowercase().contains("expression(") {
+            return (Some("style:expression".to_string()), i);
+        }
+    }
+}
+
+fn attr_value(b: &[u8], i: usize) -> (String, usize) {
+    match b.get(i) {
+        Some(&q @ (b'"' | b'\'' | b'`')) => {
+            let end = b[i + 1..].iter().position(|&c| c == q).map_or(b.len(), |p| i + 1 + p);
+            (String::from_utf8_lossy(&b[i + 1..end]).into_owned(), (end + 1).min(b.len()))
+        }
+        _ => {
+            let end = i + b[i..].iter().take_while(|c| !c.is_ascii_whitespace() && **c != b'>').count();
+            (String::from_utf8_lossy(&b[i..end]).into_owned(), end)
+        }
+    }
+}
+
+/// Calls, tagged templates, assignments or statement separators: `alert(1)`, `alert`1``,
+/// `location=name`, `alert;throw 1`.
+fn looks_like_script(value: &str) -> bool {
+    value.contains(['(', '`', '=', ';'])
+}
+
+/// Browsers ignore whitespace and control characters inside a scheme (`java\tscript:`).
+fn bad_scheme(value: &str) -> Option<&'static str> {
+    let squeezed: String = value
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .take(16)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    BAD_SCHEMES.iter().copied().find(|s| squeezed.starts_with(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::detect_xss;
+
+    #[test]
+    fn detects_script_constructs() {
+        for (s, fp) in [
+            ("<script>alert(1)</script>", "tag:script"),
+            ("<svg onload=alert(1)>", "tag:svg"),
+            ("<img src=x onerror=alert(1)>", "attr:onerror"),
+            ("<a href=\"java\tscript:alert(1)\">x</a>", "url:javascript"),
+            ("java\tscript:alert(1)", "url:javascript"),
+            ("<form><button formaction=javascript:alert(1)>", "url:javascript"),
+            ("<div style=\"width: expression(alert(1))\">", "style:expression"),
+        ] {
+            assert_eq!(detect_xss(s).as_deref(), Some(fp), "{s}");
+        }
+    }
+
+    #[test]
+    fn detects_attribute_breakouts() {
+        for (s, fp) in [
+            ("\" onmouseover=\"alert(1)", "attr:onmouseover"),
+            ("' onfocus='alert(1)' autofocus='", "attr:onfocus"),
+            ("x onerror=alert(1)", "attr:onerror"),
+            ("x onerror=alert`1`", "attr:onerror"),
+            ("\" onclick=location=name //", "attr:onclick"),
+        ] {
+            assert_eq!(detect_xss(s).as_deref(), Some(fp), "{s}");
+        }
+    }
+
+    #[test]
+    fn ignores_prose_about_html() {
+        for s in [
+            "set onload=1",
+            "use the onclick= attribute",
+            "the onclick=handler pattern",
+            "wrap the fields in a <form> element",
+            "a <form action=\"/search\"> posts here",
+            "a < b and c > d",
+            "\"online\" only",
+            "javascript is fun",
+        ] {
+            assert_eq!(detect_xss(s), None, "{s}");
+        }
+    }
+}
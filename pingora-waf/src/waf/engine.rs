@@ -1,84 +1,383 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use arc_swap::ArcSwap;
 
-use super::context::WafContext;
+use super::anomaly::{AnomalyScore, ANOMALY_RULE_ID};
+use super::body::BodyArgs;
+use super::context::{HeaderView, WafContext};
 use super::decision::Decision;
+use super::detect::{detect_sqli, detect_xss};
+use super::exclusion::RuleExclusions;
+use super::normalizer::Normalizer;
 use super::rules::compiler::{CompiledRule, CompiledRuleset};
+use super::rules::rule::{Action, DetectTarget};
+use super::targets::{Targets, TransformCache};
 use crate::metrics;
 
+/// Named rulesets; policies pick one via `waf.ruleset`.
 #[derive(Clone)]
 pub struct WafEngine {
-    rules: Arc<ArcSwap<CompiledRuleset>>,
+    rulesets: Arc<ArcSwap<HashMap<String, Arc<CompiledRuleset>>>>,
 }
 
 impl WafEngine {
-    pub fn new(initial: CompiledRuleset) -> Self {
+    pub fn new(initial: HashMap<String, CompiledRuleset>) -> Self {
+        let map = initial.into_iter().map(|(k, v)| (k, Arc::new(v))).collect::<HashMap<_, _>>();
         Self {
-            rules: Arc::new(ArcSwap::from_pointee(initial)),
+            rulesets: Arc::new(ArcSwap::from_pointee(map)),
         }
     }
 
-    pub fn swap_rules(&self, new_rules: CompiledRuleset) {
-        self.rules.store(Arc::new(new_rules));
+    /// Replace (or add) a single ruleset; the others are untouched.
+    pub fn swap_ruleset(&self, name: &str, new_rules: CompiledRuleset) {
+        let new_rules = Arc::new(new_rules);
+        self.rulesets.rcu(|cur| {
+            let mut m = HashMap::clone(cur);
+            m.insert(name.to_string(), new_rules.clone());
+            m
+        });
     }
 
-    pub fn rules_snapshot(&self) -> Arc<CompiledRuleset> {
-        self.rules.load_full()
+    pub fn ruleset(&self, name: &str) -> Option<Arc<CompiledRuleset>> {
+        self.rulesets.load().get(name).cloned()
+    }
+
+    pub fn has_ruleset(&self, name: &str) -> bool {
+        self.rulesets.load().contains_key(name)
     }
 
     /// Evaluate request HEADERS only. Returns:
     /// (decision, request_body_rule_indexes, response_body_rule_indexes)
     ///
     /// For now:
-    /// - uri_ac and path/method rules can decide immediately
+    /// - uri_ac, args, path/method and header rules can decide immediately
     /// - body_ac rules are deferred to request_body_filter
-    /// - (optional) response_body rules reuse the same body_ac set (can be split in DSL later)
-    pub fn eval_request_headers(&self, ctx: &WafContext) -> (Decision, Vec<usize>, Vec<usize>) {
-        let rs = self.rules_snapshot();
+    /// - body-arg and body_regex rules are deferred too and run once on the buffered body (see `eval_request_body`)
+    /// - block / challenge body_ac rules also scan the response body, unless `scan_response: false`
+    ///
+    /// The returned indexes point into `rs`, so callers must keep that snapshot for the body phases.
+    ///
+    /// With `score` set (anomaly mode) matching block/challenge rules add their score and
+    /// evaluation continues; the total is checked against the threshold once all header rules
+    /// ran. Allow rules still end evaluation immediately.
+    ///
+    /// Rules removed by `excl` are skipped (and never deferred to the body phases); rules with
+    /// excluded targets see the request without those arguments / headers.
+    pub fn eval_request_headers(
+        rs: &CompiledRuleset,
+        ctx: &WafContext,
+        headers: &dyn HeaderView,
+        excl: &RuleExclusions,
+        mut score: Option<&mut AnomalyScore>,
+    ) -> (Decision, Vec<usize>, Vec<usize>) {
         let method = ctx.method.as_str();
-        let path = ctx.path.as_str();
-        let uri_bytes = path.as_bytes();
+        let cache = TransformCache::new(rs, ctx, headers);
 
         let mut req_body_rules = Vec::new();
         let mut resp_body_rules = Vec::new();
+        // first `log` rule hit; reported unless something terminal decides
+        let mut logged = None;
 
         for (idx, rule) in rs.rules.iter().enumerate() {
+            if excl.is_removed(idx) {
+                continue;
+            }
             if let Some(ms) = &rule.methods {
                 if !ms.iter().any(|m| m == method) {
                     continue;
                 }
             }
+            if !rule.ip_match.is_empty()
+                && !ctx.client_ip.is_some_and(|ip| rule.ip_match.iter().any(|n| n.contains(&ip)))
+            {
+                continue;
+            }
+            let narrowed;
+            let t = match excl.targets(idx) {
+                Some(x) => {
+                    narrowed = cache.targets_excluding(rule.chain, x);
+                    &narrowed
+                }
+                None => cache.targets(rule.chain),
+            };
             if let Some(pfxs) = &rule.path_prefix {
-                if !pfxs.iter().any(|p| path.starts_with(p)) {
+                if !pfxs.iter().any(|p| t.path.starts_with(p.as_str())) {
                     continue;
                 }
             }
             if let Some(ac) = &rule.uri_ac {
-                if !ac.is_match(uri_bytes) {
+                // path and raw query are scanned separately
+                if !ac.is_match(t.path.as_bytes()) && !ac.is_match(t.query.as_bytes()) {
                     continue;
                 }
             }
+            if rule.uri_regex.is_some() && !t.uri_re_hits.contains(&idx) {
+                continue;
+            }
+            if rule.args_regex.is_some() && !t.args_re_hits.contains(&idx) {
+                continue;
+            }
+            if !rule.args_match(&t.args) {
+                continue;
+            }
+            if !rule.headers_match(t) {
+                continue;
+            }
+            // body detect targets are checked with the parsed body
+            let mut detail = None;
+            if !rule.needs_full_body() && rule.detect_targets().next().is_some() {
+                match rule.detect(|target| t.detect_values(target)) {
+                    Some(fp) => detail = Some(fp),
+                    None => continue,
+                }
+            }
 
             // defer body scan
+            if rule.needs_full_body() {
+                req_body_rules.push(idx);
+                continue;
+            }
             if rule.body_ac.is_some() {
                 req_body_rules.push(idx);
-                // simple default: also scan response body for the same patterns
-                resp_body_rules.push(idx);
+                // also scan the response body for the same patterns; a log / allow rule
+                // there would still cut the response, so only rules that stop the request
+                if rule.scan_response && matches!(rule.action, Action::Block | Action::Challenge) {
+                    resp_body_rules.push(idx);
+                }
+                continue;
+            }
+
+            // matched w/o body => decide now, or score and keep going
+            let d = match &detail {
+                Some(fp) => rule.decision_with_reason(fp),
+                None => rule.action_to_decision(),
+            };
+            if let Decision::Log { .. } = d {
+                logged.get_or_insert(d);
+                continue;
+            }
+            match score.as_deref_mut() {
+                Some(sc) if d.is_terminal() => sc.add(&rule.id, rule.score, detail),
+                _ => return (d, req_body_rules, resp_body_rules),
+            }
+        }
+
+        let d = score.map(|sc| sc.decision()).unwrap_or(Decision::Allow);
+        if !d.is_terminal() {
+            if let Some(l) = logged {
+                return (l, req_body_rules, resp_body_rules);
+            }
+        }
+        (d, req_body_rules, resp_body_rules)
+    }
+
+    /// Buffered body phase: which of the rules in `idxs` match the parsed body (`None` when it
+    /// failed to parse). `raw` is the whole buffered body, for `body_regex` and rules that also set
+    /// `body_ac`; argument exclusions in `excl` apply to the parsed args only.
+    pub fn eval_request_body<'r>(
+        rs: &'r CompiledRuleset,
+        idxs: &[usize],
+        body: Option<&BodyArgs>,
+        raw: &[u8],
+        excl: &RuleExclusions,
+    ) -> Vec<(&'r CompiledRule, Option<String>)> {
+        // unparsable body: raw-body conditions still run, rules on parsed arguments are skipped
+        let empty = BodyArgs::default();
+        let parsed = body.is_some();
+        let body = body.unwrap_or(&empty);
+        // body args transformed once per chain, like TransformCache does for the header phase
+        let mut by_chain: HashMap<usize, BodyArgs> = HashMap::new();
+        let re_hits = rs.body_re.as_ref().map(|re| re.matched_rules([raw])).unwrap_or_default();
+        let mut hits = Vec::new();
+        for (idx, rule) in idxs.iter().filter_map(|&i| rs.rules.get(i).map(|r| (i, r))) {
+            if !parsed && rule.uses_body_args() {
                 continue;
             }
+            let transforms = &rs.chains[rule.chain].transforms;
+            let t = |s: &str| Normalizer::apply_chain(transforms, s);
+            let narrowed;
+            let args = if let Some(x) = excl.targets(idx) {
+                narrowed = BodyArgs {
+                    args: body.args.iter().filter(|(k, _)| !x.has_arg(k)).map(|(k, v)| (t(k), t(v))).collect(),
+                    filenames: body.filenames.iter().map(|f| t(f)).collect(),
+                };
+                &narrowed
+            } else if transforms.is_empty() {
+                body
+            } else {
+                by_chain.entry(rule.chain).or_insert_with(|| BodyArgs {
+                    args: body.args.iter().map(|(k, v)| (t(k), t(v))).collect(),
+                    filenames: body.filenames.iter().map(|f| t(f)).collect(),
+                })
+            };
+            if rule.body_regex.is_some() && !re_hits.contains(&idx) {
+                continue;
+            }
+            if !rule.body_args_match(args) || (rule.body_ac.is_some() && !rule.body_match(raw)) {
+                continue;
+            }
+            let mut detail = None;
+            if rule.detect_targets().next().is_some() {
+                match rule.detect(|target| body_detect_values(args, target)) {
+                    Some(fp) => detail = Some(fp),
+                    None => continue,
+                }
+            }
+            hits.push((rule, detail));
+        }
+        hits
+    }
+
+    /// Response body phase: which of the rules in `idxs` match `window` (the kept tail of the
+    /// previous chunks plus the current one).
+    pub fn eval_response_body(rs: &CompiledRuleset, idxs: &[usize], window: &[u8]) -> Vec<usize> {
+        idxs.iter()
+            .copied()
+            .filter(|&i| rs.rules.get(i).is_some_and(|r| r.body_match(window)))
+            .collect()
+    }
 
-            // matched w/o body => decide now
-            let d = rule.action_to_decision();
-            return (d, req_body_rules, resp_body_rules);
+    /// Whether body-phase hits stop the request, as (rule id, reason). The first block/challenge hit
+    /// does; with `score` set (anomaly mode) they add their score instead and only a total at or over
+    /// the threshold does. Allow and log hits never decide here.
+    pub fn body_block(
+        hits: Vec<(&CompiledRule, Option<String>)>,
+        mut score: Option<&mut AnomalyScore>,
+        default_reason: &str,
+    ) -> Option<(String, String)> {
+        let mut block = None;
+        for (rule, detail) in hits {
+            if !matches!(rule.action, Action::Block | Action::Challenge) {
+                continue;
+            }
+            match score.as_deref_mut() {
+                Some(sc) => sc.add(&rule.id, rule.score, detail),
+                None => {
+                    block.get_or_insert_with(|| (rule.id.clone(), detail.unwrap_or_else(|| default_reason.to_string())));
+                }
+            }
+        }
+        match score {
+            Some(sc) => sc.exceeded().then(|| (ANOMALY_RULE_ID.to_string(), sc.reason())),
+            None => block,
         }
+    }
+}
+
+type Detector = fn(&str) -> Option<String>;
 
-        (Decision::Allow, req_body_rules, resp_body_rules)
+fn body_detect_values<'a>(body: &'a BodyArgs, target: &DetectTarget) -> Vec<&'a str> {
+    match target {
+        DetectTarget::BodyArgs => body.args.iter().map(|(_, v)| v.as_str()).collect(),
+        DetectTarget::BodyArg(n) => body.args.iter().filter(|(k, _)| k == n).map(|(_, v)| v.as_str()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+impl CompiledRule {
+    /// Detector conditions: every configured detector must fire on at least one of its targets.
+    /// Returns the fingerprints for the event reason, e.g. `sqli fingerprint s&1=1 in arg:id`.
+    pub fn detect<'v>(&self, values: impl Fn(&DetectTarget) -> Vec<&'v str>) -> Option<String> {
+        let detectors = [
+            ("sqli", &self.detect_sqli, detect_sqli as Detector),
+            ("xss", &self.detect_xss, detect_xss as Detector),
+        ];
+        let mut found = Vec::new();
+        for (kind, targets, detector) in detectors {
+            let Some(targets) = targets else {
+                continue;
+            };
+            let hit = targets.iter().find_map(|t| {
+                values(t)
+                    .into_iter()
+                    .find_map(|v| detector(v).map(|fp| format!("{kind} fingerprint {fp} in {t}")))
+            });
+            found.push(hit?);
+        }
+        Some(found.join(", "))
+    }
+
+    /// Query argument conditions, ANDed like the other `when` dimensions.
+    pub fn args_match(&self, args: &[(String, String)]) -> bool {
+        if let Some(n) = self.args_count_gt {
+            if args.len() <= n {
+                return false;
+            }
+        }
+        if let Some(n) = self.arg_len_gt {
+            if !args.iter().any(|(_, v)| v.len() > n) {
+                return false;
+            }
+        }
+        if let Some(ac) = &self.args_ac {
+            if !args.iter().any(|(_, v)| ac.is_match(v.as_bytes())) {
+                return false;
+            }
+        }
+        if let Some(ac) = &self.arg_names_ac {
+            if !args.iter().any(|(k, _)| ac.is_match(k.as_bytes())) {
+                return false;
+            }
+        }
+        self.arg_ac.iter().all(|m| {
+            args.iter()
+                .any(|(k, v)| *k == m.name && m.ac.is_match(v.as_bytes()))
+        }) && self
+            .arg_regex
+            .iter()
+            .all(|m| args.iter().any(|(k, v)| *k == m.name && m.re.is_match(v)))
+    }
+
+    /// All header conditions must hold (AND), same as the other `when` dimensions; on a repeated
+    /// header a value condition holds if any value matches. Exclusions hide a header's value, not its presence.
+    pub fn headers_match(&self, headers: &Targets) -> bool {
+        self.header_exists.iter().all(|n| headers.has_header(n))
+            && self.header_absent.iter().all(|n| !headers.has_header(n))
+            && self
+                .header_equals
+                .iter()
+                .all(|h| headers.get_all(&h.name).into_iter().any(|v| v == h.value))
+            && self
+                .header_regex
+                .iter()
+                .all(|h| headers.get_all(&h.name).into_iter().any(|v| h.re.is_match(v)))
     }
 }
 
 /// Helpers used by proxy streaming filters
 impl CompiledRule {
+    /// Rules on parsed body arguments or body regexes need the whole body, not a streaming window.
+    pub fn needs_full_body(&self) -> bool {
+        self.uses_body_args() || self.body_regex.is_some()
+    }
+
+    /// Conditions on parsed body arguments / filenames (as opposed to the raw body).
+    pub fn uses_body_args(&self) -> bool {
+        self.detect_targets().any(|t| t.is_body())
+            || self.body_args_ac.is_some()
+            || !self.body_arg_ac.is_empty()
+            || self.body_filename_ac.is_some()
+    }
+
+    pub fn body_args_match(&self, body: &BodyArgs) -> bool {
+        if let Some(ac) = &self.body_args_ac {
+            if !body.args.iter().any(|(_, v)| ac.is_match(v.as_bytes())) {
+                return false;
+            }
+        }
+        if let Some(ac) = &self.body_filename_ac {
+            if !body.filenames.iter().any(|f| ac.is_match(f.as_bytes())) {
+                return false;
+            }
+        }
+        self.body_arg_ac.iter().all(|m| {
+            body.args
+                .iter()
+                .any(|(k, v)| *k == m.name && m.ac.is_match(v.as_bytes()))
+        })
+    }
+
     pub fn body_match(&self, window: &[u8]) -> bool {
         self.body_ac.as_ref().map(|ac| ac.is_match(window)).unwrap_or(false)
     }
@@ -92,21 +391,340 @@ impl CompiledRule {
 
 impl CompiledRule {
     pub fn action_to_decision(&self) -> Decision {
+        match self.action {
+            super::rules::rule::Action::Allow => Decision::Allow,
+            super::rules::rule::Action::Block => self.decision_with_reason("matched"),
+            super::rules::rule::Action::Challenge => self.decision_with_reason("challenge"),
+            super::rules::rule::Action::Log => self.decision_with_reason("matched"),
+        }
+    }
+
+    /// Same as `action_to_decision`, with a specific reason (e.g. a detector fingerprint).
+    pub fn decision_with_reason(&self, reason: &str) -> Decision {
         match self.action {
             super::rules::rule::Action::Allow => Decision::Allow,
             super::rules::rule::Action::Block => Decision::Block {
                 status: 403,
-                reason: "matched".into(),
+                reason: reason.into(),
                 rule_id: self.id.clone(),
             },
             super::rules::rule::Action::Challenge => Decision::Challenge {
                 status: 403,
-                reason: "challenge".into(),
+                reason: reason.into(),
                 rule_id: self.id.clone(),
+                kind: Default::default(),
             },
+            super::rules::rule::Action::Log => Decision::log(self.id.clone(), reason),
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"
+rules:
+  - id: "resp-leak"
+    when: { body_ac: ["stack trace"] }
+    action: block
+    score: 3
+"#;
+
+    fn response_hits(rs: &CompiledRuleset, score: Option<&mut AnomalyScore>, body: &[u8]) -> Option<(String, String)> {
+        let ctx = WafContext { method: "GET".into(), path: "/".into(), ..Default::default() };
+        let (d, _, resp) = WafEngine::eval_request_headers(rs, &ctx, &http::HeaderMap::new(), &RuleExclusions::default(), None);
+        assert!(matches!(d, Decision::Allow));
+        let hits = WafEngine::eval_response_body(rs, &resp, body)
+            .into_iter()
+            .map(|i| (&rs.rules[i], None))
+            .collect();
+        WafEngine::body_block(hits, score, "response body match")
+    }
 
+    #[test]
+    fn response_match_below_threshold_only_scores() {
+        let rs = CompiledRuleset::compile(RULES).unwrap();
+        let mut sc = AnomalyScore::new(5);
+        assert_eq!(response_hits(&rs, Some(&mut sc), b"... stack trace ..."), None);
+        assert_eq!(sc.total, 3);
+
+        // a request already at 2 reaches the threshold with the response match
+        let mut sc = AnomalyScore::new(5);
+        sc.add("earlier", 2, None);
+        let (id, _) = response_hits(&rs, Some(&mut sc), b"... stack trace ...").unwrap();
+        assert_eq!(id, ANOMALY_RULE_ID);
+    }
+
+    #[test]
+    fn response_match_blocks_without_scoring() {
+        let rs = CompiledRuleset::compile(RULES).unwrap();
+        let (id, _) = response_hits(&rs, None, b"... stack trace ...").unwrap();
+        assert_eq!(id, "resp-leak");
+        assert_eq!(response_hits(&rs, None, b"all good"), None);
+    }
+
+    #[test]
+    fn only_blocking_rules_scan_the_response() {
+        let rs = CompiledRuleset::compile(
+            r#"
+rules:
+  - { id: "pass", when: { body_ac: ["secret"] }, action: log }
+  - { id: "req-only", when: { body_ac: ["secret"] }, action: block, scan_response: false }
+"#,
+        )
+        .unwrap();
+        assert_eq!(response_hits(&rs, None, b"secret"), None);
+
+        let (rules, _) = crate::waf::rules::seclang::parse(r#"SecRule REQUEST_BODY "@pm secret" "id:1,phase:2,deny""#);
+        let rs = CompiledRuleset::from_rules(None, rules).unwrap();
+        assert_eq!(response_hits(&rs, None, b"secret"), None);
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> http::HeaderMap {
+        let mut h = http::HeaderMap::new();
+        for (k, v) in pairs {
+            h.append(http::HeaderName::from_bytes(k.as_bytes()).unwrap(), v.parse().unwrap());
+        }
+        h
+    }
+
+    /// IDs of every block rule matching in the header phase (scored, so evaluation doesn't stop at the first).
+    fn header_hits(rs: &CompiledRuleset, ctx: &WafContext, h: &http::HeaderMap, excl: &RuleExclusions) -> Vec<String> {
+        let mut sc = AnomalyScore::new(u32::MAX);
+        WafEngine::eval_request_headers(rs, ctx, h, excl, Some(&mut sc));
+        sc.hits.into_iter().map(|h| h.rule_id).collect()
+    }
 
+    fn get(path: &str) -> WafContext {
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        WafContext {
+            method: "GET".into(),
+            path: path.into(),
+            query: Some(query.into()),
+            args: crate::waf::context::parse_args(query),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn header_conditions() {
+        let rs = CompiledRuleset::compile(
+            r#"
+rules:
+  - { id: "exists", when: { header_exists: ["X-Debug"] }, action: block }
+  - { id: "absent", when: { header_absent: ["User-Agent"] }, action: block }
+  - { id: "equals", when: { header_equals: [{ name: "X-Api-Key", value: "Secret" }] }, action: block }
+  - { id: "regex", when: { header_regex: [{ name: "User-Agent", pattern: "(?i)^sqlmap" }] }, action: block }
+  - { id: "both", when: { header_exists: ["x-debug"], header_regex: [{ name: "user-agent", pattern: "curl" }] }, action: block }
+"#,
+        )
+        .unwrap();
+        let hits = |pairs: &[(&str, &str)]| header_hits(&rs, &get("/"), &headers(pairs), &RuleExclusions::default());
+
+        assert_eq!(hits(&[("user-agent", "Mozilla/5.0")]), Vec::<String>::new());
+        assert_eq!(hits(&[]), ["absent"]);
+        // rule header names are case-insensitive; values compare exactly
+        assert_eq!(hits(&[("User-Agent", "SQLMap/1.7"), ("X-DEBUG", "")]), ["exists", "regex"]);
+        assert_eq!(hits(&[("user-agent", "x"), ("x-api-key", "Secret")]), ["equals"]);
+        assert_eq!(hits(&[("user-agent", "x"), ("x-api-key", "secret")]), Vec::<String>::new());
+        assert_eq!(hits(&[("user-agent", "curl/8"), ("x-debug", "1")]), ["exists", "both"]);
+
+        // repeated headers: any value can match
+        assert_eq!(hits(&[("user-agent", "Mozilla/5.0"), ("user-agent", "sqlmap/1.7")]), ["regex"]);
+        assert_eq!(hits(&[("user-agent", "x"), ("x-api-key", "a"), ("x-api-key", "Secret")]), ["equals"]);
+    }
+
+    #[test]
+    fn repeated_headers_reach_detectors_and_transforms() {
+        let rs = CompiledRuleset::compile(
+            r#"
+rules:
+  - { id: "xss-referer", when: { detect_xss: ["header:referer"] }, action: block }
+  - { id: "sqli-cookie", when: { detect_sqli: [cookies] }, action: block }
+  - id: "lower-ua"
+    when: { header_equals: [{ name: "user-agent", value: "nikto" }] }
+    transforms: [lowercase]
+    action: block
+"#,
+        )
+        .unwrap();
+        let hits = |pairs: &[(&str, &str)]| header_hits(&rs, &get("/"), &headers(pairs), &RuleExclusions::default());
+        assert_eq!(hits(&[("referer", "https://example.com/"), ("referer", "<script>alert(1)</script>")]), ["xss-referer"]);
+        // HTTP/2 sends each cookie in its own header
+        assert_eq!(hits(&[("cookie", "a=1"), ("cookie", "id=1' or '1'='1")]), ["sqli-cookie"]);
+        assert_eq!(hits(&[("user-agent", "Mozilla"), ("user-agent", "NIKTO")]), ["lower-ua"]);
+    }
+
+    #[test]
+    fn query_argument_conditions() {
+        let rs = CompiledRuleset::compile(
+            r#"
+rules:
+  - { id: "arg-ac", when: { arg_ac: [{ name: "q", patterns: ["<script"] }] }, action: block }
+  - { id: "arg-re", when: { arg_regex: [{ name: "id", pattern: "^[0-9]+$" }] }, action: block }
+  - { id: "empty-re", when: { arg_regex: [{ name: "token", pattern: "^$" }] }, action: block }
+  - { id: "names", when: { arg_names_ac: ["$where", "__proto__"] }, action: block }
+  - { id: "count", when: { args_count_gt: 3 }, action: block }
+  - { id: "len", when: { arg_len_gt: 16 }, action: block }
+  - { id: "all", when: { arg_ac: [{ name: "a", patterns: ["x"] }, { name: "b", patterns: ["y"] }] }, action: block }
+"#,
+        )
+        .unwrap();
+        let hits = |uri: &str| header_hits(&rs, &get(uri), &http::HeaderMap::new(), &RuleExclusions::default());
+
+        assert_eq!(hits("/"), Vec::<String>::new());
+        assert_eq!(hits("/?q=hello&id=abc"), Vec::<String>::new());
+        // values and names are matched decoded (`%XX` and `+`); AC patterns ignore ASCII case
+        assert_eq!(hits("/?q=%3CSCRIPT%3Ealert(1)"), ["arg-ac"]);
+        assert_eq!(hits("/?q=a+%3Cscript"), ["arg-ac"]);
+        assert_eq!(hits("/?%24where=1"), ["names"]);
+        assert_eq!(hits("/?id=42"), ["arg-re"]);
+        // only the named argument is looked at
+        assert_eq!(hits("/?x=%3Cscript&id=4a"), Vec::<String>::new());
+
+        // repeated arguments: any value can match
+        assert_eq!(hits("/?id=x&id=7"), ["arg-re"]);
+        assert_eq!(hits("/?q=ok&q=%3Cscript"), ["arg-ac"]);
+        // every named matcher must hold, each on its own argument
+        assert_eq!(hits("/?a=x&b=y"), ["all"]);
+        assert_eq!(hits("/?a=x&a=y"), Vec::<String>::new());
+
+        // empty values are still arguments: counted, and matched as ""
+        assert_eq!(hits("/?token="), ["empty-re"]);
+        assert_eq!(hits("/?token"), ["empty-re"]);
+        assert_eq!(hits("/?a=&b=&c=&d="), ["count"]);
+        assert_eq!(hits("/?a=&b=&c="), Vec::<String>::new());
+        // length is counted on the decoded value, in bytes
+        assert_eq!(hits(&format!("/?v={}", "%41".repeat(16))), Vec::<String>::new());
+        assert_eq!(hits("/?v=12345678901234567"), ["len"]);
+        assert_eq!(hits(&format!("/?v={}", "%C3%A9".repeat(9))), ["len"]);
+    }
+
+    const EXCL_RULES: &str = r#"
+rules:
+  - { id: "sqli", when: { detect_sqli: [args] }, action: block, tags: [attack-sqli] }
+  - { id: "xss", when: { args_ac: ["<script"] }, action: block, tags: [attack-xss, paranoia-level/1] }
+  - { id: "raw-query", when: { uri_ac: ["union"] }, action: block, tags: [attack-sqli] }
+  - { id: "args-re", when: { args_regex: ["(?i)union\\s+select"] }, action: block }
+  - { id: "ua", when: { header_regex: [{ name: "user-agent", pattern: "sqlmap" }] }, action: block }
+  - { id: "debug", when: { header_exists: ["x-debug"] }, action: block }
+"#;
+
+    fn excluding(rs: &CompiledRuleset, ids: &[&str], tags: &[&str], args: &[&str], headers: &[&str]) -> RuleExclusions {
+        let ids: Vec<String> = ids.iter().map(|s| s.to_string()).collect();
+        let tags: Vec<String> = tags.iter().map(|s| s.to_string()).collect();
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        let headers: Vec<String> = headers.iter().map(|s| s.to_string()).collect();
+        let mut excl = RuleExclusions::default();
+        let idxs: Vec<usize> = if ids.is_empty() && tags.is_empty() { (0..rs.rules.len()).collect() } else { rs.select(&ids, &tags).collect() };
+        for idx in idxs {
+            if args.is_empty() && headers.is_empty() {
+                excl.remove(idx);
+            } else {
+                excl.exclude_targets(idx, &args, &headers);
+            }
+        }
+        excl
+    }
+
+    #[test]
+    fn exclusions_remove_rules_by_id_and_tag() {
+        let rs = CompiledRuleset::compile(EXCL_RULES).unwrap();
+        let ctx = get("/?id=1%27+union+select+password+from+users--&q=%3Cscript%3E");
+        let h = headers(&[("user-agent", "sqlmap/1.7")]);
+        let hits = |excl: &RuleExclusions| header_hits(&rs, &ctx, &h, excl);
+
+        assert_eq!(hits(&RuleExclusions::default()), ["sqli", "xss", "raw-query", "args-re", "ua"]);
+        assert_eq!(hits(&excluding(&rs, &["xss", "ua"], &[], &[], &[])), ["sqli", "raw-query", "args-re"]);
+        assert_eq!(hits(&excluding(&rs, &[], &["attack-sqli"], &[], &[])), ["xss", "args-re", "ua"]);
+        assert_eq!(hits(&excluding(&rs, &["ua"], &["paranoia-level/1"], &[], &[])), ["sqli", "raw-query", "args-re"]);
+        // unknown ids and tags select nothing
+        assert_eq!(hits(&excluding(&rs, &["nope"], &["nope"], &[], &[])).len(), 5);
+
+        // removed rules aren't deferred to the body phase either
+        let rs = CompiledRuleset::compile("rules:\n  - { id: body, when: { body_ac: [x] }, action: block }\n").unwrap();
+        let (_, req, _) = WafEngine::eval_request_headers(&rs, &ctx, &h, &excluding(&rs, &["body"], &[], &[], &[]), None);
+        assert!(req.is_empty());
+    }
+
+    #[test]
+    fn exclusions_hide_args_and_headers_per_rule() {
+        let rs = CompiledRuleset::compile(EXCL_RULES).unwrap();
+        let ctx = get("/?id=1%27+union+select+password+from+users--&q=%3Cscript%3E");
+        let h = headers(&[("user-agent", "sqlmap/1.7"), ("x-debug", "1")]);
+        let hits = |excl: &RuleExclusions| header_hits(&rs, &ctx, &h, excl);
+        let all = ["sqli", "xss", "raw-query", "args-re", "ua", "debug"];
+        assert_eq!(hits(&RuleExclusions::default()), all);
+
+        // `id` hidden from every rule: the raw query loses `id=...` too, `q` still matches
+        assert_eq!(hits(&excluding(&rs, &[], &[], &["id"], &[])), ["xss", "ua", "debug"]);
+        // only from the rules selected
+        assert_eq!(hits(&excluding(&rs, &["sqli", "raw-query"], &[], &["id"], &[])), ["xss", "args-re", "ua", "debug"]);
+        // argument names compare exactly, after decoding
+        assert_eq!(hits(&excluding(&rs, &[], &[], &["ID"], &[])), all);
+        let encoded = get("/?%69d=1%27+union+select+password+from+users--");
+        let excl = excluding(&rs, &[], &[], &["id"], &[]);
+        assert_eq!(header_hits(&rs, &encoded, &h, &excl), ["ua", "debug"]);
+
+        // header names are case-insensitive; an excluded header still counts as present
+        assert_eq!(hits(&excluding(&rs, &[], &[], &[], &["User-Agent", "X-Debug"])), ["sqli", "xss", "raw-query", "args-re", "debug"]);
+        assert_eq!(hits(&excluding(&rs, &["ua"], &[], &[], &["user-agent"])), ["sqli", "xss", "raw-query", "args-re", "debug"]);
+    }
+
+    #[test]
+    fn exclusions_strip_args_from_the_body() {
+        let rs = CompiledRuleset::compile(
+            r#"
+rules:
+  - { id: "body-sqli", when: { body_args_ac: ["union"], detect_sqli: [body_args] }, action: block }
+  - { id: "body-raw", when: { body_ac: ["union"] }, action: block }
+"#,
+        )
+        .unwrap();
+        let body = BodyArgs { args: vec![("sql".into(), "1 union select password from users".into())], ..Default::default() };
+        let raw = b"sql=1+union+select+password+from+users";
+        let hits = |excl: &RuleExclusions| {
+            WafEngine::eval_request_body(&rs, &[0, 1], Some(&body), raw, excl).into_iter().map(|(r, _)| r.id.as_str()).collect::<Vec<_>>()
+        };
+        assert_eq!(hits(&RuleExclusions::default()), ["body-sqli", "body-raw"]);
+        // parsed args lose the excluded field; the raw body is scanned as is
+        assert_eq!(hits(&excluding(&rs, &[], &[], &["sql"], &[])), ["body-raw"]);
+        assert_eq!(hits(&excluding(&rs, &[], &[], &["other"], &[])), ["body-sqli", "body-raw"]);
+    }
+
+    #[test]
+    fn request_detectors_cannot_wait_for_the_body() {
+        for when in [
+            r#"{ body_args_ac: ["x"], detect_sqli: [args] }"#,
+            r#"{ body_arg_ac: [{ name: "q", patterns: ["x"] }], detect_xss: ["header:referer"] }"#,
+            r#"{ body_regex: ["x"], detect_sqli: [cookies] }"#,
+            r#"{ body_filename_ac: [".php"], detect_xss: [args] }"#,
+        ] {
+            let err = CompiledRuleset::compile(&format!("rules:\n  - {{ id: mixed-1, when: {when}, action: block }}\n")).unwrap_err();
+            assert!(format!("{err:#}").contains("rule mixed-1"), "{when}: {err:#}");
+        }
+
+        // body detectors with body conditions and request detectors with streamed body_ac still work
+        let rs = CompiledRuleset::compile(
+            r#"
+rules:
+  - { id: "body", when: { body_args_ac: ["1"], detect_sqli: [body_args] }, action: block }
+  - { id: "stream", when: { body_ac: ["x"], detect_sqli: [args] }, action: block }
+"#,
+        )
+        .unwrap();
+        let ctx = WafContext {
+            method: "POST".into(),
+            path: "/".into(),
+            args: vec![("id".into(), "1' or '1'='1".into())],
+            ..Default::default()
+        };
+        let (d, req, _) = WafEngine::eval_request_headers(&rs, &ctx, &http::HeaderMap::new(), &RuleExclusions::default(), None);
+        assert!(matches!(d, Decision::Allow));
+        assert_eq!(req, vec![0, 1]);
+        let body = BodyArgs { args: vec![("id".into(), "1 union select password from users".into())], ..Default::default() };
+        let hits = WafEngine::eval_request_body(&rs, &[0], Some(&body), b"", &RuleExclusions::default());
+        assert_eq!(hits.iter().map(|(r, _)| r.id.as_str()).collect::<Vec<_>>(), ["body"]);
+    }
+}
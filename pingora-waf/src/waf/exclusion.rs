@@ -0,0 +1,48 @@
+use std::collections::{HashMap, HashSet};
+
+/// Policy exclusions resolved for one request against one ruleset snapshot.
+/// Rule indexes point into that snapshot, like the body rule lists.
+#[derive(Debug, Clone, Default)]
+pub struct RuleExclusions {
+    removed: HashSet<usize>,
+    targets: HashMap<usize, ExcludedTargets>,
+}
+
+/// Inputs a rule must not look at. Names are compared before transformation.
+#[derive(Debug, Clone, Default)]
+pub struct ExcludedTargets {
+    /// Query and body argument names.
+    pub args: HashSet<String>,
+    /// Lowercased header names.
+    pub headers: HashSet<String>,
+}
+
+impl ExcludedTargets {
+    pub fn has_arg(&self, name: &str) -> bool {
+        self.args.contains(name)
+    }
+
+    pub fn has_header(&self, name: &str) -> bool {
+        self.headers.contains(name)
+    }
+}
+
+impl RuleExclusions {
+    pub fn remove(&mut self, idx: usize) {
+        self.removed.insert(idx);
+    }
+
+    pub fn exclude_targets(&mut self, idx: usize, args: &[String], headers: &[String]) {
+        let t = self.targets.entry(idx).or_default();
+        t.args.extend(args.iter().cloned());
+        t.headers.extend(headers.iter().map(|h| h.to_ascii_lowercase()));
+    }
+
+    pub fn is_removed(&self, idx: usize) -> bool {
+        self.removed.contains(&idx)
+    }
+
+    pub fn targets(&self, idx: usize) -> Option<&ExcludedTargets> {
+        self.targets.get(&idx)
+    }
+}
@@ -1,7 +1,13 @@
+pub mod anomaly;
+pub mod body;
 pub mod context;
+pub mod cookie;
 pub mod decision;
+pub mod detect;
 pub mod engine;
+pub mod exclusion;
 pub mod normalizer;
 pub mod ratelimit;
 pub mod rules;
+pub mod targets;
 pub mod update;
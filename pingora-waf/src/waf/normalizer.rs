@@ -1,5 +1,36 @@
+use base64::alphabet;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+use serde::Deserialize;
+
 pub struct Normalizer;
 
+/// One stage of a rule's transformation chain (names follow ModSecurity's `t:` actions).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Transform {
+    /// `%XX` and `+` decoding.
+    UrlDecode,
+    /// `urlDecode` plus IIS-style `%uXXXX` escapes.
+    UrlDecodeUni,
+    /// `&lt;`, `&#60;`, `&#x3c;` and friends.
+    HtmlEntityDecode,
+    Lowercase,
+    RemoveNulls,
+    /// Backslashes to slashes, collapse `//`, resolve `.` and `..` segments.
+    NormalizePath,
+    /// Any run of whitespace becomes a single space.
+    CompressWhitespace,
+    /// Decode standard base64; input that isn't valid base64 is kept as is.
+    Base64Decode,
+}
+
+const BASE64_LENIENT: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
 impl Normalizer {
     pub fn normalize_path(raw: &str) -> String {
         let mut out = String::with_capacity(raw.len());
@@ -21,4 +52,307 @@ impl Normalizer {
             out
         }
     }
+
+    /// Run `input` through every stage of `chain`, in order.
+    pub fn apply_chain(chain: &[Transform], input: &str) -> String {
+        let mut cur = input.to_string();
+        for t in chain {
+            cur = Self::apply(*t, &cur);
+        }
+        cur
+    }
+
+    pub fn apply(t: Transform, input: &str) -> String {
+        match t {
+            Transform::UrlDecode => url_decode(input, false),
+            Transform::UrlDecodeUni => url_decode(input, true),
+            Transform::HtmlEntityDecode => html_entity_decode(input),
+            Transform::Lowercase => input.to_lowercase(),
+            Transform::RemoveNulls => input.replace('\0', ""),
+            Transform::NormalizePath => normalize_segments(input),
+            Transform::CompressWhitespace => compress_whitespace(input),
+            Transform::Base64Decode => match BASE64_LENIENT.decode(input.trim()) {
+                Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+                Err(_) => input.to_string(),
+            },
+        }
+    }
+}
+
+fn hex_val(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Invalid escapes are kept verbatim; decoded bytes that aren't UTF-8 become U+FFFD.
+fn url_decode(input: &str, uni: bool) -> String {
+    let b = input.as_bytes();
+    let mut out: Vec<u8> = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        match b[i] {
+            b'+' => {
+                out.push(b' ');
+                i += 1;
+            }
+            b'%' => {
+                if uni && i + 5 < b.len() && (b[i + 1] == b'u' || b[i + 1] == b'U') {
+                    let code = b[i + 2..i + 6]
+                        .iter()
+                        .try_fold(0u32, |acc, &c| hex_val(c).map(|v| acc * 16 + v as u32));
+                    if let Some(code) = code {
+                        // Full-width ASCII (U+FF01..U+FF5E) folds to plain ASCII, as IIS does.
+                        let code = if (0xff01..=0xff5e).contains(&code) { code - 0xfee0 } else { code };
+                        let ch = char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER);
+                        let mut buf = [0u8; 4];
+                        out.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+                        i += 6;
+                        continue;
+                    }
+                }
+                match (b.get(i + 1).and_then(|&c| hex_val(c)), b.get(i + 2).and_then(|&c| hex_val(c))) {
+                    (Some(h), Some(l)) => {
+                        out.push(h * 16 + l);
+                        i += 3;
+                    }
+                    _ => {
+                        out.push(b'%');
+                        i += 1;
+                    }
+                }
+            }
+            c => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn html_entity_decode(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        match decode_entity(rest) {
+            Some((ch, used)) => {
+                out.push(ch);
+                rest = &rest[used..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// `s` starts with '&'. Returns the decoded char and how many bytes were consumed.
+fn decode_entity(s: &str) -> Option<(char, usize)> {
+    let body = &s[1..];
+    if let Some(num) = body.strip_prefix('#') {
+        let (digits, radix, skip) = match num.strip_prefix(['x', 'X']) {
+            Some(hex) => (hex, 16, 3),
+            None => (num, 10, 2),
+        };
+        let len = digits
+            .bytes()
+            .take_while(|c| if radix == 16 { c.is_ascii_hexdigit() } else { c.is_ascii_digit() })
+            .count();
+        if len == 0 {
+            return None;
+        }
+        let code = u32::from_str_radix(&digits[..len], radix).ok()?;
+        let ch = char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER);
+        // The trailing ';' is optional for numeric references.
+        let semi = usize::from(digits[len..].starts_with(';'));
+        return Some((ch, skip + len + semi));
+    }
+
+    let end = body.find(';').filter(|&e| e <= 6)?;
+    let ch = match &body[..end] {
+        "lt" => '<',
+        "gt" => '>',
+        "amp" => '&',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        _ => return None,
+    };
+    Some((ch, end + 2))
+}
+
+fn normalize_segments(input: &str) -> String {
+    let s = input.replace('\\', "/");
+    let absolute = s.starts_with('/');
+    let trailing = s.ends_with('/') || s.ends_with("/.") || s.ends_with("/..");
+
+    let mut segs: Vec<&str> = Vec::new();
+    for seg in s.split('/') {
+        match seg {
+            "" | "." => {}
+            ".." => {
+                // Never climb above the root.
+                segs.pop();
+            }
+            x => segs.push(x),
+        }
+    }
+
+    let mut out = String::with_capacity(s.len());
+    if absolute {
+        out.push('/');
+    }
+    out.push_str(&segs.join("/"));
+    if trailing && !segs.is_empty() {
+        out.push('/');
+    }
+    out
+}
+
+fn compress_whitespace(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut in_ws = false;
+    for ch in input.chars() {
+        if ch.is_whitespace() {
+            if !in_ws {
+                out.push(' ');
+            }
+            in_ws = true;
+        } else {
+            in_ws = false;
+            out.push(ch);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(t: Transform, cases: &[(&str, &str)]) {
+        for (input, want) in cases {
+            assert_eq!(Normalizer::apply(t, input), *want, "{t:?}({input:?})");
+        }
+    }
+
+    #[test]
+    fn url_decode_uni() {
+        check(
+            Transform::UrlDecodeUni,
+            &[
+                ("a%20b+c", "a b c"),
+                ("%3Cscript%3e", "<script>"),
+                ("%u003cscript%U003E", "<script>"),
+                // full-width ＜ folds to ASCII
+                ("%uff1cscript%uFF1E", "<script>"),
+                ("%u4e2d", "中"),
+                // surrogates aren't chars
+                ("%ud800", "\u{fffd}"),
+                // truncated or invalid escapes are kept
+                ("%", "%"),
+                ("%4", "%4"),
+                ("%zz", "%zz"),
+                ("%u00", "%u00"),
+                ("%u00zz", "%u00zz"),
+                ("100%", "100%"),
+                // not UTF-8 once decoded
+                ("%ff", "\u{fffd}"),
+            ],
+        );
+        // plain urlDecode leaves %u alone
+        check(Transform::UrlDecode, &[("%u003c", "%u003c"), ("%3c", "<")]);
+    }
+
+    #[test]
+    fn html_entity_decode() {
+        check(
+            Transform::HtmlEntityDecode,
+            &[
+                ("&lt;b&gt; &amp; &quot;x&quot; &apos;", "<b> & \"x\" '"),
+                ("a&nbsp;b", "a\u{a0}b"),
+                ("&#60;&#x3c;&#X3C;", "<<<"),
+                // numeric references don't need the ';'
+                ("&#60script&#x3e", "<script>"),
+                ("&#1114112;", "\u{fffd}"),
+                // named references do; unknown names and bare '&' are kept
+                ("&lt", "&lt"),
+                ("&ltscript&gt", "&ltscript&gt"),
+                ("&bogus; & &#; &#x;", "&bogus; & &#; &#x;"),
+                ("&", "&"),
+            ],
+        );
+    }
+
+    #[test]
+    fn normalize_path() {
+        check(
+            Transform::NormalizePath,
+            &[
+                ("/a/b/../c", "/a/c"),
+                ("/a/./b/", "/a/b/"),
+                ("/../../etc/passwd", "/etc/passwd"),
+                ("/a/..", "/"),
+                ("//a///b", "/a/b"),
+                ("\\a\\..\\..\\windows\\win.ini", "/windows/win.ini"),
+                ("/a\\b/.", "/a/b/"),
+                ("a/../../b", "b"),
+                ("", ""),
+            ],
+        );
+        assert_eq!(Normalizer::normalize_path("//a//b/"), "/a/b/");
+        assert_eq!(Normalizer::normalize_path(""), "/");
+    }
+
+    #[test]
+    fn base64_decode() {
+        check(
+            Transform::Base64Decode,
+            &[
+                ("PHNjcmlwdD4=", "<script>"),
+                // padding is optional, surrounding whitespace ignored
+                (" PHNjcmlwdD4 ", "<script>"),
+                // not base64: kept as is
+                ("not base64!", "not base64!"),
+                ("PHNjcmlwdD4=$", "PHNjcmlwdD4=$"),
+                ("", ""),
+                // bytes that aren't UTF-8
+                ("/w==", "\u{fffd}"),
+            ],
+        );
+    }
+
+    #[test]
+    fn nulls_whitespace_and_case() {
+        check(Transform::RemoveNulls, &[("se\0le\0ct", "select"), ("\0", "")]);
+        check(
+            Transform::CompressWhitespace,
+            &[("union \t\r\n select", "union select"), ("  a  ", " a "), ("a\u{a0}\u{3000}b", "a b"), ("", "")],
+        );
+        check(Transform::Lowercase, &[("SeLeCT", "select")]);
+    }
+
+    #[test]
+    fn chains_apply_in_order() {
+        use Transform::*;
+        let chain = [UrlDecodeUni, HtmlEntityDecode, RemoveNulls, CompressWhitespace, Lowercase];
+        assert_eq!(
+            Normalizer::apply_chain(&chain, "UNION%00%20%09SELECT+%26lt;SCRIPT%u003e"),
+            "union select <script>"
+        );
+        // double encoding needs the decoder twice
+        assert_eq!(Normalizer::apply_chain(&[UrlDecode], "%253c"), "%3c");
+        assert_eq!(Normalizer::apply_chain(&[UrlDecode, UrlDecode], "%253c"), "<");
+        assert_eq!(Normalizer::apply_chain(&[Base64Decode, NormalizePath], "Ly4uLy4uL2V0Yy9wYXNzd2Q="), "/etc/passwd");
+        assert_eq!(Normalizer::apply_chain(&[], "As Is"), "As Is");
+    }
 }
@@ -9,17 +9,33 @@ pub struct TokenBucket {
 }
 
 impl TokenBucket {
-    pub fn new(cap: u64, refill_per_sec: f64) -> Self {
+    /// A full bucket, refilling from `now`.
+    pub fn new(cap: u64, refill_per_sec: f64, now: Instant) -> Self {
         Self {
             cap,
             tokens: cap as f64,
             refill_per_sec,
-            last: Instant::now(),
+            last: now,
         }
     }
 
-    pub fn allow(&mut self, cost: u64) -> bool {
-        self.refill();
+    /// Rebuild a bucket from a saved `state()`.
+    pub fn with_state(cap: u64, refill_per_sec: f64, tokens: f64, last: Instant) -> Self {
+        Self {
+            cap,
+            tokens: tokens.clamp(0.0, cap as f64),
+            refill_per_sec,
+            last,
+        }
+    }
+
+    /// `(cap, refill_per_sec, tokens, last refill)`
+    pub fn state(&self) -> (u64, f64, f64, Instant) {
+        (self.cap, self.refill_per_sec, self.tokens, self.last)
+    }
+
+    pub fn allow(&mut self, cost: u64, now: Instant) -> bool {
+        self.refill(now);
         if self.tokens >= cost as f64 {
             self.tokens -= cost as f64;
             true
@@ -28,10 +44,15 @@ impl TokenBucket {
         }
     }
 
-    fn refill(&mut self) {
-        let now = Instant::now();
-        let dt = now.duration_since(self.last);
-        self.last = now;
+    /// Take `cost` tokens spent elsewhere (e.g. on other nodes); never goes below empty.
+    pub fn consume(&mut self, cost: u64, now: Instant) {
+        self.refill(now);
+        self.tokens = (self.tokens - cost as f64).max(0.0);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let dt = now.saturating_duration_since(self.last);
+        self.last = self.last.max(now);
         let add = dt.as_secs_f64() * self.refill_per_sec;
         self.tokens = (self.tokens + add).min(self.cap as f64);
     }
@@ -1,49 +1,272 @@
 use anyhow::{Context, Result};
+use ipnet::IpNet;
 use regex::Regex;
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
-use super::matcher::{AcMatcher, HeaderRegexMatcher};
-use super::rule::{Action, Rule, Ruleset};
+use super::matcher::{validate_regex, AcMatcher, ArgAcMatcher, ArgRegexMatcher, HeaderEqualsMatcher, HeaderRegexMatcher, RegexIndex};
+use super::seclang;
+use super::rule::{Action, ArgAc, DetectTarget, Rule, Ruleset, Severity};
+use crate::waf::normalizer::Transform;
 
 #[derive(Debug)]
 pub struct CompiledRule {
     pub id: String,
+    pub tags: Vec<String>,
     pub action: Action,
+    /// Anomaly score added when this rule matches in scoring mode.
+    pub score: u32,
     pub methods: Option<Vec<String>>,
     pub path_prefix: Option<Vec<String>>,
     pub uri_ac: Option<AcMatcher>,
+    /// Regex patterns live in the ruleset's per-phase `RegexIndex`es; kept here to know the condition is set.
+    pub uri_regex: Option<Vec<String>>,
+    pub args_ac: Option<AcMatcher>,
+    pub args_regex: Option<Vec<String>>,
+    pub arg_ac: Vec<ArgAcMatcher>,
+    pub arg_regex: Vec<ArgRegexMatcher>,
+    pub arg_names_ac: Option<AcMatcher>,
+    pub args_count_gt: Option<usize>,
+    pub arg_len_gt: Option<usize>,
     pub body_ac: Option<AcMatcher>,
-    pub _header_regex: Vec<HeaderRegexMatcher>,
+    /// `body_ac` applies to the response body too.
+    pub scan_response: bool,
+    pub body_regex: Option<Vec<String>>,
+    pub body_args_ac: Option<AcMatcher>,
+    pub body_arg_ac: Vec<ArgAcMatcher>,
+    pub body_filename_ac: Option<AcMatcher>,
+    pub header_regex: Vec<HeaderRegexMatcher>,
+    pub header_equals: Vec<HeaderEqualsMatcher>,
+    pub header_exists: Vec<String>,
+    pub header_absent: Vec<String>,
+    pub ip_match: Vec<IpNet>,
+    pub detect_sqli: Option<Vec<DetectTarget>>,
+    pub detect_xss: Option<Vec<DetectTarget>>,
+    /// Index into `CompiledRuleset::chains`.
+    pub chain: usize,
+}
+
+impl CompiledRule {
+    pub fn detect_targets(&self) -> impl Iterator<Item = &DetectTarget> {
+        self.detect_sqli.iter().chain(self.detect_xss.iter()).flatten()
+    }
+}
+
+/// A distinct transformation chain shared by one or more rules.
+#[derive(Debug, Default)]
+pub struct TransformChain {
+    pub transforms: Vec<Transform>,
+    /// Header names whose values rules on this chain compare.
+    pub headers: Vec<String>,
+    /// `uri_regex` / `args_regex` of all rules on this chain.
+    pub uri_re: Option<RegexIndex>,
+    pub args_re: Option<RegexIndex>,
 }
 
 #[derive(Debug)]
 pub struct CompiledRuleset {
     pub version: Option<String>,
     pub rules: Vec<CompiledRule>,
+    /// `chains[0]` is always the empty (raw) chain.
+    pub chains: Vec<TransformChain>,
+    /// `body_regex` of all rules; bodies are matched raw, so one set per ruleset.
+    pub body_re: Option<RegexIndex>,
 }
 
 impl CompiledRuleset {
     pub fn compile(yaml: &str) -> Result<Self> {
         let rs: Ruleset = serde_yaml::from_str(yaml).context("parse rules yaml")?;
-        let mut rules = Vec::with_capacity(rs.rules.len());
-        for r in rs.rules {
-            rules.push(compile_rule(&r)?);
+        Self::from_rules(rs.version, rs.rules)
+    }
+
+    /// Compile already-parsed rules; shared by the YAML and SecLang loaders.
+    pub fn from_rules(version: Option<String>, src: Vec<Rule>) -> Result<Self> {
+        let mut rules = Vec::with_capacity(src.len());
+        let mut chains = vec![TransformChain::default()];
+        for r in src {
+            let mut cr = compile_rule(&r)?;
+            cr.chain = intern_chain(&mut chains, r.transforms.as_deref().unwrap_or(&[]), &cr);
+            rules.push(cr);
         }
+
+        for (ci, chain) in chains.iter_mut().enumerate() {
+            let on_chain = || rules.iter().enumerate().filter(move |(_, r)| r.chain == ci);
+            chain.uri_re = RegexIndex::build(collect_patterns(on_chain(), |r| &r.uri_regex))
+                .context("compile uri_regex set")?;
+            chain.args_re = RegexIndex::build(collect_patterns(on_chain(), |r| &r.args_regex))
+                .context("compile args_regex set")?;
+        }
+        let body_re = RegexIndex::build(collect_patterns(rules.iter().enumerate(), |r| &r.body_regex))
+            .context("compile body_regex set")?;
+
         Ok(Self {
-            version: rs.version,
+            version,
             rules,
+            chains,
+            body_re,
         })
     }
+
+    /// Indexes of rules with one of `ids` or carrying one of `tags`.
+    pub fn select<'s>(&'s self, ids: &'s [String], tags: &'s [String]) -> impl Iterator<Item = usize> + 's {
+        self.rules
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| ids.contains(&r.id) || r.tags.iter().any(|t| tags.contains(t)))
+            .map(|(i, _)| i)
+    }
+}
+
+/// Dedupe chains so the engine transforms each target once per chain, not once per rule.
+fn intern_chain(chains: &mut Vec<TransformChain>, transforms: &[Transform], rule: &CompiledRule) -> usize {
+    let idx = match chains.iter().position(|c| c.transforms == transforms) {
+        Some(i) => i,
+        None => {
+            chains.push(TransformChain {
+                transforms: transforms.to_vec(),
+                ..Default::default()
+            });
+            chains.len() - 1
+        }
+    };
+
+    let names = rule
+        .header_regex
+        .iter()
+        .map(|h| &h.name)
+        .chain(rule.header_equals.iter().map(|h| &h.name))
+        .chain(rule.header_exists.iter())
+        .chain(rule.header_absent.iter())
+        .chain(rule.detect_targets().filter_map(|t| match t {
+            DetectTarget::Header(n) => Some(n),
+            _ => None,
+        }));
+    let cookie = "cookie".to_string();
+    let cookies = rule
+        .detect_targets()
+        .any(|t| matches!(t, DetectTarget::Cookies | DetectTarget::Cookie(_)))
+        .then_some(&cookie);
+    for n in names.chain(cookies) {
+        if !chains[idx].headers.contains(n) {
+            chains[idx].headers.push(n.clone());
+        }
+    }
+    idx
 }
 
+/// (rule index, pattern) pairs for one regex condition, ready for `RegexIndex::build`.
+fn collect_patterns<'a>(
+    rules: impl Iterator<Item = (usize, &'a CompiledRule)>,
+    field: impl Fn(&CompiledRule) -> &Option<Vec<String>>,
+) -> Vec<(usize, String)> {
+    rules
+        .flat_map(|(i, r)| field(r).iter().flatten().map(move |p| (i, p.clone())))
+        .collect()
+}
+
+/// Compile a rules file: `.conf` files are SecLang, anything else is YAML.
 pub fn compile_from_file(path: &Path) -> Result<CompiledRuleset> {
-    let yaml = std::fs::read_to_string(path).with_context(|| format!("read rules file: {}", path.display()))?;
-    CompiledRuleset::compile(&yaml)
+    let text = std::fs::read_to_string(path).with_context(|| format!("read rules file: {}", path.display()))?;
+    if path.extension().and_then(|s| s.to_str()) != Some("conf") {
+        return CompiledRuleset::compile(&text);
+    }
+    let (rs, warnings) = seclang::compile(&text)?;
+    for w in warnings {
+        tracing::warn!("seclang {}: {}", path.display(), w);
+    }
+    Ok(rs)
+}
+
+/// Ruleset used by policies that don't set `waf.ruleset`.
+pub const DEFAULT_RULESET: &str = "default";
+
+/// Where named rulesets come from.
+#[derive(Debug, Clone)]
+pub enum RulesSource {
+    /// One ruleset per `<dir>/<name>.yaml` (or SecLang `<name>.conf`), named by file stem.
+    Dir(PathBuf),
+    /// Legacy single rules file, served as the `default` ruleset.
+    File(PathBuf),
+}
+
+impl RulesSource {
+    /// List (ruleset name, file path) pairs currently present on disk.
+    pub fn files(&self) -> Result<Vec<(String, PathBuf)>> {
+        match self {
+            RulesSource::File(p) => Ok(vec![(DEFAULT_RULESET.to_string(), p.clone())]),
+            RulesSource::Dir(dir) => {
+                let rd = std::fs::read_dir(dir).with_context(|| format!("read rules dir: {}", dir.display()))?;
+                let mut out = Vec::new();
+                for ent in rd {
+                    let path = ent?.path();
+                    if !matches!(path.extension().and_then(|s| s.to_str()), Some("yaml" | "yml" | "conf")) {
+                        continue;
+                    }
+                    let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+                        continue;
+                    };
+                    out.push((name.to_string(), path.clone()));
+                }
+                Ok(out)
+            }
+        }
+    }
+}
+
+pub fn compile_source(src: &RulesSource) -> Result<HashMap<String, CompiledRuleset>> {
+    let mut map = HashMap::new();
+    for (name, path) in src.files()? {
+        let rs = compile_from_file(&path).with_context(|| format!("compile ruleset '{}'", name))?;
+        if map.insert(name.clone(), rs).is_some() {
+            anyhow::bail!("duplicate ruleset name '{}' ({})", name, path.display());
+        }
+    }
+    Ok(map)
+}
+
+/// Validate a single rule without building a ruleset (lets loaders skip bad rules one by one).
+pub fn check_rule(r: &Rule) -> Result<()> {
+    compile_rule(r).map(|_| ())
 }
 
 fn compile_rule(r: &Rule) -> Result<CompiledRule> {
     let uri_ac = r.when.uri_ac.as_ref().map(|p| AcMatcher::new(p));
     let body_ac = r.when.body_ac.as_ref().map(|p| AcMatcher::new(p));
+    let args_ac = r.when.args_ac.as_ref().map(|p| AcMatcher::new(p));
+    let arg_names_ac = r.when.arg_names_ac.as_ref().map(|p| AcMatcher::new(p));
+    let body_args_ac = r.when.body_args_ac.as_ref().map(|p| AcMatcher::new(p));
+    let body_filename_ac = r.when.body_filename_ac.as_ref().map(|p| AcMatcher::new(p));
+    let arg_ac = compile_arg_ac(&r.when.arg_ac);
+    let body_arg_ac = compile_arg_ac(&r.when.body_arg_ac);
+
+    for (field, pats) in [
+        ("uri_regex", &r.when.uri_regex),
+        ("args_regex", &r.when.args_regex),
+        ("body_regex", &r.when.body_regex),
+    ] {
+        for p in pats.iter().flatten() {
+            validate_regex(p).with_context(|| format!("invalid {} '{}' in rule {}", field, p, r.id))?;
+        }
+    }
+
+    let targets = || r.when.detect_sqli.iter().chain(r.when.detect_xss.iter()).flatten();
+    if targets().any(|t| t.is_body()) && !targets().all(|t| t.is_body()) {
+        anyhow::bail!("rule {}: detect targets mix body and non-body targets, split it into two rules", r.id);
+    }
+    // body conditions defer the rule to the body phase, which only sees the parsed body
+    let body_conds = r.when.body_args_ac.is_some()
+        || r.when.body_arg_ac.as_ref().is_some_and(|v| !v.is_empty())
+        || r.when.body_regex.is_some()
+        || r.when.body_filename_ac.is_some();
+    if body_conds && targets().any(|t| !t.is_body()) {
+        anyhow::bail!("rule {}: non-body detect targets can't be combined with body conditions, split it into two rules", r.id);
+    }
+
+    let mut ip_match = Vec::new();
+    for s in r.when.ip_match.iter().flatten() {
+        let net = parse_ip_net(s).with_context(|| format!("invalid ip_match '{}' in rule {}", s, r.id))?;
+        ip_match.push(net);
+    }
 
     let mut header_regex = Vec::new();
     if let Some(v) = &r.when.header_regex {
@@ -51,15 +274,35 @@ fn compile_rule(r: &Rule) -> Result<CompiledRule> {
             let re = Regex::new(&hr.pattern)
                 .with_context(|| format!("invalid regex for header {} in rule {}", hr.name, r.id))?;
             header_regex.push(HeaderRegexMatcher {
-                _name: hr.name.to_ascii_lowercase(),
+                name: hr.name.to_ascii_lowercase(),
                 re,
             });
         }
     }
 
+    let mut arg_regex = Vec::new();
+    for ar in r.when.arg_regex.iter().flatten() {
+        let re = Regex::new(&ar.pattern)
+            .with_context(|| format!("invalid regex for arg {} in rule {}", ar.name, r.id))?;
+        arg_regex.push(ArgRegexMatcher { name: ar.name.clone(), re });
+    }
+
+    let header_equals = r
+        .when
+        .header_equals
+        .iter()
+        .flatten()
+        .map(|he| HeaderEqualsMatcher {
+            name: he.name.to_ascii_lowercase(),
+            value: he.value.clone(),
+        })
+        .collect();
+
     Ok(CompiledRule {
         id: r.id.clone(),
+        tags: r.tags.clone().unwrap_or_default(),
         action: r.action.clone(),
+        score: r.score.unwrap_or_else(|| r.severity.unwrap_or(Severity::Critical).score()),
         methods: r
             .when
             .methods
@@ -67,7 +310,168 @@ fn compile_rule(r: &Rule) -> Result<CompiledRule> {
             .map(|ms| ms.into_iter().map(|m| m.to_ascii_uppercase()).collect()),
         path_prefix: r.when.path_prefix.clone(),
         uri_ac,
+        uri_regex: r.when.uri_regex.clone(),
+        args_ac,
+        args_regex: r.when.args_regex.clone(),
+        arg_ac,
+        arg_regex,
+        arg_names_ac,
+        args_count_gt: r.when.args_count_gt,
+        arg_len_gt: r.when.arg_len_gt,
         body_ac,
-        _header_regex:header_regex,
+        scan_response: r.scan_response.unwrap_or(true),
+        body_regex: r.when.body_regex.clone(),
+        body_args_ac,
+        body_arg_ac,
+        body_filename_ac,
+        header_regex,
+        header_equals,
+        header_exists: lowercase_names(&r.when.header_exists),
+        header_absent: lowercase_names(&r.when.header_absent),
+        ip_match,
+        detect_sqli: r.when.detect_sqli.clone(),
+        detect_xss: r.when.detect_xss.clone(),
+        chain: 0,
     })
 }
+
+fn lowercase_names(names: &Option<Vec<String>>) -> Vec<String> {
+    names
+        .iter()
+        .flatten()
+        .map(|n| n.to_ascii_lowercase())
+        .collect()
+}
+
+fn compile_arg_ac(v: &Option<Vec<ArgAc>>) -> Vec<ArgAcMatcher> {
+    v.iter()
+        .flatten()
+        .map(|a| ArgAcMatcher {
+            name: a.name.clone(),
+            ac: AcMatcher::new(&a.patterns),
+        })
+        .collect()
+}
+
+/// `10.0.0.0/8`, `2001:db8::/32`, or a bare address as a single-host network.
+fn parse_ip_net(s: &str) -> Result<IpNet> {
+    let s = s.trim();
+    match s.parse::<IpNet>() {
+        Ok(n) => Ok(n),
+        Err(_) => Ok(IpNet::from(s.parse::<std::net::IpAddr>()?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fresh rules dir holding `files` (name, contents).
+    fn rules_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("waf-rules-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (f, text) in files {
+            std::fs::write(dir.join(f), text).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn rules_dir_names_rulesets_by_file_stem() {
+        let dir = rules_dir(
+            "stems",
+            &[
+                ("default.yaml", "rules:\n  - { id: d-1, when: { uri_ac: [x] }, action: block }\n"),
+                ("strict.yml", "rules:\n  - { id: s-1, when: { uri_ac: [x] }, action: block }\n  - { id: s-2, when: { uri_ac: [y] }, action: log }\n"),
+                ("crs.conf", "SecRule REQUEST_URI \"@contains /etc/passwd\" \"id:930120,phase:1,deny\"\n"),
+                ("notes.txt", "not a ruleset"),
+            ],
+        );
+        let map = compile_source(&RulesSource::Dir(dir.clone())).unwrap();
+        let mut names: Vec<_> = map.keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, ["crs", "default", "strict"]);
+        assert_eq!(map["strict"].rules.len(), 2);
+        assert_eq!(map["crs"].rules[0].id, "930120");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn duplicate_ruleset_stem_is_rejected() {
+        let dir = rules_dir(
+            "dup",
+            &[
+                ("shop.yaml", "rules:\n  - { id: y-1, when: { uri_ac: [x] }, action: block }\n"),
+                ("shop.conf", "SecRule REQUEST_URI \"@contains x\" \"id:1,phase:1,deny\"\n"),
+            ],
+        );
+        let err = format!("{:#}", compile_source(&RulesSource::Dir(dir.clone())).unwrap_err());
+        assert!(err.contains("duplicate ruleset name 'shop'"), "{err}");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn compile_err(when: &str) -> String {
+        let err = CompiledRuleset::compile(&format!("rules:\n  - {{ id: ok-1, when: {{ uri_ac: [x] }}, action: block }}\n  - {{ id: bad-7, when: {when}, action: block }}\n")).unwrap_err();
+        format!("{err:#}")
+    }
+
+    #[test]
+    fn invalid_regex_names_the_rule() {
+        for (field, when) in [
+            ("uri_regex", r#"{ uri_regex: ["^/ok", "(unclosed"] }"#),
+            ("args_regex", r#"{ args_regex: ["[z-a]"] }"#),
+            ("body_regex", r#"{ body_regex: ["a{2,1}"] }"#),
+            ("header user-agent", r#"{ header_regex: [{ name: user-agent, pattern: "(" }] }"#),
+            ("arg id", r#"{ arg_regex: [{ name: id, pattern: "*" }] }"#),
+        ] {
+            let err = compile_err(when);
+            assert!(err.contains("in rule bad-7"), "{when}: {err}");
+            assert!(err.contains(field), "{when}: {err}");
+        }
+        // over the per-pattern size limit
+        let err = compile_err(r#"{ uri_regex: ["\\w{1000}{1000}"] }"#);
+        assert!(err.contains("in rule bad-7") && err.contains("size limit"), "{err}");
+    }
+
+    #[test]
+    fn regex_sets_map_hits_back_to_rules() {
+        let rs = CompiledRuleset::compile(
+            r#"
+rules:
+  - { id: "admin", when: { uri_regex: ["^/admin", "^/manage"] }, action: block }
+  - { id: "plain", when: { uri_ac: ["x"] }, action: block }
+  - { id: "php", when: { uri_regex: ["\\.php$"], args_regex: ["^[0-9]+$"] }, action: block }
+  - { id: "lower", when: { uri_regex: ["^/wp-login"] }, transforms: [lowercase], action: block }
+  - { id: "lower-args", when: { args_regex: ["select"] }, transforms: [lowercase], action: block }
+  - { id: "body-a", when: { body_regex: ["^a"] }, action: block }
+  - { id: "body-b", when: { body_regex: ["b$"] }, transforms: [lowercase], action: block }
+"#,
+        )
+        .unwrap();
+        let uri = |chain: usize, path: &str| {
+            let mut hits: Vec<_> = rs.chains[chain].uri_re.as_ref().unwrap().matched_rules([path.as_bytes()]).into_iter().collect();
+            hits.sort();
+            hits
+        };
+
+        // one set per transform chain, each pattern owned by its rule
+        assert_eq!(rs.chains.len(), 2);
+        assert_eq!(uri(0, "/admin/x.php"), [0, 2]);
+        assert_eq!(uri(0, "/manage"), [0]);
+        assert_eq!(uri(0, "/index.php"), [2]);
+        assert_eq!(uri(0, "/wp-login"), Vec::<usize>::new());
+        assert_eq!(uri(1, "/wp-login"), [3]);
+        assert_eq!(uri(1, "/admin"), Vec::<usize>::new());
+
+        let args = |chain: usize, vals: &[&str]| rs.chains[chain].args_re.as_ref().unwrap().matched_rules(vals.iter().map(|v| v.as_bytes()));
+        assert_eq!(args(0, &["abc", "42"]), [2].into());
+        assert_eq!(args(1, &["union select"]), [4].into());
+
+        // bodies are matched raw, so body_regex shares one set across chains
+        let body = |raw: &str| rs.body_re.as_ref().unwrap().matched_rules([raw.as_bytes()]);
+        assert_eq!(body("ab"), [5, 6].into());
+        assert_eq!(body("xb"), [6].into());
+        assert!(body("c").is_empty());
+    }
+}
@@ -1,6 +1,15 @@
+use std::collections::HashSet;
+
 use aho_corasick::{AhoCorasick, AhoCorasickBuilder};
+use anyhow::{Context, Result};
+use regex::bytes::{RegexBuilder, RegexSet, RegexSetBuilder};
 use regex::Regex;
 
+/// Compiled program size cap for a single rule pattern.
+pub const REGEX_SIZE_LIMIT: usize = 1 << 20;
+/// Cap for a whole per-phase set.
+pub const REGEX_SET_SIZE_LIMIT: usize = 32 << 20;
+
 #[derive(Debug)]
 pub struct AcMatcher {
     ac: AhoCorasick,
@@ -30,6 +39,66 @@ impl AcMatcher {
 
 #[derive(Debug)]
 pub struct HeaderRegexMatcher {
-    pub _name: String,
+    pub name: String,
     pub re: Regex,
 }
+
+#[derive(Debug)]
+pub struct HeaderEqualsMatcher {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug)]
+pub struct ArgRegexMatcher {
+    pub name: String,
+    pub re: Regex,
+}
+
+#[derive(Debug)]
+pub struct ArgAcMatcher {
+    pub name: String,
+    pub ac: AcMatcher,
+}
+
+/// Check one rule pattern on its own, so errors point at the rule rather than the batched set.
+pub fn validate_regex(pattern: &str) -> Result<()> {
+    RegexBuilder::new(pattern)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .build()
+        .map(|_| ())
+        .map_err(Into::into)
+}
+
+/// Regex conditions of many rules batched into one `RegexSet`; a single scan per target
+/// tells which rules matched, however many rules there are.
+#[derive(Debug)]
+pub struct RegexIndex {
+    set: RegexSet,
+    /// Rule index (into `CompiledRuleset::rules`) owning each pattern of `set`.
+    owners: Vec<usize>,
+}
+
+impl RegexIndex {
+    /// `patterns` are (rule index, pattern). Returns None when there is nothing to match.
+    pub fn build(patterns: Vec<(usize, String)>) -> Result<Option<Self>> {
+        if patterns.is_empty() {
+            return Ok(None);
+        }
+        let (owners, pats): (Vec<usize>, Vec<String>) = patterns.into_iter().unzip();
+        let set = RegexSetBuilder::new(&pats)
+            .size_limit(REGEX_SET_SIZE_LIMIT)
+            .build()
+            .context("build regex set")?;
+        Ok(Some(Self { set, owners }))
+    }
+
+    /// Rules with at least one pattern matching any of `hays`.
+    pub fn matched_rules<'h>(&self, hays: impl IntoIterator<Item = &'h [u8]>) -> HashSet<usize> {
+        let mut out = HashSet::new();
+        for hay in hays {
+            out.extend(self.set.matches(hay).iter().map(|i| self.owners[i]));
+        }
+        out
+    }
+}
@@ -1,3 +1,4 @@
 pub mod compiler;
 pub mod matcher;
 pub mod rule;
+pub mod seclang;
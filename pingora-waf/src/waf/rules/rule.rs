@@ -1,5 +1,7 @@
 use serde::Deserialize;
 
+use crate::waf::normalizer::Transform;
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Ruleset {
     pub version: Option<String>,
@@ -11,16 +13,124 @@ pub struct Rule {
     pub id: String,
     pub _description: Option<String>,
     pub when: When,
+    /// Applied to URI, args, header values and parsed body args before matching. `body_ac` is matched raw.
+    pub transforms: Option<Vec<Transform>>,
+    /// `body_ac` also scans the upstream response body (default true); only block / challenge rules do.
+    pub scan_response: Option<bool>,
     pub action: Action,
+    /// Anomaly scoring: contribution when the policy scores instead of blocking on first match.
+    /// `score` wins over `severity`; with neither the rule counts as critical.
+    pub severity: Option<Severity>,
+    pub score: Option<u32>,
+    /// Free-form labels (`attack-sqli`, `paranoia-level/2`); policy exclusions can remove rules by tag.
+    pub tags: Option<Vec<String>>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct When {
     pub methods: Option<Vec<String>>,
     pub path_prefix: Option<Vec<String>>,
     pub uri_ac: Option<Vec<String>>,
+    /// Path or raw query matches one of the regexes.
+    pub uri_regex: Option<Vec<String>>,
+    /// Any decoded query argument value contains one of the patterns.
+    pub args_ac: Option<Vec<String>>,
+    /// Any decoded query argument value matches one of the regexes.
+    pub args_regex: Option<Vec<String>>,
+    /// Values of the named query argument contain one of the patterns.
+    pub arg_ac: Option<Vec<ArgAc>>,
+    /// Values of the named query argument match the regex (case-sensitive unless the pattern says `(?i)`).
+    pub arg_regex: Option<Vec<ArgRegex>>,
+    /// Any query argument name contains one of the patterns.
+    pub arg_names_ac: Option<Vec<String>>,
+    /// More than N query arguments.
+    pub args_count_gt: Option<usize>,
+    /// Some query argument value is longer than N bytes.
+    pub arg_len_gt: Option<usize>,
     pub body_ac: Option<Vec<String>>,
+    /// The whole request body (buffered, up to the policy's `waf.body.max_bytes`) matches one of the regexes.
+    pub body_regex: Option<Vec<String>>,
+    /// Any parsed body argument value (form field, JSON scalar, multipart field) contains one of the patterns.
+    pub body_args_ac: Option<Vec<String>>,
+    /// Values of the named body argument contain one of the patterns; JSON values are named by path (`user.name`, `items[0]`).
+    pub body_arg_ac: Option<Vec<ArgAc>>,
+    /// Any multipart upload filename contains one of the patterns.
+    pub body_filename_ac: Option<Vec<String>>,
     pub header_regex: Option<Vec<HeaderRegex>>,
+    pub header_equals: Option<Vec<HeaderEq>>,
+    pub header_exists: Option<Vec<String>>,
+    pub header_absent: Option<Vec<String>>,
+    /// Client IP is in one of the networks (`10.0.0.0/8`) or addresses.
+    pub ip_match: Option<Vec<String>>,
+    /// Native SQL injection detector on any of the listed targets.
+    pub detect_sqli: Option<Vec<DetectTarget>>,
+    /// Native XSS detector on any of the listed targets.
+    pub detect_xss: Option<Vec<DetectTarget>>,
+}
+
+/// Where a detector looks: `path`, `query`, `args`, `arg:<name>`, `header:<name>`, `cookies`,
+/// `cookie:<name>`, `body_args`, `body_arg:<name>`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum DetectTarget {
+    Path,
+    Query,
+    Args,
+    Arg(String),
+    Header(String),
+    Cookies,
+    Cookie(String),
+    BodyArgs,
+    BodyArg(String),
+}
+
+impl DetectTarget {
+    /// Body targets are only known once the body is buffered and parsed.
+    pub fn is_body(&self) -> bool {
+        matches!(self, DetectTarget::BodyArgs | DetectTarget::BodyArg(_))
+    }
+}
+
+impl TryFrom<String> for DetectTarget {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let t = match s.split_once(':') {
+            None => match s.as_str() {
+                "path" => DetectTarget::Path,
+                "query" => DetectTarget::Query,
+                "args" => DetectTarget::Args,
+                "cookies" => DetectTarget::Cookies,
+                "body_args" => DetectTarget::BodyArgs,
+                _ => return Err(format!("unknown detect target '{s}'")),
+            },
+            Some((kind, name)) if !name.is_empty() => match kind {
+                "arg" => DetectTarget::Arg(name.to_string()),
+                "header" => DetectTarget::Header(name.to_ascii_lowercase()),
+                "cookie" => DetectTarget::Cookie(name.to_string()),
+                "body_arg" => DetectTarget::BodyArg(name.to_string()),
+                _ => return Err(format!("unknown detect target '{s}'")),
+            },
+            Some(_) => return Err(format!("detect target '{s}' needs a name")),
+        };
+        Ok(t)
+    }
+}
+
+impl std::fmt::Display for DetectTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DetectTarget::Path => f.write_str("path"),
+            DetectTarget::Query => f.write_str("query"),
+            DetectTarget::Args => f.write_str("args"),
+            DetectTarget::Arg(n) => write!(f, "arg:{n}"),
+            DetectTarget::Header(n) => write!(f, "header:{n}"),
+            DetectTarget::Cookies => f.write_str("cookies"),
+            DetectTarget::Cookie(n) => write!(f, "cookie:{n}"),
+            DetectTarget::BodyArgs => f.write_str("body_args"),
+            DetectTarget::BodyArg(n) => write!(f, "body_arg:{n}"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -29,10 +139,51 @@ pub struct HeaderRegex {
     pub pattern: String,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct ArgAc {
+    pub name: String,
+    pub patterns: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ArgRegex {
+    pub name: String,
+    pub pattern: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HeaderEq {
+    pub name: String,
+    pub value: String,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Action {
     Allow,
     Block,
     Challenge,
+    /// Record a log event and keep evaluating.
+    Log,
+}
+
+/// CRS severities and their default anomaly scores.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Critical,
+    Error,
+    Warning,
+    Notice,
+}
+
+impl Severity {
+    pub fn score(self) -> u32 {
+        match self {
+            Severity::Critical => 5,
+            Severity::Error => 4,
+            Severity::Warning => 3,
+            Severity::Notice => 2,
+        }
+    }
 }
@@ -0,0 +1,690 @@
+//! Loader for a subset of ModSecurity SecLang, so CRS-style `.conf` files compile into the
+//! same `CompiledRuleset` as `rules.yaml`.
+//!
+//! Supported:
+//! - `SecRule VARIABLES "OPERATOR" "ACTIONS"`, with `\` line continuations and `#` comments.
+//! - Variables: `ARGS` / `ARGS_GET` (query arguments), `ARGS:name`, `REQUEST_HEADERS:name`,
+//!   `REQUEST_URI`, `REQUEST_BODY`, `REMOTE_ADDR`, joined with `|`.
+//! - Operators: `@rx` (also the default), `@pm`, `@contains`, `@streq`, `@ipMatch`.
+//! - Actions: `id`, `phase` (1 and 2), `deny` / `block`, `pass`, `allow`, `log`, `msg`,
+//!   `severity`, `tag`, `t:` and `chain`.
+//!
+//! Anything else is reported as a line-numbered warning; rules that can't be expressed
+//! faithfully are skipped rather than loaded with different semantics.
+
+use anyhow::Result;
+
+use super::compiler::{check_rule, CompiledRuleset};
+use super::rule::{Action, ArgAc, ArgRegex, HeaderEq, HeaderRegex, Rule, Severity, When};
+use crate::waf::normalizer::Transform;
+
+/// Alternatives a rule may expand into (`ARGS|REQUEST_URI` across chained links).
+const MAX_ALTERNATIVES: usize = 32;
+
+/// Actions that only carry metadata for ModSecurity's own logging; accepted without effect.
+const METADATA_ACTIONS: &[&str] = &[
+    "nolog", "auditlog", "noauditlog", "capture", "rev", "ver", "maturity", "accuracy", "logdata",
+];
+
+#[derive(Debug, Clone)]
+pub struct SecLangWarning {
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for SecLangWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Parse and compile a SecLang file. Unsupported constructs come back as warnings.
+pub fn compile(text: &str) -> Result<(CompiledRuleset, Vec<SecLangWarning>)> {
+    let (rules, warnings) = parse(text);
+    Ok((CompiledRuleset::from_rules(None, rules)?, warnings))
+}
+
+/// Translate SecLang into rules. Every rule returned compiles on its own.
+pub fn parse(text: &str) -> (Vec<Rule>, Vec<SecLangWarning>) {
+    let mut p = Parser::default();
+    let mut chain: Vec<Link> = Vec::new();
+
+    for (line, logical) in logical_lines(text) {
+        let tokens = match tokenize(&logical) {
+            Ok(t) => t,
+            Err(e) => {
+                p.warn(line, e);
+                continue;
+            }
+        };
+        let Some(directive) = tokens.first() else {
+            continue;
+        };
+        if !directive.eq_ignore_ascii_case("SecRule") {
+            p.warn(line, format!("directive {directive} not supported, skipped"));
+            continue;
+        }
+
+        let more = tokens.get(3).is_some_and(|a| split_actions(a).contains(&"chain"));
+        let link = match p.parse_sec_rule(line, &tokens[1..]) {
+            Ok(l) => l,
+            Err(e) => {
+                // a broken link takes the whole chain down with it
+                p.warn(line, format!("{e}, rule skipped"));
+                chain.clear();
+                p.skip_chain = more;
+                continue;
+            }
+        };
+        if p.skip_chain {
+            p.skip_chain = more;
+            continue;
+        }
+        chain.push(link);
+        if !more {
+            p.finish(std::mem::take(&mut chain));
+        }
+    }
+    if let Some(head) = chain.first() {
+        p.warn(head.line, "chain has no final rule, skipped");
+    }
+    (p.rules, p.warnings)
+}
+
+#[derive(Default)]
+struct Parser {
+    rules: Vec<Rule>,
+    warnings: Vec<SecLangWarning>,
+    /// Remaining links of a chain whose earlier link failed to parse.
+    skip_chain: bool,
+}
+
+struct Link {
+    line: usize,
+    /// One condition per usable variable; the link matches if any does.
+    conds: Vec<Cond>,
+    actions: Actions,
+}
+
+#[derive(Default)]
+struct Actions {
+    id: Option<String>,
+    phase: Option<String>,
+    action: Option<Action>,
+    msg: Option<String>,
+    severity: Option<Severity>,
+    transforms: Option<Vec<Transform>>,
+    tags: Vec<String>,
+    chain: bool,
+}
+
+/// A single `when` condition produced by one variable / operator pair.
+#[derive(Clone)]
+enum Cond {
+    UriAc(Vec<String>),
+    UriRegex(String),
+    ArgsAc(Vec<String>),
+    ArgsRegex(String),
+    ArgAc(ArgAc),
+    ArgRegex(ArgRegex),
+    BodyAc(Vec<String>),
+    BodyRegex(String),
+    HeaderRegex(HeaderRegex),
+    HeaderEquals(HeaderEq),
+    IpMatch(Vec<String>),
+}
+
+impl Cond {
+    fn is_body(&self) -> bool {
+        matches!(self, Cond::BodyAc(_) | Cond::BodyRegex(_))
+    }
+
+    /// AND this condition into `w`. Pattern lists in `when` are any-of, so a second
+    /// condition on the same field can't be expressed.
+    fn apply(self, w: &mut When) -> Result<(), &'static str> {
+        fn set<T>(slot: &mut Option<T>, v: T, field: &'static str) -> Result<(), &'static str> {
+            if slot.is_some() {
+                return Err(field);
+            }
+            *slot = Some(v);
+            Ok(())
+        }
+        match self {
+            Cond::UriAc(p) => set(&mut w.uri_ac, p, "uri_ac"),
+            Cond::UriRegex(p) => set(&mut w.uri_regex, vec![p], "uri_regex"),
+            Cond::ArgsAc(p) => set(&mut w.args_ac, p, "args_ac"),
+            Cond::ArgsRegex(p) => set(&mut w.args_regex, vec![p], "args_regex"),
+            Cond::BodyAc(p) => set(&mut w.body_ac, p, "body_ac"),
+            Cond::BodyRegex(p) => set(&mut w.body_regex, vec![p], "body_regex"),
+            Cond::IpMatch(p) => set(&mut w.ip_match, p, "ip_match"),
+            Cond::ArgAc(a) => {
+                w.arg_ac.get_or_insert_with(Vec::new).push(a);
+                Ok(())
+            }
+            Cond::ArgRegex(a) => {
+                w.arg_regex.get_or_insert_with(Vec::new).push(a);
+                Ok(())
+            }
+            Cond::HeaderRegex(h) => {
+                w.header_regex.get_or_insert_with(Vec::new).push(h);
+                Ok(())
+            }
+            Cond::HeaderEquals(h) => {
+                w.header_equals.get_or_insert_with(Vec::new).push(h);
+                Ok(())
+            }
+        }
+    }
+}
+
+enum Var {
+    Args,
+    Arg(String),
+    Header(String),
+    Uri,
+    Body,
+    RemoteAddr,
+}
+
+impl Parser {
+    fn warn(&mut self, line: usize, message: impl Into<String>) {
+        self.warnings.push(SecLangWarning {
+            line,
+            message: message.into(),
+        });
+    }
+
+    fn parse_sec_rule(&mut self, line: usize, args: &[String]) -> Result<Link, String> {
+        let (vars, op) = match args {
+            [vars, op] | [vars, op, _] => (vars, op),
+            _ => return Err(format!("SecRule expects 2 or 3 arguments, got {}", args.len())),
+        };
+        let actions = match args.get(2) {
+            Some(a) => self.parse_actions(line, a),
+            None => Actions::default(),
+        };
+        let (name, arg) = parse_operator(op)?;
+
+        let mut conds = Vec::new();
+        for var in vars.split('|').map(str::trim).filter(|v| !v.is_empty()) {
+            let parsed = parse_variable(var).and_then(|v| operator_cond(&v, var, name, arg));
+            match parsed {
+                Ok(c) => conds.push(c),
+                Err(e) => self.warn(line, e),
+            }
+        }
+        if conds.is_empty() {
+            return Err("no supported variable/operator combination".to_string());
+        }
+        Ok(Link { line, conds, actions })
+    }
+
+    fn parse_actions(&mut self, line: usize, s: &str) -> Actions {
+        let mut a = Actions::default();
+        for item in split_actions(s) {
+            let (key, value) = match item.split_once(':') {
+                Some((k, v)) => (k.trim(), Some(unquote(v.trim()))),
+                None => (item.trim(), None),
+            };
+            match (key, value) {
+                ("id", Some(v)) => a.id = Some(v.to_string()),
+                ("phase", Some(v)) => a.phase = Some(v.to_string()),
+                ("deny" | "block", None) => a.action = Some(Action::Block),
+                ("pass", None) => a.action = Some(Action::Log),
+                ("allow", None) => a.action = Some(Action::Allow),
+                ("log", None) => {}
+                ("msg", Some(v)) => a.msg = Some(v.to_string()),
+                ("severity", Some(v)) => match parse_severity(v) {
+                    Some(sev) => a.severity = Some(sev),
+                    None => self.warn(line, format!("unknown severity '{v}', ignored")),
+                },
+                ("t", Some(v)) => {
+                    let chain = a.transforms.get_or_insert_with(Vec::new);
+                    if v.eq_ignore_ascii_case("none") {
+                        chain.clear();
+                    } else {
+                        match parse_transform(v) {
+                            Some(t) => chain.push(t),
+                            None => self.warn(line, format!("transform t:{v} not supported, ignored")),
+                        }
+                    }
+                }
+                ("tag", Some(v)) => a.tags.push(v.to_string()),
+                ("chain", None) => a.chain = true,
+                (k, _) if METADATA_ACTIONS.contains(&k) => {}
+                ("", _) => {}
+                (k, _) => self.warn(line, format!("action '{k}' not supported, ignored")),
+            }
+        }
+        a
+    }
+
+    /// Turn a complete chain into rules: one per combination of the links' alternatives.
+    fn finish(&mut self, links: Vec<Link>) {
+        let head = &links[0];
+        let line = head.line;
+        let Some(id) = head.actions.id.clone() else {
+            self.warn(line, "rule without id, skipped");
+            return;
+        };
+        match head.actions.phase.as_deref() {
+            None | Some("1" | "2" | "request") => {}
+            Some(p) => {
+                self.warn(line, format!("rule {id}: phase {p} not supported, skipped"));
+                return;
+            }
+        }
+        let mut notes = Vec::new();
+        for l in &links[1..] {
+            if l.actions.id.is_some() || l.actions.phase.is_some() || l.actions.action.is_some() {
+                notes.push((l.line, format!("rule {id}: id/phase/disruptive action in chained rule ignored")));
+            }
+            if l.actions.transforms.is_some() && l.actions.transforms != head.actions.transforms {
+                notes.push((l.line, format!("rule {id}: chained rule transforms differ, using the first rule's")));
+            }
+        }
+        for (l, m) in notes {
+            self.warn(l, m);
+        }
+
+        // the request body is matched raw, so a transform chain there can't be honoured
+        let transformed = head.actions.transforms.as_ref().is_some_and(|t| !t.is_empty());
+        if transformed && links.iter().flat_map(|l| &l.conds).any(Cond::is_body) {
+            self.warn(line, format!("rule {id}: t: transforms on REQUEST_BODY not supported (matched raw), skipped"));
+            return;
+        }
+
+        let combos: usize = links.iter().map(|l| l.conds.len()).product();
+        if combos > MAX_ALTERNATIVES {
+            self.warn(line, format!("rule {id}: {combos} variable combinations (max {MAX_ALTERNATIVES}), skipped"));
+            return;
+        }
+
+        let mut whens = vec![When::default()];
+        for l in &links {
+            let mut next = Vec::with_capacity(whens.len() * l.conds.len());
+            for w in &whens {
+                for c in &l.conds {
+                    let mut w = w.clone();
+                    if let Err(field) = c.clone().apply(&mut w) {
+                        self.warn(l.line, format!("rule {id}: chain sets {field} twice, skipped"));
+                        return;
+                    }
+                    next.push(w);
+                }
+            }
+            whens = next;
+        }
+
+        let a = &head.actions;
+        for when in whens {
+            let rule = Rule {
+                id: id.clone(),
+                _description: a.msg.clone(),
+                when,
+                transforms: a.transforms.clone(),
+                // REQUEST_BODY is the request's body only
+                scan_response: Some(false),
+                action: a.action.clone().unwrap_or(Action::Log),
+                severity: a.severity,
+                score: None,
+                tags: (!a.tags.is_empty()).then(|| a.tags.clone()),
+            };
+            if let Err(e) = check_rule(&rule) {
+                // regex errors span several lines; their last line says what's wrong
+                let cause = e.root_cause().to_string();
+                let cause = cause.lines().last().unwrap_or_default().trim_start_matches("error: ");
+                self.warn(line, format!("{e}: {cause}, skipped"));
+                return;
+            }
+            self.rules.push(rule);
+        }
+    }
+}
+
+fn parse_variable(var: &str) -> Result<Var, String> {
+    if var.starts_with('!') {
+        return Err(format!("variable exclusion '{var}' not supported"));
+    }
+    if var.starts_with('&') {
+        return Err(format!("variable count '{var}' not supported"));
+    }
+    let (name, sel) = match var.split_once(':') {
+        Some((n, s)) => (n, Some(s)),
+        None => (var, None),
+    };
+    if sel.is_some_and(|s| s.starts_with('/')) {
+        return Err(format!("regex selector in '{var}' not supported"));
+    }
+    let v = match (name.to_ascii_uppercase().as_str(), sel) {
+        ("ARGS" | "ARGS_GET", None) => Var::Args,
+        ("ARGS" | "ARGS_GET", Some(n)) => Var::Arg(n.to_string()),
+        ("REQUEST_HEADERS", Some(n)) => Var::Header(n.to_ascii_lowercase()),
+        ("REQUEST_URI", None) => Var::Uri,
+        ("REQUEST_BODY", None) => Var::Body,
+        ("REMOTE_ADDR", None) => Var::RemoteAddr,
+        _ => return Err(format!("variable '{var}' not supported")),
+    };
+    Ok(v)
+}
+
+/// Split `@op argument`; a bare pattern means `@rx`.
+fn parse_operator(op: &str) -> Result<(&str, &str), String> {
+    if op.starts_with('!') {
+        return Err(format!("negated operator '{op}' not supported"));
+    }
+    let Some(rest) = op.strip_prefix('@') else {
+        return Ok(("rx", op));
+    };
+    let (name, arg) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    Ok((name, arg.trim()))
+}
+
+fn operator_cond(var: &Var, var_src: &str, op: &str, arg: &str) -> Result<Cond, String> {
+    let words = || arg.split_whitespace().map(str::to_string).collect::<Vec<_>>();
+    let literal = || regex::escape(arg);
+    let exact = || format!("^{}$", regex::escape(arg));
+    let c = match (var, op) {
+        (Var::Args, "rx") => Cond::ArgsRegex(arg.to_string()),
+        (Var::Args, "pm") => Cond::ArgsAc(words()),
+        (Var::Args, "contains") => Cond::ArgsRegex(literal()),
+        (Var::Args, "streq") => Cond::ArgsRegex(exact()),
+        (Var::Arg(name), "rx") => Cond::ArgRegex(ArgRegex {
+            name: name.clone(),
+            pattern: arg.to_string(),
+        }),
+        (Var::Arg(name), "pm") => Cond::ArgAc(ArgAc {
+            name: name.clone(),
+            patterns: words(),
+        }),
+        // @contains is case-sensitive, like the ARGS / URI / header forms (AC matching isn't)
+        (Var::Arg(name), "contains") => Cond::ArgRegex(ArgRegex {
+            name: name.clone(),
+            pattern: literal(),
+        }),
+        (Var::Arg(name), "streq") => Cond::ArgRegex(ArgRegex {
+            name: name.clone(),
+            pattern: exact(),
+        }),
+        (Var::Header(name), "rx") => header_regex(name, arg.to_string()),
+        (Var::Header(name), "pm") => {
+            let alts: Vec<String> = arg.split_whitespace().map(regex::escape).collect();
+            header_regex(name, format!("(?i)(?:{})", alts.join("|")))
+        }
+        (Var::Header(name), "contains") => header_regex(name, literal()),
+        (Var::Header(name), "streq") => Cond::HeaderEquals(HeaderEq {
+            name: name.clone(),
+            value: arg.to_string(),
+        }),
+        (Var::Uri, "rx") => Cond::UriRegex(arg.to_string()),
+        (Var::Uri, "pm") => Cond::UriAc(words()),
+        (Var::Uri, "contains") => Cond::UriRegex(literal()),
+        (Var::Uri, "streq") => Cond::UriRegex(exact()),
+        (Var::Body, "rx") => Cond::BodyRegex(arg.to_string()),
+        (Var::Body, "pm") => Cond::BodyAc(words()),
+        (Var::Body, "contains") => Cond::BodyRegex(literal()),
+        (Var::Body, "streq") => Cond::BodyRegex(exact()),
+        (Var::RemoteAddr, "ipMatch") => Cond::IpMatch(arg.split(',').map(|s| s.trim().to_string()).collect()),
+        (_, "rx" | "pm" | "contains" | "streq" | "ipMatch") => {
+            return Err(format!("operator @{op} not supported on {var_src}"));
+        }
+        _ => return Err(format!("operator @{op} not supported")),
+    };
+    Ok(c)
+}
+
+fn header_regex(name: &str, pattern: String) -> Cond {
+    Cond::HeaderRegex(HeaderRegex {
+        name: name.to_string(),
+        pattern,
+    })
+}
+
+fn parse_severity(v: &str) -> Option<Severity> {
+    let s = match v.to_ascii_uppercase().as_str() {
+        "0" | "1" | "2" | "EMERGENCY" | "ALERT" | "CRITICAL" => Severity::Critical,
+        "3" | "ERROR" => Severity::Error,
+        "4" | "WARNING" => Severity::Warning,
+        "5" | "6" | "7" | "NOTICE" | "INFO" | "DEBUG" => Severity::Notice,
+        _ => return None,
+    };
+    Some(s)
+}
+
+fn parse_transform(v: &str) -> Option<Transform> {
+    let t = match v {
+        "urlDecode" => Transform::UrlDecode,
+        "urlDecodeUni" => Transform::UrlDecodeUni,
+        "htmlEntityDecode" => Transform::HtmlEntityDecode,
+        "lowercase" => Transform::Lowercase,
+        "removeNulls" => Transform::RemoveNulls,
+        "normalizePath" | "normalisePath" => Transform::NormalizePath,
+        "compressWhitespace" => Transform::CompressWhitespace,
+        "base64Decode" => Transform::Base64Decode,
+        _ => return None,
+    };
+    Some(t)
+}
+
+/// Join `\`-continued lines and drop comments; yields (first physical line number, text).
+fn logical_lines(text: &str) -> Vec<(usize, String)> {
+    let mut out = Vec::new();
+    let mut cur: Option<(usize, String)> = None;
+    for (i, raw) in text.lines().enumerate() {
+        let trimmed = raw.trim();
+        if cur.is_none() && (trimmed.is_empty() || trimmed.starts_with('#')) {
+            continue;
+        }
+        let (cont, body) = match trimmed.strip_suffix('\\') {
+            Some(b) => (true, b),
+            None => (false, trimmed),
+        };
+        let entry = cur.get_or_insert_with(|| (i + 1, String::new()));
+        if !entry.1.is_empty() {
+            entry.1.push(' ');
+        }
+        entry.1.push_str(body.trim());
+        if !cont {
+            out.extend(cur.take());
+        }
+    }
+    out.extend(cur);
+    out
+}
+
+/// Split a directive into whitespace-separated arguments; `"..."` groups, `\"` is a literal quote.
+/// Other backslash escapes are kept as is, since they usually belong to a regex.
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut out = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut tok = String::new();
+        if c == '"' {
+            chars.next();
+            let mut closed = false;
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' if chars.peek() == Some(&'"') => {
+                        tok.push('"');
+                        chars.next();
+                    }
+                    '"' => {
+                        closed = true;
+                        break;
+                    }
+                    c => tok.push(c),
+                }
+            }
+            if !closed {
+                return Err("unterminated quoted argument".to_string());
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                tok.push(c);
+                chars.next();
+            }
+        }
+        out.push(tok);
+    }
+    Ok(out)
+}
+
+/// Split an action list on commas outside single quotes (`msg:'a, b'`).
+fn split_actions(s: &str) -> Vec<&str> {
+    let mut out = Vec::new();
+    let mut in_quote = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '\'' => in_quote = !in_quote,
+            ',' if !in_quote => {
+                out.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    out.push(s[start..].trim());
+    out
+}
+
+fn unquote(v: &str) -> &str {
+    v.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')).unwrap_or(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn warned(w: &[SecLangWarning], line: usize, needle: &str) -> bool {
+        w.iter().any(|w| w.line == line && w.message.contains(needle))
+    }
+
+    #[test]
+    fn joins_continuations_and_unescapes_quotes() {
+        let text = "# comment\n\nSecRule ARGS \"@rx a\\\"b\" \\\n    \"id:1,\\\n    deny\"\nSecRule REQUEST_URI \"@streq /x\" \"id:2,deny\"\n";
+        let lines = logical_lines(text);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].0, 3);
+        assert_eq!(lines[1].0, 6);
+        assert_eq!(tokenize(&lines[0].1).unwrap(), ["SecRule", "ARGS", "@rx a\"b", "id:1, deny"]);
+        // backslashes that aren't before a quote stay for the regex
+        assert_eq!(tokenize(r#"SecRule ARGS "@rx \d+""#).unwrap()[2], r"@rx \d+");
+        assert!(tokenize(r#"SecRule ARGS "@rx open"#).is_err());
+
+        let (rules, warnings) = parse(text);
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].when.args_regex.as_deref(), Some(&["a\"b".to_string()][..]));
+        assert!(matches!(rules[0].action, Action::Block));
+    }
+
+    #[test]
+    fn splits_actions_outside_quotes() {
+        assert_eq!(split_actions("id:1,msg:'a, b',deny"), ["id:1", "msg:'a, b'", "deny"]);
+        let (rules, _) = parse(r#"SecRule ARGS "@pm x" "id:1,msg:'a, b',tag:'t1',deny""#);
+        assert_eq!(rules[0]._description.as_deref(), Some("a, b"));
+        assert_eq!(rules[0].tags.as_deref(), Some(&["t1".to_string()][..]));
+    }
+
+    #[test]
+    fn pass_and_missing_action_log() {
+        let (rules, _) = parse(
+            "SecRule ARGS \"@pm x\" \"id:1,pass\"\nSecRule ARGS \"@pm y\" \"id:2\"\nSecRule ARGS \"@pm z\" \"id:3,allow\"",
+        );
+        assert!(matches!(rules[0].action, Action::Log));
+        assert!(matches!(rules[1].action, Action::Log));
+        assert!(matches!(rules[2].action, Action::Allow));
+    }
+
+    #[test]
+    fn expands_chains_into_alternatives() {
+        let text = "SecRule ARGS|REQUEST_URI \"@contains evil\" \"id:1,deny,chain\"\n\
+                    SecRule REQUEST_HEADERS:User-Agent|REQUEST_HEADERS:X-Test \"@pm bot\" \"t:lowercase\"";
+        let (rules, warnings) = parse(text);
+        assert_eq!(rules.len(), 4);
+        assert!(rules.iter().all(|r| r.id == "1" && r.when.header_regex.as_ref().is_some_and(|h| h.len() == 1)));
+        assert_eq!(rules.iter().filter(|r| r.when.args_regex.is_some()).count(), 2);
+        assert_eq!(rules.iter().filter(|r| r.when.uri_regex.is_some()).count(), 2);
+        assert!(warned(&warnings, 2, "chained rule transforms differ"));
+
+        // 6 * 6 = 36 combinations
+        let six = "ARGS|REQUEST_URI|REQUEST_BODY|REQUEST_HEADERS:a|REQUEST_HEADERS:b|REQUEST_HEADERS:c";
+        let text = format!("SecRule {six} \"@contains x\" \"id:2,deny,chain\"\nSecRule {six} \"@contains y\"");
+        let (rules, warnings) = parse(&text);
+        assert!(rules.is_empty());
+        assert!(warned(&warnings, 1, &format!("36 variable combinations (max {MAX_ALTERNATIVES})")));
+
+        let (rules, warnings) = parse("SecRule ARGS \"@rx a\" \"id:3,deny,chain\"");
+        assert!(rules.is_empty());
+        assert!(warned(&warnings, 1, "chain has no final rule"));
+    }
+
+    #[test]
+    fn warns_with_line_numbers() {
+        let text = "SecRule RESPONSE_BODY \"@rx a\" \"id:1,deny\"\n\
+                    SecRule ARGS \"@detectSQLi\" \"id:2,deny\"\n\
+                    SecRule ARGS \"@rx a\" \"id:3,phase:4,deny\"\n\
+                    SecRule ARGS|XML \"@rx a\" \"id:4,deny,foo\"\n\
+                    SecAction \"id:5\"\n\
+                    SecRule REQUEST_BODY \"@rx a\" \"id:6,deny,t:lowercase\"";
+        let (rules, w) = parse(text);
+        assert!(warned(&w, 1, "variable 'RESPONSE_BODY' not supported"));
+        assert!(warned(&w, 2, "operator @detectSQLi not supported"));
+        assert!(warned(&w, 3, "phase 4 not supported"));
+        assert!(warned(&w, 4, "variable 'XML' not supported"));
+        assert!(warned(&w, 4, "action 'foo' not supported"));
+        assert!(warned(&w, 5, "directive SecAction not supported"));
+        assert!(warned(&w, 6, "transforms on REQUEST_BODY not supported"));
+        // only rule 4 survives, on its supported variable
+        assert_eq!(rules.iter().map(|r| r.id.as_str()).collect::<Vec<_>>(), ["4"]);
+    }
+
+    #[test]
+    fn contains_is_case_sensitive_on_every_args_form() {
+        let (rules, _) = parse("SecRule ARGS \"@contains Foo\" \"id:1,deny\"\nSecRule ARGS:q \"@contains Foo\" \"id:2,deny\"");
+        let rs = CompiledRuleset::from_rules(None, rules).unwrap();
+        let args = |v: &str| vec![("q".to_string(), v.to_string())];
+        for r in &rs.rules {
+            let re_hit = |v: &str| match &r.args_regex {
+                Some(p) => regex::Regex::new(&p[0]).unwrap().is_match(v),
+                None => true,
+            };
+            assert!(r.args_match(&args("a Foo b")) && re_hit("a Foo b"), "rule {}", r.id);
+            assert!(!(r.args_match(&args("a foo b")) && re_hit("a foo b")), "rule {}", r.id);
+        }
+    }
+
+    #[test]
+    fn rx_and_streq_on_named_args() {
+        let text = "SecRule ARGS:q \"@rx ^a\\d+$\" \"id:1,deny\"\n\
+                    SecRule ARGS:q \"b\\d+\" \"id:2,deny\"\n\
+                    SecRule ARGS_GET:q \"@streq a.b\" \"id:3,deny\"";
+        let (rules, warnings) = parse(text);
+        assert!(warnings.is_empty(), "{warnings:?}");
+        let named = |i: usize| rules[i].when.arg_regex.as_ref().map(|a| (a[0].name.as_str(), a[0].pattern.as_str()));
+        assert_eq!(named(0), Some(("q", r"^a\d+$")));
+        assert_eq!(named(1), Some(("q", r"b\d+")));
+        assert_eq!(named(2), Some(("q", r"^a\.b$")));
+
+        let rs = CompiledRuleset::from_rules(None, rules).unwrap();
+        let hit = |id: &str, name: &str, v: &str| {
+            let r = rs.rules.iter().find(|r| r.id == id).unwrap();
+            r.args_match(&[(name.to_string(), v.to_string())])
+        };
+        assert!(hit("1", "q", "a12"));
+        assert!(!hit("1", "q", "xa12"));
+        assert!(!hit("1", "other", "a12"));
+        assert!(hit("2", "q", "xb7"));
+        assert!(hit("3", "q", "a.b"));
+        assert!(!hit("3", "q", "axb"));
+        assert!(!hit("3", "q", "a.bc"));
+    }
+}
@@ -0,0 +1,163 @@
+use std::borrow::Cow;
+use std::cell::OnceCell;
+use std::collections::{HashMap, HashSet};
+
+use super::context::{HeaderView, WafContext};
+use super::cookie::{cookie_pairs, get_cookie_value};
+use super::exclusion::ExcludedTargets;
+use super::normalizer::Normalizer;
+use super::rules::compiler::{CompiledRuleset, TransformChain};
+use super::rules::rule::DetectTarget;
+
+/// Request values as seen by rules on one transformation chain.
+///
+/// Excluded arguments are dropped from `args` and from the raw `query`; excluded headers read as
+/// missing through `get` but still count for `has_header`, so presence checks are unaffected.
+/// Repeated headers keep every value (`get_all`); value conditions match if any of them does.
+pub struct Targets<'a> {
+    pub path: Cow<'a, str>,
+    pub query: Cow<'a, str>,
+    pub args: Cow<'a, [(String, String)]>,
+    headers: HeaderTargets<'a>,
+    raw_headers: &'a dyn HeaderView,
+    /// Rules on this chain whose `uri_regex` / `args_regex` matched, from one `RegexSet` scan each.
+    pub uri_re_hits: HashSet<usize>,
+    pub args_re_hits: HashSet<usize>,
+}
+
+enum HeaderTargets<'a> {
+    Raw(&'a dyn HeaderView),
+    /// Only the headers rules on this chain look at, every value of each.
+    Transformed(HashMap<String, Vec<String>>),
+}
+
+impl HeaderView for Targets<'_> {
+    fn get(&self, name: &str) -> Option<&str> {
+        match &self.headers {
+            HeaderTargets::Raw(h) => h.get(name),
+            HeaderTargets::Transformed(m) => m.get(name).and_then(|v| v.first()).map(|s| s.as_str()),
+        }
+    }
+
+    fn get_all(&self, name: &str) -> Vec<&str> {
+        match &self.headers {
+            HeaderTargets::Raw(h) => h.get_all(name),
+            HeaderTargets::Transformed(m) => m.get(name).map(|v| v.iter().map(|s| s.as_str()).collect()).unwrap_or_default(),
+        }
+    }
+}
+
+impl Targets<'_> {
+    /// Whether the request carries `name`, exclusions notwithstanding.
+    pub fn has_header(&self, name: &str) -> bool {
+        self.raw_headers.get(name).is_some()
+    }
+
+    /// Values a detector should look at for `target`; body targets have none here.
+    pub fn detect_values(&self, target: &DetectTarget) -> Vec<&str> {
+        match target {
+            DetectTarget::Path => vec![&self.path],
+            DetectTarget::Query => vec![&self.query],
+            DetectTarget::Args => self.args.iter().map(|(_, v)| v.as_str()).collect(),
+            DetectTarget::Arg(n) => self.args.iter().filter(|(k, _)| k == n).map(|(_, v)| v.as_str()).collect(),
+            DetectTarget::Header(n) => self.get_all(n),
+            // HTTP/2 clients may split cookies over several `cookie` headers
+            DetectTarget::Cookies => self.get_all("cookie").into_iter().flat_map(|c| cookie_pairs(c).map(|(_, v)| v)).collect(),
+            DetectTarget::Cookie(n) => self.get_all("cookie").into_iter().filter_map(|c| get_cookie_value(c, n)).collect(),
+            DetectTarget::BodyArgs | DetectTarget::BodyArg(_) => Vec::new(),
+        }
+    }
+}
+
+/// Per-request cache: each chain's targets are built on first use and shared by every rule on it.
+pub struct TransformCache<'a> {
+    rs: &'a CompiledRuleset,
+    ctx: &'a WafContext,
+    headers: &'a dyn HeaderView,
+    slots: Vec<OnceCell<Targets<'a>>>,
+}
+
+impl<'a> TransformCache<'a> {
+    pub fn new(rs: &'a CompiledRuleset, ctx: &'a WafContext, headers: &'a dyn HeaderView) -> Self {
+        Self {
+            rs,
+            ctx,
+            headers,
+            slots: (0..rs.chains.len()).map(|_| OnceCell::new()).collect(),
+        }
+    }
+
+    pub fn targets(&self, chain: usize) -> &Targets<'a> {
+        self.slots[chain].get_or_init(|| self.build(&self.rs.chains[chain], None))
+    }
+
+    /// Targets for a single rule with some arguments / headers excluded by policy; not cached.
+    pub fn targets_excluding(&self, chain: usize, excl: &ExcludedTargets) -> Targets<'a> {
+        self.build(&self.rs.chains[chain], Some(excl))
+    }
+
+    fn build(&self, chain: &TransformChain, excl: Option<&ExcludedTargets>) -> Targets<'a> {
+        let ctx = self.ctx;
+        let mut t = if chain.transforms.is_empty() && excl.is_none() {
+            Targets {
+                path: Cow::Borrowed(ctx.path.as_str()),
+                query: Cow::Borrowed(ctx.query.as_deref().unwrap_or("")),
+                args: Cow::Borrowed(ctx.args.as_slice()),
+                headers: HeaderTargets::Raw(self.headers),
+                raw_headers: self.headers,
+                uri_re_hits: HashSet::new(),
+                args_re_hits: HashSet::new(),
+            }
+        } else {
+            let t = |s: &str| Normalizer::apply_chain(&chain.transforms, s);
+            let headers = chain
+                .headers
+                .iter()
+                .filter(|n| !excl.is_some_and(|x| x.has_header(n)))
+                .map(|n| (n.clone(), self.headers.get_all(n).into_iter().map(t).collect::<Vec<_>>()))
+                .filter(|(_, v)| !v.is_empty())
+                .collect();
+            let args = ctx
+                .args
+                .iter()
+                .filter(|(k, _)| !excl.is_some_and(|x| x.has_arg(k)))
+                .map(|(k, v)| (t(k), t(v)))
+                .collect();
+            let query = ctx.query.as_deref().unwrap_or("");
+            let query = match excl {
+                Some(x) if !x.args.is_empty() => Cow::Owned(strip_args(query, x)),
+                _ => Cow::Borrowed(query),
+            };
+
+            Targets {
+                path: Cow::Owned(t(&ctx.path)),
+                query: Cow::Owned(t(&query)),
+                args: Cow::Owned(args),
+                headers: HeaderTargets::Transformed(headers),
+                raw_headers: self.headers,
+                uri_re_hits: HashSet::new(),
+                args_re_hits: HashSet::new(),
+            }
+        };
+
+        if let Some(re) = &chain.uri_re {
+            t.uri_re_hits = re.matched_rules([t.path.as_bytes(), t.query.as_bytes()]);
+        }
+        if let Some(re) = &chain.args_re {
+            t.args_re_hits = re.matched_rules(t.args.iter().map(|(_, v)| v.as_bytes()));
+        }
+        t
+    }
+}
+
+/// The raw query without the `name=value` pairs of excluded arguments.
+fn strip_args(query: &str, excl: &ExcludedTargets) -> String {
+    query
+        .split('&')
+        .filter(|pair| {
+            let name = pair.split('=').next().unwrap_or("");
+            !form_urlencoded::parse(name.as_bytes()).next().is_some_and(|(k, _)| excl.has_arg(&k))
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
@@ -1,33 +1,73 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use async_trait::async_trait;
 use pingora::server::ShutdownWatch;
 use pingora_core::services::background::BackgroundService;
 
 use super::engine::WafEngine;
-use super::rules::compiler::compile_from_file;
+use super::rules::compiler::{compile_from_file, RulesSource};
 
+/// Per-file hot reload: only rulesets whose file changed are recompiled and swapped.
+/// A deleted file keeps its last loaded ruleset, so policies bound to it keep working.
 pub struct RuleUpdater {
     engine: WafEngine,
-    rules_path: PathBuf,
+    source: RulesSource,
     interval: Duration,
 }
 
 impl RuleUpdater {
-    pub fn new(engine: WafEngine, rules_path: PathBuf, interval: Duration) -> Self {
+    pub fn new(engine: WafEngine, source: RulesSource, interval: Duration) -> Self {
         Self {
             engine,
-            rules_path,
+            source,
             interval,
         }
     }
+
+    async fn reload_changed(&self, last_mtime: &mut HashMap<PathBuf, SystemTime>) {
+        let files = match self.source.files() {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::warn!("rules source error: {}", e);
+                return;
+            }
+        };
+
+        for (name, path) in files {
+            let meta = match tokio::fs::metadata(&path).await {
+                Ok(m) => m,
+                Err(e) => {
+                    tracing::warn!("rules metadata error: {} err={}", path.display(), e);
+                    continue;
+                }
+            };
+            let Ok(mtime) = meta.modified() else {
+                continue;
+            };
+            if last_mtime.get(&path).map(|x| *x >= mtime).unwrap_or(false) {
+                continue;
+            }
+            last_mtime.insert(path.clone(), mtime);
+
+            match compile_from_file(&path) {
+                Ok(new_rules) => {
+                    self.engine.swap_ruleset(&name, new_rules);
+                    tracing::info!(ruleset=%name, "rules reloaded");
+                }
+                Err(e) => {
+                    tracing::error!(ruleset=%name, "rules reload failed (keep old): {:#}", e);
+                }
+            }
+        }
+    }
 }
 
 #[async_trait]
 impl BackgroundService for RuleUpdater {
     async fn start(&self, mut shutdown: ShutdownWatch) {
-        let mut last_mtime: Option<std::time::SystemTime> = None;
+        let mut last_mtime: HashMap<PathBuf, SystemTime> = HashMap::new();
         let mut ticker = tokio::time::interval(self.interval);
 
         loop {
@@ -37,31 +77,7 @@ impl BackgroundService for RuleUpdater {
                     return;
                 }
                 _ = ticker.tick() => {
-                    let meta = match tokio::fs::metadata(&self.rules_path).await {
-                        Ok(m) => m,
-                        Err(e) => {
-                            tracing::warn!("rules metadata error: {}", e);
-                            continue;
-                        }
-                    };
-                    let mtime = match meta.modified() {
-                        Ok(t) => t,
-                        Err(_) => continue,
-                    };
-                    if last_mtime.map(|x| x >= mtime).unwrap_or(false) {
-                        continue;
-                    }
-                    last_mtime = Some(mtime);
-
-                    match compile_from_file(&self.rules_path) {
-                        Ok(new_rules) => {
-                            self.engine.swap_rules(new_rules);
-                            tracing::info!("rules reloaded");
-                        }
-                        Err(e) => {
-                            tracing::error!("rules reload failed: {}", e);
-                        }
-                    }
+                    self.reload_changed(&mut last_mtime).await;
                 }
             }
         }